@@ -0,0 +1,114 @@
+//! Heap allocations per proxied connection, with and without the buffer pool
+//!
+//! Run with `cargo bench --bench allocations`.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{proxy_client, socks5_connect};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use stpro::{Config, DesyncConfig, ProxyServer, SplitConfig, SplitFlags};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const CONNECTIONS: usize = 1000;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// A target answering each connection's first read with a copy, then
+/// hanging up
+async fn one_shot_echo() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut request = [0u8; 64];
+                if let Ok(n) = stream.read(&mut request).await {
+                    stream.write_all(&request[..n]).await.ok();
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Proxy one request/response exchange through a desync tunnel
+async fn exchange(server: &Arc<ProxyServer>, target: SocketAddr) {
+    let (mut client, handler) = proxy_client(server);
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    client.write_all(&socks5_connect(target)).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    
+    client.write_all(b"ping").await.unwrap();
+    let mut response = [0u8; 4];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"ping");
+    drop(client);
+    handler.await.unwrap().unwrap();
+}
+
+/// Allocations and bytes allocated per connection
+async fn measure(buffer_pool_size: usize, target: SocketAddr) -> (usize, usize) {
+    let server = Arc::new(ProxyServer::new(Config {
+        buffer_pool_size,
+        // Desync keeps the tunnel on stpro's own forwarding loops
+        desync: DesyncConfig {
+            split: vec![SplitConfig {
+                offset: 1,
+                flags: SplitFlags::default(),
+                repeats: None,
+                skip: None,
+                jitter: None,
+            }],
+            ..DesyncConfig::default()
+        },
+        ..Config::default()
+    }));
+    // Fill the pool and any lazily initialised state first
+    exchange(&server, target).await;
+    
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    for _ in 0..CONNECTIONS {
+        exchange(&server, target).await;
+    }
+    (
+        (ALLOCATIONS.load(Ordering::Relaxed) - allocations) / CONNECTIONS,
+        (ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes) / CONNECTIONS,
+    )
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let target = one_shot_echo().await;
+    let default_pool = Config::default().buffer_pool_size;
+    for (label, pool) in [("without pool", 0), ("with pool", default_pool)] {
+        let (allocations, bytes) = measure(pool, target).await;
+        println!("{:<12}  {} allocations, {} bytes per connection", label, allocations, bytes);
+    }
+}
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(name) = stpro::extract_quic_sni(data) {
+        assert!(name.len() <= data.len());
+    }
+    if let Some(offset) = stpro::find_quic_sni_offset(data) {
+        assert!(offset <= data.len());
+    }
+});
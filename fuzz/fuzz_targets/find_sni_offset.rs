@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(offset) = stpro::find_sni_offset(data) {
+        assert!(offset <= data.len());
+        if let Some(end) = stpro::find_sni_end_offset(data) {
+            assert!(offset <= end && end <= data.len());
+        }
+    }
+});
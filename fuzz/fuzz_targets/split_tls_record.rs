@@ -0,0 +1,36 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The first two bytes select the split position, the rest is the record
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    
+    let position = u16::from_be_bytes([data[0], data[1]]) as usize;
+    let mut buffer = data[2..].to_vec();
+    let original_len = buffer.len();
+    
+    let record_len = |buffer: &[u8], at: usize| {
+        u16::from_be_bytes([buffer[at + 3], buffer[at + 4]]) as usize
+    };
+    let total = (buffer.len() >= 5).then(|| record_len(&buffer, 0));
+    
+    // A single cut in one pass frames exactly like split_tls_record
+    let mut multi = buffer.clone();
+    let multi_ok = stpro::split_tls_record_multi(&mut multi, &[position]).is_ok();
+    
+    if stpro::split_tls_record(&mut buffer, position).is_ok() {
+        assert!(multi_ok);
+        assert_eq!(multi, buffer);
+        assert_eq!(buffer.len(), original_len + 5);
+        // The two records carry exactly the original payload
+        let (first, second) = (record_len(&buffer, 0), record_len(&buffer, position));
+        assert_eq!(position, 5 + first);
+        assert!(first > 0 && second > 0);
+        assert_eq!(Some(first + second), total);
+    } else {
+        assert!(!multi_ok);
+    }
+});
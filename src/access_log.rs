@@ -0,0 +1,134 @@
+use crate::stats::ConnectionStats;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessLogConfig {
+    /// File to append access lines to (stdout when unset)
+    pub path: Option<PathBuf>,
+    pub format: AccessLogFormat,
+}
+
+/// Line layout, modelled on the Apache Common/Combined log formats
+///
+/// `Common`:   `client - user [time] "CONNECT target" outcome bytes_up bytes_down`
+/// `Combined`: `Common` followed by `duration_ms "strategy" ttfb_ms "tag"`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default)]
+pub enum AccessLogFormat {
+    Common,
+    #[default]
+    Combined,
+}
+
+/// Writes one line per completed connection
+///
+/// This is independent of the diagnostic output on stderr. When logging to
+/// a file, `reopen` lets log rotation move the file away and have a fresh
+/// one created (the server calls it on SIGUSR1).
+pub struct AccessLog {
+    config: AccessLogConfig,
+    file: Mutex<Option<File>>,
+}
+
+impl AccessLog {
+    pub fn open(config: AccessLogConfig) -> io::Result<Self> {
+        let file = match &config.path {
+            Some(path) => Some(open_append(path)?),
+            None => None,
+        };
+        
+        Ok(Self {
+            config,
+            file: Mutex::new(file),
+        })
+    }
+    
+    /// Close and reopen the log file so rotated files are released
+    pub fn reopen(&self) -> io::Result<()> {
+        if let Some(path) = &self.config.path {
+            let file = open_append(path)?;
+            *self.file.lock().unwrap() = Some(file);
+        }
+        Ok(())
+    }
+    
+    /// Append the access line for a finished connection
+    pub fn log(&self, stats: &ConnectionStats) {
+        let line = self.format_line(stats);
+        let mut file = self.file.lock().unwrap();
+        
+        let result = match file.as_mut() {
+            Some(file) => writeln!(file, "{}", line),
+            None => writeln!(io::stdout(), "{}", line),
+        };
+        
+        if let Err(e) = result {
+            eprintln!("[!] Failed to write access log: {}", e);
+        }
+    }
+    
+    pub fn format_line(&self, stats: &ConnectionStats) -> String {
+        let mut line = format!(
+            "{} - {} [{}] \"CONNECT {}\" {} {} {}",
+            stats.client_addr.ip(),
+            stats.username.as_deref().unwrap_or("-"),
+            format_clf_time(stats.started_at),
+            stats.target.as_deref().unwrap_or("-"),
+            stats.outcome,
+            stats.bytes_up,
+            stats.bytes_down,
+        );
+        
+        if let AccessLogFormat::Combined = self.config.format {
+            line.push_str(&format!(
+                " {} \"{}{}\" {} \"{}\"",
+                stats.duration.as_millis(),
+                if stats.canary { "canary:" } else { "" },
+                stats.strategy,
+                stats.ttfb.map_or("-".to_string(), |t| t.as_millis().to_string()),
+                stats.tag.as_deref().unwrap_or("-"),
+            ));
+        }
+        
+        line
+    }
+}
+
+fn open_append(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Format a timestamp as `10/Oct/2000:13:55:36 +0000` (always UTC)
+pub fn format_clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as i64;
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    
+    // Civil date from days since the epoch (proleptic Gregorian calendar)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+    )
+}
@@ -0,0 +1,181 @@
+use crate::config::{AutoConfig, AutoDetect, DesyncConfig};
+use crate::desync::DesyncEngine;
+use crate::lru::LruMap;
+use crate::packets::{http_header, is_http, is_tls_chello};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long to wait for the server's first response unless `timeout` is set
+const DEFAULT_AUTO_TIMEOUT: Duration = Duration::from_millis(3000);
+
+/// Maximum number of hosts whose working strategy is remembered before the
+/// least recently used is forgotten
+const STRATEGY_CACHE_CAPACITY: usize = 1024;
+
+/// TLS record type of a handshake message (a ServerHello, when all is well)
+const TLS_HANDSHAKE: u8 = 0x16;
+
+/// What came back from the target after the first flight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirstResponse<'a> {
+    /// The first bytes the target sent
+    Data(&'a [u8]),
+    /// The target closed the connection without sending anything
+    Closed,
+    /// The connection was reset
+    Reset,
+    /// Nothing arrived within the auto timeout
+    Timeout,
+}
+
+/// Verdict on whether a strategy got the first flight through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectionOutcome {
+    Passed,
+    /// The detector that tripped
+    Blocked(AutoDetect),
+}
+
+/// Check the target's reply to `request` against the enabled detectors
+///
+/// - `Torst`: the target reset or closed the connection, or stayed silent
+///   until the timeout
+/// - `Reset`: the target reset the connection
+/// - `Redirect`: an HTTP request got a 3xx pointing at another host (a
+///   block page)
+/// - `SslErr`: a ClientHello got something other than a handshake record,
+///   typically an alert
+pub fn detect(
+    detectors: &[AutoDetect],
+    request: &[u8],
+    response: &FirstResponse,
+) -> DetectionOutcome {
+    for &detector in detectors {
+        let tripped = match detector {
+            AutoDetect::Torst => !matches!(response, FirstResponse::Data(_)),
+            AutoDetect::Reset => matches!(response, FirstResponse::Reset),
+            AutoDetect::Redirect => match response {
+                FirstResponse::Data(data) => is_http(request) && redirects_away(request, data),
+                _ => false,
+            },
+            AutoDetect::SslErr => match response {
+                FirstResponse::Data(data) => {
+                    is_tls_chello(request) && data.first() != Some(&TLS_HANDSHAKE)
+                }
+                _ => false,
+            },
+            AutoDetect::None => false,
+        };
+        if tripped {
+            return DetectionOutcome::Blocked(detector);
+        }
+    }
+    DetectionOutcome::Passed
+}
+
+/// Whether `response` is an HTTP redirect to a host other than the one
+/// `request` was for
+fn redirects_away(request: &[u8], response: &[u8]) -> bool {
+    let status = response.get(9..12).and_then(|code| std::str::from_utf8(code).ok());
+    if !response.starts_with(b"HTTP/1.") || !status.is_some_and(|code| code.starts_with('3')) {
+        return false;
+    }
+    
+    // Only the header block is text; the body may be anything
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .unwrap_or(response.len());
+    let Some(location) = http_header(&response[..end], "Location") else {
+        return false;
+    };
+    let Some((_, rest)) = location.split_once("://") else {
+        // A relative redirect stays on the same host
+        return false;
+    };
+    let target = rest.split(['/', '?', '#']).next().unwrap_or("");
+    let target = strip_port(target).to_ascii_lowercase();
+    
+    let host = http_header(request, "Host").unwrap_or_default();
+    let host = strip_port(&host).to_ascii_lowercase();
+    !(target == host
+        || target.ends_with(&format!(".{}", host))
+        || host.ends_with(&format!(".{}", target)))
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !name.contains(':') && port.bytes().all(|b| b.is_ascii_digit()) => {
+            name
+        }
+        _ => host,
+    }
+}
+
+/// Fallback strategies of an auto config, with the one that last worked
+/// for each host
+#[derive(Debug)]
+pub struct AutoStrategies {
+    detect: Vec<AutoDetect>,
+    timeout: Duration,
+    fallbacks: Vec<DesyncEngine>,
+    max_retries: usize,
+    working: Mutex<LruMap<String, usize>>,
+}
+
+impl AutoStrategies {
+    pub fn new(config: &AutoConfig) -> Self {
+        Self {
+            detect: config.detect.clone(),
+            timeout: config.timeout.map_or(DEFAULT_AUTO_TIMEOUT, Duration::from_millis),
+            fallbacks: config
+                .fallbacks
+                .iter()
+                .map(|fallback| DesyncEngine::new(DesyncConfig { auto: None, ..fallback.clone() }))
+                .collect(),
+            max_retries: config.max_retries.unwrap_or(usize::MAX),
+            working: Mutex::new(LruMap::new(STRATEGY_CACHE_CAPACITY)),
+        }
+    }
+    
+    pub fn detectors(&self) -> &[AutoDetect] {
+        &self.detect
+    }
+    
+    /// How long to wait for the first response to a strategy
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+    
+    /// How many strategies a connection may try after its first one
+    pub fn max_retries(&self) -> usize {
+        self.max_retries
+    }
+    
+    /// Fallback strategies, in the order they are tried
+    pub fn fallbacks(&self) -> &[DesyncEngine] {
+        &self.fallbacks
+    }
+    
+    /// Index of the strategy to start with for `host` (0 is the primary
+    /// strategy, `i` is fallback `i - 1`)
+    pub fn preferred(&self, host: &str) -> usize {
+        let mut working = self.working.lock().unwrap();
+        working.get(host).copied().filter(|&i| i <= self.fallbacks.len()).unwrap_or(0)
+    }
+    
+    /// Remember that strategy `index` got through to `host`
+    pub fn remember(&self, host: &str, index: usize) {
+        let mut working = self.working.lock().unwrap();
+        if index == 0 {
+            working.remove(host);
+            return;
+        }
+        working.insert(host.to_string(), index);
+    }
+    
+    /// Start over with the primary strategy next time `host` is probed
+    pub fn forget(&self, host: &str) {
+        self.working.lock().unwrap().remove(host);
+    }
+}
@@ -0,0 +1,61 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// Forwarding buffers shared across connections
+///
+/// Connections rent a buffer for each forwarding direction and hand it back
+/// when they finish, so connection churn doesn't turn into allocator churn.
+/// At most `capacity` idle buffers are kept; the rest are freed on return.
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    size: usize,
+    capacity: usize,
+    idle: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    pub(crate) fn new(size: usize, capacity: usize) -> Self {
+        Self { size, capacity, idle: Mutex::new(Vec::new()) }
+    }
+    
+    /// Take an idle buffer, or allocate one if none is left; it returns to
+    /// the pool when dropped
+    pub(crate) fn rent(self: &Arc<Self>) -> PooledBuffer {
+        let buffer = self.idle.lock().unwrap().pop().unwrap_or_else(|| vec![0u8; self.size]);
+        PooledBuffer { buffer, pool: self.clone() }
+    }
+}
+
+/// A buffer of `size` bytes borrowed from a [`BufferPool`]
+pub(crate) struct PooledBuffer {
+    buffer: Vec<u8>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+    
+    fn deref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if self.pool.capacity == 0 {
+            return;
+        }
+        // Wipe the previous connection's data but keep the allocation
+        self.buffer.fill(0);
+        let mut idle = self.pool.idle.lock().unwrap();
+        if idle.len() < self.pool.capacity {
+            idle.push(std::mem::take(&mut self.buffer));
+        }
+    }
+}
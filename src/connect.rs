@@ -0,0 +1,642 @@
+use crate::config::{AddressPreference, Config, SocketOpts, UpstreamProxy};
+use crate::desync::SocketControl;
+use crate::lru::LruMap;
+use socket2::{SockRef, TcpKeepalive};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// Head start each connection attempt gets before the next address is
+/// tried in parallel, unless `connect_attempt_delay` is set (RFC 8305
+/// "Connection Attempt Delay")
+const DEFAULT_CONNECT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Longest a fake packet is waited for to leave the send queue before the
+/// real bytes are swapped in regardless
+#[cfg(target_os = "linux")]
+const FAKE_SEND_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Maximum number of hosts whose last working address is remembered; the
+/// least recently connected host is forgotten first
+const PREFERRED_CACHE_CAPACITY: usize = 1024;
+
+/// Whether the keepalive idle time can be set here (OpenBSD and Haiku
+/// only have the system-wide setting)
+pub const KEEPALIVE_TIME_SUPPORTED: bool =
+    !cfg!(any(target_os = "openbsd", target_os = "haiku"));
+
+/// Whether the time between keepalive probes can be set here
+pub const KEEPALIVE_INTERVAL_SUPPORTED: bool = cfg!(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+    target_os = "illumos",
+    target_os = "windows",
+));
+
+/// Why an outbound connection could not be established
+#[derive(Debug)]
+pub enum ConnectError {
+    /// No connect slot became free within the queue timeout
+    QueueTimeout,
+    /// `bind_addr` and the target are of different address families
+    FamilyMismatch { bind_addr: SocketAddr, target: SocketAddr },
+    /// The target wasn't reached within `connect_timeout`
+    Timeout(Duration),
+    Io(io::Error),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::QueueTimeout => f.write_str("timed out waiting for a connect slot"),
+            ConnectError::FamilyMismatch { bind_addr, target } => write!(
+                f,
+                "cannot reach {} from bind_addr {}: address families differ",
+                target, bind_addr
+            ),
+            ConnectError::Timeout(timeout) => {
+                write!(f, "timed out after {} ms", timeout.as_millis())
+            }
+            ConnectError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectError::QueueTimeout
+            | ConnectError::FamilyMismatch { .. }
+            | ConnectError::Timeout(_) => None,
+            ConnectError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for ConnectError {
+    fn from(e: io::Error) -> Self {
+        ConnectError::Io(e)
+    }
+}
+
+/// Opens outbound connections to targets
+///
+/// Socket options that must be in place before the SYN is sent (such as the
+/// outbound TTL) and the `bind_addr` source address are applied here. With
+/// `max_concurrent_connects` set, connects beyond the limit queue for a
+/// slot, for at most the configured queue timeout.
+#[derive(Debug, Clone, Default)]
+pub struct Connector {
+    bind_addr: Option<SocketAddr>,
+    outbound_ttl: Option<u8>,
+    slots: Option<Arc<Semaphore>>,
+    queue_timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    attempt_delay: Duration,
+    address_preference: AddressPreference,
+    preferred: Arc<PreferredAddrs>,
+    upstream: Option<UpstreamProxy>,
+    socket_opts: SocketOpts,
+}
+
+/// Address that last connected, per host
+#[derive(Debug)]
+struct PreferredAddrs {
+    addrs: Mutex<LruMap<String, SocketAddr>>,
+}
+
+impl Default for PreferredAddrs {
+    fn default() -> Self {
+        Self { addrs: Mutex::new(LruMap::new(PREFERRED_CACHE_CAPACITY)) }
+    }
+}
+
+impl Connector {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            bind_addr: config.bind_addr,
+            outbound_ttl: config.outbound_ttl,
+            slots: config.max_concurrent_connects.map(|n| Arc::new(Semaphore::new(n.max(1)))),
+            queue_timeout: config.connect_queue_timeout,
+            connect_timeout: config.connect_timeout,
+            attempt_delay: config.connect_attempt_delay.unwrap_or(DEFAULT_CONNECT_ATTEMPT_DELAY),
+            address_preference: config.address_preference,
+            preferred: Arc::default(),
+            upstream: config.upstream.clone(),
+            socket_opts: config.socket.clone(),
+        }
+    }
+    
+    /// Proxy that targets are reached through, if any
+    pub fn upstream(&self) -> Option<&UpstreamProxy> {
+        self.upstream.as_ref()
+    }
+    
+    /// Connect to `host`:`port`: through the upstream proxy when one is
+    /// configured (which resolves `host` itself, so `addrs` goes unused),
+    /// otherwise directly to whichever of `addrs` answers first
+    ///
+    /// Returns the stream and the target's address, which through an
+    /// upstream proxy is only known for IP targets; the proxy's address
+    /// stands in for a domain. Fails with `Timeout` once `connect_timeout`
+    /// has passed.
+    pub async fn connect_target(
+        &self,
+        host: &str,
+        port: u16,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<(TcpStream, SocketAddr), ConnectError> {
+        let connecting = self.open_target(host, port, addrs);
+        match self.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connecting)
+                .await
+                .map_err(|_| ConnectError::Timeout(timeout))?,
+            None => connecting.await,
+        }
+    }
+    
+    async fn open_target(
+        &self,
+        host: &str,
+        port: u16,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<(TcpStream, SocketAddr), ConnectError> {
+        let Some(upstream) = &self.upstream else {
+            return self.connect_any(host, addrs).await;
+        };
+        
+        let proxy_addrs = tokio::net::lookup_host((upstream.host.as_str(), upstream.port))
+            .await?
+            .collect();
+        let (mut stream, proxy_addr) = self.connect_any(&upstream.host, proxy_addrs).await?;
+        crate::upstream::tunnel(&mut stream, upstream, host, port).await?;
+        
+        let target_addr = host.parse::<IpAddr>().map_or(proxy_addr, |ip| SocketAddr::new(ip, port));
+        Ok((stream, target_addr))
+    }
+    
+    /// Connect to whichever of `host`'s addresses answers first
+    ///
+    /// Attempts are staggered Happy Eyeballs style: each address gets a
+    /// short head start before the next one is tried alongside it, and a
+    /// failed attempt starts the next one immediately. Addresses are tried
+    /// in the configured family order, except that the one that last
+    /// worked for `host` goes first. Addresses of a different family than
+    /// `bind_addr` are skipped.
+    pub async fn connect_any(
+        &self,
+        host: &str,
+        mut addrs: Vec<SocketAddr>,
+    ) -> Result<(TcpStream, SocketAddr), ConnectError> {
+        if let Some(bind_addr) = self.bind_addr {
+            let first = addrs.first().copied();
+            addrs.retain(|a| a.is_ipv4() == bind_addr.is_ipv4());
+            if let (true, Some(target)) = (addrs.is_empty(), first) {
+                return Err(ConnectError::FamilyMismatch { bind_addr, target });
+            }
+        }
+        
+        let mut addrs = order_addresses(addrs, self.address_preference);
+        let preferred = self.preferred.addrs.lock().unwrap().get(host).copied();
+        if let Some(preferred) = preferred {
+            if let Some(i) = addrs.iter().position(|a| *a == preferred) {
+                addrs[..=i].rotate_right(1);
+            }
+        }
+        
+        let mut pending = addrs.into_iter();
+        let mut attempts = JoinSet::new();
+        let mut last_error = None;
+        
+        loop {
+            if let Some(addr) = pending.next() {
+                let connector = self.clone();
+                attempts.spawn(async move { (addr, connector.connect(addr).await) });
+            } else if attempts.is_empty() {
+                break;
+            }
+            
+            let joined = if pending.len() > 0 {
+                tokio::select! {
+                    joined = attempts.join_next() => joined,
+                    _ = tokio::time::sleep(self.attempt_delay) => continue,
+                }
+            } else {
+                attempts.join_next().await
+            };
+            
+            match joined {
+                Some(Ok((addr, Ok(stream)))) => {
+                    self.remember(host, addr);
+                    return Ok((stream, addr));
+                }
+                Some(Ok((addr, Err(e)))) => {
+                    eprintln!("[*] Connect to {} failed: {}", addr, e);
+                    last_error = Some(e);
+                }
+                Some(Err(e)) => last_error = Some(ConnectError::Io(io::Error::other(e))),
+                None => {}
+            }
+        }
+        
+        Err(last_error.unwrap_or_else(|| {
+            ConnectError::Io(io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to"))
+        }))
+    }
+    
+    fn remember(&self, host: &str, addr: SocketAddr) {
+        self.preferred.addrs.lock().unwrap().insert(host.to_string(), addr);
+    }
+    
+    pub async fn connect(&self, addr: SocketAddr) -> Result<TcpStream, ConnectError> {
+        if let Some(bind_addr) = self.bind_addr.filter(|b| b.is_ipv4() != addr.is_ipv4()) {
+            return Err(ConnectError::FamilyMismatch { bind_addr, target: addr });
+        }
+        
+        let _permit = match &self.slots {
+            Some(slots) => {
+                let acquire = slots.clone().acquire_owned();
+                let permit = match self.queue_timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, acquire)
+                        .await
+                        .map_err(|_| ConnectError::QueueTimeout)?,
+                    None => acquire.await,
+                };
+                Some(permit.map_err(io::Error::other)?)
+            }
+            None => None,
+        };
+        
+        Ok(self.open(addr).await?)
+    }
+    
+    async fn open(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        
+        if let Some(ttl) = self.outbound_ttl {
+            set_ttl(&SockRef::from(&socket), addr, ttl as u32)?;
+        }
+        // Before connecting, so the buffer sizes count towards the window
+        // scale negotiated in the handshake
+        apply_socket_opts(&SockRef::from(&socket), &self.socket_opts);
+        
+        if let Some(bind_addr) = self.bind_addr {
+            socket.bind(bind_addr)?;
+        }
+        
+        socket.connect(addr).await
+    }
+}
+
+/// Interleave address families as `preference` asks, keeping the
+/// resolver's order within each family
+fn order_addresses(addrs: Vec<SocketAddr>, preference: AddressPreference) -> Vec<SocketAddr> {
+    let ipv6_first = match preference {
+        AddressPreference::Ipv6 => true,
+        AddressPreference::Ipv4 => false,
+        AddressPreference::Resolver => return addrs,
+    };
+    
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6() == ipv6_first);
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    while !first.is_empty() || !second.is_empty() {
+        ordered.extend(first.pop_front());
+        ordered.extend(second.pop_front());
+    }
+    ordered
+}
+
+/// Reads how many TCP segments a connection has sent so far
+///
+/// Holds the raw descriptor rather than the stream so it can be used next to
+/// the split halves; it must not outlive the stream it was created from.
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentCounter {
+    #[cfg(target_os = "linux")]
+    fd: std::os::unix::io::RawFd,
+}
+
+impl SegmentCounter {
+    pub fn new(stream: &TcpStream) -> Self {
+        #[cfg(not(target_os = "linux"))]
+        let _ = stream;
+        Self {
+            #[cfg(target_os = "linux")]
+            fd: std::os::unix::io::AsRawFd::as_raw_fd(stream),
+        }
+    }
+    
+    /// Segments sent (`tcpi_segs_out`), or None where unsupported
+    #[cfg(target_os = "linux")]
+    pub fn segments_out(&self) -> Option<u32> {
+        tcp_info(self.fd).map(|info| info.tcpi_segs_out)
+    }
+    
+    #[cfg(not(target_os = "linux"))]
+    pub fn segments_out(&self) -> Option<u32> {
+        None
+    }
+}
+
+/// Changes the TTL of an established connection between writes
+///
+/// Like `SegmentCounter`, holds the raw descriptor so it can be used next
+/// to the split halves, and must not outlive the stream it was created from.
+#[derive(Debug, Clone, Copy)]
+pub struct TtlControl {
+    #[cfg(unix)]
+    fd: std::os::unix::io::RawFd,
+    peer: SocketAddr,
+}
+
+impl TtlControl {
+    /// None where per-connection TTL changes aren't supported
+    pub fn new(stream: &TcpStream) -> Option<Self> {
+        #[cfg(unix)]
+        {
+            Some(Self {
+                fd: std::os::unix::io::AsRawFd::as_raw_fd(stream),
+                peer: stream.peer_addr().ok()?,
+            })
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = stream;
+            None
+        }
+    }
+}
+
+#[cfg(unix)]
+impl SocketControl for TtlControl {
+    fn ttl(&self) -> io::Result<u8> {
+        // SAFETY: the stream owning the descriptor outlives this handle
+        let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(self.fd) };
+        socket_ttl(&SockRef::from(&fd), self.peer)
+    }
+    
+    fn set_ttl(&self, ttl: u8) -> io::Result<()> {
+        // SAFETY: the stream owning the descriptor outlives this handle
+        let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(self.fd) };
+        set_ttl(&SockRef::from(&fd), self.peer, ttl as u32)
+    }
+    
+    /// The splice and the wait for the fake to leave block, so they run on
+    /// the blocking pool, on a duplicate of the descriptor that stays valid
+    /// even if the connection is dropped meanwhile
+    #[cfg(target_os = "linux")]
+    fn send_fake<'a>(
+        &'a self,
+        fake: &'a [u8],
+        real: &'a [u8],
+        ttl: u8,
+    ) -> crate::desync::SendFakeFuture<'a> {
+        Box::pin(async move {
+            if fake.len() != real.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "fake and real data differ in length",
+                ));
+            }
+            if fake.is_empty() {
+                return Ok(());
+            }
+            
+            // SAFETY: the stream owning the descriptor outlives this handle
+            let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(self.fd) };
+            let fd = fd.try_clone_to_owned()?;
+            let (fake, real, peer) = (fake.to_vec(), real.to_vec(), self.peer);
+            let sending = tokio::task::spawn_blocking(move || {
+                let socket = SockRef::from(&fd);
+                let original = socket_ttl(&socket, peer)?;
+                set_ttl(&socket, peer, ttl as u32)?;
+                let sent = splice_fake(std::os::fd::AsRawFd::as_raw_fd(&fd), &fake, &real);
+                let restored = set_ttl(&socket, peer, original as u32);
+                sent.and(restored)
+            });
+            sending.await.map_err(io::Error::other)?
+        })
+    }
+    
+    #[cfg(target_os = "linux")]
+    fn unsent_bytes(&self) -> io::Result<usize> {
+        tcp_info(self.fd)
+            .map(|info| info.tcpi_notsent_bytes as usize)
+            .ok_or_else(io::Error::last_os_error)
+    }
+}
+
+/// TTL (IPv6 hop limit) `socket` sends to `peer` with
+#[cfg(unix)]
+fn socket_ttl(socket: &SockRef<'_>, peer: SocketAddr) -> io::Result<u8> {
+    let ttl = match peer {
+        SocketAddr::V4(_) => socket.ttl_v4()?,
+        SocketAddr::V6(_) => socket.unicast_hops_v6()?,
+    };
+    Ok(ttl.min(u8::MAX as u32) as u8)
+}
+
+/// `TCP_INFO` of a socket, or None if it can't be read
+#[cfg(target_os = "linux")]
+fn tcp_info(fd: std::os::unix::io::RawFd) -> Option<libc::tcp_info> {
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (ret == 0).then_some(info)
+}
+
+/// Send `fake` on socket `fd`, leaving `real` behind for retransmissions
+///
+/// The fake is copied into a private mapping and spliced into the socket
+/// through a pipe, so the kernel queues references to the mapping's pages
+/// instead of a copy. Once the fake has left the send queue the mapping is
+/// overwritten with `real`: anything the kernel sends again for those
+/// sequence numbers carries the real bytes.
+#[cfg(target_os = "linux")]
+fn splice_fake(fd: std::os::unix::io::RawFd, fake: &[u8], real: &[u8]) -> io::Result<()> {
+    let len = fake.len();
+    let page = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if page == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    let page = page as *mut u8;
+    
+    let mut pipe = [0; 2];
+    if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        let e = io::Error::last_os_error();
+        unsafe { libc::munmap(page as *mut libc::c_void, len) };
+        return Err(e);
+    }
+    
+    // SAFETY: the mapping is `len` bytes long and only used here
+    unsafe { std::ptr::copy_nonoverlapping(fake.as_ptr(), page, len) };
+    let sent = splice_through(fd, pipe, page, len);
+    if sent.is_ok() {
+        wait_sent(fd);
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(real.as_ptr(), page, len);
+        libc::close(pipe[0]);
+        libc::close(pipe[1]);
+        libc::munmap(page as *mut libc::c_void, len);
+    }
+    sent
+}
+
+/// Move `len` bytes at `page` into socket `fd` by way of `pipe`
+#[cfg(target_os = "linux")]
+fn splice_through(
+    fd: std::os::unix::io::RawFd,
+    pipe: [libc::c_int; 2],
+    page: *mut u8,
+    len: usize,
+) -> io::Result<()> {
+    let mut offset = 0;
+    while offset < len {
+        let iov = libc::iovec {
+            iov_base: unsafe { page.add(offset) } as *mut libc::c_void,
+            iov_len: len - offset,
+        };
+        let queued = unsafe { libc::vmsplice(pipe[1], &iov, 1, 0) };
+        if queued < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        
+        let mut left = queued as usize;
+        while left > 0 {
+            let moved = unsafe {
+                libc::splice(
+                    pipe[0],
+                    std::ptr::null_mut(),
+                    fd,
+                    std::ptr::null_mut(),
+                    left,
+                    0,
+                )
+            };
+            if moved < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    return Err(e);
+                }
+                // The socket is non-blocking; wait for room in its buffer
+                let mut pollfd = libc::pollfd { fd, events: libc::POLLOUT, revents: 0 };
+                let timeout = FAKE_SEND_TIMEOUT.as_millis() as libc::c_int;
+                if unsafe { libc::poll(&mut pollfd, 1, timeout) } <= 0 {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "socket not writable"));
+                }
+                continue;
+            }
+            left -= moved as usize;
+        }
+        offset += queued as usize;
+    }
+    Ok(())
+}
+
+/// Wait (briefly) until nothing queued on socket `fd` is still unsent
+#[cfg(target_os = "linux")]
+fn wait_sent(fd: std::os::unix::io::RawFd) {
+    let deadline = std::time::Instant::now() + FAKE_SEND_TIMEOUT;
+    while tcp_info(fd).is_some_and(|info| info.tcpi_notsent_bytes > 0)
+        && std::time::Instant::now() < deadline
+    {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Apply `opts` to a client or target socket; options the OS refuses are
+/// reported and skipped
+pub fn apply_socket_opts(socket: &SockRef<'_>, opts: &SocketOpts) {
+    let mut results = vec![("TCP_NODELAY", socket.set_tcp_nodelay(opts.nodelay))];
+    if let Some(time) = opts.keepalive {
+        let keepalive = with_interval(TcpKeepalive::new().with_time(time), opts.keepalive_interval);
+        results.push(("TCP keepalive", socket.set_tcp_keepalive(&keepalive)));
+    }
+    if let Some(size) = opts.recv_buffer_size {
+        results.push(("SO_RCVBUF", socket.set_recv_buffer_size(size)));
+    }
+    if let Some(size) = opts.send_buffer_size {
+        results.push(("SO_SNDBUF", socket.set_send_buffer_size(size)));
+    }
+    
+    for (option, result) in results {
+        if let Err(e) = result {
+            eprintln!("[!] Failed to set {}: {}", option, e);
+        }
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+    target_os = "illumos",
+    target_os = "windows",
+))]
+fn with_interval(keepalive: TcpKeepalive, interval: Option<Duration>) -> TcpKeepalive {
+    match interval {
+        Some(interval) => keepalive.with_interval(interval),
+        None => keepalive,
+    }
+}
+
+/// The interval can't be set here and is left to the OS
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+    target_os = "illumos",
+    target_os = "windows",
+)))]
+fn with_interval(keepalive: TcpKeepalive, _interval: Option<Duration>) -> TcpKeepalive {
+    keepalive
+}
+
+/// Set the IPv4 TTL or IPv6 hop limit, depending on the address family
+pub fn set_ttl(socket: &SockRef<'_>, addr: SocketAddr, ttl: u32) -> io::Result<()> {
+    match addr {
+        SocketAddr::V4(_) => socket.set_ttl_v4(ttl),
+        SocketAddr::V6(_) => socket.set_unicast_hops_v6(ttl),
+    }
+}
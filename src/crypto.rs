@@ -0,0 +1,269 @@
+//! Just enough cryptography to read QUIC Initial packets
+//!
+//! Initial packets are protected with keys anyone can derive from the
+//! packet itself (RFC 9001, section 5.2), so reading the ClientHello inside
+//! needs SHA-256, HKDF, AES-128 and GCM but no secrets. These are plain
+//! implementations for reading a packet or two per flow; they make no
+//! attempt at constant time and must not protect anything.
+
+/// SHA-256 round constants
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 initial hash value
+const H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+    0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// AES S-box
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h = H0;
+    let bit_len = (data.len() as u64).wrapping_mul(8);
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&bit_len.to_be_bytes());
+    
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (word, add) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+    
+    let mut out = [0u8; 32];
+    for (chunk, word) in out.chunks_exact_mut(4).zip(h) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    out
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..32].copy_from_slice(&sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    
+    let mut inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let mut outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+/// HKDF-Extract with SHA-256 (RFC 5869)
+pub(crate) fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> [u8; 32] {
+    hmac_sha256(salt, ikm)
+}
+
+/// HKDF-Expand-Label from TLS 1.3 (RFC 8446, section 7.1) with an empty
+/// context, for outputs of up to one hash length
+pub(crate) fn hkdf_expand_label(secret: &[u8], label: &str, len: usize) -> Vec<u8> {
+    let label = format!("tls13 {}", label);
+    let mut info = Vec::with_capacity(4 + label.len());
+    info.extend_from_slice(&(len as u16).to_be_bytes());
+    info.push(label.len() as u8);
+    info.extend_from_slice(label.as_bytes());
+    info.push(0); // Context length
+    // Single HKDF-Expand block: T(1) = HMAC(secret, info | 0x01)
+    info.push(1);
+    hmac_sha256(secret, &info)[..len.min(32)].to_vec()
+}
+
+/// AES-128 block cipher, encryption direction only (CTR and GCM never
+/// decrypt blocks)
+pub(crate) struct Aes128 {
+    round_keys: [[u8; 16]; 11],
+}
+
+impl Aes128 {
+    pub(crate) fn new(key: &[u8; 16]) -> Self {
+        let mut words = [[0u8; 4]; 44];
+        for (word, chunk) in words.iter_mut().zip(key.chunks_exact(4)) {
+            word.copy_from_slice(chunk);
+        }
+        let mut rcon = 1u8;
+        for i in 4..44 {
+            let mut word = words[i - 1];
+            if i % 4 == 0 {
+                word.rotate_left(1);
+                for byte in &mut word {
+                    *byte = SBOX[*byte as usize];
+                }
+                word[0] ^= rcon;
+                rcon = xtime(rcon);
+            }
+            for (byte, prev) in word.iter_mut().zip(words[i - 4]) {
+                *byte ^= prev;
+            }
+            words[i] = word;
+        }
+        
+        let mut round_keys = [[0u8; 16]; 11];
+        for (round_key, group) in round_keys.iter_mut().zip(words.chunks_exact(4)) {
+            for (chunk, word) in round_key.chunks_exact_mut(4).zip(group) {
+                chunk.copy_from_slice(word);
+            }
+        }
+        Self { round_keys }
+    }
+    
+    pub(crate) fn encrypt_block(&self, block: &[u8; 16]) -> [u8; 16] {
+        let mut state = *block;
+        add_round_key(&mut state, &self.round_keys[0]);
+        for round in 1..11 {
+            for byte in &mut state {
+                *byte = SBOX[*byte as usize];
+            }
+            shift_rows(&mut state);
+            if round < 10 {
+                mix_columns(&mut state);
+            }
+            add_round_key(&mut state, &self.round_keys[round]);
+        }
+        state
+    }
+}
+
+fn xtime(byte: u8) -> u8 {
+    (byte << 1) ^ if byte & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+fn add_round_key(state: &mut [u8; 16], key: &[u8; 16]) {
+    for (byte, k) in state.iter_mut().zip(key) {
+        *byte ^= k;
+    }
+}
+
+/// The state is column-major: byte `4 * column + row`
+fn shift_rows(state: &mut [u8; 16]) {
+    let old = *state;
+    for column in 0..4 {
+        for row in 1..4 {
+            state[4 * column + row] = old[4 * ((column + row) % 4) + row];
+        }
+    }
+}
+
+fn mix_columns(state: &mut [u8; 16]) {
+    for column in state.chunks_exact_mut(4) {
+        let [a0, a1, a2, a3] = [column[0], column[1], column[2], column[3]];
+        let all = a0 ^ a1 ^ a2 ^ a3;
+        column[0] ^= all ^ xtime(a0 ^ a1);
+        column[1] ^= all ^ xtime(a1 ^ a2);
+        column[2] ^= all ^ xtime(a2 ^ a3);
+        column[3] ^= all ^ xtime(a3 ^ a0);
+    }
+}
+
+/// Decrypt and authenticate AES-128-GCM `ciphertext` (tag appended) with a
+/// 96-bit nonce; None if the tag doesn't match
+pub(crate) fn aes128_gcm_open(
+    key: &[u8; 16],
+    nonce: &[u8; 12],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> Option<Vec<u8>> {
+    let split = ciphertext.len().checked_sub(16)?;
+    let (data, tag) = ciphertext.split_at(split);
+    let aes = Aes128::new(key);
+    let h = u128::from_be_bytes(aes.encrypt_block(&[0u8; 16]));
+    
+    let mut counter = [0u8; 16];
+    counter[..12].copy_from_slice(nonce);
+    counter[15] = 1;
+    let tag_mask = aes.encrypt_block(&counter);
+    
+    let mut ghash = 0u128;
+    for chunk in aad.chunks(16).chain(data.chunks(16)) {
+        let mut block = [0u8; 16];
+        block[..chunk.len()].copy_from_slice(chunk);
+        ghash = gf128_mul(ghash ^ u128::from_be_bytes(block), h);
+    }
+    let lengths = ((aad.len() as u128 * 8) << 64) | (data.len() as u128 * 8);
+    ghash = gf128_mul(ghash ^ lengths, h);
+    let expected = (ghash ^ u128::from_be_bytes(tag_mask)).to_be_bytes();
+    if expected.iter().zip(tag).fold(0, |diff, (a, b)| diff | (a ^ b)) != 0 {
+        return None;
+    }
+    
+    let mut plaintext = Vec::with_capacity(data.len());
+    for (i, chunk) in data.chunks(16).enumerate() {
+        counter[12..].copy_from_slice(&(i as u32 + 2).to_be_bytes());
+        let keystream = aes.encrypt_block(&counter);
+        plaintext.extend(chunk.iter().zip(keystream).map(|(c, k)| c ^ k));
+    }
+    Some(plaintext)
+}
+
+/// Multiplication in GF(2^128) with GCM's bit order
+fn gf128_mul(x: u128, y: u128) -> u128 {
+    const R: u128 = 0xe1 << 120;
+    let mut z = 0u128;
+    let mut v = y;
+    for i in 0..128 {
+        if x & (1 << (127 - i)) != 0 {
+            z ^= v;
+        }
+        v = if v & 1 != 0 { (v >> 1) ^ R } else { v >> 1 };
+    }
+    z
+}
@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Detach from the terminal and keep running in the background
+///
+/// The usual double fork: the first child calls `setsid` to leave the
+/// terminal's session, and its own child, which can never reacquire a
+/// controlling terminal, carries on while both parents exit. stdin is
+/// pointed at /dev/null, as are stdout and stderr while they are a
+/// terminal; redirected elsewhere (`2>>stpro.log`), they are kept so the
+/// log still goes there. The working directory is left alone so relative
+/// paths in the config and flags keep their meaning.
+///
+/// This must be called before any threads are started, i.e. before the
+/// tokio runtime is built: only the calling thread survives a fork.
+#[cfg(unix)]
+pub fn daemonize() -> Result<()> {
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(std::io::Error::last_os_error()).context("setsid failed");
+    }
+    fork_and_exit_parent()?;
+    
+    let null = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("Failed to open /dev/null")?;
+    redirect(&null, libc::STDIN_FILENO)?;
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::isatty(fd) } == 1 {
+            redirect(&null, fd)?;
+        }
+    }
+    Ok(())
+}
+
+/// Stays in the foreground: there is no fork to detach with
+#[cfg(not(unix))]
+pub fn daemonize() -> Result<()> {
+    eprintln!("[!] --daemon is not supported on this platform, running in the foreground");
+    Ok(())
+}
+
+/// Fork, letting only the child return
+#[cfg(unix)]
+fn fork_and_exit_parent() -> Result<()> {
+    // The process is still single-threaded (see `daemonize`)
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error()).context("fork failed"),
+        0 => Ok(()),
+        // _exit skips destructors and atexit handlers, which belong to
+        // the child now
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/// Point `fd` at the file `to` has open
+#[cfg(unix)]
+fn redirect(to: &std::fs::File, fd: libc::c_int) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    
+    if unsafe { libc::dup2(to.as_raw_fd(), fd) } == -1 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to redirect descriptor {}", fd));
+    }
+    Ok(())
+}
+
+/// A file holding the PID of the running server, removed when dropped
+///
+/// A file left behind by a process that has died (after a crash or a
+/// `kill -9`) is stale and gets overwritten; one whose process is still
+/// alive means another instance is running, and is an error.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    pid: u32,
+}
+
+impl PidFile {
+    /// Fail if `path` names a process that is still running
+    ///
+    /// Called before daemonizing, so that the error reaches the terminal;
+    /// `create` checks again.
+    pub fn check(path: &Path) -> Result<()> {
+        match Self::running(path) {
+            Some(pid) => anyhow::bail!(
+                "{} belongs to a running process (pid {}); is stpro already running?",
+                path.display(),
+                pid
+            ),
+            None => Ok(()),
+        }
+    }
+    
+    /// Write this process's PID to `path`
+    pub fn create(path: &Path) -> Result<PidFile> {
+        Self::check(path)?;
+        let pid = std::process::id();
+        std::fs::write(path, format!("{}\n", pid))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(PidFile { path: path.to_path_buf(), pid })
+    }
+    
+    /// The live process, other than this one, whose PID is in `path`
+    fn running(path: &Path) -> Option<u32> {
+        let pid: u32 = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+        (pid != std::process::id() && process_alive(pid)).then_some(pid)
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Leave the file alone if another instance has since taken it over
+        let contents = std::fs::read_to_string(&self.path).unwrap_or_default();
+        if contents.trim().parse() == Ok(self.pid) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Whether a process with this PID exists
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    if pid <= 0 {
+        return false;
+    }
+    // Signal 0 only checks that the process could be signalled
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // EPERM: it exists but belongs to another user
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without a way to check, every PID file is taken to be stale
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    false
+}
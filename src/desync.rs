@@ -1,183 +1,1223 @@
-use crate::config::{DesyncConfig, SplitConfig};
-use crate::packets::{is_tls_chello, find_sni_offset, find_http_host_offset};
-use std::io;
-use tokio::io::AsyncWriteExt;
+use crate::auto::AutoStrategies;
+use crate::config::{DesyncConfig, SegmentSeparation, SplitConfig, SplitFlags};
+use crate::packets::{
+    is_tls_chello, is_http, is_http2_preface, find_sni_offset, find_sni_end_offset,
+    find_http_host_offset, split_tls_record_multi, tls_record_len,
+};
+use crate::lru::LruMap;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::future::Future;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, IoSlice};
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Maximum number of plans kept by the per-host plan cache before the least
+/// recently used is evicted
+const PLAN_CACHE_CAPACITY: usize = 1024;
+
+/// Leading bytes of a first flight hashed into its plan cache key
+const PLAN_KEY_PREFIX: usize = 512;
+
+/// Smallest payload `tls_rec` leaves in the record after a split
+const MIN_TLS_RECORD_PAYLOAD: usize = 5;
+
+/// TTL of disorder segments unless `ttl` is configured: low enough to be
+/// dropped by the first router
+const DEFAULT_DISORDER_TTL: u8 = 1;
+
+/// TTL of fake packets unless the fake or `ttl` configures one: enough to
+/// pass the DPI box, too little to reach most servers
+const DEFAULT_FAKE_TTL: u8 = 8;
+
+/// Longest `SegmentSeparation::WaitSent` waits for a segment to leave,
+/// about a slow round trip (the kernel waits for an ACK to send more)
+const MAX_SEPARATION_WAIT: Duration = Duration::from_millis(250);
+
+/// How often `SegmentSeparation::WaitSent` checks for unsent data
+const SEPARATION_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Boxed future returned by [`SocketControl::send_fake`]
+pub type SendFakeFuture<'a> = Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>>;
+
+/// Access to the socket under the stream a plan is written to, for
+/// techniques that change the IP options of individual segments
+pub trait SocketControl: Send + Sync {
+    /// TTL (IPv6 hop limit) of outgoing packets
+    fn ttl(&self) -> io::Result<u8>;
+    
+    /// Change the TTL (IPv6 hop limit) of packets sent from now on
+    fn set_ttl(&self, ttl: u8) -> io::Result<()>;
+    
+    /// Send `fake` with TTL `ttl`, arranging for retransmissions of those
+    /// bytes to carry `real` (of the same length) instead
+    ///
+    /// The fake expires on the way, so the server only ever receives
+    /// `real`. The TTL is restored before the future completes, also on
+    /// error. Returns `ErrorKind::Unsupported` where this isn't possible.
+    fn send_fake<'a>(&'a self, fake: &'a [u8], real: &'a [u8], ttl: u8) -> SendFakeFuture<'a> {
+        let _ = (fake, real, ttl);
+        Box::pin(async {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "fake packets not supported"))
+        })
+    }
+    
+    /// Bytes written to the socket that the kernel hasn't sent yet
+    ///
+    /// Returns `ErrorKind::Unsupported` where this can't be read.
+    fn unsent_bytes(&self) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "unsent bytes not available"))
+    }
+}
+
+/// Desync applied to the first flight of a connection, for embedding in
+/// programs other than this proxy
+///
+/// `stream` is whatever carries the connection towards the server: the
+/// buffer is written to it in full, in the segments the implementation
+/// chooses, and the number of bytes written is returned. Later data needs
+/// no desync and can be written to `stream` directly. [`DesyncEngine`]
+/// implements this through [`DesyncEngine::apply_desync`].
+///
+/// Wrapping a stream so its first write goes through desync:
+///
+/// ```
+/// use stpro::{parse_split_config, Desync, DesyncConfig, DesyncEngine, SegmentRecorder};
+/// use tokio::io::{AsyncWrite, AsyncWriteExt};
+///
+/// /// A stream whose first write is desynced
+/// struct DesyncStream<W, D> {
+///     inner: W,
+///     desync: Option<D>,
+/// }
+///
+/// impl<W: AsyncWrite + Unpin + Send, D: Desync> DesyncStream<W, D> {
+///     async fn send(&mut self, data: &[u8]) -> std::io::Result<()> {
+///         match self.desync.take() {
+///             Some(desync) => desync.apply(&mut self.inner, data).await.map(drop),
+///             None => self.inner.write_all(data).await,
+///         }
+///     }
+/// }
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> std::io::Result<()> {
+/// let engine = DesyncEngine::new(DesyncConfig {
+///     split: vec![parse_split_config("1+s").unwrap()],
+///     ..DesyncConfig::default()
+/// });
+/// let mut stream = DesyncStream { inner: SegmentRecorder::new(), desync: Some(engine) };
+/// let hello = stpro::sample_client_hello("example.com");
+/// stream.send(&hello).await?;
+/// stream.send(b"later data").await?;
+///
+/// // The ClientHello left in two segments, cut just inside the hostname
+/// let segments = stream.inner.into_segments();
+/// assert_eq!(segments.len(), 3);
+/// assert_eq!(segments[..2].concat(), hello);
+/// # Ok(())
+/// # }
+/// ```
+pub trait Desync {
+    /// Write `buffer` to `stream` with desync applied, returning the
+    /// number of bytes written
+    fn apply<W: AsyncWrite + Unpin + Send>(
+        &self,
+        stream: &mut W,
+        buffer: &[u8],
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+impl Desync for DesyncEngine {
+    fn apply<W: AsyncWrite + Unpin + Send>(
+        &self,
+        stream: &mut W,
+        buffer: &[u8],
+    ) -> impl Future<Output = io::Result<usize>> + Send {
+        self.apply_desync(stream, buffer)
+    }
+}
+
+/// A single step of a desync plan
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteOp {
+    /// Write `buffer[range]` and flush it so it leaves as its own segment
+    Segment(Range<usize>),
+    /// Write `buffer[range]` in the same vectored write as the next step,
+    /// so no segment boundary is forced after it
+    Queued(Range<usize>),
+    /// Like `Segment`, but sent with a low TTL so it is dropped on the way
+    /// and only reaches the server as a retransmission, after the data
+    /// following it
+    Disordered(Range<usize>),
+    /// Send the data of fake config `index` in place of `buffer[range]`
+    /// with a low TTL; retransmissions then deliver `buffer[range]`
+    Fake { index: usize, range: Range<usize> },
+}
+
+/// What the engine knows about the connection a buffer belongs to
+#[derive(Debug, Clone, Default)]
+pub struct FlowInfo {
+    /// Target host as requested by the client (domain or IP literal)
+    pub host: Option<String>,
+    pub port: Option<u16>,
+}
+
+/// What a first flight is, as far as the anchor flags are concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// A TLS ClientHello: `sni`, `sni_end` and `record_end` can anchor
+    Tls,
+    /// An HTTP/1 request: `host` can anchor
+    Http,
+    /// Anything else: every anchor flag falls back to its bare offset
+    Other,
+}
+
+impl Protocol {
+    /// Classify the first flight in `buffer`
+    pub fn detect(buffer: &[u8]) -> Self {
+        if is_tls_chello(buffer) {
+            Protocol::Tls
+        } else if is_http(buffer) {
+            Protocol::Http
+        } else {
+            Protocol::Other
+        }
+    }
+}
+
+/// Description of what the engine does with a buffer, for display in tools
+///
+/// Serializes to a stable JSON schema:
+/// `{"mode", "length", "is_tls", "protocol", "steps": [{"kind", "start",
+/// "end", "len", "split", "flush", "ttl"}]}`. `protocol` is `tls`, `http`
+/// or `other`. `start`/`end` are the buffer offsets a step covers (a fake
+/// stands in for those bytes), `split` describes the rule that produced
+/// the cut at `end` (null when the step runs to the end of the buffer) and
+/// `ttl` is the TTL a step is sent with when it differs from the
+/// connection's.
+#[derive(Debug, Clone, Serialize)]
+pub struct DesyncPlan {
+    pub mode: &'static str,
+    pub length: usize,
+    pub is_tls: bool,
+    pub protocol: Protocol,
+    pub steps: Vec<PlanStep>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlanStep {
+    /// `segment` (real data) or `fake`
+    pub kind: &'static str,
+    pub start: Option<usize>,
+    pub end: Option<usize>,
+    pub len: usize,
+    pub split: Option<SplitAnchor>,
+    /// Whether the write is flushed onto the wire before the next step
+    pub flush: bool,
+    pub ttl: Option<u8>,
+}
+
+/// The split rule behind a segment boundary
+#[derive(Debug, Clone, Serialize)]
+pub struct SplitAnchor {
+    /// Rule in command-line syntax, e.g. `5+s`
+    pub rule: String,
+    /// Position the offset is relative to: `sni`, `auto`, `host`,
+    /// `record_end`, `start` or `end`
+    pub anchor: &'static str,
+    pub offset: i64,
+}
+
+impl std::fmt::Display for DesyncPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "mode: {}, {} bytes{}",
+            self.mode,
+            self.length,
+            match self.protocol {
+                Protocol::Tls => " (TLS ClientHello)",
+                Protocol::Http => " (HTTP request)",
+                Protocol::Other => "",
+            }
+        )?;
+        for (i, step) in self.steps.iter().enumerate() {
+            match (step.start, step.end) {
+                (Some(start), Some(end)) => write!(
+                    f,
+                    "  {}. {} [{}..{}) {} bytes",
+                    i + 1,
+                    step.kind,
+                    start,
+                    end,
+                    step.len
+                )?,
+                _ => write!(f, "  {}. {} {} bytes", i + 1, step.kind, step.len)?,
+            }
+            if let Some(split) = &step.split {
+                write!(f, ", cut by {} ({}{:+})", split.rule, split.anchor, split.offset)?;
+            }
+            if let Some(ttl) = step.ttl {
+                write!(f, ", ttl {}", ttl)?;
+            }
+            writeln!(f, "{}", if step.flush { ", flush" } else { "" })?;
+        }
+        Ok(())
+    }
+}
+
+type PlanKey = (String, Option<u16>, usize, u64);
+
+/// Hash of the first `PLAN_KEY_PREFIX` bytes of `buffer`
+///
+/// Stands in for the anchors a rule cuts at, which would take parsing to
+/// find: the SNI or Host header sits in the first few hundred bytes, and
+/// two first flights of the same length that start alike place it alike.
+/// A ClientHello's random and session ID, new on every connection without
+/// moving anything, are left out.
+fn prefix_hash(buffer: &[u8]) -> u64 {
+    let prefix = &buffer[..buffer.len().min(PLAN_KEY_PREFIX)];
+    let mut hasher = DefaultHasher::new();
+    match prefix.get(43) {
+        // Record and handshake headers, version, random (11..43), then
+        // the session ID behind its length byte
+        Some(&session_len) if prefix[0] == 0x16 => {
+            prefix[..11].hash(&mut hasher);
+            session_len.hash(&mut hasher);
+            prefix[(44 + session_len as usize).min(prefix.len())..].hash(&mut hasher);
+        }
+        _ => prefix.hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
+/// Plans computed for previous first-flights, keyed on (host, port,
+/// length, prefix hash)
+#[derive(Debug)]
+struct PlanCache {
+    plans: Mutex<LruMap<PlanKey, Arc<Vec<WriteOp>>>>,
+}
+
+impl Default for PlanCache {
+    fn default() -> Self {
+        Self { plans: Mutex::new(LruMap::new(PLAN_CACHE_CAPACITY)) }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct DesyncEngine {
     config: DesyncConfig,
+    plan_cache: Option<Arc<PlanCache>>,
+    plans_computed: Arc<AtomicU64>,
+    auto: Option<Arc<AutoStrategies>>,
+    /// Source of split position jitter
+    rng: Arc<Mutex<StdRng>>,
 }
 
 impl DesyncEngine {
+    /// Engine for `config`, with jitter seeded from `config.seed` if set
     pub fn new(config: DesyncConfig) -> Self {
-        Self { config }
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self::with_rng(config, rng)
+    }
+    
+    /// Engine for `config` whose jitter is drawn from an RNG seeded with
+    /// `seed`, so the same buffers are split the same way on every run
+    pub fn with_seed(config: DesyncConfig, seed: u64) -> Self {
+        Self::with_rng(config, StdRng::seed_from_u64(seed))
+    }
+    
+    fn with_rng(config: DesyncConfig, rng: StdRng) -> Self {
+        // A cached plan would pin jittered positions to their first draw
+        let plan_cache = (config.plan_cache && !config.has_jitter()).then(Arc::default);
+        let auto = config.auto.as_ref().map(|auto| Arc::new(AutoStrategies::new(auto)));
+        Self {
+            config,
+            plan_cache,
+            plans_computed: Arc::new(AtomicU64::new(0)),
+            auto,
+            rng: Arc::new(Mutex::new(rng)),
+        }
+    }
+    
+    /// Strategies to fall back to when this one is blocked, in auto mode
+    pub fn auto(&self) -> Option<&Arc<AutoStrategies>> {
+        self.auto.as_ref()
+    }
+    
+    /// Name of the technique `apply_desync` will use
+    pub fn mode_name(&self) -> &'static str {
+        if !self.config.split.is_empty() {
+            "split"
+        } else if !self.config.disorder.is_empty() {
+            "disorder"
+        } else if !self.config.fake.is_empty() {
+            "fake"
+        } else if !self.config.tls_rec.is_empty() {
+            "tls_rec"
+        } else {
+            "none"
+        }
+    }
+    
+    /// Whether data passes through untouched, with no technique configured
+    pub fn is_passthrough(&self) -> bool {
+        !self.config.has_rules()
+    }
+    
+    /// Whether first-flight segmentation should be verified on the wire
+    pub fn verify_segments(&self) -> bool {
+        self.config.verify_segments
+    }
+    
+    /// Number of separate writes the plan for `buffer` performs
+    pub fn planned_segments(&self, buffer: &[u8], flow: &FlowInfo) -> usize {
+        let buffer = self.apply_tls_rec(buffer, flow);
+        self.cached_plan(&buffer, flow)
+            .iter()
+            .filter(|op| match op {
+                WriteOp::Segment(range) | WriteOp::Disordered(range) => !range.is_empty(),
+                WriteOp::Fake { range, .. } => !range.is_empty(),
+                WriteOp::Queued(_) => false,
+            })
+            .count()
+    }
+    
+    /// Boundaries between the `tls_rec` records of `buffer` that fall
+    /// inside a planned segment, so those records reach the wire together
+    pub fn shared_record_boundaries(&self, buffer: &[u8], flow: &FlowInfo) -> usize {
+        let buffer = self.apply_tls_rec(buffer, flow);
+        if matches!(buffer, Cow::Borrowed(_)) {
+            return 0;
+        }
+        let cuts: Vec<usize> = self
+            .cached_plan(&buffer, flow)
+            .iter()
+            .filter_map(|op| match op {
+                WriteOp::Segment(range) | WriteOp::Disordered(range) => Some(range.end),
+                WriteOp::Fake { range, .. } => Some(range.end),
+                WriteOp::Queued(_) => None,
+            })
+            .collect();
+        let mut shared = 0;
+        let mut pos = 0;
+        while let Some(len) = tls_record_len(&buffer[pos..]) {
+            pos += len;
+            if pos >= buffer.len() {
+                break;
+            }
+            if !cuts.contains(&pos) {
+                shared += 1;
+            }
+        }
+        shared
+    }
+    
+    /// Number of first flights parsed and planned from scratch (i.e. not
+    /// served from the cache)
+    pub fn plans_computed(&self) -> u64 {
+        self.plans_computed.load(Ordering::Relaxed)
     }
     
     /// Apply desync techniques to outgoing data
+    ///
+    /// This is the stable entry point for embedding the engine (see
+    /// [`Desync`]): it needs nothing but a stream and the first flight.
+    /// Without socket access disorder degrades to a plain split and fakes
+    /// are left out; `apply_desync_controlled` adds them.
     pub async fn apply_desync<W: AsyncWriteExt + Unpin>(
         &self,
         stream: &mut W,
         buffer: &[u8],
+    ) -> io::Result<usize> {
+        self.apply_desync_flow(stream, buffer, &FlowInfo::default()).await
+    }
+    
+    /// Apply desync techniques to data belonging to a known flow
+    ///
+    /// With `plan_cache` enabled, the plan for a buffer is reused for later
+    /// buffers to the same host with the same length and leading bytes,
+    /// skipping the ClientHello/HTTP parsing (`tls_rec` still parses each
+    /// buffer to re-frame it).
+    pub async fn apply_desync_flow<W: AsyncWriteExt + Unpin>(
+        &self,
+        stream: &mut W,
+        buffer: &[u8],
+        flow: &FlowInfo,
+    ) -> io::Result<usize> {
+        self.apply_desync_controlled(stream, None, buffer, flow).await
+    }
+    
+    /// Apply desync techniques, changing socket options through `control`
+    /// where a technique needs it
+    ///
+    /// Without `control`, disorder segments are sent in order with the normal
+    /// TTL (a plain split) and fakes are left out.
+    pub async fn apply_desync_controlled<W: AsyncWriteExt + Unpin>(
+        &self,
+        stream: &mut W,
+        control: Option<&dyn SocketControl>,
+        buffer: &[u8],
+        flow: &FlowInfo,
     ) -> io::Result<usize> {
         if buffer.is_empty() {
             return Ok(0);
         }
         
-        // Check if this is TLS ClientHello
-        let is_tls = is_tls_chello(buffer);
+        let buffer = self.apply_tls_rec(buffer, flow);
+        let plan = self.cached_plan(&buffer, flow);
+        self.execute(stream, control, &buffer, &plan).await
+    }
+    
+    /// Re-frame a ClientHello into several TLS records, one cut per
+    /// `tls_rec` rule
+    ///
+    /// Unlike a TCP split, every piece is a complete, valid record, so the
+    /// stream still parses as TLS. Cuts outside the first record, inside its
+    /// header, or leaving less than `MIN_TLS_RECORD_PAYLOAD` bytes on either
+    /// side are skipped. Other techniques then work on the re-framed buffer.
+    pub fn apply_tls_rec<'a>(&self, buffer: &'a [u8], flow: &FlowInfo) -> Cow<'a, [u8]> {
+        if self.config.tls_rec.is_empty() || !is_tls_chello(buffer) {
+            return Cow::Borrowed(buffer);
+        }
+        let Some(record_end) = tls_record_len(buffer) else {
+            return Cow::Borrowed(buffer);
+        };
+        
+        let mut positions: Vec<usize> = self.config.tls_rec
+            .iter()
+            .filter(|rule| self.rule_applies(rule, buffer, Protocol::Tls, flow))
+            .flat_map(|rule| self.split_positions(rule, buffer, Protocol::Tls))
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        
+        // Going from the back, each kept cut becomes the end of the record
+        // the cuts before it are measured against
+        let mut kept = Vec::with_capacity(positions.len());
+        let mut limit = record_end;
+        for &pos in positions.iter().rev() {
+            if pos < 5 + MIN_TLS_RECORD_PAYLOAD || pos + MIN_TLS_RECORD_PAYLOAD > limit {
+                eprintln!("[*] Skipping TLS record split at {}: record would be too short", pos);
+            } else if pos > buffer.len() {
+                eprintln!("[*] Skipping TLS record split at {}: past the data received", pos);
+            } else {
+                kept.push(pos);
+                limit = pos;
+            }
+        }
+        
+        if kept.is_empty() {
+            return Cow::Borrowed(buffer);
+        }
+        let mut framed = buffer.to_vec();
+        match split_tls_record_multi(&mut framed, &kept) {
+            Ok(()) => Cow::Owned(framed),
+            Err(e) => {
+                eprintln!("[*] Skipping TLS record splits: {}", e);
+                Cow::Borrowed(buffer)
+            }
+        }
+    }
+    
+    /// Compute the writes `apply_desync` performs for `buffer`
+    pub fn plan_writes(&self, buffer: &[u8]) -> Vec<WriteOp> {
+        self.plan_writes_flow(buffer, &FlowInfo::default())
+    }
+    
+    /// Compute the writes `apply_desync_flow` performs for `buffer`
+    pub fn plan_writes_flow(&self, buffer: &[u8], flow: &FlowInfo) -> Vec<WriteOp> {
+        self.plans_computed.fetch_add(1, Ordering::Relaxed);
+        
+        let protocol = Protocol::detect(buffer);
+        
+        // The HTTP/2 preface carries nothing a DPI box could match on, while
+        // a fake or out-of-band byte reaching a cleartext HTTP/2 peer ends
+        // the connection outright
+        if is_http2_preface(buffer) {
+            return vec![WriteOp::Segment(0..buffer.len())];
+        }
+        
+        if self.config.desync_only_tls_http && protocol == Protocol::Other {
+            return vec![WriteOp::Segment(0..buffer.len())];
+        }
         
-        // Apply split techniques
         if !self.config.split.is_empty() {
-            return self.apply_split(stream, buffer, is_tls).await;
+            return self.plan_split(buffer, protocol, flow);
         }
         
-        // Apply disorder techniques
         if !self.config.disorder.is_empty() {
-            return self.apply_disorder(stream, buffer, is_tls).await;
+            return self.plan_disorder(buffer, protocol, flow);
         }
         
-        // Apply fake packet techniques
         if !self.config.fake.is_empty() {
-            return self.apply_fake(stream, buffer, is_tls).await;
+            return self.plan_fake(buffer, protocol, flow);
         }
         
         // Default: send normally
-        stream.write_all(buffer).await?;
-        stream.flush().await?;
-        Ok(buffer.len())
+        vec![WriteOp::Segment(0..buffer.len())]
+    }
+    
+    /// Describe the plan for `buffer`, including which rule produced each cut
+    ///
+    /// With `tls_rec` rules, the plan describes the re-framed buffer.
+    pub fn explain(&self, buffer: &[u8]) -> DesyncPlan {
+        let buffer = &*self.apply_tls_rec(buffer, &FlowInfo::default());
+        let protocol = Protocol::detect(buffer);
+        let rules: Vec<&SplitConfig> = if !self.config.split.is_empty() {
+            self.config.split.iter().collect()
+        } else if !self.config.disorder.is_empty() {
+            self.config.disorder.iter().collect()
+        } else {
+            self.config.fake.iter().map(|f| &f.split).collect()
+        };
+        
+        let steps = self
+            .plan_writes(buffer)
+            .into_iter()
+            .map(|op| match op {
+                WriteOp::Segment(ref range)
+                | WriteOp::Disordered(ref range)
+                | WriteOp::Queued(ref range) => PlanStep {
+                    kind: "segment",
+                    start: Some(range.start),
+                    end: Some(range.end),
+                    len: range.len(),
+                    split: (range.end < buffer.len())
+                        .then(|| {
+                            rules
+                                .iter()
+                                .find(|rule| {
+                                    let jitter = rule.jitter.unwrap_or(0);
+                                    self.unjittered_positions(rule, buffer, protocol)
+                                        .iter()
+                                        .any(|pos| pos.abs_diff(range.end) <= jitter)
+                                })
+                                .map(|rule| split_anchor(rule, buffer.len()))
+                        })
+                        .flatten(),
+                    flush: !matches!(op, WriteOp::Queued(_)),
+                    ttl: matches!(op, WriteOp::Disordered(_)).then(|| self.disorder_ttl()),
+                },
+                WriteOp::Fake { index, range } => PlanStep {
+                    kind: "fake",
+                    start: Some(range.start),
+                    end: Some(range.end),
+                    len: range.len(),
+                    split: (range.end < buffer.len())
+                        .then(|| split_anchor(&self.config.fake[index].split, buffer.len())),
+                    flush: true,
+                    ttl: Some(self.fake_ttl(index)),
+                },
+            })
+            .collect();
+        
+        DesyncPlan {
+            mode: self.mode_name(),
+            length: buffer.len(),
+            is_tls: protocol == Protocol::Tls,
+            protocol,
+            steps,
+        }
     }
     
-    async fn apply_split<W: AsyncWriteExt + Unpin>(
+    fn cached_plan(&self, buffer: &[u8], flow: &FlowInfo) -> Arc<Vec<WriteOp>> {
+        let (Some(cache), Some(host)) = (&self.plan_cache, &flow.host) else {
+            return Arc::new(self.plan_writes_flow(buffer, flow));
+        };
+        
+        let key = (host.clone(), flow.port, buffer.len(), prefix_hash(buffer));
+        if let Some(plan) = cache.plans.lock().unwrap().get(&key) {
+            return plan.clone();
+        }
+        
+        let plan = Arc::new(self.plan_writes_flow(buffer, flow));
+        cache.plans.lock().unwrap().insert(key, plan.clone());
+        plan
+    }
+    
+    /// TTL disorder segments are sent with
+    fn disorder_ttl(&self) -> u8 {
+        self.config.ttl.unwrap_or(DEFAULT_DISORDER_TTL)
+    }
+    
+    /// TTL fake config `index` is sent with
+    fn fake_ttl(&self, index: usize) -> u8 {
+        self.config.fake[index].ttl.or(self.config.ttl).unwrap_or(DEFAULT_FAKE_TTL)
+    }
+    
+    /// Fake data of config `index`, cut or zero-padded to `len` bytes
+    fn fake_bytes(&self, index: usize, len: usize) -> Vec<u8> {
+        let mut fake = self.config.fake[index].data.clone().unwrap_or_default();
+        fake.resize(len, 0);
+        fake
+    }
+    
+    async fn execute<W: AsyncWriteExt + Unpin>(
         &self,
         stream: &mut W,
+        control: Option<&dyn SocketControl>,
         buffer: &[u8],
-        is_tls: bool,
+        plan: &[WriteOp],
     ) -> io::Result<usize> {
+        // Writing the segments in another order would scramble the stream
+        // itself; without TTL control they can only go out as a plain split
+        let disordered = plan.iter().any(|op| matches!(op, WriteOp::Disordered(_)));
+        if disordered && control.is_none() {
+            eprintln!("[!] Per-segment TTL unavailable, sending disorder segments in order");
+        }
+        
         let mut total_sent = 0;
+        let mut queued: Vec<IoSlice<'_>> = Vec::new();
+        
+        for op in plan {
+            if !queued.is_empty() && !matches!(op, WriteOp::Segment(_) | WriteOp::Queued(_)) {
+                write_all_vectored(stream, &mut queued).await?;
+                stream.flush().await?;
+            }
+            if total_sent > 0 && !matches!(op, WriteOp::Queued(_)) {
+                self.separate(control).await;
+            }
+            match op {
+                WriteOp::Segment(range) if !queued.is_empty() => {
+                    queued.push(IoSlice::new(&buffer[range.clone()]));
+                    write_all_vectored(stream, &mut queued).await?;
+                    stream.flush().await?;
+                    total_sent += range.len();
+                }
+                WriteOp::Segment(range) => {
+                    stream.write_all(&buffer[range.clone()]).await?;
+                    stream.flush().await?;
+                    total_sent += range.len();
+                }
+                WriteOp::Queued(range) => {
+                    queued.push(IoSlice::new(&buffer[range.clone()]));
+                    total_sent += range.len();
+                }
+                WriteOp::Disordered(range) => {
+                    let restore = match control {
+                        Some(control) => {
+                            let ttl = control.ttl()?;
+                            control.set_ttl(self.disorder_ttl())?;
+                            Some((control, ttl))
+                        }
+                        None => None,
+                    };
+                    let written = async {
+                        stream.write_all(&buffer[range.clone()]).await?;
+                        stream.flush().await
+                    }.await;
+                    if let Some((control, ttl)) = restore {
+                        control.set_ttl(ttl)?;
+                    }
+                    written?;
+                    total_sent += range.len();
+                }
+                WriteOp::Fake { index, range } => {
+                    let real = &buffer[range.clone()];
+                    let fake = self.fake_bytes(*index, range.len());
+                    let sent = match control {
+                        Some(control) => {
+                            control.send_fake(&fake, real, self.fake_ttl(*index)).await
+                        }
+                        None => Err(io::Error::new(io::ErrorKind::Unsupported, "no socket access")),
+                    };
+                    match sent {
+                        Ok(()) => {}
+                        // Never let the fake reach the server: send just the real data
+                        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                            eprintln!("[!] Can't send fake packet ({}), sending real data only", e);
+                            stream.write_all(real).await?;
+                            stream.flush().await?;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                    total_sent += range.len();
+                }
+            }
+        }
+        if !queued.is_empty() {
+            write_all_vectored(stream, &mut queued).await?;
+            stream.flush().await?;
+        }
+        
+        Ok(total_sent)
+    }
+    
+    /// Keep what was written so far from merging with the next segment,
+    /// as `segment_separation` says
+    ///
+    /// `WaitSent` without a way to read the unsent bytes yields instead.
+    async fn separate(&self, control: Option<&dyn SocketControl>) {
+        if self.config.segment_separation == SegmentSeparation::Flush {
+            return;
+        }
+        let unsent = || control.and_then(|control| control.unsent_bytes().ok());
+        let mut pending = match self.config.segment_separation {
+            SegmentSeparation::WaitSent => unsent(),
+            _ => None,
+        };
+        if pending.is_none() {
+            tokio::task::yield_now().await;
+            return;
+        }
+        
+        let deadline = Instant::now() + MAX_SEPARATION_WAIT;
+        while pending.is_some_and(|bytes| bytes > 0) {
+            if Instant::now() >= deadline {
+                eprintln!(
+                    "[!] Segment still unsent after {:?}, the next may be merged with it",
+                    MAX_SEPARATION_WAIT
+                );
+                return;
+            }
+            tokio::time::sleep(SEPARATION_POLL_INTERVAL).await;
+            pending = unsent();
+        }
+    }
+    
+    fn plan_split(&self, buffer: &[u8], protocol: Protocol, flow: &FlowInfo) -> Vec<WriteOp> {
+        let mut plan = Vec::new();
         let mut last_pos = 0;
         
         for split_cfg in &self.config.split {
-            let pos = self.calculate_offset(split_cfg, buffer, is_tls)?;
-            
-            if pos > last_pos && pos <= buffer.len() {
-                // Send chunk from last_pos to pos
-                stream.write_all(&buffer[last_pos..pos]).await?;
-                stream.flush().await?;
-                total_sent += pos - last_pos;
-                last_pos = pos;
+            if !self.rule_applies(split_cfg, buffer, protocol, flow) {
+                continue;
+            }
+            for pos in self.split_positions(split_cfg, buffer, protocol) {
+                let Some(pos) = self.enforce_min_segment(last_pos, pos, buffer.len()) else {
+                    continue;
+                };
+                
+                if pos > last_pos && pos <= buffer.len() {
+                    let coalesce = self.config.coalesce_records
+                        && protocol == Protocol::Tls
+                        && pos < buffer.len()
+                        && is_record_boundary(buffer, pos);
+                    plan.push(if coalesce {
+                        WriteOp::Queued(last_pos..pos)
+                    } else {
+                        WriteOp::Segment(last_pos..pos)
+                    });
+                    last_pos = pos;
+                }
             }
         }
         
         // Send remaining data
         if last_pos < buffer.len() {
-            stream.write_all(&buffer[last_pos..]).await?;
-            stream.flush().await?;
-            total_sent += buffer.len() - last_pos;
+            plan.push(WriteOp::Segment(last_pos..buffer.len()));
         }
         
-        Ok(total_sent)
+        plan
     }
     
-    async fn apply_disorder<W: AsyncWriteExt + Unpin>(
-        &self,
-        stream: &mut W,
-        buffer: &[u8],
-        is_tls: bool,
-    ) -> io::Result<usize> {
-        // For disorder, we send parts out of order
-        // This is a simplified version - full implementation would use TTL manipulation
-        let mut total_sent = 0;
-        let mut positions: Vec<usize> = vec![0];
+    /// Cut the buffer at each disorder position and send every segment
+    /// but the last with a low TTL
+    ///
+    /// The low-TTL segments die on the way, so the server first receives
+    /// the data after them and then their retransmissions: DPI watching the
+    /// path sees the stream out of order.
+    fn plan_disorder(&self, buffer: &[u8], protocol: Protocol, flow: &FlowInfo) -> Vec<WriteOp> {
+        let mut cuts: Vec<usize> = self.config.disorder
+            .iter()
+            .filter(|rule| self.rule_applies(rule, buffer, protocol, flow))
+            .flat_map(|rule| self.split_positions(rule, buffer, protocol))
+            .filter(|&pos| pos > 0 && pos < buffer.len())
+            .collect();
+        cuts.sort_unstable();
+        cuts.dedup();
         
-        for disorder_cfg in &self.config.disorder {
-            let pos = self.calculate_offset(disorder_cfg, buffer, is_tls)?;
-            if pos <= buffer.len() {
-                positions.push(pos);
+        // Boundaries are strictly increasing, so every segment is non-empty
+        // and together they cover the buffer exactly once, in order
+        let mut positions = vec![0];
+        for pos in cuts {
+            let last = *positions.last().unwrap();
+            if let Some(pos) = self.enforce_min_segment(last, pos, buffer.len()) {
+                if pos > last && pos < buffer.len() {
+                    positions.push(pos);
+                }
             }
         }
         positions.push(buffer.len());
-        positions.sort();
         positions.dedup();
         
-        // Send in reverse order (simplified - real implementation uses TTL=1)
-        for i in (1..positions.len()).rev() {
-            let start = positions[i - 1];
-            let end = positions[i];
-            stream.write_all(&buffer[start..end]).await?;
-            stream.flush().await?;
-            total_sent += end - start;
+        let last = positions.len() - 1;
+        (1..positions.len())
+            .map(|i| {
+                let range = positions[i - 1]..positions[i];
+                if i < last {
+                    WriteOp::Disordered(range)
+                } else {
+                    WriteOp::Segment(range)
+                }
+            })
+            .collect()
+    }
+    
+    fn plan_fake(&self, buffer: &[u8], protocol: Protocol, flow: &FlowInfo) -> Vec<WriteOp> {
+        // The fake stands in for the bytes before the split point; the
+        // real bytes follow once it has been sent
+        let fake_cfg = &self.config.fake[0];
+        let pos = self.calculate_offset(&fake_cfg.split, buffer, protocol);
+        let applies = fake_cfg.data.is_some()
+            && pos > 0
+            && self.rule_applies(&fake_cfg.split, buffer, protocol, flow);
+        if !applies {
+            return vec![WriteOp::Segment(0..buffer.len())];
         }
         
-        Ok(total_sent)
+        let mut plan = vec![WriteOp::Fake { index: 0, range: 0..pos }];
+        if pos < buffer.len() {
+            plan.push(WriteOp::Segment(pos..buffer.len()));
+        }
+        plan
     }
     
-    async fn apply_fake<W: AsyncWriteExt + Unpin>(
+    /// Whether a rule may be used for this flow
+    ///
+    /// Rules anchored on the SNI only apply to targets on a TLS port, and
+    /// rules anchored on the Host header only to targets on an HTTP port,
+    /// so anchor-based splits don't mangle other protocols. Flows with an
+    /// unknown port and empty port lists are not restricted. With
+    /// `strict_anchors`, a rule whose anchor isn't found in the buffer is
+    /// skipped rather than applied at its bare numeric offset.
+    fn rule_applies(
         &self,
-        stream: &mut W,
+        rule: &SplitConfig,
         buffer: &[u8],
-        is_tls: bool,
-    ) -> io::Result<usize> {
-        // Simplified fake implementation
-        // Real implementation would send fake packet with low TTL first
-        for fake_cfg in &self.config.fake {
-            let pos = self.calculate_offset(&fake_cfg.split, buffer, is_tls)?;
-            
-            if let Some(fake_data) = &fake_cfg.data {
-                // Send fake data first (simplified)
-                if fake_data.len() <= pos {
-                    stream.write_all(&fake_data[..fake_data.len().min(pos)]).await?;
-                    stream.flush().await?;
-                }
-            }
-            
-            // Then send real data
-            stream.write_all(buffer).await?;
-            stream.flush().await?;
-            return Ok(buffer.len());
+        protocol: Protocol,
+        flow: &FlowInfo,
+    ) -> bool {
+        if self.config.strict_anchors && !anchor_found(rule, buffer, protocol) {
+            return false;
+        }
+        
+        let Some(port) = flow.port else {
+            return true;
+        };
+        let allowed = |ports: &[u16]| ports.is_empty() || ports.contains(&port);
+        
+        let flags = &rule.flags;
+        let auto = |anchored: Protocol| flags.auto_anchor && protocol == anchored;
+        let wants_tls = flags.sni || flags.sni_end || auto(Protocol::Tls);
+        let wants_http = flags.host || auto(Protocol::Http);
+        
+        (!wants_tls || allowed(&self.config.tls_ports))
+            && (!wants_http || allowed(&self.config.http_ports))
+    }
+    
+    /// Apply `min_segment_size` to a split at `pos` following one at `last`
+    ///
+    /// Returns the position to split at, or None if the split is dropped.
+    fn enforce_min_segment(&self, last: usize, pos: usize, len: usize) -> Option<usize> {
+        let Some(min) = self.config.min_segment_size else {
+            return Some(pos);
+        };
+        if pos <= last || pos >= len {
+            return Some(pos);
         }
         
-        // Fallback
-        stream.write_all(buffer).await?;
-        stream.flush().await?;
-        Ok(buffer.len())
+        let adjusted = pos.max(last + min);
+        if len.saturating_sub(adjusted) < min {
+            eprintln!("[*] Dropping split at {}: segments would be under {} bytes", pos, min);
+            return None;
+        }
+        if adjusted != pos {
+            eprintln!("[*] Moving split from {} to {} (min segment size {})", pos, adjusted, min);
+        }
+        Some(adjusted)
+    }
+    
+    /// Every position a rule splits at: the base offset, then `repeats - 1`
+    /// more at a stride of `skip` bytes, each moved by up to `jitter` bytes
+    /// either way, clamped to the buffer, sorted and deduped
+    fn split_positions(
+        &self,
+        split_cfg: &SplitConfig,
+        buffer: &[u8],
+        protocol: Protocol,
+    ) -> Vec<usize> {
+        let mut positions = self.unjittered_positions(split_cfg, buffer, protocol);
+        let jitter = split_cfg.jitter.unwrap_or(0);
+        if jitter > 0 {
+            let mut rng = self.rng.lock().unwrap();
+            for pos in &mut positions {
+                let low = pos.saturating_sub(jitter);
+                let high = pos.saturating_add(jitter).min(buffer.len());
+                *pos = rng.gen_range(low..=high);
+            }
+            positions.sort_unstable();
+            positions.dedup();
+        }
+        positions
     }
     
+    /// Positions of a rule before any jitter
+    fn unjittered_positions(
+        &self,
+        split_cfg: &SplitConfig,
+        buffer: &[u8],
+        protocol: Protocol,
+    ) -> Vec<usize> {
+        let base = self.calculate_offset(split_cfg, buffer, protocol);
+        let skip = split_cfg.skip.unwrap_or(0);
+        let mut positions: Vec<usize> = (0..split_cfg.repeats.unwrap_or(1).max(1))
+            .map(|i| base.saturating_add(i.saturating_mul(skip)).min(buffer.len()))
+            .collect();
+        positions.dedup();
+        positions
+    }
+    
+    /// Position a rule splits at, clamped to the buffer
+    ///
+    /// The offset is measured from one reference point, picked in this order:
+    /// 1. `end`: the end of the buffer, counting back by the offset's
+    ///    magnitude, so `5+e` and `-5+e` both mean 5 bytes before the end
+    ///    and any anchor flag is ignored
+    /// 2. the first anchor flag that is found, in the order `sni`,
+    ///    `sni_end`, `auto`, `record_end`, `host`; a negative offset lands
+    ///    before the anchor
+    /// 3. the end of the buffer for a negative offset, so `-5` is the same
+    ///    as `5+e`
+    /// 4. the start of the buffer
+    ///
+    /// A `percent` offset is first turned into bytes of the buffer length.
+    /// `middle` then halves it, splitting halfway between the reference
+    /// point and where the offset alone would land.
     fn calculate_offset(
         &self,
         split_cfg: &SplitConfig,
         buffer: &[u8],
-        is_tls: bool,
-    ) -> io::Result<usize> {
-        let mut offset = split_cfg.offset;
+        protocol: Protocol,
+    ) -> usize {
+        let flags = &split_cfg.flags;
+        let len = buffer.len() as i64;
+        let offset = offset_bytes(split_cfg, buffer.len());
         
-        // Handle negative offsets (relative to end)
-        if offset < 0 {
-            offset = buffer.len() as i64 + offset;
+        let pos = if flags.end {
+            len - offset.saturating_abs()
+        } else if let Some(anchor) = anchor_position(flags, buffer, protocol) {
+            (anchor as i64).saturating_add(offset)
+        } else if offset < 0 {
+            len + offset
+        } else {
+            offset
+        };
+        pos.clamp(0, len) as usize
+    }
+}
+
+/// A rule's offset in bytes for a `len`-byte buffer, after the `percent`
+/// and `middle` flags
+fn offset_bytes(rule: &SplitConfig, len: usize) -> i64 {
+    let mut offset = rule.offset;
+    if rule.flags.percent {
+        offset = (len as i64).saturating_mul(offset) / 100;
+    }
+    if rule.flags.middle {
+        offset /= 2;
+    }
+    offset
+}
+
+/// Position of the first anchor flag found in `buffer`, in the order
+/// `sni`, `sni_end`, `auto`, `record_end`, `host`
+///
+/// Flags for another protocol than the buffer's, such as `sni` on an HTTP
+/// request, are never found there.
+fn anchor_position(flags: &SplitFlags, buffer: &[u8], protocol: Protocol) -> Option<usize> {
+    let is_tls = protocol == Protocol::Tls;
+    if flags.sni && is_tls {
+        if let Some(pos) = find_sni_offset(buffer) {
+            return Some(pos);
         }
-        
-        // Apply flags
-        if split_cfg.flags.sni && is_tls {
-            if let Some(sni_offset) = find_sni_offset(buffer) {
-                offset += sni_offset as i64;
-            }
+    }
+    if flags.sni_end && is_tls {
+        if let Some(pos) = find_sni_end_offset(buffer) {
+            return Some(pos);
         }
-        
-        if split_cfg.flags.host {
-            if let Some(host_offset) = find_http_host_offset(buffer) {
-                offset += host_offset as i64;
-            }
+    }
+    if flags.auto_anchor {
+        let pos = match protocol {
+            Protocol::Tls => find_sni_offset(buffer),
+            Protocol::Http => find_http_host_offset(buffer),
+            Protocol::Other => None,
+        };
+        if pos.is_some() {
+            return pos;
         }
-        
-        if split_cfg.flags.middle {
-            offset = offset / 2;
+    }
+    if flags.record_end && is_tls {
+        if let Some(pos) = tls_record_len(buffer) {
+            return Some(pos);
         }
-        
-        if split_cfg.flags.end {
-            offset = buffer.len() as i64 - offset;
+    }
+    if flags.host && protocol == Protocol::Http {
+        return find_http_host_offset(buffer);
+    }
+    None
+}
+
+/// Whether a TLS record of `buffer` ends exactly at `pos`
+fn is_record_boundary(buffer: &[u8], pos: usize) -> bool {
+    let mut end = 0;
+    while end < pos {
+        match tls_record_len(&buffer[end..]) {
+            Some(len) => end += len,
+            None => return false,
         }
-        
-        let pos = offset.max(0) as usize;
-        Ok(pos.min(buffer.len()))
     }
+    end == pos
 }
 
+/// Write all of `slices`, in as few vectored writes as the stream allows
+async fn write_all_vectored<W: AsyncWriteExt + Unpin>(
+    stream: &mut W,
+    slices: &mut Vec<IoSlice<'_>>,
+) -> io::Result<()> {
+    let mut remaining = &mut slices[..];
+    while !remaining.is_empty() {
+        let n = stream.write_vectored(remaining).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut remaining, n);
+    }
+    slices.clear();
+    Ok(())
+}
+
+/// Whether every anchor flag of `rule` can be located in `buffer`
+fn anchor_found(rule: &SplitConfig, buffer: &[u8], protocol: Protocol) -> bool {
+    let flags = &rule.flags;
+    let is_tls = protocol == Protocol::Tls;
+    let http = protocol == Protocol::Http;
+    
+    (!flags.sni || (is_tls && find_sni_offset(buffer).is_some()))
+        && (!flags.sni_end || (is_tls && find_sni_end_offset(buffer).is_some()))
+        && (!flags.record_end || (is_tls && tls_record_len(buffer).is_some()))
+        && (!flags.host || (http && find_http_host_offset(buffer).is_some()))
+        && (!flags.auto_anchor
+            || (is_tls && find_sni_offset(buffer).is_some())
+            || (http && find_http_host_offset(buffer).is_some()))
+}
+
+fn split_anchor(rule: &SplitConfig, len: usize) -> SplitAnchor {
+    let flags = &rule.flags;
+    let anchor = if flags.end {
+        "end"
+    } else if flags.sni {
+        "sni"
+    } else if flags.sni_end {
+        "sni_end"
+    } else if flags.auto_anchor {
+        "auto"
+    } else if flags.record_end {
+        "record_end"
+    } else if flags.host {
+        "host"
+    } else if rule.offset < 0 {
+        "end"
+    } else {
+        "start"
+    };
+    
+    // Distance as calculate_offset applies it
+    let mut offset = offset_bytes(rule, len);
+    if flags.end {
+        offset = -offset.saturating_abs();
+    }
+    
+    let flag_chars: String = [
+        (flags.sni, 's'),
+        (flags.sni_end, 'n'),
+        (flags.host, 'h'),
+        (flags.end, 'e'),
+        (flags.middle, 'm'),
+        (flags.record_end, 'r'),
+        (flags.auto_anchor, 'a'),
+    ]
+    .iter()
+    .filter(|(set, _)| *set)
+    .map(|(_, c)| *c)
+    .collect();
+    
+    let mut spec = rule.offset.to_string();
+    if flags.percent {
+        spec.push('%');
+    }
+    if !flag_chars.is_empty() {
+        spec = format!("{}+{}", spec, flag_chars);
+    }
+    
+    SplitAnchor {
+        rule: spec,
+        anchor,
+        offset,
+    }
+}
+
+/// In-memory sink recording the segments the engine emits
+///
+/// Every flush closes the current segment, mirroring how the engine forces
+/// each desync segment onto the wire. Useful for replaying captured traffic
+/// through a config without a live connection.
+#[derive(Debug, Default)]
+pub struct SegmentRecorder {
+    segments: Vec<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl SegmentRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Recorded segments, including any unflushed trailing bytes
+    pub fn into_segments(mut self) -> Vec<Vec<u8>> {
+        self.close_segment();
+        self.segments
+    }
+    
+    fn close_segment(&mut self) {
+        if !self.pending.is_empty() {
+            self.segments.push(std::mem::take(&mut self.pending));
+        }
+    }
+}
+
+impl AsyncWrite for SegmentRecorder {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.pending.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+    
+    fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.close_segment();
+        Poll::Ready(Ok(()))
+    }
+    
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.close_segment();
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OnceCell, Semaphore};
+
+/// Upper bound on how long a cache entry lives, whatever the configured TTL
+const MAX_CACHE_TTL: Duration = Duration::from_secs(365 * 86400);
+
+/// Boxed future returned by [`Resolve::resolve`]
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'a>>;
+
+/// Name resolution backend used for domain targets
+pub trait Resolve: Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a>;
+}
+
+/// Resolver backed by the system resolver (`getaddrinfo`)
+#[derive(Debug, Clone, Default)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host, port)).await?;
+            Ok(addrs.collect())
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DnsCacheConfig {
+    /// Maximum number of cached (host, port) entries; the least recently
+    /// used entry is evicted when full
+    pub capacity: usize,
+    /// How long successful lookups are kept. Resolution backends don't
+    /// report record TTLs, so this applies to every answer.
+    pub ttl: Duration,
+    /// How long failed lookups (e.g. NXDOMAIN) are remembered
+    pub negative_ttl: Duration,
+}
+
+/// Resolver shared by all connections
+///
+/// Wraps a [`Resolve`] backend and optionally bounds the number of
+/// resolutions in flight; excess lookups wait for a free slot. With a cache
+/// attached, answers and failures are reused until they expire. Concurrent
+/// lookups of the same (host, port) share a single backend query.
+#[derive(Clone)]
+pub struct Resolver {
+    backend: Arc<dyn Resolve>,
+    limit: Option<Arc<Semaphore>>,
+    cache: Option<Arc<DnsCache>>,
+    in_flight: Arc<Mutex<InFlight>>,
+}
+
+/// Lookups under way, keyed on (host, port)
+type InFlight = HashMap<(String, u16), Arc<OnceCell<CachedAnswer>>>;
+
+impl Resolver {
+    pub fn new(backend: Arc<dyn Resolve>, max_concurrent: Option<usize>) -> Self {
+        Self {
+            backend,
+            limit: max_concurrent.map(|n| Arc::new(Semaphore::new(n.max(1)))),
+            cache: None,
+            in_flight: Arc::default(),
+        }
+    }
+    
+    /// Cache lookups according to `config`
+    pub fn with_cache(mut self, config: DnsCacheConfig) -> Self {
+        self.cache = Some(Arc::new(DnsCache::new(config)));
+        self
+    }
+    
+    /// Resolve `host:port`, queueing if the concurrency limit is reached
+    pub async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Some(cached) = self.cache.as_ref().and_then(|c| c.get(host, port)) {
+            return cached;
+        }
+        
+        // Join a lookup of the same name already under way. Should its
+        // owner give up, one of the waiters runs the lookup instead.
+        let key = (host.to_string(), port);
+        let cell = self.in_flight.lock().unwrap().entry(key.clone()).or_default().clone();
+        let answer = cell.get_or_init(|| async {
+            let result = self.lookup(host, port).await;
+            if let Some(cache) = &self.cache {
+                cache.insert(host, port, &result);
+            }
+            CachedAnswer::from(&result)
+        });
+        let answer = answer.await.to_result();
+        
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.get(&key).is_some_and(|current| Arc::ptr_eq(current, &cell)) {
+            in_flight.remove(&key);
+        }
+        answer
+    }
+    
+    async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let _permit = match &self.limit {
+            Some(limit) => Some(limit.acquire().await.map_err(io::Error::other)?),
+            None => None,
+        };
+        self.backend.resolve(host, port).await
+    }
+}
+
+/// Cached outcome of a lookup; failures keep the error kind and message
+#[derive(Debug, Clone)]
+enum CachedAnswer {
+    Addrs(Vec<SocketAddr>),
+    Failed(io::ErrorKind, String),
+}
+
+impl CachedAnswer {
+    fn to_result(&self) -> io::Result<Vec<SocketAddr>> {
+        match self {
+            CachedAnswer::Addrs(addrs) => Ok(addrs.clone()),
+            CachedAnswer::Failed(kind, message) => Err(io::Error::new(*kind, message.clone())),
+        }
+    }
+}
+
+impl From<&io::Result<Vec<SocketAddr>>> for CachedAnswer {
+    fn from(result: &io::Result<Vec<SocketAddr>>) -> Self {
+        match result {
+            Ok(addrs) => CachedAnswer::Addrs(addrs.clone()),
+            Err(e) => CachedAnswer::Failed(e.kind(), e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    answer: CachedAnswer,
+    expires: Instant,
+    last_used: u64,
+}
+
+/// Bounded LRU cache of lookups keyed on (host, port)
+#[derive(Debug)]
+struct DnsCache {
+    config: DnsCacheConfig,
+    entries: Mutex<CacheEntries>,
+}
+
+#[derive(Debug, Default)]
+struct CacheEntries {
+    map: HashMap<(String, u16), CacheEntry>,
+    /// Monotonic use counter driving LRU eviction
+    clock: u64,
+}
+
+impl DnsCache {
+    fn new(config: DnsCacheConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::default(),
+        }
+    }
+    
+    fn get(&self, host: &str, port: u16) -> Option<io::Result<Vec<SocketAddr>>> {
+        let mut entries = self.entries.lock().unwrap();
+        let key = (host.to_string(), port);
+        
+        let entry = entries.map.get(&key)?;
+        if entry.expires <= Instant::now() {
+            entries.map.remove(&key);
+            return None;
+        }
+        
+        entries.clock += 1;
+        let clock = entries.clock;
+        let entry = entries.map.get_mut(&key)?;
+        entry.last_used = clock;
+        
+        Some(entry.answer.to_result())
+    }
+    
+    fn insert(&self, host: &str, port: u16, result: &io::Result<Vec<SocketAddr>>) {
+        if self.config.capacity == 0 {
+            return;
+        }
+        
+        let ttl = match result {
+            Ok(_) => self.config.ttl,
+            Err(_) => self.config.negative_ttl,
+        };
+        let answer = CachedAnswer::from(result);
+        if ttl.is_zero() {
+            return;
+        }
+        
+        let mut entries = self.entries.lock().unwrap();
+        let key = (host.to_string(), port);
+        if !entries.map.contains_key(&key) && entries.map.len() >= self.config.capacity {
+            // Drop expired entries first, then the least recently used one
+            let now = Instant::now();
+            entries.map.retain(|_, entry| entry.expires > now);
+            if entries.map.len() >= self.config.capacity {
+                let oldest = entries
+                    .map
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.map.remove(&oldest);
+                }
+            }
+        }
+        
+        entries.clock += 1;
+        let last_used = entries.clock;
+        entries.map.insert(
+            key,
+            CacheEntry {
+                answer,
+                expires: Instant::now() + ttl.min(MAX_CACHE_TTL),
+                last_used,
+            },
+        );
+    }
+}
@@ -0,0 +1,543 @@
+use crate::access_log::{AccessLogConfig, AccessLogFormat};
+use crate::config::{
+    parse_split_config, AddressPreference, AuthConfig, CanaryConfig, Config, DesyncConfig,
+    FakeConfig, HostPattern, HostRule, IpMode, SocketOpts, SplitConfig, SplitFlags,
+};
+use crate::dns::DnsCacheConfig;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::time::Duration;
+
+/// Settings the example shows but leaves off, as they change where traffic
+/// goes or who may connect
+const DISABLED: &[&str] = &[
+    "bind_addr",
+    "outbound_ttl",
+    "auth",
+    "max_rate_bytes_per_sec",
+    "access_log.path",
+    "metrics_listen",
+    "desync.seed",
+    "canary.seed",
+    "upstream",
+];
+
+const HEADER: &str = "\
+# stpro example configuration, written by `stpro generate-config`
+#
+# Every setting is optional and falls back to its default when left out.
+# Settings written as `# key = value` are shown but not enabled. Durations
+# are tables of whole seconds and nanoseconds. Split rules take an offset
+# in bytes (a negative one counts from the end) plus anchor flags: sni,
+# sni_end, host, end, middle, record_end, auto_anchor and percent.
+";
+
+/// Comment above each documented setting
+const COMMENTS: &[(&str, &str)] = &[
+    ("listen", "Where clients connect: IP:PORT, or a Unix socket path (unix:/path)"),
+    (
+        "ip_mode",
+        "Address families a TCP listener accepts: v4_only, v6_only or dual_stack\n\
+         (IPv4-mapped clients too on an IPv6 address)",
+    ),
+    ("bind_addr", "Source address for outbound connections (port 0: any port)"),
+    ("max_connections", "Most client connections served at once"),
+    (
+        "buffer_size",
+        "Read buffer per forwarding direction; also the most of the first flight\n\
+         the desync engine sees at once",
+    ),
+    ("buffer_pool_size", "Idle forwarding buffers kept for reuse (0: allocate per connection)"),
+    ("reuse_port", "Bind with SO_REUSEPORT so several stpro processes share the port"),
+    ("workers", "Accept loops; on Linux each gets its own SO_REUSEPORT listener"),
+    ("transparent", "Serve firewall-redirected connections instead of proxy clients (Linux)"),
+    ("max_concurrent_resolves", "Most DNS lookups in flight at once"),
+    ("dns_cache", "Cache DNS answers and failures"),
+    ("dns_cache.capacity", "Most cached (host, port) entries"),
+    ("dns_cache.ttl", "How long answers are kept"),
+    ("dns_cache.negative_ttl", "How long failed lookups are remembered"),
+    ("max_concurrent_connects", "Most outbound connects in progress at once; the rest queue"),
+    ("connect_queue_timeout", "How long a queued connect waits for a slot"),
+    ("connect_timeout", "Give up connecting to a target after this long"),
+    ("idle_timeout", "Close tunnels idle in both directions for this long"),
+    ("connect_attempt_delay", "Head start of each address before the next one is tried too"),
+    ("address_preference", "Address family tried first: ipv6, ipv4 or resolver (as returned)"),
+    ("outbound_ttl", "TTL of every outbound packet (not the low TTL of fakes)"),
+    ("socket", "TCP options for client and target sockets"),
+    (
+        "socket.nodelay",
+        "Send each write at once (TCP_NODELAY); desync needs it to keep split\n\
+         segments apart",
+    ),
+    ("socket.keepalive", "Idle time before keepalive probes start"),
+    ("socket.keepalive_interval", "Time between unanswered keepalive probes"),
+    ("socket.recv_buffer_size", "SO_RCVBUF in bytes"),
+    ("socket.send_buffer_size", "SO_SNDBUF in bytes"),
+    ("disable_ipv6", "Never connect over IPv6"),
+    ("abort_with_rst", "Reset rather than close clients refused for policy violations"),
+    (
+        "auth_method_priority",
+        "SOCKS5 methods in order of preference: 0 (no auth), 2 (username/password)",
+    ),
+    ("auth", "Require a login (SOCKS5 username/password, HTTP Basic)"),
+    ("auth.users", "Password of each user"),
+    ("allow", "Client address ranges allowed to connect (everyone if empty)"),
+    ("deny", "Client address ranges refused even if allowed above"),
+    ("block_hosts", "Targets refused by hostname; *.example.com covers its subdomains"),
+    ("max_connections_per_ip", "Most connections open at once from one client address"),
+    ("max_rate_bytes_per_sec", "Throughput cap per connection and direction"),
+    ("http_max_request_line", "Longest HTTP proxy request line accepted"),
+    ("http_max_header_bytes", "Largest HTTP proxy header block accepted"),
+    ("access_log", "One line per finished connection"),
+    ("access_log.path", "File to append to (stdout if unset)"),
+    ("access_log.format", "Common or Combined (adds timing, strategy and tag)"),
+    ("summary_interval", "Log an activity summary this often"),
+    ("metrics_listen", "Serve Prometheus metrics at /metrics and readiness at /readyz"),
+    ("drain_timeout", "In drain mode, stop waiting for connections after this long"),
+    ("shutdown_grace", "On SIGINT/SIGTERM, time connections get to finish"),
+    ("desync_ports", "Target ports desync applies to (every port if empty)"),
+    ("desync", "DPI evasion applied to the first flight of those connections"),
+    (
+        "desync.split",
+        "Split the first flight into TCP segments at each rule's position.\n\
+         Only one of split, disorder and fake is used, in that order.",
+    ),
+    ("desync.split.offset", "Bytes from the anchor the flags pick (the buffer start if none)"),
+    ("desync.split.flags", "Anchor and interpretation of the offset"),
+    (
+        "desync.disorder",
+        "Like split, but the first segment is sent with a low TTL and retransmitted",
+    ),
+    ("desync.fake", "Send a fake first segment with a low TTL ahead of the real one"),
+    ("desync.tls_rec", "Split a ClientHello into several TLS records at each rule's position"),
+    ("desync.ttl", "TTL of disorder segments (default 1) and of fakes without their own (8)"),
+    ("desync.min_segment_size", "Push or drop splits that would leave a shorter segment"),
+    ("desync.plan_cache", "Reuse plans for repeated first flights to the same host"),
+    (
+        "desync.verify_segments",
+        "Check with TCP_INFO that planned segments and TLS records left apart (Linux)",
+    ),
+    (
+        "desync.segment_separation",
+        "Between split segments: flush, yield, or wait_sent (wait until the kernel\n\
+         has sent the previous one, Linux)",
+    ),
+    ("desync.tls_ports", "Target ports SNI-anchored rules apply to"),
+    ("desync.http_ports", "Target ports Host-anchored rules apply to"),
+    (
+        "desync.strict_anchors",
+        "Skip rules whose anchor isn't found instead of using the offset alone",
+    ),
+    (
+        "desync.desync_only_tls_http",
+        "Pass first flights that are neither TLS nor HTTP through untouched",
+    ),
+    (
+        "desync.coalesce_records",
+        "Write a split ending on a TLS record boundary together with the next",
+    ),
+    ("desync.seed", "Seed for jitter, for reproducible runs"),
+    (
+        "strategies",
+        "Strategies retried in order when a target resets the first flight;\n\
+         each takes the same settings as [desync]",
+    ),
+    ("strategy_retries", "Most strategies tried on one connection after the first"),
+    ("canary", "Try another strategy on a share of connections"),
+    ("canary.percent", "Share of connections, 0-100"),
+    ("canary.seed", "Seed for picking connections, for reproducible rollouts"),
+    ("canary.desync", "Strategy under evaluation"),
+    (
+        "tag_profiles",
+        "Strategies picked by client tag: SOCKS5 username tag:<name> or the\n\
+         X-Stpro-Tag HTTP header",
+    ),
+    (
+        "host_overrides",
+        "Strategies for particular targets; the most specific match wins.\n\
+         match is { exact = host }, { suffix = domain } or { cidr = range }",
+    ),
+    ("upstream", "Reach targets through another proxy: socks5:// or http://"),
+];
+
+fn rule(spec: &str) -> SplitConfig {
+    parse_split_config(spec).expect("example split rules are valid")
+}
+
+fn secs(secs: u64) -> Option<Duration> {
+    Some(Duration::from_secs(secs))
+}
+
+impl Config {
+    /// A config with every setting filled in with a plausible value, for
+    /// users to start from
+    ///
+    /// It uses every desync mode: split and TLS record splitting up front,
+    /// disorder and fakes as fallback strategies, tag and host overrides.
+    pub fn example() -> Config {
+        let fake_request = b"GET / HTTP/1.1\r\n\r\n".to_vec();
+        Config {
+            listen: "127.0.0.1:1080".parse().expect("valid listen address"),
+            ip_mode: IpMode::DualStack,
+            bind_addr: "192.0.2.10:0".parse().ok(),
+            max_connections: 1024,
+            buffer_size: 16384,
+            buffer_pool_size: 256,
+            reuse_port: false,
+            workers: 2,
+            transparent: false,
+            max_concurrent_resolves: Some(64),
+            dns_cache: Some(DnsCacheConfig {
+                capacity: 4096,
+                ttl: Duration::from_secs(300),
+                negative_ttl: Duration::from_secs(30),
+            }),
+            max_concurrent_connects: Some(256),
+            connect_queue_timeout: secs(5),
+            connect_timeout: secs(10),
+            idle_timeout: secs(300),
+            connect_attempt_delay: Some(Duration::from_millis(250)),
+            address_preference: AddressPreference::Ipv4,
+            outbound_ttl: Some(64),
+            socket: SocketOpts {
+                nodelay: true,
+                keepalive: secs(60),
+                keepalive_interval: secs(10),
+                recv_buffer_size: Some(262144),
+                send_buffer_size: Some(262144),
+            },
+            disable_ipv6: false,
+            abort_with_rst: false,
+            auth_method_priority: vec![0x00, 0x02],
+            auth: Some(AuthConfig {
+                users: HashMap::from([("alice".to_string(), "change-me".to_string())]),
+            }),
+            allow: ["127.0.0.0/8", "::1/128", "192.168.0.0/16"]
+                .iter()
+                .map(|net| net.parse().expect("valid example range"))
+                .collect(),
+            deny: vec!["192.168.66.0/24".parse().expect("valid example range")],
+            block_hosts: vec!["ads.example.net".to_string(), "*.tracker.example".to_string()],
+            max_connections_per_ip: Some(64),
+            max_rate_bytes_per_sec: Some(10 * 1024 * 1024),
+            http_max_request_line: 8192,
+            http_max_header_bytes: 16384,
+            access_log: Some(AccessLogConfig {
+                path: Some("/var/log/stpro/access.log".into()),
+                format: AccessLogFormat::Combined,
+            }),
+            summary_interval: secs(60),
+            metrics_listen: "127.0.0.1:9090".parse().ok(),
+            drain_timeout: secs(30),
+            shutdown_grace: secs(10),
+            desync_ports: vec![443, 80, 8443],
+            desync: DesyncConfig {
+                split: vec![rule("0+r")],
+                tls_rec: vec![rule("1+s")],
+                ttl: Some(8),
+                min_segment_size: Some(1),
+                plan_cache: true,
+                strict_anchors: true,
+                desync_only_tls_http: true,
+                seed: Some(42),
+                ..DesyncConfig::default()
+            },
+            strategies: vec![
+                DesyncConfig { disorder: vec![rule("1+s")], ..DesyncConfig::default() },
+                DesyncConfig {
+                    fake: vec![FakeConfig {
+                        split: rule("1+s"),
+                        ttl: Some(6),
+                        data: Some(fake_request),
+                    }],
+                    ..DesyncConfig::default()
+                },
+            ],
+            strategy_retries: Some(2),
+            canary: Some(CanaryConfig {
+                desync: DesyncConfig {
+                    split: vec![SplitConfig { jitter: Some(2), ..rule("2+s") }],
+                    ..DesyncConfig::default()
+                },
+                percent: 5.0,
+                seed: Some(7),
+            }),
+            tag_profiles: HashMap::from([(
+                "bulk".to_string(),
+                DesyncConfig { split: vec![rule("50%")], ..DesyncConfig::default() },
+            )]),
+            host_overrides: vec![HostRule {
+                pattern: HostPattern::Suffix("example.org".to_string()),
+                desync: DesyncConfig { disorder: vec![rule("2+h")], ..DesyncConfig::default() },
+            }],
+            upstream: "socks5://127.0.0.1:9050".parse().ok(),
+        }
+    }
+    
+    /// [`Config::example`] as a commented TOML document
+    pub fn example_toml() -> String {
+        let annotations = Annotations { comments: COMMENTS, disabled: DISABLED };
+        format!("{}\n{}", HEADER, write_toml(&example_document(), &annotations))
+    }
+    
+    /// [`Config::example`] as JSON, without the settings the TOML example
+    /// leaves commented out (JSON has no comments)
+    pub fn example_json() -> String {
+        let mut document = example_document();
+        for path in DISABLED {
+            remove_path(&mut document, path);
+        }
+        serde_json::to_string_pretty(&document).expect("configs serialize to JSON")
+    }
+}
+
+/// The example, with the nested strategies and split flags trimmed to the
+/// settings that differ from their defaults
+fn example_document() -> Map<String, Value> {
+    let Value::Object(mut document) = to_value(&Config::example()) else {
+        unreachable!("configs serialize to objects");
+    };
+    
+    let defaults = to_value(&DesyncConfig::default());
+    if let Some(Value::Array(strategies)) = document.get_mut("strategies") {
+        strategies.iter_mut().for_each(|desync| strip_defaults(desync, &defaults));
+    }
+    if let Some(Value::Object(profiles)) = document.get_mut("tag_profiles") {
+        profiles.values_mut().for_each(|desync| strip_defaults(desync, &defaults));
+    }
+    if let Some(Value::Array(rules)) = document.get_mut("host_overrides") {
+        for desync in rules.iter_mut().filter_map(|rule| rule.get_mut("desync")) {
+            strip_defaults(desync, &defaults);
+        }
+    }
+    if let Some(desync) = document.get_mut("canary").and_then(|canary| canary.get_mut("desync")) {
+        strip_defaults(desync, &defaults);
+    }
+    
+    for value in document.values_mut() {
+        strip_flags(value, &to_value(&SplitFlags::default()));
+    }
+    document
+}
+
+fn to_value<T: serde::Serialize>(value: &T) -> Value {
+    serde_json::to_value(value).expect("configs serialize to JSON")
+}
+
+/// Drop the keys of `value` that equal those of `defaults`
+fn strip_defaults(value: &mut Value, defaults: &Value) {
+    if let (Value::Object(table), Value::Object(defaults)) = (value, defaults) {
+        table.retain(|key, value| defaults.get(key) != Some(value));
+    }
+}
+
+/// Leave only the set flags of every split rule under `value`, and drop
+/// `flags` altogether where none is
+fn strip_flags(value: &mut Value, defaults: &Value) {
+    match value {
+        Value::Object(table) => {
+            if let Some(flags) = table.get_mut("flags") {
+                strip_defaults(flags, defaults);
+                if flags.as_object().is_some_and(Map::is_empty) {
+                    table.remove("flags");
+                }
+            }
+            table.values_mut().for_each(|value| strip_flags(value, defaults));
+        }
+        Value::Array(items) => items.iter_mut().for_each(|value| strip_flags(value, defaults)),
+        _ => {}
+    }
+}
+
+fn remove_path(document: &mut Map<String, Value>, path: &str) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            if let Some(Value::Object(table)) = document.get_mut(head) {
+                remove_path(table, rest);
+            }
+        }
+        None => {
+            document.remove(path);
+        }
+    }
+}
+
+/// Comments and commented-out settings for `write_toml`, keyed on dotted paths
+/// with array indices left out (`desync.split.offset`)
+struct Annotations<'a> {
+    /// Comment written above the key or table at each path
+    comments: &'a [(&'a str, &'a str)],
+    /// Paths written commented out, showing a setting without enabling it
+    disabled: &'a [&'a str],
+}
+
+impl Annotations<'_> {
+    fn comment(&self, path: &str) -> Option<&str> {
+        self.comments.iter().find(|(p, _)| *p == path).map(|(_, c)| *c)
+    }
+    
+    /// Where `path` is written among its siblings: in the order of
+    /// `comments`, then undocumented keys in alphabetical order
+    fn rank(&self, path: &str) -> usize {
+        self.comments.iter().position(|(p, _)| *p == path).unwrap_or(usize::MAX)
+    }
+    
+    fn has_comments_below(&self, path: &str) -> bool {
+        self.comments.iter().any(|(p, _)| {
+            p.strip_prefix(path).is_some_and(|rest| rest.starts_with('.'))
+        })
+    }
+}
+
+/// Write a JSON object as a TOML document
+///
+/// Nulls are left out, as TOML has no null and a missing key deserializes
+/// to `None`. Objects without annotated keys of their own are written as
+/// inline tables when everything in them fits on one line.
+fn write_toml(root: &Map<String, Value>, annotations: &Annotations) -> String {
+    let mut out = String::new();
+    write_table(&mut out, root, "", annotations);
+    out
+}
+
+fn write_table(out: &mut String, table: &Map<String, Value>, path: &str, notes: &Annotations) {
+    let child = |key: &str| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+    
+    let mut entries: Vec<(&String, &Value)> = table.iter().collect();
+    entries.sort_by_key(|(key, _)| notes.rank(&child(key)));
+    
+    // Plain keys have to come before the sub-tables of a table
+    for &(key, value) in &entries {
+        let at = child(key);
+        if !value.is_null() && is_inline(value, &at, notes) {
+            let mut line = String::new();
+            write_comment(&mut line, notes.comment(&at));
+            let _ = writeln!(line, "{} = {}", write_key(key), inline(value));
+            push_lines(out, &line, notes.disabled.contains(&at.as_str()));
+        }
+    }
+    
+    for &(key, value) in &entries {
+        let at = child(key);
+        if value.is_null() || is_inline(value, &at, notes) {
+            continue;
+        }
+        let header = header_path(path, key);
+        let mut section = String::new();
+        match value {
+            Value::Object(sub) => {
+                section.push('\n');
+                write_comment(&mut section, notes.comment(&at));
+                let _ = writeln!(section, "[{}]", header);
+                write_table(&mut section, sub, &at, notes);
+            }
+            Value::Array(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    let Value::Object(sub) = item else { continue };
+                    section.push('\n');
+                    if i == 0 {
+                        write_comment(&mut section, notes.comment(&at));
+                    }
+                    let _ = writeln!(section, "[[{}]]", header);
+                    write_table(&mut section, sub, &at, notes);
+                }
+            }
+            _ => {}
+        }
+        push_lines(out, &section, notes.disabled.contains(&at.as_str()));
+    }
+}
+
+/// Whether `value` is written on the line of its key
+///
+/// Tables, and arrays of them, with annotated keys get sections of their
+/// own so the comments have somewhere to go.
+fn is_inline(value: &Value, path: &str, notes: &Annotations) -> bool {
+    match value {
+        Value::Object(_) => !notes.has_comments_below(path) && fits_inline(value),
+        Value::Array(items) => {
+            let tables = items.iter().any(Value::is_object);
+            items.iter().all(fits_inline) && !(tables && notes.has_comments_below(path))
+        }
+        _ => true,
+    }
+}
+
+/// Whether `value` can be an inline value below a table written inline:
+/// anything but arrays of tables, which read better as sections
+fn fits_inline(value: &Value) -> bool {
+    match value {
+        Value::Object(table) => table.values().all(fits_inline),
+        Value::Array(items) => items.iter().all(|item| !item.is_object() && fits_inline(item)),
+        _ => true,
+    }
+}
+
+/// `value` as an inline TOML value, with nulls left out
+fn inline(value: &Value) -> String {
+    let mut value = value.clone();
+    strip_nulls(&mut value);
+    toml::Value::try_from(value).expect("configs serialize to TOML").to_string()
+}
+
+fn strip_nulls(value: &mut Value) {
+    match value {
+        Value::Object(table) => {
+            table.retain(|_, value| !value.is_null());
+            table.values_mut().for_each(strip_nulls);
+        }
+        Value::Array(items) => items.iter_mut().for_each(strip_nulls),
+        _ => {}
+    }
+}
+
+fn header_path(path: &str, key: &str) -> String {
+    let mut segments: Vec<String> = if path.is_empty() {
+        Vec::new()
+    } else {
+        path.split('.').map(write_key).collect()
+    };
+    segments.push(write_key(key));
+    segments.join(".")
+}
+
+fn write_key(key: &str) -> String {
+    let bare = !key.is_empty()
+        && key.bytes().all(|b| b.is_ascii_alphanumeric() || b"_-".contains(&b));
+    if bare {
+        key.to_string()
+    } else {
+        toml::Value::String(key.to_string()).to_string()
+    }
+}
+
+fn write_comment(out: &mut String, comment: Option<&str>) {
+    for line in comment.into_iter().flat_map(str::lines) {
+        let _ = writeln!(out, "# {}", line);
+    }
+}
+
+/// Append `text`, commenting out every line that isn't blank or a comment
+/// already when `disabled`
+fn push_lines(out: &mut String, text: &str, disabled: bool) {
+    if !disabled {
+        out.push_str(text);
+        return;
+    }
+    for line in text.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            out.push_str(line);
+        } else {
+            out.push_str("# ");
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+}
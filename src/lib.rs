@@ -2,9 +2,40 @@ pub mod proxy;
 pub mod desync;
 pub mod packets;
 pub mod config;
+pub mod dns;
+pub mod connect;
+pub mod stats;
+pub mod access_log;
+pub mod fingerprint;
+pub mod metrics;
+pub mod udp;
+pub mod auto;
+pub mod upstream;
+pub mod presets;
+pub mod zapret;
+pub mod transparent;
+pub mod daemon;
+mod listener;
+mod limits;
+mod buffers;
+mod lru;
+mod crypto;
+mod example;
 
 pub use proxy::*;
 pub use desync::*;
 pub use packets::*;
 pub use config::*;
+pub use dns::*;
+pub use connect::*;
+pub use stats::*;
+pub use access_log::*;
+pub use fingerprint::*;
+pub use metrics::*;
+pub use udp::*;
+pub use auto::*;
+pub use upstream::*;
+pub use presets::*;
+pub use transparent::*;
+pub use daemon::*;
 
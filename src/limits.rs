@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Share of a second's worth of bytes a rate-limited read may take, so
+/// slow limits send many small writes rather than rare large ones
+const CHUNKS_PER_SECOND: u64 = 10;
+
+/// Token bucket pacing a byte stream to a fixed rate
+///
+/// Holds up to a tenth of a second's worth of bytes. Sending more than is
+/// available borrows against the future and waits the debt off, so the
+/// long-run rate stays at the limit whatever the write sizes.
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        let burst = (rate / CHUNKS_PER_SECOND as f64).max(1.0);
+        Self { rate, burst, tokens: burst, last: Instant::now() }
+    }
+    
+    /// Largest read worth pacing as one write
+    pub(crate) fn chunk_size(&self) -> usize {
+        self.burst as usize
+    }
+    
+    /// Wait until `n` more bytes may be sent
+    pub(crate) async fn consume(&mut self, n: usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst) - n as f64;
+        self.last = now;
+        if self.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-self.tokens / self.rate)).await;
+        }
+    }
+}
+
+/// Open connections per client address, capped at `limit`
+#[derive(Debug)]
+pub(crate) struct IpSlots {
+    limit: usize,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl IpSlots {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self { limit, open: Arc::default() }
+    }
+    
+    /// Take one of `ip`'s slots, or None if it already has `limit`
+    /// connections open; the slot is given back when dropped
+    pub(crate) fn acquire(&self, ip: IpAddr) -> Option<IpSlot> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_insert(0);
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(IpSlot { ip, open: self.open.clone() })
+    }
+}
+
+/// A connection counted against its client address
+pub(crate) struct IpSlot {
+    ip: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use crate::config::{Config, IpMode, ListenAddr, SocketOpts};
+use crate::connect::apply_socket_opts;
+use socket2::SockRef;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// Whether listeners sharing a port through SO_REUSEPORT get connections
+/// balanced across them by the kernel
+pub(crate) const REUSEPORT_BALANCING: bool = cfg!(any(target_os = "linux", target_os = "android"));
+
+/// Client address reported for Unix socket clients, which have none
+const UNIX_CLIENT_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+/// First descriptor a socket-activated service is passed (`SD_LISTEN_FDS_START`)
+#[cfg(unix)]
+const LISTEN_FDS_START: std::os::unix::io::RawFd = 3;
+
+/// Set once the socket passed by systemd has been adopted, so it is never
+/// wrapped twice when `run` is called again in the same process
+#[cfg(unix)]
+static INHERITED_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Socket the server accepts clients on
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Bind `config.listen`, replacing a stale socket file left behind by a
+    /// server that didn't shut down cleanly
+    pub(crate) fn bind(config: &Config) -> io::Result<Self> {
+        match &config.listen {
+            ListenAddr::Tcp(addr) => {
+                let reuse_port = config.reuse_port || balanced_workers(config);
+                bind_tcp(*addr, config.ip_mode, reuse_port).map(Listener::Tcp)
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                
+                if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                UnixListener::bind(path).map(Listener::Unix)
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not available on this platform",
+            )),
+        }
+    }
+    
+    /// The listening socket systemd passed to this process, if it was
+    /// socket-activated
+    ///
+    /// systemd (with `Accept=no`) hands the sockets of the `.socket` unit
+    /// over as descriptors 3 and up, naming their count in `LISTEN_FDS` and
+    /// the process meant to use them in `LISTEN_PID`; when `LISTEN_PID` is
+    /// some other process the variables were merely inherited and are
+    /// ignored. Only the first socket is served. It can be TCP or Unix.
+    #[cfg(unix)]
+    pub(crate) fn inherited() -> io::Result<Option<Self>> {
+        use socket2::{Socket, Type};
+        use std::mem::ManuallyDrop;
+        use std::os::unix::io::FromRawFd;
+        
+        let var = |name| std::env::var(name).ok().and_then(|v| v.trim().parse::<u32>().ok());
+        let count = match (var("LISTEN_PID"), var("LISTEN_FDS")) {
+            (Some(pid), Some(count)) if pid == std::process::id() && count > 0 => count,
+            _ => return Ok(None),
+        };
+        if INHERITED_TAKEN.swap(true, Ordering::SeqCst) {
+            return Ok(None);
+        }
+        if count > 1 {
+            eprintln!("[!] systemd passed {} sockets, only the first is used", count);
+        }
+        
+        // Left open if it turns out not to be a listening socket: the
+        // descriptor isn't this process's to close then
+        let socket = ManuallyDrop::new(unsafe { Socket::from_raw_fd(LISTEN_FDS_START) });
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let listening = socket.is_listener()?;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let listening = true;
+        if socket.r#type()? != Type::STREAM || !listening {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the socket passed by systemd is not a listening stream socket \
+                 (Accept=yes is not supported)",
+            ));
+        }
+        let socket = ManuallyDrop::into_inner(socket);
+        socket.set_cloexec(true)?;
+        socket.set_nonblocking(true)?;
+        let listener = match socket.local_addr()?.as_socket() {
+            Some(_) => Listener::Tcp(TcpListener::from_std(socket.into())?),
+            None => Listener::Unix(UnixListener::from_std(socket.into())?),
+        };
+        Ok(Some(listener))
+    }
+    
+    /// Never socket-activated: there is no descriptor passing
+    #[cfg(not(unix))]
+    pub(crate) fn inherited() -> io::Result<Option<Self>> {
+        Ok(None)
+    }
+    
+    /// The address the socket is bound to
+    pub(crate) fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                let path = addr.as_pathname().unwrap_or_else(|| std::path::Path::new(""));
+                Ok(ListenAddr::Unix(path.to_path_buf()))
+            }
+        }
+    }
+    
+    /// Which clients the socket accepts, as bound: IPv6 listeners are
+    /// asked whether IPV6_V6ONLY is set. None for Unix sockets.
+    pub(crate) fn accepted_families(&self) -> io::Result<Option<&'static str>> {
+        let listener = match self {
+            Listener::Tcp(listener) => listener,
+            #[cfg(unix)]
+            Listener::Unix(_) => return Ok(None),
+        };
+        let families = match listener.local_addr()? {
+            SocketAddr::V4(_) => "IPv4 clients only",
+            SocketAddr::V6(_) if SockRef::from(listener).only_v6()? => "IPv6 clients only",
+            SocketAddr::V6(_) => "IPv6 clients and IPv4 ones as IPv4-mapped addresses",
+        };
+        Ok(Some(families))
+    }
+    
+    /// Bind a listener for each of `config.workers` accept loops
+    ///
+    /// Where the kernel balances SO_REUSEPORT listeners, every worker gets
+    /// its own; otherwise (and for Unix sockets) they all share one. Fails
+    /// if any worker's listener can't be bound rather than running with
+    /// fewer workers than configured.
+    pub(crate) fn bind_workers(config: &Config) -> io::Result<Vec<Arc<Self>>> {
+        let workers = config.workers.max(1);
+        let first = Arc::new(Self::bind(config)?);
+        let addr = match &*first {
+            Listener::Tcp(listener) if balanced_workers(config) => listener.local_addr()?,
+            _ => return Ok(vec![first; workers]),
+        };
+        
+        let mut listeners = vec![first];
+        for worker in 1..workers {
+            // Bound to the first listener's address, so port 0 is only
+            // picked once
+            let listener = bind_tcp(addr, config.ip_mode, true).map_err(|e| {
+                io::Error::new(e.kind(), format!("listener of worker {}: {}", worker, e))
+            })?;
+            listeners.push(Arc::new(Listener::Tcp(listener)));
+        }
+        Ok(listeners)
+    }
+    
+    pub(crate) async fn accept(&self) -> io::Result<(ClientStream, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((ClientStream::Tcp(stream), addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((ClientStream::Unix(stream), UNIX_CLIENT_ADDR))
+            }
+        }
+    }
+}
+
+/// Whether workers get a listener each
+fn balanced_workers(config: &Config) -> bool {
+    config.workers > 1 && REUSEPORT_BALANCING && matches!(config.listen, ListenAddr::Tcp(_))
+}
+
+/// Bind a TCP listener accepting the families `ip_mode` allows, with
+/// SO_REUSEPORT if asked to
+fn bind_tcp(addr: SocketAddr, ip_mode: IpMode, reuse_port: bool) -> io::Result<TcpListener> {
+    if let Some(conflict) = ip_mode.conflict(addr) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, conflict));
+    }
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => {
+            let socket = TcpSocket::new_v6()?;
+            SockRef::from(&socket).set_only_v6(ip_mode == IpMode::V6Only)?;
+            socket
+        }
+    };
+    
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+    ))]
+    if reuse_port {
+        socket.set_reuseport(true)?;
+    }
+    
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// A client connection accepted by a [`Listener`]
+pub(crate) enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl ClientStream {
+    /// Apply the configured TCP options; Unix sockets have none
+    pub(crate) fn apply_socket_opts(&self, opts: &SocketOpts) {
+        if let ClientStream::Tcp(stream) = self {
+            apply_socket_opts(&SockRef::from(stream), opts);
+        }
+    }
+    
+    /// Reset rather than close the connection when it is dropped; Unix
+    /// sockets have no reset and just close
+    pub(crate) fn set_zero_linger(&self) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.set_zero_linger(),
+            #[cfg(unix)]
+            ClientStream::Unix(_) => Ok(()),
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+    
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+    
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Bounded map that evicts its least recently used entry when full
+///
+/// Eviction scans for the oldest entry, which is cheap at the few thousand
+/// entries the caches here are capped at.
+#[derive(Debug)]
+pub(crate) struct LruMap<K, V> {
+    capacity: usize,
+    entries: HashMap<K, (V, u64)>,
+    /// Monotonic use counter driving eviction
+    clock: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruMap<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self { capacity, entries: HashMap::new(), clock: 0 }
+    }
+    
+    /// The entry for `key`, marked as just used
+    pub(crate) fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.clock += 1;
+        let clock = self.clock;
+        let (value, last_used) = self.entries.get_mut(key)?;
+        *last_used = clock;
+        Some(value)
+    }
+    
+    /// Insert or replace the entry for `key`, evicting the least recently
+    /// used one if the map is full
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.entries.insert(key, (value, self.clock));
+    }
+    
+    /// Drop the entry for `key`, handing back its value
+    pub(crate) fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entries.remove(key).map(|(value, _)| value)
+    }
+}
@@ -1,115 +1,527 @@
-use anyhow::Result;
-use clap::Parser;
-use stpro::{Config, ProxyServer};
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use stpro::{Config, DesyncConfig, DesyncEngine, PidFile, ProxyServer, SegmentRecorder};
 
 #[derive(Parser, Debug)]
 #[command(name = "stpro")]
 #[command(about = "A lightweight, high-performance SOCKS5 proxy server with DPI evasion")]
+#[command(after_help = "Settings are taken, in order of precedence, from the command-line flags, \
+    the STPRO_* environment variables shown with them, the config file, and the defaults.")]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    
+    /// Config file (.toml, .json or .yaml); the flags below override its settings
+    #[arg(short, long, value_name = "FILE", env = "STPRO_CONFIG")]
+    config: Option<PathBuf>,
+    
+    /// Reload the config file whenever it changes on disk, as on SIGHUP
+    #[arg(long, requires = "config")]
+    watch_config: bool,
+    
+    /// Fork into the background and detach from the terminal (Unix)
+    #[arg(long)]
+    daemon: bool,
+    
+    /// Write the server's PID to FILE, removing it on shutdown
+    #[arg(long, value_name = "FILE")]
+    pid_file: Option<PathBuf>,
+    
+    /// Listen address: IP:PORT, or a Unix socket path (unix:/path); --ip,
+    /// --port and --unix override it
+    #[arg(long, value_name = "ADDR", env = "STPRO_LISTEN")]
+    listen: Option<stpro::ListenAddr>,
+    
     /// Listening port (default: 1080)
-    #[arg(short, long, default_value = "1080")]
-    port: u16,
+    #[arg(short, long)]
+    port: Option<u16>,
     
     /// Listening IP address (default: 127.0.0.1)
-    #[arg(short, long, default_value = "127.0.0.1")]
-    ip: String,
+    #[arg(short, long)]
+    ip: Option<String>,
+    
+    /// Listen on a Unix socket at this path instead of TCP
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["port", "ip"])]
+    unix: Option<PathBuf>,
     
-    /// Enable split desync at position (can be specified multiple times)
-    #[arg(short = 's', long)]
+    /// Serve connections redirected here by iptables (REDIRECT) instead of
+    /// proxy clients, forwarding each to its original destination (Linux)
+    #[arg(long)]
+    transparent: bool,
+    
+    /// Start from a named desync strategy (see `stpro presets`); the desync
+    /// flags below override its rules
+    #[arg(long, value_name = "NAME")]
+    preset: Option<String>,
+    
+    /// Take the desync rules from zapret options, e.g.
+    /// "--dpi-desync=split2 --dpi-desync-split-pos=1"; applied after
+    /// --preset, and overridden by the desync flags below
+    #[arg(long, value_name = "ARGS", allow_hyphen_values = true)]
+    zapret: Option<String>,
+    
+    /// Enable split desync at position (can be specified multiple times);
+    /// positions are OFFSET[%][:REPEATS[:SKIP]][+FLAGS] or
+    /// START-END/STEP[+FLAGS], with FLAGS from s, n, h, e, m, r, a; the
+    /// environment variable takes a comma-separated list
+    #[arg(short = 's', long, env = "STPRO_SPLIT", value_delimiter = ',')]
     split: Vec<String>,
     
     /// Enable disorder desync at position (can be specified multiple times)
-    #[arg(short = 'd', long)]
+    #[arg(short = 'd', long, env = "STPRO_DISORDER", value_delimiter = ',')]
     disorder: Vec<String>,
     
     /// Enable fake packet at position (can be specified multiple times)
-    #[arg(short = 'f', long)]
+    #[arg(short = 'f', long, env = "STPRO_FAKE", value_delimiter = ',')]
     fake: Vec<String>,
     
+    /// Send the contents of FILE as the fake packets, e.g. a ClientHello for
+    /// another host (zeros by default)
+    #[arg(long, value_name = "FILE", conflicts_with = "fake_hex")]
+    fake_data: Option<PathBuf>,
+    
+    /// Send these hex-encoded bytes as the fake packets
+    #[arg(long, value_name = "HEX")]
+    fake_hex: Option<String>,
+    
+    /// Split the TLS record of a ClientHello at position (can be specified
+    /// multiple times)
+    #[arg(short = 'r', long)]
+    tls_rec: Vec<String>,
+    
     /// TTL for fake packets (default: 8)
-    #[arg(short = 't', long)]
+    #[arg(short = 't', long, env = "STPRO_TTL")]
     ttl: Option<u8>,
+    
+    /// Serve Prometheus metrics at http://IP:PORT/metrics and readiness at /readyz
+    #[arg(long, value_name = "IP:PORT")]
+    metrics_addr: Option<SocketAddr>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Replay a captured first flight (e.g. a ClientHello) through the desync
+    /// engine and show the resulting segments
+    DesyncFile {
+        /// Raw bytes to replay
+        #[arg(long = "in", value_name = "FILE")]
+        input: PathBuf,
+        
+        /// Config file to take the desync settings from (default: the desync flags)
+        #[arg(long, value_name = "FILE")]
+        config: Option<PathBuf>,
+        
+        /// Also write the segments to FILE, each prefixed by its length as a
+        /// big-endian u32
+        #[arg(long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+    
+    /// Show how the desync engine would split a first flight, without
+    /// sending anything
+    Explain {
+        /// Raw bytes to plan for (default: a sample ClientHello for --sni)
+        #[arg(long = "in", value_name = "FILE")]
+        input: Option<PathBuf>,
+        
+        /// Hostname for the sample ClientHello
+        #[arg(long, default_value = "example.com")]
+        sni: String,
+        
+        /// Config file to take the desync settings from (default: the desync flags)
+        #[arg(long, value_name = "FILE")]
+        config: Option<PathBuf>,
+        
+        /// Print the plan as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check a config file for errors and risky settings without starting
+    /// the server; exits non-zero if any errors are found
+    Validate {
+        /// Config file (.toml, .json or .yaml) to check
+        #[arg(long, value_name = "FILE")]
+        config: PathBuf,
+    },
+    
+    /// Write a fully populated, commented example config to start from
+    GenerateConfig {
+        /// Output format; JSON has no comments
+        #[arg(long, value_enum, default_value_t = ConfigFormat::Toml)]
+        format: ConfigFormat,
+        
+        /// Write to FILE instead of stdout
+        #[arg(long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+    
+    /// Describe the desync strategy presets `--preset` accepts
+    Presets {
+        /// List every preset (the default)
+        #[arg(long)]
+        list: bool,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ConfigFormat {
+    Toml,
+    Json,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     let args = Args::parse();
     
-    // Build configuration
-    let mut config = Config::default();
-    config.listen = format!("{}:{}", args.ip, args.port).parse()?;
+    let config = build_config(&args)?;
     
-    // Parse split configurations
-    for split_str in &args.split {
-        config.desync.split.push(parse_split_config(split_str)?);
+    match &args.command {
+        Some(Command::DesyncFile { input, config: config_path, out }) => {
+            let desync = match config_path {
+                Some(path) => Config::from_file(path)?.desync,
+                None => config.desync,
+            };
+            return runtime()?.block_on(desync_file(desync, input, out.as_deref()));
+        }
+        Some(Command::Explain { input, sni, config: config_path, json }) => {
+            let desync = match config_path {
+                Some(path) => Config::from_file(path)?.desync,
+                None => config.desync,
+            };
+            return explain(desync, input.as_deref(), sni, *json);
+        }
+        Some(Command::Validate { config: config_path }) => {
+            return validate(config_path);
+        }
+        Some(Command::GenerateConfig { format, out }) => {
+            let text = match format {
+                ConfigFormat::Toml => Config::example_toml(),
+                ConfigFormat::Json => Config::example_json() + "\n",
+            };
+            match out {
+                Some(path) => std::fs::write(path, text)
+                    .with_context(|| format!("Failed to write {}", path.display()))?,
+                None => print!("{}", text),
+            }
+            return Ok(());
+        }
+        Some(Command::Presets { list: _ }) => {
+            for preset in stpro::PRESETS {
+                println!("{:<16} {}", preset.name, preset.description);
+            }
+            return Ok(());
+        }
+        None => {}
     }
     
-    // Parse disorder configurations
-    for disorder_str in &args.disorder {
-        config.desync.disorder.push(parse_split_config(disorder_str)?);
+    // Report every problem with the merged file and flag settings at once,
+    // before anything is bound
+    let diagnostics = config.validate();
+    for diagnostic in &diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+    let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+    if errors > 0 {
+        anyhow::bail!("Invalid configuration: {} error(s)", errors);
     }
     
-    // Parse fake configurations
-    for fake_str in &args.fake {
-        config.desync.fake.push(stpro::FakeConfig {
-            split: parse_split_config(fake_str)?,
-            ttl: args.ttl,
-            data: None,
-        });
+    // Fork before the runtime starts its threads; a live PID file is
+    // reported while the terminal is still attached
+    if let Some(path) = &args.pid_file {
+        PidFile::check(path)?;
+    }
+    if args.daemon {
+        stpro::daemonize()?;
     }
+    let pid_file = args.pid_file.as_deref().map(PidFile::create).transpose()?;
+    
+    runtime()?.block_on(serve(config, args))?;
+    drop(pid_file);
     
-    // Create and run proxy server
-    let server = ProxyServer::new(config);
+    Ok(())
+}
+
+/// The multi-threaded runtime the server and `desync-file` run on
+fn runtime() -> Result<tokio::runtime::Runtime> {
+    tokio::runtime::Runtime::new().context("Failed to start the async runtime")
+}
+
+/// Run the proxy server until it is shut down
+async fn serve(config: Config, args: Args) -> Result<()> {
+    let server = Arc::new(ProxyServer::new(config));
+    let args = Arc::new(args);
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(server.clone(), args.clone()));
+    if let Some(path) = args.config.clone().filter(|_| args.watch_config) {
+        tokio::spawn(watch_config(server.clone(), args.clone(), path));
+    }
     server.run().await?;
     
     Ok(())
 }
 
-fn parse_split_config(s: &str) -> Result<stpro::SplitConfig> {
-    // Simple parser for split configuration
-    // Format: offset[+flags] or offset:repeats:skip[+flags]
-    // Flags: s (SNI), h (host), e (end), m (middle)
-    
-    let mut offset_str = s;
-    let mut flags = stpro::SplitFlags::default();
-    let mut repeats = None;
-    let mut skip = None;
-    
-    // Check for flags
-    if let Some(plus_pos) = s.find('+') {
-        offset_str = &s[..plus_pos];
-        let flags_str = &s[plus_pos + 1..];
-        
-        for ch in flags_str.chars() {
-            match ch {
-                's' => flags.sni = true,
-                'h' => flags.host = true,
-                'e' => flags.end = true,
-                'm' => flags.middle = true,
-                _ => {}
-            }
+/// Build the configuration from the config file and the command-line
+/// flags layered over it
+fn build_config(args: &Args) -> Result<Config> {
+    let mut config = match &args.config {
+        Some(path) => Config::from_file(path)?,
+        None => Config::default(),
+    };
+    if let Some(listen) = &args.listen {
+        config.listen = listen.clone();
+    }
+    if let Some(ip) = &args.ip {
+        config.listen.set_ip(ip.parse().with_context(|| format!("Invalid IP address: {}", ip))?);
+    }
+    if let Some(port) = args.port {
+        config.listen.set_port(port);
+    }
+    if let Some(path) = &args.unix {
+        config.listen = stpro::ListenAddr::Unix(path.clone());
+    }
+    if args.transparent {
+        config.transparent = true;
+    }
+    if let Some(addr) = args.metrics_addr {
+        config.metrics_listen = Some(addr);
+    }
+    
+    if let Some(name) = &args.preset {
+        let preset = stpro::preset(name).with_context(|| {
+            format!("Unknown preset: {} (run `stpro presets` for the list)", name)
+        })?;
+        preset.apply(&mut config.desync);
+    }
+    if let Some(zapret) = &args.zapret {
+        let words: Vec<String> = zapret.split_whitespace().map(String::from).collect();
+        let imported = DesyncConfig::from_zapret_args(&words).context("Invalid --zapret options")?;
+        config.desync.split = imported.split;
+        config.desync.disorder = imported.disorder;
+        config.desync.fake = imported.fake;
+        config.desync.ttl = imported.ttl;
+    }
+    
+    // Desync flags replace the corresponding lists from the config file
+    // or preset
+    if !args.split.is_empty() {
+        config.desync.split = args.split.iter()
+            .map(|s| stpro::parse_split_config(s))
+            .collect::<Result<_>>()?;
+    }
+    
+    if !args.disorder.is_empty() {
+        config.desync.disorder = args.disorder.iter()
+            .map(|s| stpro::parse_split_config(s))
+            .collect::<Result<_>>()?;
+    }
+    
+    if !args.tls_rec.is_empty() {
+        config.desync.tls_rec = args.tls_rec.iter()
+            .map(|s| stpro::parse_split_config(s))
+            .collect::<Result<_>>()?;
+    }
+    
+    let fake_data = match (&args.fake_data, &args.fake_hex) {
+        (Some(path), _) => Some(
+            std::fs::read(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?,
+        ),
+        (None, Some(hex)) => Some(parse_hex(hex)?),
+        (None, None) => None,
+    };
+    if fake_data.as_ref().is_some_and(|data| data.is_empty()) {
+        anyhow::bail!("Fake data is empty");
+    }
+    
+    if !args.fake.is_empty() {
+        config.desync.fake = args.fake.iter()
+            .map(|s| Ok(stpro::FakeConfig {
+                split: stpro::parse_split_config(s)?,
+                ttl: args.ttl,
+                data: fake_data.clone(),
+            }))
+            .collect::<Result<_>>()?;
+    } else if fake_data.is_some() {
+        for fake in &mut config.desync.fake {
+            fake.data = fake_data.clone();
+        }
+    }
+    for fake in &config.desync.fake {
+        if let Some(warning) = fake.truncation_warning() {
+            eprintln!("[!] Fake packet: {}", warning);
+        }
+    }
+    Ok(config)
+}
+
+/// Rebuild the configuration and hand it to the server, which switches new
+/// connections to its desync strategies
+fn reload(server: &ProxyServer, args: &Args) {
+    if let Err(e) = build_config(args).and_then(|config| server.reload(config)) {
+        eprintln!("[!] Reload failed, keeping the current config: {:#}", e);
+    }
+}
+
+/// Reload on every SIGHUP
+#[cfg(unix)]
+async fn reload_on_sighup(server: Arc<ProxyServer>, args: Arc<Args>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            eprintln!("[!] Config reload on SIGHUP unavailable: {}", e);
+            return;
         }
+    };
+    while hangup.recv().await.is_some() {
+        eprintln!("[*] SIGHUP received, reloading the config");
+        reload(&server, &args);
     }
+}
+
+/// How long the config file must stay untouched before `watch_config`
+/// reloads it
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Reload whenever the config file at `path` changes
+///
+/// The file's directory is watched, so a rename over the file is seen too.
+/// A reload waits until no change has come in for `WATCH_DEBOUNCE`, so an
+/// editor that writes twice, or truncates and then writes, triggers a
+/// single reload. While the file is missing the current config stays.
+async fn watch_config(server: Arc<ProxyServer>, args: Arc<Args>, path: PathBuf) {
+    use notify::{Event, RecursiveMode, Watcher};
     
-    // Check for repeats:skip format
-    if let Some(colon_pos) = offset_str.find(':') {
-        let parts: Vec<&str> = offset_str.split(':').collect();
-        if parts.len() >= 2 {
-            offset_str = parts[0];
-            repeats = parts[1].parse().ok();
-            if parts.len() >= 3 {
-                skip = parts[2].parse().ok();
-            }
+    let (changed, mut changes) = tokio::sync::mpsc::unbounded_channel();
+    let name = path.file_name().map(|name| name.to_owned());
+    let watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        // Reads, including the reload's own, aren't changes
+        let touches = |event: &Event| {
+            !event.kind.is_access()
+                && event.paths.iter().any(|path| path.file_name() == name.as_deref())
+        };
+        if event.is_ok_and(|event| touches(&event)) {
+            let _ = changed.send(());
+        }
+    });
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let _watcher = match watcher
+        .and_then(|mut watcher| watcher.watch(dir, RecursiveMode::NonRecursive).map(|()| watcher))
+    {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            eprintln!("[!] Watching {} unavailable: {}", path.display(), e);
+            return;
+        }
+    };
+    
+    while changes.recv().await.is_some() {
+        while let Ok(Some(())) = tokio::time::timeout(WATCH_DEBOUNCE, changes.recv()).await {}
+        if path.exists() {
+            eprintln!("[*] {} changed, reloading the config", path.display());
+            reload(&server, &args);
+        }
+    }
+}
+
+/// Decode a hex string, ignoring whitespace and `:` separators
+fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    let digits: Vec<u8> = hex
+        .bytes()
+        .filter(|b| !b.is_ascii_whitespace() && *b != b':')
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        anyhow::bail!("Invalid hex data: odd number of digits");
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .with_context(|| format!("Invalid hex data: {:?}", String::from_utf8_lossy(pair)))
+        })
+        .collect()
+}
+
+/// Load and check `path`, printing every diagnostic
+fn validate(path: &Path) -> Result<()> {
+    let config = Config::from_file(path)?;
+    let diagnostics = config.validate();
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic);
+    }
+    
+    let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+    let warnings = diagnostics.len() - errors;
+    println!("{}: {} error(s), {} warning(s)", path.display(), errors, warnings);
+    if errors > 0 {
+        std::process::exit(1);
+    }
+    
+    Ok(())
+}
+
+/// Print the desync plan for `input` (or a sample ClientHello for `sni`)
+fn explain(desync: DesyncConfig, input: Option<&Path>, sni: &str, json: bool) -> Result<()> {
+    let data = match input {
+        Some(path) => std::fs::read(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?,
+        None => stpro::sample_client_hello(sni),
+    };
+    
+    let plan = DesyncEngine::new(desync).explain(&data);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&plan)?);
+    } else {
+        print!("{}", plan);
+    }
+    
+    Ok(())
+}
+
+/// Run `input` through the desync engine and print the segments it produces
+async fn desync_file(desync: DesyncConfig, input: &Path, out: Option<&Path>) -> Result<()> {
+    let data = std::fs::read(input)
+        .with_context(|| format!("Failed to read {}", input.display()))?;
+    
+    let engine = DesyncEngine::new(desync);
+    let mut recorder = SegmentRecorder::new();
+    engine.apply_desync(&mut recorder, &data).await?;
+    let segments = recorder.into_segments();
+    
+    let mut offset = 0;
+    for (i, segment) in segments.iter().enumerate() {
+        println!(
+            "--- segment {} [{}..{}) {} bytes ---",
+            i + 1,
+            offset,
+            offset + segment.len(),
+            segment.len()
+        );
+        for (line, chunk) in segment.chunks(16).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            println!("{:08x}  {}", line * 16, hex.join(" "));
         }
+        offset += segment.len();
     }
     
-    let offset = offset_str.parse()
-        .map_err(|_| anyhow::anyhow!("Invalid offset: {}", offset_str))?;
+    if let Some(out) = out {
+        let mut framed = Vec::with_capacity(offset + segments.len() * 4);
+        for segment in &segments {
+            framed.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+            framed.extend_from_slice(segment);
+        }
+        std::fs::write(out, framed)
+            .with_context(|| format!("Failed to write {}", out.display()))?;
+    }
     
-    Ok(stpro::SplitConfig {
-        offset,
-        flags,
-        repeats,
-        skip,
-    })
+    Ok(())
 }
@@ -0,0 +1,72 @@
+use crate::stats::ServerStats;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Largest scrape request head accepted
+const MAX_REQUEST_SIZE: usize = 4096;
+
+/// Serve `GET /metrics` in the Prometheus text format on `listener`, and
+/// `GET /readyz`, which answers 503 once `stopping` is set
+///
+/// A deliberately tiny HTTP/1.0-style responder: one request per
+/// connection, closed after the response.
+pub async fn serve_metrics(
+    listener: TcpListener,
+    stats: Arc<ServerStats>,
+    stopping: Arc<AtomicBool>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let stats = stats.clone();
+                let stopping = stopping.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = answer_scrape(stream, &stats, &stopping).await {
+                        eprintln!("[!] Metrics request failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("Failed to accept metrics connection: {}", e),
+        }
+    }
+}
+
+async fn answer_scrape(
+    mut stream: TcpStream,
+    stats: &ServerStats,
+    stopping: &AtomicBool,
+) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    
+    let request_line = request.split(|b| *b == b'\n').next().unwrap_or_default();
+    let response = if request_line.starts_with(b"GET /metrics ") {
+        let body = stats.render_prometheus();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else if request_line.starts_with(b"GET /readyz ") {
+        let status = match stopping.load(Ordering::Relaxed) {
+            false => "200 OK",
+            true => "503 Service Unavailable",
+        };
+        format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status)
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -1,5 +1,18 @@
+use crate::crypto::{aes128_gcm_open, hkdf_expand_label, hkdf_extract, Aes128};
 use std::io;
 
+/// QUIC version 1 (RFC 9000)
+const QUIC_V1: u32 = 0x0000_0001;
+
+/// Salt the QUIC v1 Initial secrets are derived with (RFC 9001, section 5.2)
+const QUIC_V1_INITIAL_SALT: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+
+/// Longest connection ID QUIC v1 allows
+const QUIC_MAX_CID_LEN: usize = 20;
+
 /// Check if buffer contains a TLS ClientHello
 pub fn is_tls_chello(buffer: &[u8]) -> bool {
     if buffer.len() < 5 {
@@ -12,24 +25,110 @@ pub fn is_tls_chello(buffer: &[u8]) -> bool {
     
     // Content type 0x16 = Handshake
     // Version 0x0301, 0x0302, 0x0303, 0x0304 = TLS 1.0-1.3
-    content_type == 0x16 && version >= 0x0301 && version <= 0x0304
+    content_type == 0x16 && (0x0301..=0x0304).contains(&version)
 }
 
-/// Check if buffer contains HTTP request
-pub fn is_http(buffer: &[u8]) -> bool {
-    if buffer.len() < 4 {
-        return false;
+/// Total length (header included) of the TLS record at the start of `buffer`
+///
+/// Taken from the record header's length field; the record may extend past
+/// the end of `buffer`.
+pub fn tls_record_len(buffer: &[u8]) -> Option<usize> {
+    if buffer.len() < 5 {
+        return None;
     }
     
-    // Check for common HTTP methods
-    let methods = [
-        b"GET ", b"POST", b"PUT ", b"HEAD", b"DELE", b"OPTI", b"CONN", b"TRAC", b"PATC"
-    ];
+    Some(5 + u16::from_be_bytes([buffer[3], buffer[4]]) as usize)
+}
+
+/// Build a minimal TLS 1.2-framed ClientHello carrying `sni`
+///
+/// The hostname starts at offset 61. Handy as a fixture for inspecting
+/// desync plans without a captured handshake.
+pub fn sample_client_hello(sni: &str) -> Vec<u8> {
+    let name = sni.as_bytes();
+    
+    let mut sni_ext = Vec::new();
+    sni_ext.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes()); // ServerNameList length
+    sni_ext.push(0x00); // NameType: host_name
+    sni_ext.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    sni_ext.extend_from_slice(name);
+    
+    let mut extensions = Vec::new();
+    extensions.extend_from_slice(&[0x00, 0x00]); // server_name
+    extensions.extend_from_slice(&(sni_ext.len() as u16).to_be_bytes());
+    extensions.extend_from_slice(&sni_ext);
     
-    methods.iter().any(|&method| buffer.starts_with(method))
+    let mut body = Vec::new();
+    body.extend_from_slice(&[0x03, 0x03]); // ClientVersion
+    body.extend_from_slice(&[0x42; 32]); // Random
+    body.push(0x00); // SessionID
+    body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // CipherSuites
+    body.extend_from_slice(&[0x01, 0x00]); // CompressionMethods
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extensions);
+    
+    let mut handshake = vec![0x01]; // ClientHello
+    handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&body);
+    
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+/// First four bytes of the common HTTP methods
+const HTTP_METHOD_OPENINGS: [&[u8; 4]; 9] = [
+    b"GET ", b"POST", b"PUT ", b"HEAD", b"DELE", b"OPTI", b"CONN", b"TRAC", b"PATC"
+];
+
+/// Check if buffer contains HTTP request
+pub fn is_http(buffer: &[u8]) -> bool {
+    HTTP_METHOD_OPENINGS.iter().any(|&method| buffer.starts_with(method))
+}
+
+/// Whether `prefix`, up to four bytes long, is how a request `is_http`
+/// recognizes starts
+pub fn may_be_http(prefix: &[u8]) -> bool {
+    HTTP_METHOD_OPENINGS.iter().any(|method| method.starts_with(prefix))
 }
 
+/// Connection preface a client opens cleartext HTTP/2 with when it
+/// already knows the server speaks it (RFC 9113, section 3.4)
+pub const HTTP2_PREFACE: &[u8; 24] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Length of the preface's first line, `PRI * HTTP/2.0\r\n`, which an
+/// HTTP/1 parser takes for a request line
+pub const HTTP2_PREFACE_LINE_LEN: usize = 16;
+
+/// Check if buffer starts with the HTTP/2 connection preface
+///
+/// The frames following the preface (usually SETTINGS) may share the
+/// buffer.
+pub fn is_http2_preface(buffer: &[u8]) -> bool {
+    buffer.starts_with(HTTP2_PREFACE)
+}
+
+/// HTTP/2 frames that turn a client away before any stream is opened: an
+/// empty SETTINGS frame, which must open the server's side of the
+/// connection, then a GOAWAY with `error_code`
+pub fn http2_goaway(error_code: u32) -> Vec<u8> {
+    // Frame header: Length(3) | Type(1) | Flags(1) | Stream ID(4)
+    let mut frames = vec![0, 0, 0, 0x04, 0, 0, 0, 0, 0];
+    frames.extend_from_slice(&[0, 0, 8, 0x07, 0, 0, 0, 0, 0]);
+    frames.extend_from_slice(&[0, 0, 0, 0]); // Last-Stream-ID
+    frames.extend_from_slice(&error_code.to_be_bytes());
+    frames
+}
+
+/// Port a CONNECT target without one is assumed to use
+pub const DEFAULT_CONNECT_PORT: u16 = 443;
+
 /// Parse HTTP CONNECT request and extract host:port
+///
+/// The target may be a hostname, an IPv4 address or a bracketed IPv6
+/// literal (`[2001:db8::1]:443`, returned without the brackets). A missing
+/// port defaults to 443.
 pub fn parse_http_connect(buffer: &[u8]) -> Option<(String, u16)> {
     let s = std::str::from_utf8(buffer).ok()?;
     let lines: Vec<&str> = s.lines().collect();
@@ -49,119 +148,685 @@ pub fn parse_http_connect(buffer: &[u8]) -> Option<(String, u16)> {
         return None;
     }
     
-    let host_port = parts[1];
-    let colon_pos = host_port.find(':')?;
-    let host = host_port[..colon_pos].to_string();
-    let port: u16 = host_port[colon_pos + 1..].parse().ok()?;
-    
-    Some((host, port))
+    split_host_port(parts[1], DEFAULT_CONNECT_PORT)
 }
 
-/// Find SNI offset in TLS ClientHello
-pub fn find_sni_offset(buffer: &[u8]) -> Option<usize> {
-    if !is_tls_chello(buffer) || buffer.len() < 43 {
+/// Port an absolute-URI request without one is assumed to use
+pub const DEFAULT_HTTP_PORT: u16 = 80;
+
+/// Hop-by-hop headers addressed to the proxy, dropped when forwarding
+const PROXY_HEADERS: [&str; 4] = [
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "Connection",
+    "Keep-Alive",
+];
+
+/// A forward-proxy request rewritten for the origin server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpForward {
+    pub host: String,
+    pub port: u16,
+    /// Request head in origin form (`GET /path HTTP/1.1`), through the
+    /// blank line
+    pub request: Vec<u8>,
+}
+
+/// Parse a forward-proxy request head (`GET http://host/path HTTP/1.1`)
+/// and rewrite it for the origin server
+///
+/// The target comes from the absolute URI, or from the Host header for an
+/// origin-form request. The request line is rewritten to origin form, the
+/// proxy's hop-by-hop headers and any listed in `private` are dropped, a
+/// Host header is added if missing, and `Connection: close` is set since
+/// only one request is forwarded per connection.
+pub fn parse_http_forward(buffer: &[u8], private: &[&str]) -> Option<HttpForward> {
+    if !is_http(buffer) || buffer.starts_with(b"CONNECT ") {
         return None;
     }
+    let s = std::str::from_utf8(buffer).ok()?;
+    let mut lines = s.split("\r\n");
     
-    // Skip TLS record header (5 bytes)
-    let mut offset = 5;
-    
-    // Skip Handshake header (4 bytes)
-    if buffer.len() < offset + 4 {
+    let mut request_line = lines.next()?.split(' ');
+    let (method, uri, version) = (request_line.next()?, request_line.next()?, request_line.next()?);
+    if request_line.next().is_some() || !version.starts_with("HTTP/") {
         return None;
     }
-    offset += 4;
     
-    // Skip ClientVersion (2 bytes)
-    offset += 2;
+    let (authority, path) = match uri.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("http://") => {
+            let rest = &uri[7..];
+            let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+            // Credentials in the URI are not part of the host
+            let authority = rest[..end].rsplit('@').next().unwrap_or("");
+            (Some(authority), &rest[end..])
+        }
+        _ if uri.starts_with('/') => (None, uri),
+        _ => return None,
+    };
+    let path = match path {
+        "" => "/".to_string(),
+        _ if !path.starts_with('/') => format!("/{}", path),
+        _ => path.to_string(),
+    };
     
-    // Skip Random (32 bytes)
-    offset += 32;
+    let host_header = http_header(buffer, "Host");
+    let authority = match authority {
+        Some(authority) if !authority.is_empty() => authority.to_string(),
+        _ => host_header.clone()?,
+    };
+    let (host, port) = split_host_port(&authority, DEFAULT_HTTP_PORT)?;
     
-    // Skip SessionID length + SessionID
-    if buffer.len() < offset + 1 {
-        return None;
+    let mut request = format!("{} {} {}\r\n", method, path, version);
+    if host_header.is_none() {
+        request.push_str(&format!("Host: {}\r\n", authority));
+    }
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let name = line.split(':').next().unwrap_or("").trim();
+        let dropped = PROXY_HEADERS.iter().chain(private).any(|h| h.eq_ignore_ascii_case(name));
+        if !dropped {
+            request.push_str(line);
+            request.push_str("\r\n");
+        }
     }
-    let session_id_len = buffer[offset] as usize;
-    offset += 1 + session_id_len;
+    request.push_str("Connection: close\r\n\r\n");
+    
+    Some(HttpForward { host, port, request: request.into_bytes() })
+}
+
+/// Split an authority (`host`, `host:port`, `[v6]` or `[v6]:port`) into
+/// host and port, using `default_port` when there is none
+///
+/// Brackets are stripped from IPv6 literals. A bare IPv6 address without
+/// brackets is taken as a host without a port.
+pub fn split_host_port(authority: &str, default_port: u16) -> Option<(String, u16)> {
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let (host, after) = rest.split_once(']')?;
+        if host.parse::<std::net::Ipv6Addr>().is_err() {
+            return None;
+        }
+        match after {
+            "" => (host, None),
+            _ => (host, Some(after.strip_prefix(':')?)),
+        }
+    } else {
+        match authority.split_once(':') {
+            Some((host, port)) if !port.contains(':') => (host, Some(port)),
+            _ => (authority, None),
+        }
+    };
     
-    // Skip CipherSuites length + CipherSuites
-    if buffer.len() < offset + 2 {
+    if host.is_empty() {
         return None;
     }
-    let cipher_suites_len = u16::from_be_bytes([buffer[offset], buffer[offset + 1]]) as usize;
-    offset += 2 + cipher_suites_len;
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port,
+    };
+    Some((host.to_string(), port))
+}
+
+/// Value of the first header called `name` (case-insensitive) in an HTTP
+/// request head
+pub fn http_header(buffer: &[u8], name: &str) -> Option<String> {
+    let s = std::str::from_utf8(buffer).ok()?;
     
-    // Skip CompressionMethods length + CompressionMethods
-    if buffer.len() < offset + 1 {
+    s.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| value.trim().to_string())
+        })
+}
+
+/// Decode the credentials of a `Basic` authorization header value
+pub fn parse_basic_auth(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
         return None;
     }
-    let compression_len = buffer[offset] as usize;
-    offset += 1 + compression_len;
     
-    // Now we're at Extensions
-    if buffer.len() < offset + 2 {
+    let decoded = String::from_utf8(decode_base64(encoded.trim())?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// `Basic` authorization header value for the credentials
+pub fn basic_auth_value(username: &str, password: &str) -> String {
+    format!("Basic {}", encode_base64(format!("{}:{}", username, password).as_bytes()))
+}
+
+/// Encode as standard, padded base64
+fn encode_base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    
+    let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let mut group = [0u8; 3];
+        group[..chunk.len()].copy_from_slice(chunk);
+        let group = u32::from_be_bytes([0, group[0], group[1], group[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(group >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decode standard, padded base64
+fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    fn sextet(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a' + 26) as u32),
+            b'0'..=b'9' => Some((c - b'0' + 52) as u32),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+    
+    let input = input.as_bytes();
+    if !input.len().is_multiple_of(4) {
         return None;
     }
-    let extensions_len = u16::from_be_bytes([buffer[offset], buffer[offset + 1]]) as usize;
-    offset += 2;
     
-    let extensions_end = offset + extensions_len;
-    while offset < extensions_end && offset < buffer.len() - 4 {
-        let ext_type = u16::from_be_bytes([buffer[offset], buffer[offset + 1]]);
-        offset += 2;
+    let mut out = Vec::with_capacity(input.len() / 4 * 3);
+    for (i, chunk) in input.chunks(4).enumerate() {
+        let last = i == input.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
         
-        if buffer.len() < offset + 2 {
-            break;
+        let mut group = 0u32;
+        for &c in &chunk[..4 - padding] {
+            group = (group << 6) | sextet(c)?;
         }
-        let ext_len = u16::from_be_bytes([buffer[offset], buffer[offset + 1]]) as usize;
-        offset += 2;
+        group <<= 6 * padding as u32;
+        out.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+    }
+    Some(out)
+}
+
+/// Offset of the SNI hostname in a TLS ClientHello
+///
+/// None for anything malformed or cut off before the hostname starts.
+pub fn find_sni_offset(buffer: &[u8]) -> Option<usize> {
+    if !is_tls_chello(buffer) {
+        return None;
+    }
+    
+    // Every length below comes from the peer, so each advance goes through
+    // the bounds-checked reader: a field pointing past the end of the
+    // buffer ends the search instead of indexing out of bounds
+    
+    // Skip record header (5), handshake header (4), ClientVersion (2) and
+    // Random (32)
+    let mut reader = Reader { buf: buffer, pos: 43 };
+    
+    let session_id_len = reader.u8()? as usize;
+    reader.skip(session_id_len)?;
+    
+    let cipher_suites_len = reader.u16()? as usize;
+    reader.skip(cipher_suites_len)?;
+    
+    let compression_len = reader.u8()? as usize;
+    reader.skip(compression_len)?;
+    
+    let extensions_len = reader.u16()? as usize;
+    let extensions_end = reader.pos + extensions_len;
+    while reader.pos < extensions_end {
+        let ext_type = reader.u16()?;
+        let ext_len = reader.u16()? as usize;
         
         // Extension type 0x0000 = Server Name Indication
         if ext_type == 0x0000 {
-            if buffer.len() < offset + 3 {
-                break;
+            // ServerNameList length, then NameType (0x00 for hostname)
+            reader.skip(2)?;
+            if reader.u8()? != 0x00 {
+                return None;
             }
-            // Skip ServerNameList length
-            offset += 2;
-            // Skip NameType (1 byte, should be 0x00 for hostname)
-            if buffer[offset] == 0x00 {
-                offset += 1;
-                // Skip HostName length (2 bytes)
-                if buffer.len() >= offset + 2 {
-                    return Some(offset + 2);
+            // HostName length; the name itself may still be cut off
+            reader.skip(2)?;
+            return Some(reader.pos);
+        }
+        
+        reader.skip(ext_len)?;
+    }
+    
+    None
+}
+
+/// Offset just past the SNI hostname in a TLS ClientHello
+///
+/// Together with `find_sni_offset` it brackets the hostname bytes. None
+/// when there is no SNI or the name is cut off by the end of the buffer.
+pub fn find_sni_end_offset(buffer: &[u8]) -> Option<usize> {
+    let start = find_sni_offset(buffer)?;
+    
+    // HostName length precedes the name itself
+    let name_len = u16::from_be_bytes([buffer[start - 2], buffer[start - 1]]) as usize;
+    let end = start.checked_add(name_len)?;
+    (end <= buffer.len()).then_some(end)
+}
+
+/// The SNI hostname of a TLS ClientHello, borrowed from `buffer`
+///
+/// None if there is no SNI, the name is cut off by the end of the buffer
+/// or it isn't valid UTF-8.
+pub fn parse_sni(buffer: &[u8]) -> Option<&str> {
+    let start = find_sni_offset(buffer)?;
+    
+    // HostName length precedes the name itself
+    let name_len = u16::from_be_bytes([buffer[start - 2], buffer[start - 1]]) as usize;
+    let name = buffer.get(start..start.checked_add(name_len)?)?;
+    
+    std::str::from_utf8(name).ok()
+}
+
+/// Extract the SNI hostname from a TLS ClientHello
+pub fn extract_sni(buffer: &[u8]) -> Option<String> {
+    parse_sni(buffer).map(str::to_owned)
+}
+
+/// Fields of a TLS ClientHello relevant to fingerprinting
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    /// `legacy_version` from the handshake body (0x0303 for TLS 1.2/1.3)
+    pub version: u16,
+    pub cipher_suites: Vec<u16>,
+    /// Extension types in the order they appear
+    pub extensions: Vec<u16>,
+    /// Named groups from supported_groups (elliptic_curves)
+    pub supported_groups: Vec<u16>,
+    pub ec_point_formats: Vec<u8>,
+    pub sni: Option<String>,
+}
+
+/// Parse the ClientHello at the start of `buffer`
+///
+/// Extensions cut off by the end of the buffer are ignored, so a hello split
+/// across reads still yields everything up to the cut.
+pub fn parse_client_hello(buffer: &[u8]) -> Option<ClientHello> {
+    if !is_tls_chello(buffer) || buffer.get(5) != Some(&0x01) {
+        return None;
+    }
+    
+    let mut reader = Reader { buf: buffer, pos: 9 };
+    let mut hello = ClientHello {
+        version: reader.u16()?,
+        ..ClientHello::default()
+    };
+    
+    reader.skip(32)?; // Random
+    let session_id_len = reader.u8()? as usize;
+    reader.skip(session_id_len)?;
+    
+    let suites = reader.u16()? as usize;
+    hello.cipher_suites = reader.take(suites)?.chunks_exact(2).map(be_u16).collect();
+    
+    let compression_len = reader.u8()? as usize;
+    reader.skip(compression_len)?;
+    
+    // No extensions at all is valid
+    let Some(extensions_len) = reader.u16() else {
+        return Some(hello);
+    };
+    let extensions_end = reader.pos + extensions_len as usize;
+    
+    while reader.pos < extensions_end {
+        let (Some(ext_type), Some(ext_len)) = (reader.u16(), reader.u16()) else {
+            break;
+        };
+        let Some(data) = reader.take(ext_len as usize) else {
+            break;
+        };
+        hello.extensions.push(ext_type);
+        
+        let mut ext = Reader { buf: data, pos: 0 };
+        match ext_type {
+            0x0000 => {
+                hello.sni = (|| {
+                    ext.skip(3)?; // ServerNameList length, NameType
+                    let len = ext.u16()? as usize;
+                    String::from_utf8(ext.take(len)?.to_vec()).ok()
+                })();
+            }
+            0x000a => {
+                if let Some(len) = ext.u16() {
+                    if let Some(groups) = ext.take(len as usize) {
+                        hello.supported_groups = groups.chunks_exact(2).map(be_u16).collect();
+                    }
+                }
+            }
+            0x000b => {
+                if let Some(len) = ext.u8() {
+                    if let Some(formats) = ext.take(len as usize) {
+                        hello.ec_point_formats = formats.to_vec();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    
+    Some(hello)
+}
+
+fn be_u16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+/// Bounds-checked cursor over a byte slice
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(bytes)
+    }
+    
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+    
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+    
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(be_u16)
+    }
+    
+    /// QUIC variable-length integer (RFC 9000, section 16)
+    fn varint(&mut self) -> Option<u64> {
+        let first = self.u8()?;
+        let mut value = (first & 0x3f) as u64;
+        for _ in 1..1 << (first >> 6) {
+            value = (value << 8) | self.u8()? as u64;
+        }
+        Some(value)
+    }
+    
+    fn varint_len(&mut self) -> Option<usize> {
+        usize::try_from(self.varint()?).ok()
+    }
+}
+
+/// Check if `datagram` starts with a QUIC version 1 Initial packet
+///
+/// Only looks at the unprotected part of the long header, so this is
+/// cheap enough to run on every datagram.
+pub fn is_quic_initial(datagram: &[u8]) -> bool {
+    // Long header form and fixed bit set, packet type 0 (Initial)
+    datagram.len() > 5
+        && datagram[0] & 0xf0 == 0xc0
+        && u32::from_be_bytes([datagram[1], datagram[2], datagram[3], datagram[4]]) == QUIC_V1
+}
+
+/// CRYPTO stream data of the QUIC Initial at the start of `datagram`
+///
+/// Initial packets are encrypted, but with keys derived from their
+/// Destination Connection ID, so anyone on the path can read them; this
+/// removes the header protection and decrypts the payload (RFC 9001,
+/// section 5). The CRYPTO frames are stitched together by offset, whatever
+/// order they come in, for as far as they cover the stream from its start.
+/// For a client's first Initial that is (the start of) the ClientHello
+/// handshake message. None if the packet isn't a v1 Initial, fails
+/// authentication or holds no CRYPTO data at offset 0.
+pub fn quic_initial_crypto(datagram: &[u8]) -> Option<Vec<u8>> {
+    if !is_quic_initial(datagram) {
+        return None;
+    }
+    
+    let mut reader = Reader { buf: datagram, pos: 5 };
+    let dcid_len = reader.u8()? as usize;
+    if dcid_len > QUIC_MAX_CID_LEN {
+        return None;
+    }
+    let dcid = reader.take(dcid_len)?;
+    let scid_len = reader.u8()? as usize;
+    if scid_len > QUIC_MAX_CID_LEN {
+        return None;
+    }
+    reader.skip(scid_len)?;
+    let token_len = reader.varint_len()?;
+    reader.skip(token_len)?;
+    // Length covers the packet number and the payload; further packets
+    // may be coalesced into the datagram after it
+    let length = reader.varint_len()?;
+    let pn_offset = reader.pos;
+    let packet_end = pn_offset.checked_add(length)?;
+    
+    let initial_secret = hkdf_extract(&QUIC_V1_INITIAL_SALT, dcid);
+    let client_secret = hkdf_expand_label(&initial_secret, "client in", 32);
+    let key: [u8; 16] = hkdf_expand_label(&client_secret, "quic key", 16).try_into().ok()?;
+    let iv = hkdf_expand_label(&client_secret, "quic iv", 12);
+    let hp: [u8; 16] = hkdf_expand_label(&client_secret, "quic hp", 16).try_into().ok()?;
+    
+    // The header protection mask comes from a sample of the ciphertext
+    // that starts as if the packet number were 4 bytes long
+    let sample: [u8; 16] = datagram.get(pn_offset + 4..pn_offset + 20)?.try_into().ok()?;
+    let mask = Aes128::new(&hp).encrypt_block(&sample);
+    let mut header = datagram[..pn_offset].to_vec();
+    header[0] ^= mask[0] & 0x0f;
+    let pn_len = (header[0] & 0x03) as usize + 1;
+    let mut packet_number = 0u64;
+    for (i, byte) in datagram[pn_offset..pn_offset + pn_len].iter().enumerate() {
+        let byte = byte ^ mask[1 + i];
+        header.push(byte);
+        packet_number = (packet_number << 8) | byte as u64;
+    }
+    
+    let mut nonce: [u8; 12] = iv.try_into().ok()?;
+    for (n, p) in nonce[4..].iter_mut().zip(packet_number.to_be_bytes()) {
+        *n ^= p;
+    }
+    let ciphertext = datagram.get(pn_offset + pn_len..packet_end)?;
+    let payload = aes128_gcm_open(&key, &nonce, &header, ciphertext)?;
+    reassemble_crypto(&payload)
+}
+
+/// Contiguous CRYPTO stream data from offset 0 in a decrypted Initial
+/// payload
+fn reassemble_crypto(payload: &[u8]) -> Option<Vec<u8>> {
+    let mut reader = Reader { buf: payload, pos: 0 };
+    let mut frames = Vec::new();
+    while reader.pos < payload.len() {
+        match reader.varint()? {
+            // PADDING, PING
+            0x00 | 0x01 => {}
+            // ACK, with ECN counts for 0x03
+            frame_type @ (0x02 | 0x03) => {
+                reader.varint()?; // Largest Acknowledged
+                reader.varint()?; // ACK Delay
+                let ranges = reader.varint()?;
+                reader.varint()?; // First ACK Range
+                for _ in 0..ranges {
+                    reader.varint()?; // Gap
+                    reader.varint()?; // ACK Range Length
+                }
+                if frame_type == 0x03 {
+                    for _ in 0..3 {
+                        reader.varint()?;
+                    }
                 }
             }
+            // CRYPTO
+            0x06 => {
+                let offset = reader.varint_len()?;
+                let len = reader.varint_len()?;
+                frames.push((offset, reader.take(len)?));
+            }
+            // CONNECTION_CLOSE is the only other frame allowed in an
+            // Initial, and nothing follows it worth reading
+            _ => break,
+        }
+    }
+    
+    frames.sort_by_key(|(offset, _)| *offset);
+    let mut stream = Vec::new();
+    for (offset, data) in frames {
+        if offset > stream.len() {
             break;
         }
-        
-        offset += ext_len;
+        let overlap = stream.len() - offset;
+        if overlap < data.len() {
+            stream.extend_from_slice(&data[overlap..]);
+        }
     }
+    (!stream.is_empty()).then_some(stream)
+}
+
+/// The CRYPTO stream of a QUIC Initial framed as a TLS record, so the TLS
+/// ClientHello parsers can read it
+fn quic_hello_record(datagram: &[u8]) -> Option<Vec<u8>> {
+    let hello = quic_initial_crypto(datagram)?;
+    let len = u16::try_from(hello.len()).ok()?;
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&len.to_be_bytes());
+    record.extend_from_slice(&hello);
+    Some(record)
+}
+
+/// Offset of the SNI hostname in the ClientHello of a QUIC Initial,
+/// counted in the CRYPTO stream (which starts with the handshake header)
+///
+/// None if the Initial can't be read or the hostname lies beyond the
+/// CRYPTO data it carries, e.g. in a later packet of a large ClientHello.
+pub fn find_quic_sni_offset(datagram: &[u8]) -> Option<usize> {
+    let record = quic_hello_record(datagram)?;
+    find_sni_offset(&record).map(|offset| offset - 5)
+}
+
+/// Extract the SNI hostname from the ClientHello of a QUIC Initial
+pub fn extract_quic_sni(datagram: &[u8]) -> Option<String> {
+    parse_sni(&quic_hello_record(datagram)?).map(str::to_owned)
+}
+
+/// Offset of the Host header value in an HTTP request
+///
+/// Only header lines count: the name must start a line after the request
+/// line, and the search stops at the blank line ending the head, so a
+/// `Host: ` inside another header's value or the body is never picked. The
+/// name matches case-insensitively and whitespace before the value is
+/// skipped. A head cut off by the end of the buffer is searched as far as
+/// it goes.
+pub fn find_http_host_offset(buffer: &[u8]) -> Option<usize> {
+    const NAME: &[u8] = b"host:";
     
+    // Skip the request line
+    let mut line_start = buffer.windows(2).position(|w| w == b"\r\n")? + 2;
+    while line_start < buffer.len() {
+        let rest = &buffer[line_start..];
+        let line_len = rest.windows(2).position(|w| w == b"\r\n").unwrap_or(rest.len());
+        let line = &rest[..line_len];
+        if line.is_empty() {
+            break;
+        }
+        if line.len() >= NAME.len() && line[..NAME.len()].eq_ignore_ascii_case(NAME) {
+            let value = &line[NAME.len()..];
+            let spaces = value.iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+            return Some(line_start + NAME.len() + spaces);
+        }
+        line_start += line_len + 2;
+    }
     None
 }
 
-/// Find HTTP Host header offset
-pub fn find_http_host_offset(buffer: &[u8]) -> Option<usize> {
-    let s = std::str::from_utf8(buffer).ok()?;
-    let host_header = "Host: ";
+/// Split the TLS record at the start of `buffer` at every one of
+/// `positions`, in one pass
+///
+/// Positions count from the start of the buffer as it is, header included,
+/// may come in any order, and follow the rules of `split_tls_record`: each
+/// must fall inside the first record's payload and within the bytes
+/// present, and no two may be equal, so every record keeps at least one
+/// byte. If any is invalid, `buffer` is left untouched. The first record
+/// becomes `positions.len() + 1` records with its content type and version
+/// and the same payload; what follows it is kept as it is.
+pub fn split_tls_record_multi(buffer: &mut Vec<u8>, positions: &[usize]) -> io::Result<()> {
+    let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    
+    if buffer.len() < 5 {
+        return invalid("Buffer shorter than a TLS record header".to_string());
+    }
+    let record_end = 5 + u16::from_be_bytes([buffer[3], buffer[4]]) as usize;
+    
+    let mut cuts = positions.to_vec();
+    cuts.sort_unstable();
+    for (i, &pos) in cuts.iter().enumerate() {
+        if pos <= 5 {
+            return invalid(format!("Position {} inside the TLS record header", pos));
+        }
+        if pos >= record_end {
+            return invalid(format!("Position {} not inside the first TLS record", pos));
+        }
+        if pos > buffer.len() {
+            return invalid(format!("Position {} beyond the end of the buffer", pos));
+        }
+        if i > 0 && cuts[i - 1] == pos {
+            return invalid(format!("Position {} given twice", pos));
+        }
+    }
+    if cuts.is_empty() {
+        return Ok(());
+    }
+    
+    let mut framed = Vec::with_capacity(buffer.len() + 5 * cuts.len());
+    let mut start = 5;
+    for end in cuts.iter().copied().chain([record_end]) {
+        let len = end - start;
+        framed.extend_from_slice(&buffer[..3]); // ContentType, version
+        framed.extend_from_slice(&(len as u16).to_be_bytes());
+        // The last record may run past the bytes present
+        framed.extend_from_slice(&buffer[start..end.min(buffer.len())]);
+        start = end;
+    }
+    if record_end < buffer.len() {
+        framed.extend_from_slice(&buffer[record_end..]);
+    }
+    *buffer = framed;
     
-    s.find(host_header).map(|pos| pos + host_header.len())
+    Ok(())
 }
 
-/// Split TLS record at specified position
+/// Split the TLS record at the start of `buffer` in two at `position`
+///
+/// `position` counts from the start of the buffer, header included, and
+/// must fall inside the first record's payload with at least one byte on
+/// either side. Only that record is re-framed: records after it in the
+/// buffer are left as they are, and a record running past the end of the
+/// buffer (the rest still to come) can be split within the bytes present.
 pub fn split_tls_record(buffer: &mut Vec<u8>, position: usize) -> io::Result<()> {
-    if buffer.len() < position + 5 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Position too close to end of buffer"
-        ));
+    let invalid = |message| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    
+    if buffer.len() < 5 {
+        return invalid("Buffer shorter than a TLS record header");
+    }
+    if position <= 5 {
+        return invalid("Position inside the TLS record header");
     }
     
     // Get original record length
     let original_len = u16::from_be_bytes([buffer[3], buffer[4]]) as usize;
+    if position >= 5 + original_len {
+        return invalid("Position at or beyond the end of the first TLS record");
+    }
+    if position > buffer.len() {
+        return invalid("Position beyond the end of the buffer");
+    }
     
-    // Calculate split point
+    // Calculate split point; both parts are non-empty by the checks above
     let first_part_len = position - 5; // Exclude header
     let second_part_len = original_len - first_part_len;
     
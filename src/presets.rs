@@ -0,0 +1,96 @@
+use crate::config::{DesyncConfig, FakeConfig, SplitConfig, SplitFlags};
+use crate::packets::sample_client_hello;
+
+/// Hostname the fake ClientHellos of presets and imported strategies ask for
+const DECOY_SNI: &str = "www.iana.org";
+
+/// A named desync strategy that is known to get past common DPI setups
+pub struct Preset {
+    pub name: &'static str,
+    pub description: &'static str,
+    rules: fn() -> DesyncConfig,
+}
+
+impl Preset {
+    /// Replace the rule lists and TTL of `desync` with this preset's; port
+    /// lists and the other engine settings are kept
+    pub fn apply(&self, desync: &mut DesyncConfig) {
+        let rules = (self.rules)();
+        desync.split = rules.split;
+        desync.disorder = rules.disorder;
+        desync.fake = rules.fake;
+        desync.tls_rec = rules.tls_rec;
+        desync.ttl = rules.ttl;
+    }
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "tls-sni-split",
+        description: "Split the ClientHello one byte into the SNI hostname",
+        rules: || DesyncConfig {
+            split: vec![rule(1, SplitFlags { sni: true, ..SplitFlags::default() })],
+            ..DesyncConfig::default()
+        },
+    },
+    Preset {
+        name: "http-host-split",
+        description: "Split plain HTTP requests two bytes into the Host header value",
+        rules: || DesyncConfig {
+            split: vec![rule(2, SplitFlags { host: true, ..SplitFlags::default() })],
+            ..DesyncConfig::default()
+        },
+    },
+    Preset {
+        name: "disorder-sni",
+        description: "Send the ClientHello up to the SNI with a TTL of 1 so it is \
+                      retransmitted after the rest",
+        rules: || DesyncConfig {
+            disorder: vec![rule(1, SplitFlags { sni: true, ..SplitFlags::default() })],
+            ..DesyncConfig::default()
+        },
+    },
+    Preset {
+        name: "fake-ttl",
+        description: "Send a ClientHello for another host with a TTL of 8 ahead of \
+                      the real bytes up to the SNI",
+        rules: || DesyncConfig {
+            fake: vec![FakeConfig {
+                split: rule(1, SplitFlags { sni: true, ..SplitFlags::default() }),
+                ttl: Some(8),
+                data: Some(decoy_client_hello()),
+            }],
+            ..DesyncConfig::default()
+        },
+    },
+    Preset {
+        name: "tls-rec-sni",
+        description: "Cut the ClientHello into two TLS records inside the SNI, then \
+                      split the segments at the record boundary",
+        rules: || DesyncConfig {
+            split: vec![rule(0, SplitFlags { record_end: true, ..SplitFlags::default() })],
+            tls_rec: vec![rule(1, SplitFlags { sni: true, ..SplitFlags::default() })],
+            ..DesyncConfig::default()
+        },
+    },
+];
+
+/// Innocuous ClientHello to send as a fake
+pub(crate) fn decoy_client_hello() -> Vec<u8> {
+    sample_client_hello(DECOY_SNI)
+}
+
+/// The preset called `name`, if there is one
+pub fn preset(name: &str) -> Option<&'static Preset> {
+    PRESETS.iter().find(|preset| preset.name == name)
+}
+
+pub(crate) fn rule(offset: i64, flags: SplitFlags) -> SplitConfig {
+    SplitConfig {
+        offset,
+        flags,
+        repeats: None,
+        skip: None,
+        jitter: None,
+    }
+}
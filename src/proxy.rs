@@ -1,81 +1,956 @@
-use crate::config::Config;
-use crate::desync::DesyncEngine;
-use anyhow::{Context, Result};
-use std::net::SocketAddr;
-use tokio::io::{split, AsyncReadExt, AsyncWriteExt};
+use crate::access_log::AccessLog;
+use crate::auto::{detect, AutoStrategies, DetectionOutcome, FirstResponse};
+use crate::buffers::BufferPool;
+use crate::config::{
+    host_pattern_matches, AuthConfig, CanaryConfig, Config, DesyncConfig, HostPattern, HostRule,
+    IpNet, ListenAddr,
+};
+use crate::connect::{ConnectError, Connector, SegmentCounter, TtlControl};
+use crate::desync::{DesyncEngine, FlowInfo, SocketControl};
+use crate::dns::{Resolve, Resolver, SystemResolver};
+use crate::fingerprint::{ja3_hash, ja3_string};
+use crate::limits::{IpSlot, IpSlots, TokenBucket};
+use crate::listener::{ClientStream, Listener};
+use crate::metrics::serve_metrics;
+use crate::packets::{
+    http2_goaway, http_header, is_http, is_http2_preface, is_tls_chello, may_be_http,
+    parse_client_hello, parse_sni, tls_record_len, HTTP2_PREFACE, HTTP2_PREFACE_LINE_LEN,
+};
+use crate::stats::{ConnectionOutcome, ConnectionStats, ServerStats};
+use crate::transparent::{original_destination, TRANSPARENT_SUPPORTED};
+use crate::udp::{read_address, write_address, UdpRelay};
+use anyhow::{bail, Context, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use std::io::Cursor;
+use tokio::io::{
+    copy_bidirectional_with_sizes, join, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Notify, Semaphore};
+use tokio::task::JoinSet;
 
-const SOCKS5_VERSION: u8 = 0x05;
-const SOCKS5_AUTH_NONE: u8 = 0x00;
-const SOCKS5_CMD_CONNECT: u8 = 0x01;
-const SOCKS5_ATYP_IPV4: u8 = 0x01;
-const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
-const SOCKS5_ATYP_IPV6: u8 = 0x04;
-const SOCKS5_REP_SUCCESS: u8 = 0x00;
+pub(crate) const SOCKS5_VERSION: u8 = 0x05;
+pub(crate) const SOCKS5_AUTH_NONE: u8 = 0x00;
+pub(crate) const SOCKS5_AUTH_USERPASS: u8 = 0x02;
+pub(crate) const SOCKS5_AUTH_NO_ACCEPTABLE: u8 = 0xFF;
+pub(crate) const USERPASS_VERSION: u8 = 0x01;
+pub(crate) const USERPASS_SUCCESS: u8 = 0x00;
+const USERPASS_FAILURE: u8 = 0x01;
+pub(crate) const SOCKS5_CMD_CONNECT: u8 = 0x01;
+const SOCKS5_CMD_UDP_ASSOCIATE: u8 = 0x03;
+pub(crate) const SOCKS5_ATYP_IPV4: u8 = 0x01;
+pub(crate) const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+pub(crate) const SOCKS5_ATYP_IPV6: u8 = 0x04;
+pub(crate) const SOCKS5_REP_SUCCESS: u8 = 0x00;
+const SOCKS5_REP_GENERAL_FAILURE: u8 = 0x01;
+const SOCKS5_REP_NOT_ALLOWED: u8 = 0x02;
+const SOCKS5_REP_NETWORK_UNREACHABLE: u8 = 0x03;
+const SOCKS5_REP_HOST_UNREACHABLE: u8 = 0x04;
+const SOCKS5_REP_CONNECTION_REFUSED: u8 = 0x05;
+const SOCKS5_REP_TTL_EXPIRED: u8 = 0x06;
+const SOCKS5_REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const SOCKS5_REP_ATYP_NOT_SUPPORTED: u8 = 0x08;
+
+/// BND.ADDR of replies that have no bound address to report
+const NO_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+/// How often drain mode checks whether the last connection has finished
+const DRAIN_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+/// How long an accepted client waits for a free slot once `max_connections`
+/// handlers are running before it is dropped
+const CONNECTION_SLOT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Grace period in-flight connections get on shutdown unless configured
+const DEFAULT_SHUTDOWN_GRACE: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How long cut connections get to unwind before `run` returns anyway
+const FORCE_CLOSE_WAIT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Forwarding buffers outside this range are allowed but warned about
+const MIN_SANE_BUFFER_SIZE: usize = 512;
+const MAX_SANE_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Largest ClientHello record buffered in full: a maximal TLS record
+const MAX_CLIENT_HELLO_RECORD: usize = 5 + 16384;
+
+/// Longest wait for the rest of a ClientHello that arrived in pieces
+const CLIENT_HELLO_READ_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// SOCKS5 username prefix marking the rest of the username as a tag
+const TAG_USERNAME_PREFIX: &str = "tag:";
+/// HTTP proxy request header carrying a tag; never forwarded to the origin
+const TAG_HEADER: &str = "X-Stpro-Tag";
+
+/// HTTP/2 error code asking the client to retry over HTTP/1.1
+const HTTP2_HTTP_1_1_REQUIRED: u32 = 0x0d;
 
 pub struct ProxyServer {
     config: Config,
+    shared: Shared,
+    /// Strategies new connections start with; replaced by `reload` and
+    /// `reload_desync`
+    strategies: RwLock<Strategies>,
+    /// Config the current strategies were built from, for `reload` to
+    /// compare the next one against
+    applied: Mutex<Config>,
+    stats: Arc<ServerStats>,
+    /// One permit per running client handler, `max_connections` in total
+    connection_slots: Arc<Semaphore>,
+    /// Set once a drain or shutdown stops the accept loop; `/readyz`
+    /// answers 503 from then on
+    draining: Arc<AtomicBool>,
+    drain_requested: Notify,
+    shutdown_requested: Notify,
+    /// Set once the drain or shutdown grace period is over; connections
+    /// still running then are cut
+    cut_connections: watch::Sender<bool>,
+    /// Connections cut by `cut_connections`
+    force_closed: Arc<AtomicUsize>,
+}
+
+/// How the connections in flight when `run` stopped accepting ended
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// Finished on their own within the grace period
+    pub drained: usize,
+    /// Still running when the grace period ran out, and cut
+    pub force_closed: usize,
+}
+
+impl std::fmt::Display for ShutdownSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} connections drained, {} force-closed", self.drained, self.force_closed)
+    }
+}
+
+/// Why the accept loop stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    Drain,
+    Shutdown,
+}
+
+/// Server state handed to every connection handler
+///
+/// Each connection gets its own copy at accept time, so the desync engine a
+/// connection uses is fixed for its whole lifetime, reloads included.
+#[derive(Clone)]
+struct Shared {
     desync_engine: DesyncEngine,
+    resolver: Resolver,
+    connector: Connector,
+    /// Address UDP relay sockets are bound on (the listen address)
+    listen_ip: IpAddr,
+    /// Source address for outbound UDP datagrams
+    bind_addr: Option<SocketAddr>,
+    disable_ipv6: bool,
+    auth_method_priority: Arc<[u8]>,
+    /// Credentials clients must present, if required
+    auth: Option<Arc<AuthConfig>>,
+    /// Client ranges let in (everyone if empty) and kept out
+    allow: Arc<[IpNet]>,
+    deny: Arc<[IpNet]>,
+    /// Hostname patterns of refused targets
+    block_hosts: Arc<[String]>,
+    /// Open connections per client address, if capped
+    ip_slots: Option<Arc<IpSlots>>,
+    /// Throughput cap of each forwarding direction, in bytes per second
+    max_rate: Option<u64>,
+    http_max_request_line: usize,
+    http_max_header_bytes: usize,
+    /// Read buffer size for each forwarding direction
+    buffer_size: usize,
+    /// Where forwarding directions get their read buffers
+    buffers: Arc<BufferPool>,
+    tag_engines: Arc<HashMap<String, DesyncEngine>>,
+    /// Aggregate counters, for the byte totals updated while forwarding
+    server_stats: Arc<ServerStats>,
+    /// Close tunnels quiet in both directions for this long
+    idle_timeout: Option<Duration>,
+    host_engines: Arc<Vec<(HostRule, DesyncEngine)>>,
+    /// Target ports desync applies to (all if empty)
+    desync_ports: Arc<[u16]>,
+    /// Engine for connections to the other ports
+    passthrough: DesyncEngine,
+}
+
+impl Shared {
+    /// Check a new client against allow/deny (deny wins) and the
+    /// per-address connection cap; the slot is held until the connection ends
+    fn admit(&self, client_addr: SocketAddr) -> Result<Option<IpSlot>> {
+        let ip = client_addr.ip();
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip));
+        if !allowed || self.deny.iter().any(|net| net.contains(ip)) {
+            eprintln!("[!] Refusing client {}: not allowed by allow/deny", client_addr);
+            return Err(reject("client address not allowed"));
+        }
+        
+        let Some(ip_slots) = self.ip_slots.as_ref().filter(|_| !ip.is_unspecified()) else {
+            return Ok(None);
+        };
+        match ip_slots.acquire(ip) {
+            Some(slot) => Ok(Some(slot)),
+            None => {
+                eprintln!("[!] Refusing client {}: too many connections from {}", client_addr, ip);
+                Err(reject("per-address connection limit reached"))
+            }
+        }
+    }
+    
+    /// Whether `host` matches one of the `block_hosts` patterns
+    fn is_blocked(&self, host: &str) -> bool {
+        self.block_hosts.iter().any(|pattern| host_pattern_matches(pattern, host))
+    }
+    
+    /// Resolve a domain target, dropping IPv6 addresses if disabled
+    async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+        let mut addrs = self.resolver.resolve(host, port).await?;
+        if self.disable_ipv6 {
+            addrs.retain(SocketAddr::is_ipv4);
+        }
+        Ok(addrs)
+    }
+    
+    /// Record the client's tag and switch to its profile, if one is configured
+    fn apply_tag(&mut self, tag: String, stats: &mut ConnectionStats) {
+        if let Some(engine) = self.tag_engines.get(&tag) {
+            self.desync_engine = engine.clone();
+            stats.strategy = engine.mode_name();
+            stats.canary = false;
+        }
+        stats.tag = Some(tag);
+    }
+    
+    /// Engine of the most specific host override matching the target
+    fn host_override(&self, host: &str, addr: &SocketAddr) -> Option<&DesyncEngine> {
+        // Through an upstream proxy a domain's address is the proxy's, which
+        // address ranges must not match
+        let addr_unknown = self.connector.upstream().is_some() && host.parse::<IpAddr>().is_err();
+        let usable = |rule: &HostRule| {
+            let by_addr = matches!(rule.pattern, HostPattern::Cidr(_));
+            rule.matches(host, addr) && !(addr_unknown && by_addr)
+        };
+        
+        let mut best: Option<&(HostRule, DesyncEngine)> = None;
+        for entry in self.host_engines.iter().filter(|(rule, _)| usable(rule)) {
+            if best.is_none_or(|(rule, _)| entry.0.specificity() > rule.specificity()) {
+                best = Some(entry);
+            }
+        }
+        best.map(|(_, engine)| engine)
+    }
 }
 
 impl ProxyServer {
     pub fn new(config: Config) -> Self {
-        let desync_engine = DesyncEngine::new(config.desync.clone());
+        Self::with_resolver(config, Arc::new(SystemResolver))
+    }
+    
+    /// Create a server that resolves domain targets through `backend`
+    pub fn with_resolver(config: Config, backend: Arc<dyn Resolve>) -> Self {
+        let mut resolver = Resolver::new(backend, config.max_concurrent_resolves);
+        if let Some(cache) = &config.dns_cache {
+            resolver = resolver.with_cache(cache.clone());
+        }
+        let stats = Arc::new(ServerStats::default());
+        let strategies = Strategies::new(&config, None);
+        let shared = Shared {
+            desync_engine: strategies.desync_engine.clone(),
+            resolver,
+            connector: Connector::new(&config),
+            listen_ip: config.listen.tcp_addr().map_or(Ipv4Addr::LOCALHOST.into(), |a| a.ip()),
+            bind_addr: config.bind_addr,
+            disable_ipv6: config.disable_ipv6,
+            // With credentials configured, username/password is the only
+            // acceptable method
+            auth_method_priority: match &config.auth {
+                Some(_) => Arc::new([SOCKS5_AUTH_USERPASS]),
+                None => config.auth_method_priority.as_slice().into(),
+            },
+            auth: config.auth.clone().map(Arc::new),
+            allow: config.allow.as_slice().into(),
+            deny: config.deny.as_slice().into(),
+            block_hosts: config.block_hosts.as_slice().into(),
+            ip_slots: config.max_connections_per_ip.map(|limit| Arc::new(IpSlots::new(limit))),
+            max_rate: config.max_rate_bytes_per_sec,
+            http_max_request_line: config.http_max_request_line,
+            http_max_header_bytes: config.http_max_header_bytes,
+            buffer_size: config.buffer_size.max(1),
+            buffers: Arc::new(BufferPool::new(config.buffer_size.max(1), config.buffer_pool_size)),
+            idle_timeout: config.idle_timeout,
+            tag_engines: strategies.tag_engines.clone(),
+            server_stats: stats.clone(),
+            host_engines: strategies.host_engines.clone(),
+            desync_ports: config.desync_ports.as_slice().into(),
+            passthrough: DesyncEngine::new(DesyncConfig::default()),
+        };
+        let connection_slots = Arc::new(Semaphore::new(config.max_connections.max(1)));
         Self {
+            strategies: RwLock::new(strategies),
+            applied: Mutex::new(config.clone()),
             config,
-            desync_engine,
+            shared,
+            stats,
+            connection_slots,
+            draining: Arc::default(),
+            drain_requested: Notify::new(),
+            shutdown_requested: Notify::new(),
+            cut_connections: watch::Sender::new(false),
+            force_closed: Arc::default(),
         }
     }
     
-    pub async fn run(&self) -> Result<()> {
-        let listener = TcpListener::bind(&self.config.listen)
-            .await
-            .with_context(|| format!("Failed to bind to {}", self.config.listen))?;
+    /// Counters aggregated across all connections
+    pub fn stats(&self) -> &Arc<ServerStats> {
+        &self.stats
+    }
+    
+    /// Number of client handlers currently holding one of the
+    /// `max_connections` slots
+    pub fn connections_in_flight(&self) -> usize {
+        self.config.max_connections.max(1) - self.connection_slots.available_permits()
+    }
+    
+    /// Switch to a new desync configuration
+    ///
+    /// Only connections accepted afterwards use it; connections already in
+    /// progress (including ones mid-way through their first-flight desync)
+    /// keep the engine they started with.
+    pub fn reload_desync(&self, config: DesyncConfig) {
+        let mut applied = self.applied.lock().unwrap();
+        applied.desync = config.clone();
+        applied.strategies.clear();
+        self.strategies.write().unwrap().desync_engine = DesyncEngine::new(config);
+    }
+    
+    /// Switch to the desync strategies of `config`: `desync`, `strategies`,
+    /// `strategy_retries`, `canary`, `tag_profiles` and `host_overrides`
+    ///
+    /// The config is validated first and nothing changes if it has errors.
+    /// As with `reload_desync`, only connections accepted afterwards see
+    /// the new strategies. Strategies whose settings are unchanged keep
+    /// their engine, and with it their plan cache and learned fallbacks.
+    /// Other settings only take effect on a restart; they are reported but
+    /// ignored. Returns what changed, which is also logged.
+    pub fn reload(&self, config: Config) -> Result<Vec<String>> {
+        let diagnostics = config.validate();
+        for diagnostic in diagnostics.iter().filter(|d| !d.is_error()) {
+            eprintln!("[!] Reload: {}", diagnostic);
+        }
+        let errors: Vec<String> = diagnostics
+            .iter()
+            .filter(|d| d.is_error())
+            .map(|d| d.message.clone())
+            .collect();
+        if !errors.is_empty() {
+            bail!("invalid config: {}", errors.join("; "));
+        }
         
-        println!("[*] SOCKS5 Proxy listening on {}", self.config.listen);
-        println!("[*] Configure your application to use Proxy: {}", self.config.listen);
+        let mut applied = self.applied.lock().unwrap();
+        let changes = describe_changes(&applied, &config);
+        let mut strategies = self.strategies.write().unwrap();
+        *strategies = Strategies::new(&config, Some((&strategies, &applied)));
+        *applied = config;
         
-        loop {
-            match listener.accept().await {
-                Ok((stream, client_addr)) => {
-                    let desync_engine = self.desync_engine.clone();
+        for change in &changes {
+            eprintln!("[*] Reload: {}", change);
+        }
+        Ok(changes)
+    }
+    
+    /// Enter drain mode: stop accepting, let in-flight connections finish,
+    /// then return from `run`
+    ///
+    /// Unlike a shutdown, existing connections are never cut (unless
+    /// `drain_timeout` is set), so a load balancer can move traffic away
+    /// first. SIGUSR2 does the same.
+    pub fn drain(&self) {
+        self.drain_requested.notify_one();
+    }
+    
+    /// Shut down: stop accepting, give in-flight connections
+    /// `shutdown_grace` to finish, cut the rest, then return from `run`
+    ///
+    /// SIGINT and SIGTERM do the same.
+    pub fn shutdown(&self) {
+        self.shutdown_requested.notify_one();
+    }
+    
+    /// Whether the server has stopped accepting, by a drain or a shutdown,
+    /// and so reports not ready
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+    
+    /// Serve a single client over an already established stream
+    ///
+    /// Runs the same SOCKS5/HTTP proxy handshake and desync forwarding as
+    /// connections accepted by `run`, for embedders that own the client
+    /// transport (e.g. a TLS stream or an in-process pipe). The connection
+    /// counts towards `stats`, but is not written to the access log, which
+    /// only exists while `run` is serving.
+    pub async fn handle_stream<S>(
+        &self,
+        mut stream: S,
+        client_addr: SocketAddr,
+    ) -> Result<ConnectionOutcome>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let (shared, mut stats) = self.open_connection(client_addr);
+        let result = handle_client(&mut stream, client_addr, shared, &mut stats).await;
+        close_connection(&self.stats, &mut stats, &result);
+        result
+    }
+    
+    /// Accept and serve clients until drain mode or a shutdown is requested
+    /// and the connections in flight have finished or been cut
+    pub async fn run(&self) -> Result<ShutdownSummary> {
+        if self.config.buffer_size == 0 {
+            bail!("buffer_size must be at least 1");
+        }
+        if !(MIN_SANE_BUFFER_SIZE..=MAX_SANE_BUFFER_SIZE).contains(&self.config.buffer_size) {
+            eprintln!(
+                "[!] buffer_size of {} bytes is outside the usual {}-{} range",
+                self.config.buffer_size, MIN_SANE_BUFFER_SIZE, MAX_SANE_BUFFER_SIZE
+            );
+        }
+        
+        if self.config.transparent && !TRANSPARENT_SUPPORTED {
+            bail!("transparent mode needs Linux and a build with the `transparent` feature");
+        }
+        
+        if self.config.transparent && self.config.listen.tcp_addr().is_none() {
+            bail!("transparent mode needs a TCP listen address");
+        }
+        
+        // A socket passed by systemd is shared by every worker
+        let inherited = Listener::inherited()
+            .context("Failed to adopt the socket passed by systemd")?;
+        let activated = inherited.is_some();
+        let (listeners, listen) = match inherited {
+            Some(listener) => {
+                let listen = listener.local_addr()?;
+                println!("[*] Socket-activated: using the socket passed by systemd");
+                let listener = Arc::new(listener);
+                (vec![listener; self.config.workers.max(1)], listen)
+            }
+            None => {
+                let listeners = Listener::bind_workers(&self.config)
+                    .with_context(|| format!("Failed to bind to {}", self.config.listen))?;
+                (listeners, self.config.listen.clone())
+            }
+        };
+        
+        let access_log = match &self.config.access_log {
+            Some(log_config) => {
+                let log = AccessLog::open(log_config.clone())
+                    .context("Failed to open access log")?;
+                Some(Arc::new(log))
+            }
+            None => None,
+        };
+        
+        #[cfg(unix)]
+        if let Some(log) = &access_log {
+            reopen_on_sigusr1(log.clone())?;
+        }
+        
+        if let Some(addr) = self.config.metrics_listen {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Failed to bind metrics endpoint to {}", addr))?;
+            println!("[*] Metrics available at http://{}/metrics", addr);
+            tokio::spawn(serve_metrics(listener, self.stats.clone(), self.draining.clone()));
+        }
+        
+        if let Some(interval) = self.config.summary_interval.filter(|i| !i.is_zero()) {
+            log_summaries(self.stats.clone(), interval);
+        }
+        
+        if self.config.transparent {
+            println!("[*] Transparent proxy listening on {}", listen);
+            if let Some(addr) = listen.tcp_addr() {
+                println!("[*] Redirect traffic to port {} with iptables", addr.port());
+            }
+        } else {
+            println!("[*] SOCKS5 Proxy listening on {}", listen);
+            println!("[*] Configure your application to use Proxy: {}", listen);
+        }
+        if let Some(families) = listeners[0].accepted_families()? {
+            println!("[*] Accepting {}", families);
+        }
+        println!("[*] Serving up to {} connections at once", self.config.max_connections.max(1));
+        if listeners.len() > 1 {
+            let separate = !Arc::ptr_eq(&listeners[0], &listeners[1]);
+            println!(
+                "[*] Accepting with {} workers ({})",
+                listeners.len(),
+                if separate { "one SO_REUSEPORT listener each" } else { "sharing one listener" }
+            );
+        }
+        
+        let mut drain_signal = drain_signal()?;
+        let mut shutdown_signal = shutdown_signal()?;
+        
+        // Each worker accepts on its listener and hands connections to the
+        // loop below, which owns the connection setup and shutdown
+        let (accepted_tx, mut accepted_rx) = mpsc::channel(listeners.len());
+        let mut accept_loops = JoinSet::new();
+        for (worker, listener) in listeners.into_iter().enumerate() {
+            let accepted_tx = accepted_tx.clone();
+            let stats = self.stats.clone();
+            accept_loops.spawn(async move {
+                loop {
+                    let accepted = listener.accept().await;
+                    if accepted.is_ok() {
+                        stats.connection_accepted(worker);
+                    }
+                    if accepted_tx.send(accepted).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(accepted_tx);
+        
+        let stop = loop {
+            let accepted = tokio::select! {
+                Some(accepted) = accepted_rx.recv() => accepted,
+                _ = &mut drain_signal => break Stop::Drain,
+                _ = self.drain_requested.notified() => break Stop::Drain,
+                _ = &mut shutdown_signal => break Stop::Shutdown,
+                _ = self.shutdown_requested.notified() => break Stop::Shutdown,
+            };
+            
+            match accepted {
+                Ok((mut stream, client_addr)) => {
+                    stream.apply_socket_opts(&self.config.socket);
+                    let slots = self.connection_slots.clone();
+                    let max_connections = self.config.max_connections;
+                    let (shared, mut stats) = self.open_connection(client_addr);
+                    let abort_with_rst = self.config.abort_with_rst;
+                    let transparent = self.config.transparent;
+                    let access_log = access_log.clone();
+                    let server_stats = self.stats.clone();
+                    let mut cut = self.cut_connections.subscribe();
+                    let force_closed = self.force_closed.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = handle_client(stream, client_addr, desync_engine).await {
+                        let serve = async {
+                            // The owned permit is held while the handler runs
+                            // and freed however it ends, errors and panics
+                            // included
+                            let slot = tokio::time::timeout(
+                                CONNECTION_SLOT_TIMEOUT,
+                                slots.acquire_owned(),
+                            ).await;
+                            match slot {
+                                Ok(Ok(_permit)) => match &mut stream {
+                                    ClientStream::Tcp(tcp) if transparent => {
+                                        handle_redirected(tcp, client_addr, shared, &mut stats)
+                                            .await
+                                    }
+                                    stream => {
+                                        handle_client(stream, client_addr, shared, &mut stats)
+                                            .await
+                                    }
+                                },
+                                _ => Err(reject(&format!(
+                                    "connection limit of {} reached",
+                                    max_connections
+                                ))),
+                            }
+                        };
+                        // Dropping the handler closes both of its sockets
+                        let result = tokio::select! {
+                            result = serve => result,
+                            // Only a real cut: a dropped server leaves them be
+                            Ok(_) = cut.wait_for(|&cut| cut) => {
+                                force_closed.fetch_add(1, Ordering::Relaxed);
+                                Err(anyhow::anyhow!("cut by shutdown"))
+                            }
+                        };
+                        close_connection(&server_stats, &mut stats, &result);
+                        
+                        if let Err(e) = result {
+                            // Reset rather than close refused clients if asked to
+                            if abort_with_rst && e.is::<Rejected>() {
+                                if let Err(e) = stream.set_zero_linger() {
+                                    eprintln!("[!] Failed to set zero linger: {}", e);
+                                }
+                            }
                             eprintln!("Error handling client {}: {}", client_addr, e);
                         }
+                        if let Some(log) = access_log {
+                            log.log(&stats);
+                        }
                     });
                 }
                 Err(e) => {
                     eprintln!("Failed to accept connection: {}", e);
                 }
             }
+        };
+        
+        // Closing the listener refuses new connections while the spawned
+        // connection tasks carry on. A shutdown reports not ready as well as
+        // a drain: either way nothing new is accepted.
+        self.draining.store(true, Ordering::Relaxed);
+        accept_loops.shutdown().await;
+        drop(accepted_rx);
+        // A socket file systemd created stays for the next activation
+        if let (ListenAddr::Unix(path), false) = (&self.config.listen, activated) {
+            if let Err(e) = std::fs::remove_file(path) {
+                eprintln!("[!] Failed to remove socket {}: {}", path.display(), e);
+            }
+        }
+        let in_flight = self.stats.active_connections() as usize;
+        let grace = match stop {
+            Stop::Drain => {
+                eprintln!("[*] Draining: stopped accepting, {} connections in flight", in_flight);
+                self.config.drain_timeout
+            }
+            Stop::Shutdown => {
+                eprintln!(
+                    "[*] Shutting down: stopped accepting, {} connections in flight",
+                    in_flight
+                );
+                Some(self.config.shutdown_grace.unwrap_or(DEFAULT_SHUTDOWN_GRACE))
+            }
+        };
+        
+        let deadline = grace.map(|t| tokio::time::Instant::now() + t);
+        while self.stats.active_connections() > 0 {
+            if deadline.is_some_and(|d| tokio::time::Instant::now() >= d) {
+                eprintln!(
+                    "[!] Grace period over, cutting {} connections",
+                    self.stats.active_connections()
+                );
+                self.cut_connections.send_replace(true);
+                let unwound = tokio::time::Instant::now() + FORCE_CLOSE_WAIT;
+                while self.stats.active_connections() > 0 && tokio::time::Instant::now() < unwound {
+                    tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+                }
+                break;
+            }
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
         }
+        
+        let force_closed = self.force_closed.load(Ordering::Relaxed);
+        let summary = ShutdownSummary {
+            drained: in_flight.saturating_sub(force_closed),
+            force_closed,
+        };
+        eprintln!("[*] {}, exiting", summary);
+        Ok(summary)
     }
 }
 
-async fn handle_client(
-    mut client: TcpStream,
-    client_addr: SocketAddr,
+/// Resolves when SIGUSR2 asks for drain mode (never on non-Unix platforms)
+fn drain_signal() -> Result<Pin<Box<dyn Future<Output = ()> + Send>>> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        
+        let mut usr2 = signal(SignalKind::user_defined2())
+            .context("Failed to install SIGUSR2 handler")?;
+        Ok(Box::pin(async move {
+            usr2.recv().await;
+        }))
+    }
+    
+    #[cfg(not(unix))]
+    Ok(Box::pin(std::future::pending()))
+}
+
+/// Resolves on SIGINT or SIGTERM (Ctrl-C on non-Unix platforms)
+fn shutdown_signal() -> Result<Pin<Box<dyn Future<Output = ()> + Send>>> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        
+        let mut interrupt = signal(SignalKind::interrupt())
+            .context("Failed to install SIGINT handler")?;
+        let mut terminate = signal(SignalKind::terminate())
+            .context("Failed to install SIGTERM handler")?;
+        Ok(Box::pin(async move {
+            tokio::select! {
+                _ = interrupt.recv() => {}
+                _ = terminate.recv() => {}
+            }
+        }))
+    }
+    
+    #[cfg(not(unix))]
+    Ok(Box::pin(async {
+        tokio::signal::ctrl_c().await.ok();
+    }))
+}
+
+impl ProxyServer {
+    /// Snapshot the state for a new connection and count it as open
+    fn open_connection(&self, client_addr: SocketAddr) -> (Shared, ConnectionStats) {
+        let strategies = self.strategies.read().unwrap().clone();
+        let mut shared = self.shared.clone();
+        shared.desync_engine = strategies.desync_engine;
+        shared.tag_engines = strategies.tag_engines;
+        shared.host_engines = strategies.host_engines;
+        let mut stats = ConnectionStats::new(client_addr);
+        if let Some(canary) = strategies.canary.as_ref().filter(|c| c.sample()) {
+            shared.desync_engine = canary.engine.clone();
+            stats.canary = true;
+        }
+        stats.strategy = shared.desync_engine.mode_name();
+        
+        self.stats.connection_opened();
+        (shared, stats)
+    }
+}
+
+/// Record how a connection ended and fold it into the server counters
+fn close_connection(
+    server_stats: &ServerStats,
+    stats: &mut ConnectionStats,
+    result: &Result<ConnectionOutcome>,
+) {
+    stats.finish(match result {
+        Ok(outcome) => *outcome,
+        Err(e) if e.downcast_ref::<Rejected>().is_some_and(|e| e.refused) => {
+            ConnectionOutcome::Refused
+        }
+        Err(_) => ConnectionOutcome::Failed,
+    });
+    server_stats.connection_closed(stats);
+}
+
+/// The desync strategies a new connection can pick from
+///
+/// Swapped as a whole on reload, so a connection never mixes strategies of
+/// two configs.
+#[derive(Clone)]
+struct Strategies {
     desync_engine: DesyncEngine,
-) -> Result<()> {
+    canary: Option<Arc<Canary>>,
+    tag_engines: Arc<HashMap<String, DesyncEngine>>,
+    host_engines: Arc<Vec<(HostRule, DesyncEngine)>>,
+}
+
+impl Strategies {
+    /// Engines for `config`, taking over those of `previous` (built from
+    /// the config given with it) whose settings are the same
+    fn new(config: &Config, previous: Option<(&Strategies, &Config)>) -> Self {
+        let primary = config.primary_desync();
+        let desync_engine = match previous {
+            Some((old, old_config)) if same(&old_config.primary_desync(), &primary) => {
+                old.desync_engine.clone()
+            }
+            _ => DesyncEngine::new(primary),
+        };
+        let canary = match previous {
+            Some((old, old_config)) if same(&old_config.canary, &config.canary) => {
+                old.canary.clone()
+            }
+            _ => config.canary.as_ref().map(|canary| Arc::new(Canary::new(canary))),
+        };
+        let tag_engines = config.tag_profiles
+            .iter()
+            .map(|(tag, desync)| {
+                let kept = previous.and_then(|(old, old_config)| {
+                    let unchanged =
+                        old_config.tag_profiles.get(tag).is_some_and(|d| same(d, desync));
+                    old.tag_engines.get(tag).filter(|_| unchanged).cloned()
+                });
+                (tag.clone(), kept.unwrap_or_else(|| DesyncEngine::new(desync.clone())))
+            })
+            .collect();
+        let host_engines = config.host_overrides
+            .iter()
+            .map(|rule| {
+                let kept = previous.and_then(|(old, _)| {
+                    old.host_engines.iter().find(|(old_rule, _)| same(old_rule, rule))
+                });
+                let engine = match kept {
+                    Some((_, engine)) => engine.clone(),
+                    None => DesyncEngine::new(rule.desync.clone()),
+                };
+                (rule.clone(), engine)
+            })
+            .collect();
+        Self {
+            desync_engine,
+            canary,
+            tag_engines: Arc::new(tag_engines),
+            host_engines: Arc::new(host_engines),
+        }
+    }
+}
+
+/// Settings `reload` applies; everything else needs a restart
+const RELOADABLE: [&str; 6] =
+    ["desync", "strategies", "strategy_retries", "canary", "tag_profiles", "host_overrides"];
+
+/// Whether two settings serialize identically (the config types don't
+/// implement `PartialEq`)
+fn same<T: serde::Serialize>(a: &T, b: &T) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+/// One line per changed setting between `old` and `new`, for the reload log
+fn describe_changes(old: &Config, new: &Config) -> Vec<String> {
+    let (Ok(serde_json::Value::Object(old_values)), Ok(serde_json::Value::Object(new_values))) =
+        (serde_json::to_value(old), serde_json::to_value(new))
+    else {
+        return vec!["config changed".to_string()];
+    };
+    let changed = |key: &str| old_values.get(key) != new_values.get(key);
+    
+    let mut changes = Vec::new();
+    if changed("desync") {
+        let from = DesyncEngine::new(old.desync.clone());
+        let to = DesyncEngine::new(new.desync.clone());
+        changes.push(if from.mode_name() == to.mode_name() {
+            format!("desync: {} rules changed", to.mode_name())
+        } else {
+            format!("desync: {} -> {}", from.mode_name(), to.mode_name())
+        });
+    }
+    if changed("strategies") {
+        changes.push(format!(
+            "strategies: {} fallbacks (was {})",
+            new.strategies.len(),
+            old.strategies.len()
+        ));
+    }
+    if changed("strategy_retries") {
+        changes.push(format!(
+            "strategy_retries: {:?} -> {:?}",
+            old.strategy_retries, new.strategy_retries
+        ));
+    }
+    if changed("canary") {
+        changes.push(match (&old.canary, &new.canary) {
+            (None, Some(canary)) => format!("canary: added at {}%", canary.percent),
+            (Some(_), None) => "canary: removed".to_string(),
+            _ => "canary: changed".to_string(),
+        });
+    }
+    if changed("tag_profiles") {
+        let names = |filter: &dyn Fn(&String) -> bool| {
+            let mut names: Vec<&str> = old.tag_profiles
+                .keys()
+                .chain(new.tag_profiles.keys().filter(|tag| !old.tag_profiles.contains_key(*tag)))
+                .filter(|tag| filter(tag))
+                .map(String::as_str)
+                .collect();
+            names.sort_unstable();
+            names.join(", ")
+        };
+        let added = names(&|tag| !old.tag_profiles.contains_key(tag));
+        let removed = names(&|tag| !new.tag_profiles.contains_key(tag));
+        let modified = names(&|tag| match (old.tag_profiles.get(tag), new.tag_profiles.get(tag)) {
+            (Some(a), Some(b)) => !same(a, b),
+            _ => false,
+        });
+        let parts: Vec<String> = [("added", added), ("removed", removed), ("changed", modified)]
+            .into_iter()
+            .filter(|(_, names)| !names.is_empty())
+            .map(|(what, names)| format!("{} {}", what, names))
+            .collect();
+        changes.push(format!("tag_profiles: {}", parts.join("; ")));
+    }
+    if changed("host_overrides") {
+        changes.push(format!(
+            "host_overrides: {} rules (was {})",
+            new.host_overrides.len(),
+            old.host_overrides.len()
+        ));
+    }
+    
+    let restart: Vec<&str> = new_values
+        .keys()
+        .filter(|key| !RELOADABLE.contains(&key.as_str()) && changed(key))
+        .map(String::as_str)
+        .collect();
+    if !restart.is_empty() {
+        changes.push(format!("not applied until a restart: {}", restart.join(", ")));
+    }
+    if changes.is_empty() {
+        changes.push("no changes".to_string());
+    }
+    changes
+}
+
+/// Routes a fraction of connections to an alternative desync strategy
+struct Canary {
+    engine: DesyncEngine,
+    percent: f64,
+    rng: Mutex<StdRng>,
+}
+
+impl Canary {
+    fn new(config: &CanaryConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            engine: DesyncEngine::new(config.desync.clone()),
+            percent: if config.percent.is_nan() { 0.0 } else { config.percent.clamp(0.0, 100.0) },
+            rng: Mutex::new(rng),
+        }
+    }
+    
+    /// Decide whether the next connection uses the canary strategy
+    fn sample(&self) -> bool {
+        self.rng.lock().unwrap().gen_bool(self.percent / 100.0)
+    }
+}
+
+async fn handle_client<S>(
+    client: &mut S,
+    client_addr: SocketAddr,
+    mut shared: Shared,
+    stats: &mut ConnectionStats,
+) -> Result<ConnectionOutcome>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
     eprintln!("\n[*] ===== New connection from: {} =====", client_addr);
     
+    let _slot = shared.admit(client_addr)?;
+    
+    // Every handshake read below is exact-sized: clients that pipeline the
+    // greeting, the request and their first payload (e.g. a ClientHello) in
+    // one write leave the payload in the socket for the forwarding loop to
+    // pick up. Don't switch these to buffered reads without carrying any
+    // over-read bytes into the relay.
+    
     // Read first byte to detect protocol
     let mut first_byte = [0u8; 1];
     client.read_exact(&mut first_byte).await?;
     
     eprintln!("[*] First byte: {} (0x{:02X})", first_byte[0], first_byte[0]);
     
-    // Check if this is HTTP CONNECT
-    if first_byte[0] == b'C' {
-        eprintln!("[*] Detected HTTP CONNECT request");
-        return handle_http_connect(client, first_byte[0], desync_engine).await;
+    // Check if this is an HTTP proxy request (CONNECT or absolute-URI); a
+    // cleartext HTTP/2 preface starts the same way and is told apart once
+    // its first line is in
+    if let Some(head) = read_http_method(client, first_byte[0]).await? {
+        eprintln!("[*] Detected HTTP proxy request");
+        return handle_http_proxy(client, head, shared, stats).await;
     }
     
     // SOCKS5 handshake
     if first_byte[0] != SOCKS5_VERSION {
         eprintln!("[!] Invalid SOCKS version: {} (expected {})", first_byte[0], SOCKS5_VERSION);
-        anyhow::bail!("Invalid SOCKS version");
+        return Err(reject("Invalid SOCKS version"));
     }
     
     // Read number of methods
@@ -86,18 +961,32 @@ async fn handle_client(
     let mut methods = vec![0u8; n_methods];
     client.read_exact(&mut methods).await?;
     
-    if !methods.contains(&SOCKS5_AUTH_NONE) {
-        eprintln!("[!] Client does not support no authentication");
-        anyhow::bail!("Client does not support no authentication");
-    }
-    
-    eprintln!("[*] SOCKS5 handshake successful (no auth)");
+    // No acceptable method is a normal refusal rather than a protocol
+    // violation: the client is told so and typically retries with another
+    // method on a new connection
+    let Some(method) = select_auth_method(&shared.auth_method_priority, &methods) else {
+        eprintln!("[*] No acceptable authentication method offered ({:02X?}), closing", methods);
+        client.write_all(&[SOCKS5_VERSION, SOCKS5_AUTH_NO_ACCEPTABLE]).await?;
+        client.flush().await?;
+        return Ok(ConnectionOutcome::Refused);
+    };
     
     // Send auth response
-    let auth_response = [SOCKS5_VERSION, SOCKS5_AUTH_NONE];
+    let auth_response = [SOCKS5_VERSION, method];
     client.write_all(&auth_response).await?;
     client.flush().await?;
     
+    if method == SOCKS5_AUTH_USERPASS {
+        let username = read_userpass(client, shared.auth.as_deref()).await?;
+        eprintln!("[*] SOCKS5 handshake successful (user: {})", username);
+        if let Some(tag) = username.strip_prefix(TAG_USERNAME_PREFIX) {
+            shared.apply_tag(tag.to_string(), stats);
+        }
+        stats.username = Some(username);
+    } else {
+        eprintln!("[*] SOCKS5 handshake successful (no auth)");
+    }
+    
     // Read connection request
     eprintln!("[*] Waiting for CONNECT request...");
     let mut request = vec![0u8; 4];
@@ -110,19 +999,35 @@ async fn handle_client(
     
     eprintln!("[*] Request header: VER={}, CMD={}, RSV={}, ATYP={}", ver, cmd, _rsv, atyp);
     
-    if ver != SOCKS5_VERSION || cmd != SOCKS5_CMD_CONNECT {
+    if ver != SOCKS5_VERSION {
         eprintln!("[!] Invalid request: ver={}, cmd={}", ver, cmd);
-        anyhow::bail!("Invalid SOCKS5 request");
+        return Err(reject("Invalid SOCKS5 request"));
+    }
+    
+    if !matches!(cmd, SOCKS5_CMD_CONNECT | SOCKS5_CMD_UDP_ASSOCIATE) {
+        eprintln!("[!] Unsupported command: {}", cmd);
+        client.write_all(&socks5_reply(SOCKS5_REP_COMMAND_NOT_SUPPORTED, NO_ADDR)).await?;
+        client.flush().await?;
+        return Err(reject(&format!("Unsupported SOCKS5 command: {}", cmd)));
     }
     
-    let target_addr = match atyp {
+    if cmd == SOCKS5_CMD_UDP_ASSOCIATE {
+        return udp_associate(client, client_addr, atyp, &shared, stats).await;
+    }
+    
+    let mut flow = FlowInfo::default();
+    let target_addrs = match atyp {
         SOCKS5_ATYP_IPV4 => {
             let mut addr = [0u8; 4];
             client.read_exact(&mut addr).await?;
             let mut port = [0u8; 2];
             client.read_exact(&mut port).await?;
             let port = u16::from_be_bytes(port);
-            SocketAddr::from((addr, port))
+            let addr = SocketAddr::from((addr, port));
+            stats.target = Some(addr.to_string());
+            flow.host = Some(addr.ip().to_string());
+            flow.port = Some(port);
+            vec![addr]
         }
         SOCKS5_ATYP_DOMAIN => {
             let mut domain_len = [0u8; 1];
@@ -136,14 +1041,39 @@ async fn handle_client(
             
             let domain_str = String::from_utf8(domain)
                 .context("Invalid domain name")?;
-            eprintln!("[*] Resolving SOCKS5 domain: {}:{}", domain_str, port);
+            stats.target = Some(format!("{}:{}", domain_str, port));
+            flow.host = Some(domain_str.clone());
+            flow.port = Some(port);
             
-            let mut addrs = tokio::net::lookup_host(format!("{}:{}", domain_str, port))
-                .await
-                .context("Failed to resolve domain")?;
+            if shared.is_blocked(&domain_str) {
+                eprintln!("[!] Refusing blocked host {}", domain_str);
+                client.write_all(&socks5_reply(SOCKS5_REP_NOT_ALLOWED, NO_ADDR)).await?;
+                client.flush().await?;
+                return Err(refuse_blocked(&domain_str));
+            }
             
-            addrs.next()
-                .context("No addresses found for domain")?
+            // An upstream proxy resolves the domain itself
+            if shared.connector.upstream().is_some() {
+                Vec::new()
+            } else {
+                eprintln!("[*] Resolving SOCKS5 domain: {}:{}", domain_str, port);
+                let addrs = match shared.resolve(&domain_str, port).await {
+                    Ok(addrs) => addrs,
+                    Err(e) => {
+                        let reply = socks5_reply(SOCKS5_REP_HOST_UNREACHABLE, NO_ADDR);
+                        client.write_all(&reply).await?;
+                        client.flush().await?;
+                        return Err(e).context("Failed to resolve domain");
+                    }
+                };
+                
+                if addrs.is_empty() {
+                    client.write_all(&socks5_reply(SOCKS5_REP_HOST_UNREACHABLE, NO_ADDR)).await?;
+                    client.flush().await?;
+                    anyhow::bail!("No usable addresses found for domain");
+                }
+                addrs
+            }
         }
         SOCKS5_ATYP_IPV6 => {
             let mut addr = [0u8; 16];
@@ -151,71 +1081,145 @@ async fn handle_client(
             let mut port = [0u8; 2];
             client.read_exact(&mut port).await?;
             let port = u16::from_be_bytes(port);
-            SocketAddr::from((std::net::Ipv6Addr::from(addr), port))
+            let addr = SocketAddr::from((std::net::Ipv6Addr::from(addr), port));
+            stats.target = Some(addr.to_string());
+            flow.host = Some(addr.ip().to_string());
+            flow.port = Some(port);
+            
+            if shared.disable_ipv6 {
+                eprintln!("[*] Refusing IPv6 target {} (IPv6 disabled)", addr);
+                client.write_all(&socks5_reply(SOCKS5_REP_ATYP_NOT_SUPPORTED, NO_ADDR)).await?;
+                client.flush().await?;
+                return Ok(ConnectionOutcome::Refused);
+            }
+            vec![addr]
+        }
+        _ => {
+            client.write_all(&socks5_reply(SOCKS5_REP_ATYP_NOT_SUPPORTED, NO_ADDR)).await?;
+            client.flush().await?;
+            return Err(reject(&format!("Unsupported address type: {}", atyp)));
         }
-        _ => anyhow::bail!("Unsupported address type: {}", atyp),
     };
     
-    eprintln!("[*] Connecting to: {}", target_addr);
-    let target = TcpStream::connect(target_addr)
-        .await
-        .context("Failed to connect to target")?;
+    let host = flow.host.clone().unwrap_or_default();
+    let port = flow.port.unwrap_or_default();
+    eprintln!("[*] Connecting to: {} ({:?})", host, target_addrs);
+    let connect_start = Instant::now();
+    let connected = shared.connector.connect_target(&host, port, target_addrs).await;
+    let (target, target_addr) = match connected {
+        Ok(connected) => connected,
+        Err(e) => {
+            client.write_all(&socks5_reply(connect_error_reply(&e), NO_ADDR)).await?;
+            client.flush().await?;
+            return Err(e).context("Failed to connect to target");
+        }
+    };
     
-    target.set_nodelay(true).ok();
+    stats.connect_time = Some(connect_start.elapsed());
     
     println!("[*] Tunneling to: {}", target_addr);
     
-    // Send SOCKS5 success response
-    let response = vec![
-        SOCKS5_VERSION,
-        SOCKS5_REP_SUCCESS,
-        0x00,
-        SOCKS5_ATYP_IPV4,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00,
-    ];
-    
-    client.write_all(&response).await?;
+    // Send SOCKS5 success response, with the address we connected from
+    let bound_addr = target.local_addr().unwrap_or(NO_ADDR);
+    client.write_all(&socks5_reply(SOCKS5_REP_SUCCESS, bound_addr)).await?;
     client.flush().await?;
     eprintln!("[*] SOCKS5 response sent, starting data forwarding");
     
-    // Forward data with desync
-    let (client_read, client_write) = split(client);
-    let (target_read, target_write) = split(target);
+    relay(client, target, target_addr, &shared, flow, stats).await
+}
+
+/// Serve a connection the firewall redirected to the listener: there is no
+/// handshake, the target is the connection's original destination
+async fn handle_redirected(
+    client: &mut TcpStream,
+    client_addr: SocketAddr,
+    shared: Shared,
+    stats: &mut ConnectionStats,
+) -> Result<ConnectionOutcome> {
+    eprintln!("\n[*] ===== New redirected connection from: {} =====", client_addr);
     
-    let client_to_target = tokio::spawn(async move {
-        forward_with_desync(client_read, target_write, desync_engine).await
-    });
+    let _slot = shared.admit(client_addr)?;
     
-    let target_to_client = tokio::spawn(async move {
-        forward_normal(target_read, client_write).await
-    });
+    let target_addr = original_destination(client)
+        .context("Failed to read the original destination")?;
+    // A client connecting to the listener directly would be looped back to it
+    if client.local_addr().is_ok_and(|local| local == target_addr) {
+        return Err(reject("not a redirected connection"));
+    }
+    stats.target = Some(target_addr.to_string());
+    if target_addr.is_ipv6() && shared.disable_ipv6 {
+        eprintln!("[*] Refusing IPv6 target {} (IPv6 disabled)", target_addr);
+        return Ok(ConnectionOutcome::Refused);
+    }
     
-    let (client_result, target_result) = tokio::join!(client_to_target, target_to_client);
+    let flow = FlowInfo {
+        host: Some(target_addr.ip().to_string()),
+        port: Some(target_addr.port()),
+    };
+    eprintln!("[*] Connecting to original destination: {}", target_addr);
+    let connect_start = Instant::now();
+    let (target, target_addr) = shared.connector
+        .connect_target(&target_addr.ip().to_string(), target_addr.port(), vec![target_addr])
+        .await
+        .context("Failed to connect to target")?;
+    stats.connect_time = Some(connect_start.elapsed());
     
-    match client_result {
-        Ok(Ok(())) => eprintln!("[*] Client->target forwarding completed"),
-        Ok(Err(e)) => eprintln!("[!] Error forwarding client->target: {}", e),
-        Err(e) => eprintln!("[!] Task error client->target: {}", e),
+    println!("[*] Tunneling to: {}", target_addr);
+    relay(client, target, target_addr, &shared, flow, stats).await
+}
+
+/// Answer an HTTP/2 preface with a GOAWAY asking for HTTP/1.1
+async fn refuse_http2<S>(client: &mut S, mut buffer: Vec<u8>) -> Result<ConnectionOutcome>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    buffer.resize(HTTP2_PREFACE.len(), 0);
+    client.read_exact(&mut buffer[HTTP2_PREFACE_LINE_LEN..]).await?;
+    if !is_http2_preface(&buffer) {
+        client.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await?;
+        client.flush().await?;
+        return Err(reject("Malformed HTTP/2 connection preface"));
     }
     
-    match target_result {
-        Ok(Ok(())) => eprintln!("[*] Target->client forwarding completed"),
-        Ok(Err(e)) => eprintln!("[!] Error forwarding target->client: {}", e),
-        Err(e) => eprintln!("[!] Task error target->client: {}", e),
+    eprintln!("[*] Detected HTTP/2 connection preface, sending GOAWAY");
+    client.write_all(&http2_goaway(HTTP2_HTTP_1_1_REQUIRED)).await?;
+    client.flush().await?;
+    Ok(ConnectionOutcome::Refused)
+}
+
+/// Read the four bytes `is_http` needs after `first`, one at a time and only
+/// while they can still open a request or the HTTP/2 preface, so a short
+/// SOCKS greeting is never waited on; returns them if they do
+async fn read_http_method<S>(client: &mut S, first: u8) -> Result<Option<Vec<u8>>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = vec![first];
+    while head.len() < 4 {
+        if !may_be_http(&head) && !HTTP2_PREFACE.starts_with(&head) {
+            return Ok(None);
+        }
+        let mut byte = [0u8; 1];
+        client.read_exact(&mut byte).await?;
+        head.push(byte[0]);
     }
-    
-    eprintln!("[*] Connection closed");
-    Ok(())
+    Ok((is_http(&head) || HTTP2_PREFACE.starts_with(&head)).then_some(head))
 }
 
-async fn handle_http_connect(
-    mut client: TcpStream,
-    first_byte: u8,
-    desync_engine: DesyncEngine,
-) -> Result<()> {
-    // Read the rest of the HTTP CONNECT request
-    let mut buffer = vec![first_byte];
+/// Serve an HTTP proxy request: a CONNECT tunnel, or a plain request with
+/// an absolute URI that is rewritten to origin form and forwarded
+async fn handle_http_proxy<S>(
+    client: &mut S,
+    head: Vec<u8>,
+    mut shared: Shared,
+    stats: &mut ConnectionStats,
+) -> Result<ConnectionOutcome>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    // Read the rest of the HTTP request head one byte at a time so data
+    // sent right after the headers stays in the socket for the relay
+    let mut buffer = head;
     let mut line_buf = vec![0u8; 1];
     
     // Read until we get the full request line
@@ -227,56 +1231,158 @@ async fn handle_http_connect(
             break;
         }
         
-        if buffer.len() > 8192 {
-            anyhow::bail!("HTTP CONNECT request too long");
+        if buffer.len() > shared.http_max_request_line {
+            client.write_all(b"HTTP/1.1 414 URI Too Long\r\n\r\n").await?;
+            client.flush().await?;
+            return Err(reject("HTTP proxy request line too long"));
         }
     }
     
+    // "PRI * HTTP/2.0" parses as a request line, but the blank line after
+    // it belongs to the HTTP/2 preface, not to a header block
+    if buffer == HTTP2_PREFACE[..HTTP2_PREFACE_LINE_LEN] {
+        return refuse_http2(client, buffer).await;
+    }
+    
     // Read remaining headers
+    let request_line_len = buffer.len();
     let mut header_buf = vec![0u8; 1];
-    let mut last_four = vec![0u8; 4];
     
+    // The request line's CRLF counts towards the blank line, so a request
+    // without any headers ends right after the next CRLF
     loop {
         client.read_exact(&mut header_buf).await?;
         buffer.push(header_buf[0]);
         
-        last_four.push(header_buf[0]);
-        if last_four.len() > 4 {
-            last_four.remove(0);
-        }
-        
-        if last_four == b"\r\n\r\n" {
+        if buffer.ends_with(b"\r\n\r\n") {
             break;
         }
         
-        if buffer.len() > 8192 {
-            anyhow::bail!("HTTP CONNECT headers too long");
+        if buffer.len() - request_line_len > shared.http_max_header_bytes {
+            client.write_all(b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n").await?;
+            client.flush().await?;
+            return Err(reject("HTTP proxy headers too long"));
         }
     }
     
     let request_str = String::from_utf8_lossy(&buffer);
-    eprintln!("[*] HTTP CONNECT request:\n{}", request_str);
+    eprintln!("[*] HTTP proxy request:\n{}", request_str);
     
-    // Parse target address
-    let (host, port) = crate::packets::parse_http_connect(&buffer)
-        .context("Failed to parse HTTP CONNECT target")?;
+    // Parse target address; anything but CONNECT is forwarded as a request
+    let (host, port, forward) = if buffer.starts_with(b"CONNECT ") {
+        let Some((host, port)) = crate::packets::parse_http_connect(&buffer) else {
+            return Err(reject("Failed to parse HTTP CONNECT target"));
+        };
+        (host, port, None)
+    } else {
+        let Some(forward) = crate::packets::parse_http_forward(&buffer, &[TAG_HEADER]) else {
+            client.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await?;
+            client.flush().await?;
+            return Err(reject("Failed to parse HTTP proxy request target"));
+        };
+        (forward.host, forward.port, Some(forward.request))
+    };
     
-    eprintln!("[*] HTTP CONNECT target: {}:{}", host, port);
+    // Bracket IPv6 literals so the port stays unambiguous
+    let target_name = if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    eprintln!("[*] HTTP proxy target: {}", target_name);
+    stats.target = Some(target_name);
     
-    let target_addr = format!("{}:{}", host, port);
-    let mut addrs = tokio::net::lookup_host(&target_addr)
-        .await
-        .context("Failed to resolve HTTP CONNECT target")?;
+    if let Some(auth) = &shared.auth {
+        let credentials = crate::packets::http_header(&buffer, "Proxy-Authorization")
+            .and_then(|value| crate::packets::parse_basic_auth(&value));
+        match credentials {
+            Some((username, password)) if auth.verify(&username, password.as_bytes()) => {
+                stats.username = Some(username);
+            }
+            _ => {
+                client.write_all(
+                    b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                      Proxy-Authenticate: Basic realm=\"stpro\"\r\n\r\n",
+                ).await?;
+                client.flush().await?;
+                return Err(reject("HTTP proxy authentication failed"));
+            }
+        }
+    }
+    if let Some(tag) = crate::packets::http_header(&buffer, TAG_HEADER) {
+        shared.apply_tag(tag, stats);
+    }
+    // A forwarded request is its own first flight, so its Host header is
+    // checked here as well, before the target is dialed
+    let named = forward.as_deref().and_then(first_flight_host);
+    let blocked = [Some(&host), named.as_ref()]
+        .into_iter()
+        .flatten()
+        .find(|name| shared.is_blocked(name));
+    if let Some(blocked) = blocked {
+        eprintln!("[!] Refusing blocked host {}", blocked);
+        client.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").await?;
+        client.flush().await?;
+        return Err(refuse_blocked(blocked));
+    }
     
-    let target_addr = addrs.next()
-        .context("No addresses found for HTTP CONNECT target")?;
+    // An upstream proxy resolves the host itself
+    let via_upstream = shared.connector.upstream().is_some();
+    let addrs = if via_upstream {
+        Vec::new()
+    } else {
+        match shared.resolve(&host, port).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await?;
+                client.flush().await?;
+                return Err(e).context("Failed to resolve HTTP proxy target");
+            }
+        }
+    };
     
-    eprintln!("[*] Connecting to: {}", target_addr);
-    let target = TcpStream::connect(target_addr)
-        .await
-        .context("Failed to connect to HTTP CONNECT target")?;
+    if addrs.is_empty() && !via_upstream {
+        client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await?;
+        client.flush().await?;
+        anyhow::bail!("No usable addresses found for HTTP proxy target");
+    }
     
-    target.set_nodelay(true).ok();
+    eprintln!("[*] Connecting to: {} ({:?})", host, addrs);
+    let connect_start = Instant::now();
+    let (target, target_addr) = match shared.connector.connect_target(&host, port, addrs).await {
+        Ok(connected) => connected,
+        Err(e @ ConnectError::QueueTimeout) => {
+            client.write_all(b"HTTP/1.1 503 Service Unavailable\r\n\r\n").await?;
+            client.flush().await?;
+            return Err(e).context("Failed to connect to HTTP proxy target");
+        }
+        Err(e @ ConnectError::Timeout(_)) => {
+            client.write_all(b"HTTP/1.1 504 Gateway Timeout\r\n\r\n").await?;
+            client.flush().await?;
+            return Err(e).context("Failed to connect to HTTP proxy target");
+        }
+        Err(e) => {
+            client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await?;
+            client.flush().await?;
+            return Err(e).context("Failed to connect to HTTP proxy target");
+        }
+    };
+    
+    stats.connect_time = Some(connect_start.elapsed());
+    
+    let flow = FlowInfo {
+        host: Some(host),
+        port: Some(port),
+    };
+    
+    // The rewritten head is read back ahead of the body, so it is the
+    // first flight the desync engine works on
+    if let Some(request) = forward {
+        println!("[*] Forwarding request to: {}", target_addr);
+        let (client_read, client_write) = split(client);
+        let mut client = join(Cursor::new(request).chain(client_read), client_write);
+        return relay(&mut client, target, target_addr, &shared, flow, stats).await;
+    }
     
     println!("[*] Tunneling to: {}", target_addr);
     
@@ -287,90 +1393,972 @@ async fn handle_http_connect(
     
     eprintln!("[*] HTTP CONNECT response sent, starting data forwarding");
     
-    // Forward data
+    relay(client, target, target_addr, &shared, flow, stats).await
+}
+
+/// Serve a UDP ASSOCIATE request: relay datagrams for the client until it
+/// closes the control connection
+async fn udp_associate<S>(
+    client: &mut S,
+    client_addr: SocketAddr,
+    atyp: u8,
+    shared: &Shared,
+    stats: &mut ConnectionStats,
+) -> Result<ConnectionOutcome>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let requested = read_address(client, atyp).await
+        .map_err(|e| reject(&format!("Invalid UDP ASSOCIATE address: {}", e)))?;
+    eprintln!("[*] UDP ASSOCIATE from {} (client address {})", client_addr, requested);
+    
+    let mut relay = match UdpRelay::bind(
+        shared.listen_ip,
+        client_addr.ip(),
+        &requested,
+        shared.resolver.clone(),
+        shared.disable_ipv6,
+        shared.bind_addr,
+    ).await {
+        Ok(relay) => relay,
+        Err(e) => {
+            client.write_all(&socks5_reply(SOCKS5_REP_GENERAL_FAILURE, NO_ADDR)).await?;
+            client.flush().await?;
+            return Err(e).context("Failed to bind UDP relay socket");
+        }
+    };
+    
+    let relay_addr = relay.local_addr()?;
+    client.write_all(&socks5_reply(SOCKS5_REP_SUCCESS, relay_addr)).await?;
+    client.flush().await?;
+    eprintln!("[*] UDP relay listening on {}", relay_addr);
+    
+    let result = relay.run(client).await;
+    stats.bytes_up = relay.bytes_up;
+    stats.bytes_down = relay.bytes_down;
+    shared.server_stats.add_bytes(relay.bytes_up, relay.bytes_down);
+    eprintln!("[*] UDP association closed");
+    result.map(|_| ConnectionOutcome::Completed)
+}
+
+/// Build a SOCKS5 reply with status `rep` and BND.ADDR/BND.PORT `addr`
+fn socks5_reply(rep: u8, addr: SocketAddr) -> Vec<u8> {
+    let mut reply = vec![SOCKS5_VERSION, rep, 0x00];
+    write_address(&mut reply, addr);
+    reply
+}
+
+/// SOCKS5 reply code describing why a connect failed
+fn connect_error_reply(error: &ConnectError) -> u8 {
+    match error {
+        ConnectError::QueueTimeout => SOCKS5_REP_HOST_UNREACHABLE,
+        ConnectError::FamilyMismatch { .. } => SOCKS5_REP_ATYP_NOT_SUPPORTED,
+        ConnectError::Timeout(_) => SOCKS5_REP_TTL_EXPIRED,
+        ConnectError::Io(e) => match e.kind() {
+            std::io::ErrorKind::ConnectionRefused => SOCKS5_REP_CONNECTION_REFUSED,
+            std::io::ErrorKind::NetworkUnreachable => SOCKS5_REP_NETWORK_UNREACHABLE,
+            std::io::ErrorKind::HostUnreachable => SOCKS5_REP_HOST_UNREACHABLE,
+            std::io::ErrorKind::TimedOut => SOCKS5_REP_TTL_EXPIRED,
+            _ => SOCKS5_REP_GENERAL_FAILURE,
+        },
+    }
+}
+
+/// Pick the authentication method to use from those the client offered
+///
+/// The first supported method in `priority` that the client also offered
+/// wins; unsupported methods in the priority list are skipped.
+fn select_auth_method(priority: &[u8], offered: &[u8]) -> Option<u8> {
+    priority
+        .iter()
+        .copied()
+        .filter(|m| matches!(*m, SOCKS5_AUTH_NONE | SOCKS5_AUTH_USERPASS))
+        .find(|m| offered.contains(m))
+}
+
+/// Run the username/password subnegotiation (RFC 1929) and return the
+/// username
+///
+/// Without `auth`, credentials are only used for accounting and any pair is
+/// accepted; otherwise a wrong pair is told so and the client rejected.
+async fn read_userpass<S>(client: &mut S, auth: Option<&AuthConfig>) -> Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut header = [0u8; 2];
+    client.read_exact(&mut header).await?;
+    if header[0] != USERPASS_VERSION {
+        return Err(reject("Invalid username/password auth version"));
+    }
+    
+    let mut username = vec![0u8; header[1] as usize];
+    client.read_exact(&mut username).await?;
+    
+    let mut password_len = [0u8; 1];
+    client.read_exact(&mut password_len).await?;
+    let mut password = vec![0u8; password_len[0] as usize];
+    client.read_exact(&mut password).await?;
+    
+    let username = String::from_utf8_lossy(&username).into_owned();
+    if auth.is_some_and(|auth| !auth.verify(&username, &password)) {
+        client.write_all(&[USERPASS_VERSION, USERPASS_FAILURE]).await?;
+        client.flush().await?;
+        return Err(reject(&format!("Authentication failed for user {:?}", username)));
+    }
+    
+    client.write_all(&[USERPASS_VERSION, USERPASS_SUCCESS]).await?;
+    client.flush().await?;
+    
+    Ok(username)
+}
+
+/// A client refused for a policy or protocol violation
+///
+/// With `abort_with_rst` set, the accept loop switches the socket of a
+/// connection that failed with this error to a zero linger, so dropping it
+/// sends a RST instead of a graceful FIN.
+#[derive(Debug)]
+struct Rejected {
+    reason: String,
+    /// Turned away by policy (e.g. `block_hosts`) rather than for a broken
+    /// request, and recorded as `Refused` instead of a failure
+    refused: bool,
+}
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Refuse a client for a policy or protocol violation
+fn reject(reason: &str) -> anyhow::Error {
+    Rejected { reason: reason.to_string(), refused: false }.into()
+}
+
+/// Refuse a client whose request names a blocked host: it is reset like
+/// any rejected client, but the connection counts as `Refused`
+fn refuse_blocked(host: &str) -> anyhow::Error {
+    Rejected { reason: format!("blocked host {}", host), refused: true }.into()
+}
+
+/// Forward data in both directions until either side closes, applying
+/// desync to the client -> target direction
+///
+/// With `block_hosts` set, a first flight naming a blocked host ends the
+/// connection, as rejected, before any of it reaches the target. The target
+/// is connected by then: a SOCKS or CONNECT client only sends its first
+/// flight after the success reply, and a redirected one after the proxy
+/// accepted it, so the check can't come before the dial. Forwarded HTTP
+/// requests are checked before it by `handle_http_proxy`.
+async fn relay<S>(
+    client: &mut S,
+    target: TcpStream,
+    target_addr: SocketAddr,
+    shared: &Shared,
+    flow: FlowInfo,
+    stats: &mut ConnectionStats,
+) -> Result<ConnectionOutcome>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    if shared.block_hosts.is_empty() {
+        return forward(client, target, target_addr, shared, flow, stats).await;
+    }
+    
+    // The first flight is checked against the block list, then read back
+    // ahead of the rest of the stream
+    let first = read_first_flight(client, &target, shared).await?;
+    if let Some(host) = first_flight_host(&first).filter(|host| shared.is_blocked(host)) {
+        eprintln!("[!] Closing connection to blocked host {} (from the first flight)", host);
+        return Err(refuse_blocked(&host));
+    }
     let (client_read, client_write) = split(client);
-    let (target_read, target_write) = split(target);
+    let mut client = join(Cursor::new(first).chain(client_read), client_write);
+    forward(&mut client, target, target_addr, shared, flow, stats).await
+}
+
+/// The client's first flight, with a ClientHello read in full; empty if
+/// the target speaks first, or the client closes or idles out without
+/// sending anything
+async fn read_first_flight<S>(
+    client: &mut S,
+    target: &TcpStream,
+    shared: &Shared,
+) -> Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buffer = vec![0u8; MAX_CLIENT_HELLO_RECORD];
+    let read = async {
+        tokio::select! {
+            read = client.read(&mut buffer) => read.map(Some),
+            // Protocols such as SMTP wait for the server's greeting
+            _ = target.readable() => Ok(None),
+        }
+    };
+    let read = match shared.idle_timeout {
+        Some(idle) => tokio::time::timeout(idle, read).await.unwrap_or(Ok(None)),
+        None => read.await,
+    };
+    let Some(n) = read? else {
+        return Ok(Vec::new());
+    };
+    buffer.truncate(n);
+    Ok(complete_client_hello(client, &buffer).await?.unwrap_or(buffer))
+}
+
+/// Hostname a first flight names: the SNI of a ClientHello or the Host
+/// header of an HTTP request, without its port
+fn first_flight_host(data: &[u8]) -> Option<String> {
+    if let Some(sni) = parse_sni(data) {
+        return Some(sni.to_string());
+    }
+    let host = http_header(data, "Host")?;
+    match host.rsplit_once(':') {
+        Some((name, port)) if !name.contains(':') && port.parse::<u16>().is_ok() => {
+            Some(name.to_string())
+        }
+        _ => Some(host),
+    }
+}
+
+/// `relay` once the first flight passed the block list
+///
+/// In auto mode the first flight is exchanged by `probe_strategies` first,
+/// which may replace both the target connection and the engine.
+async fn forward<S>(
+    client: &mut S,
+    mut target: TcpStream,
+    target_addr: SocketAddr,
+    shared: &Shared,
+    flow: FlowInfo,
+    stats: &mut ConnectionStats,
+) -> Result<ConnectionOutcome>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let buffer_size = shared.buffer_size;
+    let mut desync_engine = shared.desync_engine.clone();
     
-    let client_to_target = tokio::spawn(async move {
-        forward_with_desync(client_read, target_write, desync_engine).await
-    });
+    // A profile the client asked for by tag beats a host override
+    let host = flow.host.as_deref().unwrap_or_default();
+    let tagged = stats.tag.as_ref().is_some_and(|tag| shared.tag_engines.contains_key(tag));
+    if let Some(engine) = shared.host_override(host, &target_addr).filter(|_| !tagged) {
+        eprintln!("[*] Using host override strategy for {}", host);
+        desync_engine = engine.clone();
+        stats.strategy = desync_engine.mode_name();
+        stats.canary = false;
+    }
     
-    let target_to_client = tokio::spawn(async move {
-        forward_normal(target_read, client_write).await
-    });
+    // The port the client asked for, not that of an upstream proxy
+    let port = flow.port.unwrap_or(target_addr.port());
+    if !shared.desync_ports.is_empty() && !shared.desync_ports.contains(&port) {
+        if !desync_engine.is_passthrough() {
+            eprintln!("[*] Port {} is not in desync_ports, forwarding untouched", port);
+        }
+        desync_engine = shared.passthrough.clone();
+        stats.strategy = desync_engine.mode_name();
+        stats.canary = false;
+    }
+    
+    // The first response byte is timed against the first flight for the
+    // time-to-first-byte measurement
+    let first_flight = FirstFlight::default();
+    let first_response = OnceLock::new();
+    
+    let mut probed = Probed::default();
+    if let Some(auto) = desync_engine.auto().cloned() {
+        let probe = ProbeContext {
+            auto: &auto,
+            connector: &shared.connector,
+            target_addr,
+            flow: &flow,
+            buffer_size,
+            first_flight: &first_flight,
+            first_response: &first_response,
+            server_stats: &shared.server_stats,
+        };
+        let Some(result) = probe_strategies(client, target, &desync_engine, probe).await? else {
+            eprintln!("[*] Connection closed");
+            return Ok(ConnectionOutcome::Completed);
+        };
+        (target, desync_engine) = (result.target, result.engine);
+        stats.strategy = desync_engine.mode_name();
+        stats.strategy_index = Some(result.index);
+        probed = result.bytes;
+        shared.server_stats.add_bytes(probed.up, probed.down);
+    }
+    
+    let activity = Activity::new();
+    let passthrough = desync_engine.is_passthrough() && shared.max_rate.is_none();
+    let (client_result, target_result) = if passthrough {
+        // Nothing to do per chunk: tokio's copy skips the flush after every
+        // read, and shuts each side down once the other reaches EOF
+        let upstream = |data: &[u8]| {
+            first_flight.record(data);
+            shared.server_stats.add_bytes(data.len() as u64, 0);
+        };
+        let downstream = |data: &[u8]| {
+            first_response.get_or_init(Instant::now);
+            shared.server_stats.add_bytes(0, data.len() as u64);
+        };
+        let mut client = Tracked::new(client, &activity, true).on_read(&upstream);
+        let mut target = Tracked::new(target, &activity, false).on_read(&downstream);
+        let copying = async {
+            let size = shared.buffer_size;
+            match copy_bidirectional_with_sizes(&mut client, &mut target, size, size).await {
+                Ok((up, down)) => (Ok(up), Ok(down)),
+                Err(e) => {
+                    let down = activity.down.load(Ordering::Relaxed);
+                    (close_or_propagate(e, activity.up.load(Ordering::Relaxed)), Ok(down))
+                }
+            }
+        };
+        until_idle(copying, &activity, shared.idle_timeout).await
+    } else {
+        let target_socket = TargetSocket {
+            segment_counter: desync_engine.verify_segments().then(|| SegmentCounter::new(&target)),
+            ttl_control: TtlControl::new(&target),
+        };
+        let (client_read, client_write) = split(client);
+        let (target_read, target_write) = split(target);
+        let client_read = Tracked::new(client_read, &activity, true);
+        let target_read = Tracked::new(target_read, &activity, false);
+        
+        let client_to_target = forward_with_desync(
+            client_read,
+            target_write,
+            desync_engine,
+            flow,
+            shared,
+            &first_flight,
+            target_socket,
+        );
+        let target_to_client =
+            forward_normal(target_read, client_write, shared, Some(&first_response));
+        
+        let forwarding = async { tokio::join!(client_to_target, target_to_client) };
+        until_idle(forwarding, &activity, shared.idle_timeout).await
+    };
     
-    let (client_result, target_result) = tokio::join!(client_to_target, target_to_client);
+    if let (Some(sent), Some(received)) = (first_flight.sent_at.get(), first_response.get()) {
+        stats.ttfb = Some(received.saturating_duration_since(*sent));
+    }
+    stats.ja3 = first_flight.ja3.into_inner();
     
+    stats.bytes_up = probed.up;
+    stats.bytes_down = probed.down;
     match client_result {
-        Ok(Ok(())) => eprintln!("[*] Client->target forwarding completed"),
-        Ok(Err(e)) => eprintln!("[!] Error forwarding client->target: {}", e),
-        Err(e) => eprintln!("[!] Task error client->target: {}", e),
+        Ok(n) => {
+            stats.bytes_up += n;
+            eprintln!("[*] Client->target forwarding completed");
+        }
+        Err(e) => eprintln!("[!] Error forwarding client->target: {}", e),
     }
     
     match target_result {
-        Ok(Ok(())) => eprintln!("[*] Target->client forwarding completed"),
-        Ok(Err(e)) => eprintln!("[!] Error forwarding target->client: {}", e),
-        Err(e) => eprintln!("[!] Task error target->client: {}", e),
+        Ok(n) => {
+            stats.bytes_down += n;
+            eprintln!("[*] Target->client forwarding completed");
+        }
+        Err(e) => eprintln!("[!] Error forwarding target->client: {}", e),
     }
     
     eprintln!("[*] Connection closed");
-    Ok(())
+    Ok(ConnectionOutcome::Completed)
+}
+
+/// When a tunnel last read data, and how much it read each way
+struct Activity {
+    start: Instant,
+    /// Milliseconds from `start` to the last read
+    last: AtomicU64,
+    up: AtomicU64,
+    down: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            up: AtomicU64::new(0),
+            down: AtomicU64::new(0),
+        }
+    }
+    
+    fn record(&self, upstream: bool, n: usize) {
+        let counter = if upstream { &self.up } else { &self.down };
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+        self.last.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+    
+    /// Resolves once nothing has been read in either direction for `idle`
+    async fn idle_for(&self, idle: Duration) {
+        loop {
+            let last = self.start + Duration::from_millis(self.last.load(Ordering::Relaxed));
+            let deadline = last + idle;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+/// Read half of a tunnel that reports what it reads to an `Activity`
+struct Tracked<'a, R> {
+    inner: R,
+    activity: &'a Activity,
+    /// Whether this is the client -> target direction
+    upstream: bool,
+    /// Called with the data of every read
+    on_read: Option<&'a OnRead<'a>>,
+}
+
+/// Callback for each chunk read from one side of a tunnel
+type OnRead<'a> = dyn Fn(&[u8]) + Sync + 'a;
+
+impl<'a, R> Tracked<'a, R> {
+    fn new(inner: R, activity: &'a Activity, upstream: bool) -> Self {
+        Self { inner, activity, upstream, on_read: None }
+    }
+    
+    fn on_read(mut self, callback: &'a OnRead<'a>) -> Self {
+        self.on_read = Some(callback);
+        self
+    }
+}
+
+/// Run `forwarding` until it finishes, or until the tunnel has been idle for
+/// `idle_timeout`, which counts as both directions completing normally
+async fn until_idle<F>(
+    forwarding: F,
+    activity: &Activity,
+    idle_timeout: Option<Duration>,
+) -> (Result<u64>, Result<u64>)
+where
+    F: Future<Output = (Result<u64>, Result<u64>)>,
+{
+    match idle_timeout {
+        Some(idle_timeout) => tokio::select! {
+            results = forwarding => results,
+            _ = activity.idle_for(idle_timeout) => {
+                eprintln!("[*] No data for {} ms, closing idle tunnel", idle_timeout.as_millis());
+                (Ok(activity.up.load(Ordering::Relaxed)), Ok(activity.down.load(Ordering::Relaxed)))
+            }
+        },
+        None => forwarding.await,
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Tracked<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        // The passthrough copy gives up on any error, so transient ones are
+        // retried here as the forwarding loops do
+        let polled = loop {
+            match Pin::new(&mut self.inner).poll_read(cx, buf) {
+                std::task::Poll::Ready(Err(e))
+                    if classify_io_error(&e) == IoErrorAction::Retry => {}
+                polled => break polled,
+            }
+        };
+        let n = buf.filled().len() - before;
+        if n > 0 {
+            self.activity.record(self.upstream, n);
+            if let Some(callback) = self.on_read {
+                callback(&buf.filled()[before..]);
+            }
+        }
+        polled
+    }
+}
+
+impl<R: AsyncWrite + Unpin> AsyncWrite for Tracked<'_, R> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+    
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Whether `data` may be the start of a TLS handshake record whose header
+/// hasn't fully arrived
+fn maybe_tls_header(data: &[u8]) -> bool {
+    match data {
+        [0x16] | [0x16, 0x03] => true,
+        [0x16, 0x03, minor, ..] => data.len() < 5 && (0x01..=0x04).contains(minor),
+        _ => false,
+    }
+}
+
+/// Read the rest of a ClientHello split across reads, so the desync engine
+/// sees the whole record (SNI-relative splits misfire on a fragment)
+///
+/// Returns None for data that isn't the start of a TLS handshake record,
+/// which is passed on without waiting, and for records that are already
+/// complete. Otherwise the returned buffer holds `first` plus whatever
+/// followed, up to the record length but at most `MAX_CLIENT_HELLO_RECORD`
+/// bytes; after `CLIENT_HELLO_READ_TIMEOUT`, or if the client closes, it
+/// holds what arrived so far.
+async fn complete_client_hello<R>(reader: &mut R, first: &[u8]) -> std::io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let wanted = |data: &[u8]| match tls_record_len(data) {
+        Some(len) if is_tls_chello(data) => Some(len.min(MAX_CLIENT_HELLO_RECORD)),
+        Some(_) => None,
+        None => maybe_tls_header(data).then_some(5),
+    };
+    if wanted(first).is_none_or(|len| first.len() >= len) {
+        return Ok(None);
+    }
+    
+    let mut hello = first.to_vec();
+    let deadline = tokio::time::Instant::now() + CLIENT_HELLO_READ_TIMEOUT;
+    while let Some(len) = wanted(&hello).filter(|&len| hello.len() < len) {
+        let mut chunk = vec![0u8; len - hello.len()];
+        match tokio::time::timeout_at(deadline, reader.read(&mut chunk)).await {
+            Ok(Ok(0)) => break,
+            Ok(Ok(n)) => hello.extend_from_slice(&chunk[..n]),
+            Ok(Err(e)) if classify_io_error(&e) == IoErrorAction::Retry => continue,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                eprintln!("[*] Timed out waiting for the rest of the ClientHello");
+                break;
+            }
+        }
+    }
+    Ok(Some(hello))
+}
+
+/// Handles on the target socket for the client -> target direction
+struct TargetSocket {
+    /// Set until the first flight has been verified
+    segment_counter: Option<SegmentCounter>,
+    ttl_control: Option<TtlControl>,
+}
+
+/// What the client -> target direction observed about the first flight
+#[derive(Default)]
+struct FirstFlight {
+    sent_at: OnceLock<Instant>,
+    /// JA3 hash, if the first flight was a ClientHello
+    ja3: OnceLock<String>,
+}
+
+impl FirstFlight {
+    /// Note the send time and fingerprint of `buffer` if it is the first
+    /// flight; later buffers are ignored
+    fn record(&self, buffer: &[u8]) {
+        if self.sent_at.set(Instant::now()).is_ok() {
+            if let Some(hello) = parse_client_hello(buffer) {
+                let ja3 = ja3_hash(&hello);
+                eprintln!("[*] ClientHello JA3: {} ({})", ja3, ja3_string(&hello));
+                self.ja3.set(ja3).ok();
+            }
+        }
+    }
+}
+
+/// What `probe_strategies` works with besides the streams
+struct ProbeContext<'a> {
+    auto: &'a AutoStrategies,
+    connector: &'a Connector,
+    target_addr: SocketAddr,
+    flow: &'a FlowInfo,
+    buffer_size: usize,
+    first_flight: &'a FirstFlight,
+    first_response: &'a OnceLock<Instant>,
+    server_stats: &'a ServerStats,
+}
+
+/// Open another connection to the probed target, through the upstream
+/// proxy if there is one
+async fn reconnect(probe: &ProbeContext<'_>) -> Result<TcpStream, ConnectError> {
+    let host = probe.flow.host.clone().unwrap_or_else(|| probe.target_addr.ip().to_string());
+    let port = probe.flow.port.unwrap_or(probe.target_addr.port());
+    let (stream, _) = probe.connector.connect_target(&host, port, vec![probe.target_addr]).await?;
+    Ok(stream)
+}
+
+/// Bytes the probe forwarded in each direction
+#[derive(Default)]
+struct Probed {
+    up: u64,
+    down: u64,
+}
+
+/// Target connection and strategy the probe settled on
+struct ProbeResult {
+    target: TcpStream,
+    engine: DesyncEngine,
+    /// Place of `engine` in the order strategies are tried
+    index: usize,
+    bytes: Probed,
+}
+
+/// Send the client's first flight with each strategy in turn until one
+/// gets a response that no detector objects to, reconnecting between tries
+///
+/// Starts from the strategy that last worked for the host and tries at most
+/// `max_retries` more. The response is passed on to the client, which never
+/// sees the failed tries. When every strategy tried is blocked the last one
+/// is kept, whatever it got. Returns None
+/// if the client closed before sending anything.
+async fn probe_strategies<S>(
+    client: &mut S,
+    target: TcpStream,
+    primary: &DesyncEngine,
+    probe: ProbeContext<'_>,
+) -> Result<Option<ProbeResult>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut request = vec![0u8; probe.buffer_size];
+    let n = loop {
+        match client.read(&mut request).await {
+            Ok(0) => return Ok(None),
+            Ok(n) => break n,
+            Err(e) => match classify_io_error(&e) {
+                IoErrorAction::Retry => continue,
+                IoErrorAction::Close => return Ok(None),
+                IoErrorAction::Propagate => return Err(e.into()),
+            },
+        }
+    };
+    request.truncate(n);
+    if let Some(hello) = complete_client_hello(client, &request).await? {
+        request = hello;
+    }
+    
+    let auto = probe.auto;
+    let host = probe.flow.host.clone().unwrap_or_default();
+    let strategies = std::iter::once(primary).chain(auto.fallbacks());
+    let first = auto.preferred(&host);
+    let last = auto.fallbacks().len().min(first.saturating_add(auto.max_retries()));
+    let mut target = Some(target);
+    let mut response = vec![0u8; probe.buffer_size];
+    
+    for (i, engine) in strategies.enumerate().skip(first) {
+        let mut stream = match target.take() {
+            Some(stream) => stream,
+            None => match reconnect(&probe).await {
+                Ok(stream) => stream,
+                Err(e) => return Err(e).context("Failed to reconnect to target"),
+            },
+        };
+        probe.first_flight.record(&request);
+        let control = TtlControl::new(&stream);
+        let control = control.as_ref().map(|c| c as &dyn SocketControl);
+        let sent = engine.apply_desync_controlled(&mut stream, control, &request, probe.flow).await;
+        
+        let reply = match sent {
+            Err(e) if classify_io_error(&e) == IoErrorAction::Close => FirstResponse::Reset,
+            Err(e) => return Err(e.into()),
+            Ok(_) => match tokio::time::timeout(auto.timeout(), stream.read(&mut response)).await {
+                Err(_) => FirstResponse::Timeout,
+                Ok(Ok(0)) => FirstResponse::Closed,
+                Ok(Ok(n)) => FirstResponse::Data(&response[..n]),
+                Ok(Err(e)) if classify_io_error(&e) == IoErrorAction::Propagate => {
+                    return Err(e.into());
+                }
+                Ok(Err(_)) => FirstResponse::Reset,
+            },
+        };
+        
+        match detect(auto.detectors(), &request, &reply) {
+            DetectionOutcome::Passed => auto.remember(&host, i),
+            DetectionOutcome::Blocked(signal) if i < last => {
+                eprintln!(
+                    "[*] Strategy {} ({}) blocked for {} ({:?}), trying the next",
+                    i,
+                    engine.mode_name(),
+                    host,
+                    signal
+                );
+                probe.server_stats.strategy_blocked(engine.mode_name(), i);
+                continue;
+            }
+            DetectionOutcome::Blocked(signal) => {
+                eprintln!("[!] Every strategy tried was blocked for {} (last: {:?})", host, signal);
+                auto.forget(&host);
+            }
+        }
+        
+        let mut down = 0;
+        if let FirstResponse::Data(data) = reply {
+            probe.first_response.get_or_init(Instant::now);
+            client.write_all(data).await?;
+            client.flush().await?;
+            down = data.len() as u64;
+        }
+        return Ok(Some(ProbeResult {
+            target: stream,
+            engine: engine.clone(),
+            index: i,
+            bytes: Probed { up: request.len() as u64, down },
+        }));
+    }
+    
+    unreachable!("the last strategy is always kept")
 }
 
 async fn forward_with_desync<R, W>(
     mut reader: R,
     mut writer: W,
     desync_engine: DesyncEngine,
-) -> Result<()>
+    flow: FlowInfo,
+    shared: &Shared,
+    first_flight: &FirstFlight,
+    mut target_socket: TargetSocket,
+) -> Result<u64>
 where
     R: AsyncReadExt + Unpin + Send,
     W: AsyncWriteExt + Unpin + Send,
 {
-    let mut buffer = vec![0u8; 8192];
+    let mut buffer = shared.buffers.rent();
+    let mut total = 0u64;
+    let mut bucket = shared.max_rate.map(TokenBucket::new);
     
     loop {
         let n = match reader.read(&mut buffer).await {
             Ok(0) => break,
             Ok(n) => n,
-            Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => {
-                eprintln!("[*] Connection reset");
-                break;
+            Err(e) => match classify_io_error(&e) {
+                IoErrorAction::Retry => continue,
+                IoErrorAction::Close => {
+                    eprintln!("[*] Connection closed: {}", e);
+                    break;
+                }
+                IoErrorAction::Propagate => return Err(e.into()),
+            },
+        };
+        
+        // Only the first flight is buffered to a whole ClientHello and
+        // desynced; later data is forwarded as it comes
+        let first = first_flight.sent_at.get().is_none();
+        let mut hello = None;
+        if first {
+            match complete_client_hello(&mut reader, &buffer[..n]).await {
+                Ok(complete) => hello = complete,
+                Err(e) if classify_io_error(&e) == IoErrorAction::Close => {
+                    eprintln!("[*] Connection closed: {}", e);
+                    break;
+                }
+                Err(e) => return Err(e.into()),
             }
-            Err(e) => return Err(e.into()),
+        }
+        let data = hello.as_deref().unwrap_or(&buffer[..n]);
+        
+        first_flight.record(data);
+        // Desync needs the flight whole, so it is paced but never cut up
+        if let Some(bucket) = &mut bucket {
+            bucket.consume(data.len()).await;
+        }
+        
+        if !first {
+            let written = async {
+                writer.write_all(data).await?;
+                writer.flush().await
+            };
+            if let Err(e) = written.await {
+                return close_or_propagate(e, total);
+            }
+            total += data.len() as u64;
+            shared.server_stats.add_bytes(data.len() as u64, 0);
+            continue;
+        }
+        
+        let verify = target_socket.segment_counter.take().and_then(|counter| {
+            let before = counter.segments_out()?;
+            Some((counter, before, desync_engine.planned_segments(data, &flow)))
+        });
+        let shared_records = match verify {
+            Some(_) => desync_engine.shared_record_boundaries(data, &flow),
+            None => 0,
         };
         
         // Apply desync techniques
-        desync_engine.apply_desync(&mut writer, &buffer[..n]).await?;
+        let control = target_socket.ttl_control.as_ref().map(|c| c as &dyn SocketControl);
+        let sent = desync_engine
+            .apply_desync_controlled(&mut writer, control, data, &flow)
+            .await;
+        if let Err(e) = sent {
+            return close_or_propagate(e, total);
+        }
+        
+        if let Some((counter, before, planned)) = verify {
+            let mut coalesced = false;
+            if shared_records > 0 {
+                eprintln!(
+                    "[!] TLS records share a segment: {} record boundaries not split",
+                    shared_records
+                );
+                coalesced = true;
+            }
+            if let Some(after) = counter.segments_out() {
+                let sent = after.wrapping_sub(before) as usize;
+                if sent < planned {
+                    eprintln!(
+                        "[!] Desync segments coalesced: planned {}, kernel sent {}",
+                        planned, sent
+                    );
+                    coalesced = true;
+                }
+            }
+            if coalesced {
+                shared.server_stats.flight_coalesced();
+            }
+        }
+        total += data.len() as u64;
+        shared.server_stats.add_bytes(data.len() as u64, 0);
     }
     
-    Ok(())
+    Ok(total)
 }
 
 async fn forward_normal<R, W>(
     mut reader: R,
     mut writer: W,
-) -> Result<()>
+    shared: &Shared,
+    first_read: Option<&OnceLock<Instant>>,
+) -> Result<u64>
 where
     R: AsyncReadExt + Unpin + Send,
     W: AsyncWriteExt + Unpin + Send,
 {
-    let mut buffer = vec![0u8; 8192];
+    let mut buffer = shared.buffers.rent();
+    let mut total = 0u64;
+    let mut bucket = shared.max_rate.map(TokenBucket::new);
+    let chunk = bucket.as_ref().map_or(buffer.len(), |b| b.chunk_size().min(buffer.len()));
     
     loop {
-        let n = match reader.read(&mut buffer).await {
+        let n = match reader.read(&mut buffer[..chunk]).await {
             Ok(0) => break,
             Ok(n) => n,
-            Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => {
-                eprintln!("[*] Connection reset");
-                break;
-            }
-            Err(e) => return Err(e.into()),
+            Err(e) => match classify_io_error(&e) {
+                IoErrorAction::Retry => continue,
+                IoErrorAction::Close => {
+                    eprintln!("[*] Connection closed: {}", e);
+                    break;
+                }
+                IoErrorAction::Propagate => return Err(e.into()),
+            },
         };
         
-        writer.write_all(&buffer[..n]).await?;
-        writer.flush().await?;
+        if let Some(first_read) = first_read {
+            first_read.get_or_init(Instant::now);
+        }
+        if let Some(bucket) = &mut bucket {
+            bucket.consume(n).await;
+        }
+        
+        let written = async {
+            writer.write_all(&buffer[..n]).await?;
+            writer.flush().await
+        };
+        if let Err(e) = written.await {
+            return close_or_propagate(e, total);
+        }
+        total += n as u64;
+        shared.server_stats.add_bytes(0, n as u64);
     }
     
-    Ok(())
+    Ok(total)
+}
+
+/// How the forwarding loops react to an IO error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IoErrorAction {
+    /// Transient; try the operation again
+    Retry,
+    /// The peer went away; end the tunnel as a normal close
+    Close,
+    /// Genuine failure; tear the tunnel down with an error
+    Propagate,
+}
+
+fn classify_io_error(e: &std::io::Error) -> IoErrorAction {
+    use std::io::ErrorKind;
+    
+    match e.kind() {
+        // A stream reporting WouldBlock has not registered for readiness,
+        // so retrying would spin instead of waiting; it falls through as
+        // an error
+        ErrorKind::Interrupted => IoErrorAction::Retry,
+        ErrorKind::ConnectionReset | ErrorKind::BrokenPipe | ErrorKind::ConnectionAborted => {
+            IoErrorAction::Close
+        }
+        _ => IoErrorAction::Propagate,
+    }
 }
 
+/// Handle a write error: a vanished peer ends forwarding cleanly, anything
+/// else is propagated. Writes are never retried since part of the data may
+/// already have been sent.
+fn close_or_propagate(e: std::io::Error, total: u64) -> Result<u64> {
+    match classify_io_error(&e) {
+        IoErrorAction::Close => {
+            eprintln!("[*] Connection closed: {}", e);
+            Ok(total)
+        }
+        _ => Err(e.into()),
+    }
+}
+
+/// Periodically log a summary of aggregate activity
+fn log_summaries(stats: Arc<ServerStats>, interval: std::time::Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            eprintln!("[*] Summary: {}", stats.take_summary());
+        }
+    });
+}
+
+/// Reopen the access log whenever SIGUSR1 is received (for log rotation)
+#[cfg(unix)]
+fn reopen_on_sigusr1(log: Arc<AccessLog>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    
+    let mut usr1 = signal(SignalKind::user_defined1())
+        .context("Failed to install SIGUSR1 handler")?;
+    
+    tokio::spawn(async move {
+        while usr1.recv().await.is_some() {
+            match log.reopen() {
+                Ok(()) => eprintln!("[*] Access log reopened"),
+                Err(e) => eprintln!("[!] Failed to reopen access log: {}", e),
+            }
+        }
+    });
+    
+    Ok(())
+}
@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How a proxied connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionOutcome {
+    /// Tunnel was established and closed normally
+    Completed,
+    /// Client was turned away during the handshake (e.g. no acceptable
+    /// authentication method); not an error
+    Refused,
+    /// Handshake, connect or forwarding failed
+    Failed,
+}
+
+impl fmt::Display for ConnectionOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionOutcome::Completed => f.write_str("ok"),
+            ConnectionOutcome::Refused => f.write_str("refused"),
+            ConnectionOutcome::Failed => f.write_str("error"),
+        }
+    }
+}
+
+/// Accounting for a single client connection
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    pub client_addr: SocketAddr,
+    /// Username from SOCKS5 username/password authentication
+    pub username: Option<String>,
+    /// Client-supplied label used for profile selection and accounting
+    pub tag: Option<String>,
+    /// Wall-clock time the connection was accepted
+    pub started_at: SystemTime,
+    /// Requested target as `host:port`, once known
+    pub target: Option<String>,
+    /// Bytes forwarded client -> target
+    pub bytes_up: u64,
+    /// Bytes forwarded target -> client
+    pub bytes_down: u64,
+    pub duration: Duration,
+    /// Time taken to establish the outbound connection
+    pub connect_time: Option<Duration>,
+    /// Time from the first write to the target until its first response byte
+    pub ttfb: Option<Duration>,
+    /// JA3 hash of the client's ClientHello, for TLS connections
+    pub ja3: Option<String>,
+    /// Desync strategy applied to the connection
+    pub strategy: &'static str,
+    /// Place of the strategy in auto mode's order (0 for `desync`, then
+    /// each of `strategies`), when auto mode picked it
+    pub strategy_index: Option<usize>,
+    /// Whether the strategy came from the canary rollout
+    pub canary: bool,
+    pub outcome: ConnectionOutcome,
+    start: Instant,
+}
+
+impl ConnectionStats {
+    pub fn new(client_addr: SocketAddr) -> Self {
+        Self {
+            client_addr,
+            username: None,
+            tag: None,
+            started_at: SystemTime::now(),
+            target: None,
+            bytes_up: 0,
+            bytes_down: 0,
+            duration: Duration::ZERO,
+            connect_time: None,
+            ttfb: None,
+            ja3: None,
+            strategy: "none",
+            strategy_index: None,
+            canary: false,
+            outcome: ConnectionOutcome::Failed,
+            start: Instant::now(),
+        }
+    }
+    
+    /// Record the final outcome and freeze the connection duration
+    pub fn finish(&mut self, outcome: ConnectionOutcome) {
+        self.outcome = outcome;
+        self.duration = self.start.elapsed();
+    }
+}
+
+/// Number of targets listed in a periodic summary
+const SUMMARY_TOP_TARGETS: usize = 5;
+
+/// Histogram bucket bounds (seconds) for connection lifetimes
+const DURATION_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// Histogram bucket bounds (seconds) for connect times and TTFB
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters aggregated across all connections
+#[derive(Debug)]
+pub struct ServerStats {
+    total_connections: AtomicU64,
+    active_connections: AtomicU64,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    /// Connections accepted by each accept loop
+    worker_accepts: Mutex<Vec<u64>>,
+    strategies: Mutex<HashMap<String, StrategyStats>>,
+    /// Verified first flights whose desync segments were merged
+    coalesced_flights: AtomicU64,
+    interval: Mutex<IntervalStats>,
+    connection_duration: Histogram,
+    connect_time: Histogram,
+    ttfb: Histogram,
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self {
+            total_connections: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            worker_accepts: Mutex::default(),
+            strategies: Mutex::default(),
+            coalesced_flights: AtomicU64::new(0),
+            interval: Mutex::default(),
+            connection_duration: Histogram::new(DURATION_BUCKETS),
+            connect_time: Histogram::new(LATENCY_BUCKETS),
+            ttfb: Histogram::new(LATENCY_BUCKETS),
+        }
+    }
+}
+
+/// Prometheus-style histogram of durations
+#[derive(Debug)]
+struct Histogram {
+    /// Upper bounds of the buckets in seconds, ascending
+    bounds: &'static [f64],
+    /// Per-bucket (non-cumulative) counts, plus a final +Inf bucket
+    counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+    
+    fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let bucket = self.bounds.iter().position(|b| secs <= *b).unwrap_or(self.bounds.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+    
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            match self.bounds.get(i) {
+                Some(bound) => {
+                    let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+                }
+                None => {
+                    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+                }
+            }
+        }
+        
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
+
+/// How well a desync strategy is doing
+///
+/// Only connections that sent data count as attempts. An attempt that got a
+/// response byte back is a success; one that ended without any response
+/// (reset, timeout or silent close) is counted as interference. In auto
+/// mode each strategy probed counts as its own attempt.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StrategyStats {
+    pub attempts: u64,
+    pub successes: u64,
+    pub failures: u64,
+}
+
+/// Name a strategy's counters are kept under
+fn strategy_label(strategy: &'static str, index: Option<usize>) -> String {
+    match index {
+        Some(index) => format!("{}#{}", strategy, index),
+        None => strategy.to_string(),
+    }
+}
+
+/// Deltas accumulated since the last summary
+#[derive(Debug, Default)]
+struct IntervalStats {
+    connections: u64,
+    bytes_up: u64,
+    bytes_down: u64,
+    ttfb_total: Duration,
+    ttfb_count: u32,
+    targets: HashMap<String, u64>,
+    modes: HashMap<&'static str, u64>,
+}
+
+impl ServerStats {
+    pub fn connection_opened(&self) {
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Count a connection accepted by accept loop `worker`
+    pub fn connection_accepted(&self, worker: usize) {
+        let mut accepts = self.worker_accepts.lock().unwrap();
+        if accepts.len() <= worker {
+            accepts.resize(worker + 1, 0);
+        }
+        accepts[worker] += 1;
+    }
+    
+    /// Connections accepted so far, per accept loop
+    pub fn worker_accepts(&self) -> Vec<u64> {
+        self.worker_accepts.lock().unwrap().clone()
+    }
+    
+    /// Count bytes as they are forwarded, so the totals move while
+    /// long-lived tunnels are still open
+    pub fn add_bytes(&self, up: u64, down: u64) {
+        self.bytes_up.fetch_add(up, Ordering::Relaxed);
+        self.bytes_down.fetch_add(down, Ordering::Relaxed);
+    }
+    
+    /// Fold a finished connection into the aggregate counters
+    ///
+    /// Its bytes are expected to have gone through `add_bytes` already.
+    pub fn connection_closed(&self, stats: &ConnectionStats) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+        
+        self.connection_duration.observe(stats.duration);
+        if let Some(connect_time) = stats.connect_time {
+            self.connect_time.observe(connect_time);
+        }
+        if let Some(ttfb) = stats.ttfb {
+            self.ttfb.observe(ttfb);
+        }
+        
+        if stats.bytes_up > 0 {
+            let mut strategies = self.strategies.lock().unwrap();
+            let label = strategy_label(stats.strategy, stats.strategy_index);
+            let counters = strategies.entry(label).or_default();
+            counters.attempts += 1;
+            if stats.ttfb.is_some() {
+                counters.successes += 1;
+            } else {
+                counters.failures += 1;
+            }
+        }
+        
+        let mut interval = self.interval.lock().unwrap();
+        interval.connections += 1;
+        interval.bytes_up += stats.bytes_up;
+        interval.bytes_down += stats.bytes_down;
+        if let Some(ttfb) = stats.ttfb {
+            interval.ttfb_total += ttfb;
+            interval.ttfb_count += 1;
+        }
+        if let Some(target) = &stats.target {
+            *interval.targets.entry(target.clone()).or_default() += 1;
+        }
+        *interval.modes.entry(stats.strategy).or_default() += 1;
+    }
+    
+    /// Count an auto mode probe of the strategy at `index`, using the
+    /// `strategy` technique, that was blocked before the next was tried
+    pub fn strategy_blocked(&self, strategy: &'static str, index: usize) {
+        let mut strategies = self.strategies.lock().unwrap();
+        let counters = strategies.entry(strategy_label(strategy, Some(index))).or_default();
+        counters.attempts += 1;
+        counters.failures += 1;
+    }
+    
+    /// Count a verified first flight whose segments or TLS records left
+    /// in fewer TCP segments than planned
+    pub fn flight_coalesced(&self) {
+        self.coalesced_flights.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    pub fn coalesced_flights(&self) -> u64 {
+        self.coalesced_flights.load(Ordering::Relaxed)
+    }
+    
+    pub fn total_connections(&self) -> u64 {
+        self.total_connections.load(Ordering::Relaxed)
+    }
+    
+    pub fn active_connections(&self) -> u64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+    
+    /// Cumulative per-strategy counters, sorted by strategy name
+    ///
+    /// Strategies auto mode picked are named after their technique and
+    /// their place in its order, as in `split#1`, so two fallbacks with the
+    /// same technique are told apart.
+    pub fn strategy_stats(&self) -> Vec<(String, StrategyStats)> {
+        let mut strategies: Vec<_> = self
+            .strategies
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, counters)| (name.clone(), *counters))
+            .collect();
+        strategies.sort_by(|(a, _), (b, _)| a.cmp(b));
+        strategies
+    }
+    
+    /// Render all counters and histograms in the Prometheus text format
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        let bytes_up = self.bytes_up.load(Ordering::Relaxed);
+        let bytes_down = self.bytes_down.load(Ordering::Relaxed);
+        
+        for (name, help, value) in [
+            ("stpro_connections_total", "Connections accepted", self.total_connections()),
+            ("stpro_bytes_up_total", "Bytes forwarded client to target", bytes_up),
+            ("stpro_bytes_down_total", "Bytes forwarded target to client", bytes_down),
+            (
+                "stpro_coalesced_flights_total",
+                "Verified first flights sent in fewer segments than planned",
+                self.coalesced_flights(),
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        
+        let _ = writeln!(out, "# HELP stpro_active_connections Connections currently open");
+        let _ = writeln!(out, "# TYPE stpro_active_connections gauge");
+        let _ = writeln!(out, "stpro_active_connections {}", self.active_connections());
+        
+        let _ = writeln!(out, "# HELP stpro_worker_accepts_total Connections accepted, by worker");
+        let _ = writeln!(out, "# TYPE stpro_worker_accepts_total counter");
+        for (worker, accepts) in self.worker_accepts().iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "stpro_worker_accepts_total{{worker=\"{}\"}} {}",
+                worker, accepts
+            );
+        }
+        
+        type Field = fn(&StrategyStats) -> u64;
+        let strategies = self.strategy_stats();
+        for (name, help, field) in [
+            (
+                "stpro_strategy_attempts_total",
+                "Connections or auto mode probes that sent data, by strategy",
+                (|c| c.attempts) as Field,
+            ),
+            (
+                "stpro_strategy_successes_total",
+                "Attempts that got a response, by strategy",
+                |c| c.successes,
+            ),
+            (
+                "stpro_strategy_failures_total",
+                "Attempts that got no response, by strategy",
+                |c| c.failures,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (strategy, counters) in &strategies {
+                let _ = writeln!(out, "{}{{strategy=\"{}\"}} {}", name, strategy, field(counters));
+            }
+        }
+        
+        self.connection_duration.render(
+            &mut out,
+            "stpro_connection_duration_seconds",
+            "Lifetime of finished connections",
+        );
+        self.connect_time.render(
+            &mut out,
+            "stpro_connect_duration_seconds",
+            "Time to establish outbound connections",
+        );
+        self.ttfb.render(
+            &mut out,
+            "stpro_ttfb_seconds",
+            "Time from the first write to the target until its first response byte",
+        );
+        
+        out
+    }
+    
+    /// Summarize activity since the previous call and reset the deltas
+    pub fn take_summary(&self) -> Summary {
+        let interval = std::mem::take(&mut *self.interval.lock().unwrap());
+        
+        let mut top_targets: Vec<_> = interval.targets.into_iter().collect();
+        top_targets.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_targets.truncate(SUMMARY_TOP_TARGETS);
+        
+        let mut modes: Vec<_> = interval.modes.into_iter().collect();
+        modes.sort();
+        
+        Summary {
+            total_connections: self.total_connections(),
+            active_connections: self.active_connections(),
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+            closed: interval.connections,
+            interval_bytes_up: interval.bytes_up,
+            interval_bytes_down: interval.bytes_down,
+            avg_ttfb: interval.ttfb_total.checked_div(interval.ttfb_count),
+            top_targets,
+            modes,
+            strategies: self.strategy_stats(),
+        }
+    }
+}
+
+/// Point-in-time view produced by [`ServerStats::take_summary`]
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub total_connections: u64,
+    pub active_connections: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    /// Connections closed during the interval
+    pub closed: u64,
+    pub interval_bytes_up: u64,
+    pub interval_bytes_down: u64,
+    /// Mean time-to-first-byte of the interval's connections
+    pub avg_ttfb: Option<Duration>,
+    /// Busiest targets of the interval with their connection counts
+    pub top_targets: Vec<(String, u64)>,
+    /// Desync mode usage during the interval
+    pub modes: Vec<(&'static str, u64)>,
+    /// Cumulative per-strategy outcomes, shown as `name=ok/failed/attempts`
+    pub strategies: Vec<(String, StrategyStats)>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connections total={} active={} closed={} | bytes up={} (+{}) down={} (+{})",
+            self.total_connections,
+            self.active_connections,
+            self.closed,
+            self.bytes_up,
+            self.interval_bytes_up,
+            self.bytes_down,
+            self.interval_bytes_down,
+        )?;
+        
+        match self.avg_ttfb {
+            Some(ttfb) => write!(f, " | ttfb avg={}ms", ttfb.as_millis())?,
+            None => write!(f, " | ttfb avg=-")?,
+        }
+        
+        write!(f, " | top")?;
+        for (target, count) in &self.top_targets {
+            write!(f, " {}={}", target, count)?;
+        }
+        
+        write!(f, " | modes")?;
+        for (mode, count) in &self.modes {
+            write!(f, " {}={}", mode, count)?;
+        }
+        
+        write!(f, " | strategies")?;
+        for (name, counters) in &self.strategies {
+            write!(
+                f,
+                " {}={}/{}/{}",
+                name, counters.successes, counters.failures, counters.attempts
+            )?;
+        }
+        
+        Ok(())
+    }
+}
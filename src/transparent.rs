@@ -0,0 +1,66 @@
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+/// Whether this build can serve connections redirected by the firewall
+pub const TRANSPARENT_SUPPORTED: bool = cfg!(all(target_os = "linux", feature = "transparent"));
+
+/// `SO_ORIGINAL_DST` (and `IP6T_SO_ORIGINAL_DST`) from linux/netfilter_ipv4.h
+#[cfg(all(target_os = "linux", feature = "transparent"))]
+const SO_ORIGINAL_DST: libc::c_int = 80;
+
+/// Where a connection redirected by netfilter (`iptables -j REDIRECT`) was
+/// originally headed
+///
+/// For a connection that wasn't redirected this is just the local address.
+#[cfg(all(target_os = "linux", feature = "transparent"))]
+pub fn original_destination(stream: &TcpStream) -> io::Result<SocketAddr> {
+    use std::os::unix::io::AsRawFd;
+    
+    let level = match stream.local_addr()? {
+        SocketAddr::V4(_) => libc::SOL_IP,
+        SocketAddr::V6(_) => libc::SOL_IPV6,
+    };
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            SO_ORIGINAL_DST,
+            &mut storage as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = len as usize;
+    
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET if len >= std::mem::size_of::<libc::sockaddr_in>() => {
+            let addr = unsafe { *(&storage as *const _ as *const libc::sockaddr_in) };
+            let ip = std::net::Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddr::from((ip, u16::from_be(addr.sin_port))))
+        }
+        libc::AF_INET6 if len >= std::mem::size_of::<libc::sockaddr_in6>() => {
+            let addr = unsafe { *(&storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = std::net::Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Ok(SocketAddr::from((ip, u16::from_be(addr.sin6_port))))
+        }
+        family => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected original destination address family {}", family),
+        )),
+    }
+}
+
+/// Where a redirected connection was originally headed; transparent mode
+/// is Linux-only and needs the `transparent` feature
+#[cfg(not(all(target_os = "linux", feature = "transparent")))]
+pub fn original_destination(_stream: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "transparent mode needs Linux and the `transparent` feature",
+    ))
+}
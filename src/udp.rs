@@ -0,0 +1,307 @@
+use crate::dns::Resolver;
+use crate::packets::{extract_quic_sni, is_quic_initial};
+use crate::proxy::{SOCKS5_ATYP_DOMAIN, SOCKS5_ATYP_IPV4, SOCKS5_ATYP_IPV6};
+use anyhow::Result;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::UdpSocket;
+
+/// Largest datagram relayed in either direction
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// A SOCKS5 address: an IP address or a domain still to be resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Ip(addr) => addr.fmt(f),
+            Address::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// Read a SOCKS5 DST.ADDR/DST.PORT of type `atyp` from a stream
+pub async fn read_address<S>(stream: &mut S, atyp: u8) -> io::Result<Address>
+where
+    S: AsyncRead + Unpin,
+{
+    let address = match atyp {
+        SOCKS5_ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            Address::Ip(SocketAddr::from((ip, 0)))
+        }
+        SOCKS5_ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            Address::Ip(SocketAddr::from((Ipv6Addr::from(ip), 0)))
+        }
+        SOCKS5_ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            let mut domain = vec![0u8; len[0] as usize];
+            stream.read_exact(&mut domain).await?;
+            let domain = String::from_utf8(domain)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid domain name"))?;
+            Address::Domain(domain, 0)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported address type {}", atyp),
+            ))
+        }
+    };
+    
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port).await?;
+    let port = u16::from_be_bytes(port);
+    Ok(match address {
+        Address::Ip(addr) => Address::Ip(SocketAddr::new(addr.ip(), port)),
+        Address::Domain(domain, _) => Address::Domain(domain, port),
+    })
+}
+
+/// Append `addr` as SOCKS5 ATYP, address and port
+pub fn write_address(out: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(SOCKS5_ATYP_IPV4);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(SOCKS5_ATYP_IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Split a client datagram into its destination and payload
+///
+/// Returns None for malformed headers and for fragments (FRAG != 0), which
+/// are not supported: RFC 1928 lets a relay drop them.
+pub fn parse_datagram(datagram: &[u8]) -> Option<(Address, &[u8])> {
+    let [0, 0, 0, atyp, rest @ ..] = datagram else {
+        return None;
+    };
+    
+    let (address, rest) = match *atyp {
+        SOCKS5_ATYP_IPV4 => {
+            let (ip, rest) = rest.split_first_chunk::<4>()?;
+            (Address::Ip(SocketAddr::from((*ip, 0))), rest)
+        }
+        SOCKS5_ATYP_IPV6 => {
+            let (ip, rest) = rest.split_first_chunk::<16>()?;
+            (Address::Ip(SocketAddr::from((Ipv6Addr::from(*ip), 0))), rest)
+        }
+        SOCKS5_ATYP_DOMAIN => {
+            let (len, rest) = rest.split_first()?;
+            let domain = rest.get(..*len as usize)?;
+            let domain = String::from_utf8(domain.to_vec()).ok()?;
+            (Address::Domain(domain, 0), &rest[*len as usize..])
+        }
+        _ => return None,
+    };
+    
+    let (port, payload) = rest.split_first_chunk::<2>()?;
+    let port = u16::from_be_bytes(*port);
+    let address = match address {
+        Address::Ip(addr) => Address::Ip(SocketAddr::new(addr.ip(), port)),
+        Address::Domain(domain, _) => Address::Domain(domain, port),
+    };
+    Some((address, payload))
+}
+
+/// Wrap a datagram received from `source` for delivery to the client
+pub fn encode_datagram(source: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(22 + payload.len());
+    out.extend_from_slice(&[0, 0, 0]);
+    write_address(&mut out, source);
+    out.extend_from_slice(payload);
+    out
+}
+
+/// A UDP relay set up by UDP ASSOCIATE
+///
+/// Datagrams are accepted from the associated client only: the source given
+/// in the request if it named one, otherwise whichever port of the control
+/// connection's host sends first. Fragmented datagrams are dropped.
+pub struct UdpRelay {
+    socket: UdpSocket,
+    client_ip: IpAddr,
+    client_addr: Option<SocketAddr>,
+    resolver: Resolver,
+    disable_ipv6: bool,
+    bind_addr: Option<SocketAddr>,
+    outbound_v4: Option<UdpSocket>,
+    outbound_v6: Option<UdpSocket>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+impl UdpRelay {
+    /// Bind the client-facing socket on `local_ip`
+    pub async fn bind(
+        local_ip: IpAddr,
+        client_ip: IpAddr,
+        requested: &Address,
+        resolver: Resolver,
+        disable_ipv6: bool,
+        bind_addr: Option<SocketAddr>,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddr::new(local_ip, 0)).await?;
+        let client_addr = match requested {
+            Address::Ip(addr) if addr.port() != 0 && !addr.ip().is_unspecified() => Some(*addr),
+            Address::Ip(addr) if addr.port() != 0 => Some(SocketAddr::new(client_ip, addr.port())),
+            _ => None,
+        };
+        Ok(Self {
+            socket,
+            client_ip,
+            client_addr,
+            resolver,
+            disable_ipv6,
+            bind_addr,
+            outbound_v4: None,
+            outbound_v6: None,
+            bytes_up: 0,
+            bytes_down: 0,
+        })
+    }
+    
+    /// Address to hand to the client in the UDP ASSOCIATE reply
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+    
+    /// Relay datagrams until the control connection closes
+    pub async fn run<S>(&mut self, control: &mut S) -> Result<()>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut control_buf = [0u8; 64];
+        let mut client_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut v4_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut v6_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        
+        loop {
+            tokio::select! {
+                read = control.read(&mut control_buf) => {
+                    // Nothing is expected on the control connection; it
+                    // only keeps the association alive
+                    if read.unwrap_or(0) == 0 {
+                        return Ok(());
+                    }
+                }
+                received = self.socket.recv_from(&mut client_buf) => {
+                    let (n, from) = received?;
+                    self.forward_from_client(&client_buf[..n], from).await;
+                }
+                received = recv_from(self.outbound_v4.as_ref(), &mut v4_buf) => {
+                    let (n, from) = received?;
+                    self.forward_to_client(&v4_buf[..n], from).await;
+                }
+                received = recv_from(self.outbound_v6.as_ref(), &mut v6_buf) => {
+                    let (n, from) = received?;
+                    self.forward_to_client(&v6_buf[..n], from).await;
+                }
+            }
+        }
+    }
+    
+    async fn forward_from_client(&mut self, datagram: &[u8], from: SocketAddr) {
+        match self.client_addr {
+            Some(client) if client != from => return,
+            None if from.ip() != self.client_ip => return,
+            None => self.client_addr = Some(from),
+            Some(_) => {}
+        }
+        
+        let Some((address, payload)) = parse_datagram(datagram) else {
+            eprintln!("[*] Dropping malformed or fragmented UDP datagram from {}", from);
+            return;
+        };
+        
+        let target = match address {
+            Address::Ip(addr) => addr,
+            Address::Domain(host, port) => match self.resolver.resolve(&host, port).await {
+                Ok(addrs) => {
+                    let usable = addrs.into_iter().find(|a| !(self.disable_ipv6 && a.is_ipv6()));
+                    match usable {
+                        Some(addr) => addr,
+                        None => return,
+                    }
+                }
+                Err(e) => {
+                    eprintln!("[*] Failed to resolve UDP target {}: {}", host, e);
+                    return;
+                }
+            },
+        };
+        if self.disable_ipv6 && target.is_ipv6() {
+            return;
+        }
+        if is_quic_initial(payload) {
+            match extract_quic_sni(payload) {
+                Some(sni) => eprintln!("[*] QUIC Initial to {} for {}", target, sni),
+                None => eprintln!("[*] QUIC Initial to {}", target),
+            }
+        }
+        
+        match self.outbound(target).await {
+            Ok(socket) => match socket.send_to(payload, target).await {
+                Ok(n) => self.bytes_up += n as u64,
+                Err(e) => eprintln!("[*] Failed to send UDP datagram to {}: {}", target, e),
+            },
+            Err(e) => eprintln!("[*] No UDP socket for {}: {}", target, e),
+        }
+    }
+    
+    async fn forward_to_client(&mut self, payload: &[u8], from: SocketAddr) {
+        let Some(client) = self.client_addr else {
+            return;
+        };
+        let datagram = encode_datagram(from, payload);
+        match self.socket.send_to(&datagram, client).await {
+            Ok(_) => self.bytes_down += payload.len() as u64,
+            Err(e) => eprintln!("[*] Failed to send UDP datagram to client {}: {}", client, e),
+        }
+    }
+    
+    /// Outbound socket for `target`'s address family, bound on first use
+    async fn outbound(&mut self, target: SocketAddr) -> io::Result<&UdpSocket> {
+        let (slot, unspecified) = match target {
+            SocketAddr::V4(_) => (&mut self.outbound_v4, IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            SocketAddr::V6(_) => (&mut self.outbound_v6, IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        };
+        if slot.is_none() {
+            let local = match self.bind_addr {
+                Some(bind) if bind.is_ipv4() == target.is_ipv4() => SocketAddr::new(bind.ip(), 0),
+                Some(bind) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("bind_addr {} is of a different address family", bind),
+                    ));
+                }
+                None => SocketAddr::new(unspecified, 0),
+            };
+            *slot = Some(UdpSocket::bind(local).await?);
+        }
+        Ok(slot.as_ref().unwrap())
+    }
+}
+
+/// Receive on `socket`, or wait forever if there is none yet
+async fn recv_from(socket: Option<&UdpSocket>, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
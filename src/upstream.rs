@@ -0,0 +1,203 @@
+use crate::config::{UpstreamKind, UpstreamProxy};
+use crate::proxy::{
+    SOCKS5_ATYP_DOMAIN, SOCKS5_ATYP_IPV4, SOCKS5_ATYP_IPV6, SOCKS5_AUTH_NONE,
+    SOCKS5_AUTH_NO_ACCEPTABLE, SOCKS5_AUTH_USERPASS, SOCKS5_CMD_CONNECT, SOCKS5_REP_SUCCESS,
+    SOCKS5_VERSION, USERPASS_SUCCESS, USERPASS_VERSION,
+};
+use crate::udp::{read_address, write_address};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest HTTP CONNECT response head accepted from an upstream proxy
+const MAX_RESPONSE_HEAD: usize = 8192;
+
+/// Open a tunnel to `host`:`port` through the upstream proxy `stream` is
+/// connected to
+///
+/// On success the stream carries the target connection.
+pub async fn tunnel<S>(
+    stream: &mut S,
+    proxy: &UpstreamProxy,
+    host: &str,
+    port: u16,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match proxy.kind {
+        UpstreamKind::Socks5 => socks5_tunnel(stream, proxy, host, port).await,
+        UpstreamKind::Http => http_tunnel(stream, proxy, host, port).await,
+    }
+}
+
+/// SOCKS5 greeting offering no authentication, or username/password
+/// authentication only when there are credentials
+pub fn socks5_greeting(proxy: &UpstreamProxy) -> Vec<u8> {
+    match proxy.credentials {
+        Some(_) => vec![SOCKS5_VERSION, 1, SOCKS5_AUTH_USERPASS],
+        None => vec![SOCKS5_VERSION, 1, SOCKS5_AUTH_NONE],
+    }
+}
+
+/// SOCKS5 CONNECT request for `host`:`port`, sending IP literals as
+/// addresses and anything else as a domain for the proxy to resolve
+pub fn socks5_connect_request(host: &str, port: u16) -> io::Result<Vec<u8>> {
+    let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(ip) => write_address(&mut request, SocketAddr::new(ip, port)),
+        Err(_) => {
+            let len = u8::try_from(host.len())
+                .map_err(|_| invalid_input("domain name longer than 255 bytes"))?;
+            request.push(SOCKS5_ATYP_DOMAIN);
+            request.push(len);
+            request.extend_from_slice(host.as_bytes());
+            request.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+    Ok(request)
+}
+
+async fn socks5_tunnel<S>(
+    stream: &mut S,
+    proxy: &UpstreamProxy,
+    host: &str,
+    port: u16,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(&socks5_greeting(proxy)).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != SOCKS5_VERSION {
+        return Err(invalid_data("upstream proxy is not a SOCKS5 server"));
+    }
+    
+    match (choice[1], &proxy.credentials) {
+        (SOCKS5_AUTH_NONE, _) => {}
+        (SOCKS5_AUTH_USERPASS, Some((username, password))) => {
+            let username_len = u8::try_from(username.len())
+                .map_err(|_| invalid_input("upstream username longer than 255 bytes"))?;
+            let password_len = u8::try_from(password.len())
+                .map_err(|_| invalid_input("upstream password longer than 255 bytes"))?;
+            let mut login = vec![USERPASS_VERSION, username_len];
+            login.extend_from_slice(username.as_bytes());
+            login.push(password_len);
+            login.extend_from_slice(password.as_bytes());
+            stream.write_all(&login).await?;
+            
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != USERPASS_SUCCESS {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "upstream proxy rejected the credentials",
+                ));
+            }
+        }
+        (SOCKS5_AUTH_NO_ACCEPTABLE, _) => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "upstream proxy accepts none of the offered authentication methods",
+            ));
+        }
+        (method, _) => {
+            return Err(invalid_data(&format!(
+                "upstream proxy chose an authentication method that wasn't offered: {}",
+                method
+            )));
+        }
+    }
+    
+    stream.write_all(&socks5_connect_request(host, port)?).await?;
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS5_VERSION {
+        return Err(invalid_data("malformed reply from upstream proxy"));
+    }
+    if reply[1] != SOCKS5_REP_SUCCESS {
+        return Err(socks5_reply_error(reply[1]));
+    }
+    // The bound address is of no use here but has to be consumed
+    if !matches!(reply[3], SOCKS5_ATYP_IPV4 | SOCKS5_ATYP_IPV6 | SOCKS5_ATYP_DOMAIN) {
+        return Err(invalid_data("malformed reply from upstream proxy"));
+    }
+    read_address(stream, reply[3]).await?;
+    Ok(())
+}
+
+/// The error a SOCKS5 failure reply stands for, with the kind a direct
+/// connect would have failed with
+fn socks5_reply_error(rep: u8) -> io::Error {
+    let (kind, reason) = match rep {
+        0x02 => (io::ErrorKind::PermissionDenied, "connection not allowed by ruleset"),
+        0x03 => (io::ErrorKind::NetworkUnreachable, "network unreachable"),
+        0x04 => (io::ErrorKind::HostUnreachable, "host unreachable"),
+        0x05 => (io::ErrorKind::ConnectionRefused, "connection refused"),
+        0x06 => (io::ErrorKind::TimedOut, "TTL expired"),
+        _ => (io::ErrorKind::Other, "general failure"),
+    };
+    io::Error::new(kind, format!("upstream proxy: {} (reply {:#04x})", reason, rep))
+}
+
+/// HTTP CONNECT request head for `host`:`port`
+pub fn http_connect_request(proxy: &UpstreamProxy, host: &str, port: u16) -> String {
+    let target = if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some((username, password)) = &proxy.credentials {
+        request.push_str(&format!(
+            "Proxy-Authorization: {}\r\n",
+            crate::packets::basic_auth_value(username, password)
+        ));
+    }
+    request.push_str("\r\n");
+    request
+}
+
+async fn http_tunnel<S>(
+    stream: &mut S,
+    proxy: &UpstreamProxy,
+    host: &str,
+    port: u16,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(http_connect_request(proxy, host, port).as_bytes()).await?;
+    
+    // Read byte by byte so nothing the target sends after the head is lost
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err(invalid_data("upstream proxy response head too long"));
+        }
+        stream.read_exact(&mut byte).await?;
+        head.push(byte[0]);
+    }
+    
+    let status_line = head.split(|&b| b == b'\r').next().unwrap_or_default();
+    let status = String::from_utf8_lossy(status_line);
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        Some("407") => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "upstream proxy requires authentication",
+        )),
+        Some(_) => Err(io::Error::other(format!("upstream proxy refused the tunnel: {}", status))),
+        None => Err(invalid_data("malformed response from upstream proxy")),
+    }
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+fn invalid_input(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason.to_string())
+}
@@ -0,0 +1,166 @@
+use crate::config::{DesyncConfig, FakeConfig, SplitConfig, SplitFlags};
+use crate::presets::{decoy_client_hello, rule};
+use anyhow::{bail, Context, Result};
+
+/// Split position zapret uses when `--dpi-desync-split-pos` is not given
+const DEFAULT_SPLIT_POS: i64 = 2;
+
+/// Desync technique a zapret mode maps to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Technique {
+    Split,
+    Disorder,
+    Fake,
+}
+
+impl DesyncConfig {
+    /// Translate zapret (nfqws/tpws) desync options into a desync config
+    ///
+    /// Supported options, as `--name=value` or `--name value`:
+    /// - `--dpi-desync=MODE`: one of `split`, `split2` and `multisplit`
+    ///   (split), `disorder`, `disorder2` and `multidisorder` (disorder), or
+    ///   `fake`. Only one mode can be given, since stpro applies a single
+    ///   technique per connection.
+    /// - `--dpi-desync-split-pos=POS[,POS...]`: a byte offset (negative from
+    ///   the end), `method+N` (from the request start) or `host+N`/`host-N`
+    ///   (from the hostname, in TLS and HTTP alike). The `multi*` modes use
+    ///   every position, the others the first. Defaults to 2.
+    /// - `--dpi-desync-ttl=N`: TTL of fakes and disordered segments
+    /// - `--dpi-desync-fake-tls=FILE`: fake payload (a built-in decoy
+    ///   ClientHello otherwise)
+    ///
+    /// Everything else is rejected rather than ignored, including the
+    /// fooling options (`--dpi-desync-fooling`, `--dpi-desync-autottl`),
+    /// `sld`/`midsld`/`endhost`/`sniext` positions, repeats, filters and
+    /// host lists.
+    pub fn from_zapret_args(args: &[String]) -> Result<DesyncConfig> {
+        let mut technique = None;
+        let mut positions = vec![SplitPos::new(DEFAULT_SPLIT_POS, false)];
+        let mut multi = false;
+        let mut ttl = None;
+        let mut fake_data = None;
+        
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            let mut value = || match &inline {
+                Some(value) => Ok(value.clone()),
+                None => args.next().cloned().with_context(|| format!("{} needs a value", name)),
+            };
+            
+            match name {
+                "--dpi-desync" => {
+                    let value = value()?;
+                    if value.contains(',') {
+                        bail!(
+                            "--dpi-desync={}: combining modes is not supported, pick one",
+                            value
+                        );
+                    }
+                    let (mode, is_multi) = match value.as_str() {
+                        "split" | "split2" => (Technique::Split, false),
+                        "multisplit" => (Technique::Split, true),
+                        "disorder" | "disorder2" => (Technique::Disorder, false),
+                        "multidisorder" => (Technique::Disorder, true),
+                        "fake" => (Technique::Fake, false),
+                        other => bail!("--dpi-desync={}: mode not supported", other),
+                    };
+                    technique = Some(mode);
+                    multi = is_multi;
+                }
+                "--dpi-desync-split-pos" => {
+                    positions = value()?
+                        .split(',')
+                        .map(SplitPos::parse)
+                        .collect::<Result<_>>()?;
+                }
+                "--dpi-desync-ttl" => {
+                    let value = value()?;
+                    ttl = Some(value.parse().with_context(|| {
+                        format!("--dpi-desync-ttl={}: not a TTL", value)
+                    })?);
+                }
+                "--dpi-desync-fake-tls" => {
+                    let path = value()?;
+                    let data = std::fs::read(&path)
+                        .with_context(|| format!("Failed to read fake payload {}", path))?;
+                    if data.is_empty() {
+                        bail!("--dpi-desync-fake-tls: {} is empty", path);
+                    }
+                    fake_data = Some(data);
+                }
+                _ => bail!("{}: option not supported", arg),
+            }
+        }
+        
+        let Some(technique) = technique else {
+            bail!("no --dpi-desync mode given");
+        };
+        if !multi {
+            positions.truncate(1);
+        }
+        let rules: Vec<SplitConfig> = positions.into_iter().map(SplitPos::to_rule).collect();
+        
+        let mut config = DesyncConfig {
+            ttl,
+            ..DesyncConfig::default()
+        };
+        match technique {
+            Technique::Split => config.split = rules,
+            Technique::Disorder => config.disorder = rules,
+            Technique::Fake => {
+                config.fake = vec![FakeConfig {
+                    split: rules[0].clone(),
+                    ttl,
+                    data: Some(fake_data.unwrap_or_else(decoy_client_hello)),
+                }];
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// A zapret split position
+#[derive(Debug, Clone, Copy)]
+struct SplitPos {
+    offset: i64,
+    /// Relative to the hostname rather than the request start
+    host: bool,
+}
+
+impl SplitPos {
+    fn new(offset: i64, host: bool) -> Self {
+        Self { offset, host }
+    }
+    
+    fn parse(pos: &str) -> Result<Self> {
+        let (marker, offset) = if pos.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            pos.split_at(pos.find(['+', '-']).unwrap_or(pos.len()))
+        } else {
+            ("", pos)
+        };
+        let offset: i64 = match offset {
+            "" => 0,
+            offset => offset.trim_start_matches('+').parse().with_context(|| {
+                format!("--dpi-desync-split-pos={}: invalid offset", pos)
+            })?,
+        };
+        
+        match marker {
+            "" | "method" => Ok(Self::new(offset, false)),
+            "host" => Ok(Self::new(offset, true)),
+            other => bail!("--dpi-desync-split-pos={}: position {:?} not supported", pos, other),
+        }
+    }
+    
+    fn to_rule(self) -> SplitConfig {
+        let flags = SplitFlags {
+            auto_anchor: self.host,
+            ..SplitFlags::default()
+        };
+        rule(self.offset, flags)
+    }
+}
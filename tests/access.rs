@@ -0,0 +1,117 @@
+mod common;
+
+use common::{proxy_client_from, socks5_connect};
+use std::sync::Arc;
+use stpro::{Config, IpNet, ProxyServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn nets(ranges: &[&str]) -> Vec<IpNet> {
+    ranges.iter().map(|range| range.parse().unwrap()).collect()
+}
+
+/// Whether a client at `client` gets through the greeting
+async fn admitted(config: &Config, client: &str) -> bool {
+    let server = Arc::new(ProxyServer::new(config.clone()));
+    let (mut stream, handler) = proxy_client_from(&server, client.parse().unwrap());
+    
+    stream.write_all(&[5, 1, 0]).await.ok();
+    let mut choice = [0u8; 2];
+    let greeted = stream.read_exact(&mut choice).await.is_ok() && choice == [5, 0];
+    drop(stream);
+    handler.await.unwrap().ok();
+    greeted
+}
+
+#[tokio::test]
+async fn single_address() {
+    let config = Config {
+        allow: nets(&["192.0.2.7"]),
+        ..Config::default()
+    };
+    
+    assert!(admitted(&config, "192.0.2.7:50000").await);
+    assert!(!admitted(&config, "192.0.2.8:50000").await);
+}
+
+#[tokio::test]
+async fn cidr_range_with_deny_exception() {
+    let config = Config {
+        allow: nets(&["10.0.0.0/8"]),
+        deny: nets(&["10.1.0.0/16"]),
+        ..Config::default()
+    };
+    
+    assert!(admitted(&config, "10.200.3.4:50000").await);
+    assert!(!admitted(&config, "10.1.2.3:50000").await);
+    assert!(!admitted(&config, "192.168.1.1:50000").await);
+}
+
+#[tokio::test]
+async fn empty_allow_admits_all_but_denied() {
+    let config = Config {
+        deny: nets(&["203.0.113.0/24"]),
+        ..Config::default()
+    };
+    
+    assert!(admitted(&config, "198.51.100.1:50000").await);
+    assert!(!admitted(&config, "203.0.113.9:50000").await);
+}
+
+#[tokio::test]
+async fn ipv6_rules() {
+    let config = Config {
+        allow: nets(&["2001:db8::/32", "127.0.0.1"]),
+        deny: nets(&["2001:db8:bad::/48"]),
+        ..Config::default()
+    };
+    
+    assert!(admitted(&config, "[2001:db8:1::5]:50000").await);
+    assert!(!admitted(&config, "[2001:db8:bad::5]:50000").await);
+    assert!(!admitted(&config, "[2001:db9::1]:50000").await);
+    // IPv4-mapped clients are matched as their IPv4 address
+    assert!(admitted(&config, "[::ffff:127.0.0.1]:50000").await);
+}
+
+#[tokio::test]
+async fn per_ip_cap_rejects_extra_connections() {
+    // Target that hangs up at once, so a tunnel ends when its client does
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = listener.local_addr().unwrap();
+    tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+    let server = Arc::new(ProxyServer::new(Config {
+        max_connections_per_ip: Some(2),
+        ..Config::default()
+    }));
+    let client: std::net::SocketAddr = "192.0.2.1:50000".parse().unwrap();
+    
+    let mut open = Vec::new();
+    for _ in 0..2 {
+        let (mut stream, handler) = proxy_client_from(&server, client);
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        stream.write_all(&socks5_connect(target)).await.unwrap();
+        let mut replies = [0u8; 12];
+        stream.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[..4], [5, 0, 5, 0]);
+        open.push((stream, handler));
+    }
+    
+    // The third from the same address is turned away, others still get in
+    let (mut third, handler) = proxy_client_from(&server, client);
+    third.write_all(&[5, 1, 0]).await.ok();
+    let mut rest = Vec::new();
+    third.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    assert!(handler.await.unwrap().is_err());
+    let other = Config { max_connections_per_ip: Some(2), ..Config::default() };
+    assert!(admitted(&other, "192.0.2.2:50000").await);
+    
+    // Closing one frees its slot
+    let (stream, handler) = open.pop().unwrap();
+    drop(stream);
+    handler.await.unwrap().unwrap();
+    let (mut again, _) = proxy_client_from(&server, client);
+    again.write_all(&[5, 1, 0]).await.unwrap();
+    let mut choice = [0u8; 2];
+    again.read_exact(&mut choice).await.unwrap();
+    assert_eq!(choice, [5, 0]);
+}
@@ -0,0 +1,148 @@
+mod common;
+
+use common::{
+    dial, echo_once, finish_request, proxy_client, serve, socks5_connect, socks5_tunnel_handled,
+};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use stpro::{
+    format_clf_time, AccessLog, AccessLogConfig, AccessLogFormat, Config, ConnectionOutcome,
+    ConnectionStats, ProxyServer,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Stats of a finished connection with every field set to a known value
+fn finished_connection() -> ConnectionStats {
+    let mut stats = ConnectionStats::new("192.0.2.7:50000".parse().unwrap());
+    stats.username = Some("alice".to_string());
+    stats.tag = Some("phone".to_string());
+    stats.started_at = UNIX_EPOCH + Duration::from_secs(971_186_136);
+    stats.target = Some("example.com:443".to_string());
+    stats.bytes_up = 517;
+    stats.bytes_down = 4096;
+    stats.duration = Duration::from_millis(1250);
+    stats.ttfb = Some(Duration::from_millis(42));
+    stats.strategy = "split";
+    stats.outcome = ConnectionOutcome::Completed;
+    stats
+}
+
+fn format_line(format: AccessLogFormat, stats: &ConnectionStats) -> String {
+    AccessLog::open(AccessLogConfig { path: None, format }).unwrap().format_line(stats)
+}
+
+#[test]
+fn clf_time_is_utc() {
+    let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+    assert_eq!(format_clf_time(time), "10/Oct/2000:13:55:36 +0000");
+    assert_eq!(format_clf_time(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+    // Leap day
+    let time = UNIX_EPOCH + Duration::from_secs(1_709_164_800);
+    assert_eq!(format_clf_time(time), "29/Feb/2024:00:00:00 +0000");
+}
+
+#[test]
+fn common_line_layout() {
+    assert_eq!(
+        format_line(AccessLogFormat::Common, &finished_connection()),
+        "192.0.2.7 - alice [10/Oct/2000:13:55:36 +0000] \"CONNECT example.com:443\" ok 517 4096",
+    );
+}
+
+#[test]
+fn combined_line_layout() {
+    let mut stats = finished_connection();
+    assert_eq!(
+        format_line(AccessLogFormat::Combined, &stats),
+        "192.0.2.7 - alice [10/Oct/2000:13:55:36 +0000] \"CONNECT example.com:443\" ok 517 4096 \
+         1250 \"split\" 42 \"phone\"",
+    );
+    
+    stats.username = None;
+    stats.tag = None;
+    stats.ttfb = None;
+    stats.canary = true;
+    stats.outcome = ConnectionOutcome::Failed;
+    assert_eq!(
+        format_line(AccessLogFormat::Combined, &stats),
+        "192.0.2.7 - - [10/Oct/2000:13:55:36 +0000] \"CONNECT example.com:443\" error 517 4096 \
+         1250 \"canary:split\" - \"-\"",
+    );
+}
+
+#[tokio::test]
+async fn served_connection_is_logged() {
+    let path = std::env::temp_dir().join(format!("stpro-access-{}.log", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let target = echo_once().await;
+    let access_log = AccessLogConfig { path: Some(path.clone()), format: AccessLogFormat::Common };
+    let config = Config { access_log: Some(access_log), ..Config::default() };
+    let (server, addr, running) = serve(config);
+    
+    let mut client = dial(addr).await;
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    client.write_all(&socks5_connect(target)).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await.unwrap();
+    let mut echoed = [0u8; 27];
+    client.read_exact(&mut echoed).await.unwrap();
+    drop(client);
+    
+    let mut log = String::new();
+    for _ in 0..100 {
+        log = std::fs::read_to_string(&path).unwrap_or_default();
+        if log.ends_with('\n') {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    server.shutdown();
+    running.await.unwrap().unwrap();
+    std::fs::remove_file(&path).ok();
+    
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 1, "{}", log);
+    assert!(lines[0].starts_with("127.0.0.1 - - ["), "{}", lines[0]);
+    assert!(lines[0].ends_with(&format!("] \"CONNECT {}\" ok 27 27", target)), "{}", lines[0]);
+}
+
+#[tokio::test]
+async fn counters_follow_connections() {
+    let target = echo_once().await;
+    let server = Arc::new(ProxyServer::new(Config::default()));
+    
+    let (client, handler) = socks5_tunnel_handled(&server, target).await;
+    assert_eq!(server.stats().total_connections(), 1);
+    assert_eq!(server.stats().active_connections(), 1);
+    finish_request(client, handler).await;
+    
+    // A client turned away during the handshake still counts
+    let (mut client, handler) = proxy_client(&server);
+    client.write_all(&[4, 1]).await.unwrap();
+    drop(client);
+    assert!(handler.await.unwrap().is_err());
+    
+    let stats = server.stats();
+    assert_eq!((stats.total_connections(), stats.active_connections()), (2, 0));
+    let metrics = stats.render_prometheus();
+    let expected = [
+        "stpro_connections_total 2",
+        "stpro_bytes_up_total 27",
+        "stpro_bytes_down_total 27",
+    ];
+    for line in expected {
+        assert!(metrics.lines().any(|l| l == line), "missing {:?} in\n{}", line, metrics);
+    }
+    
+    let summary = stats.take_summary();
+    assert_eq!(summary.closed, 2);
+    assert_eq!((summary.interval_bytes_up, summary.interval_bytes_down), (27, 27));
+    assert_eq!(summary.top_targets, [(target.to_string(), 1)]);
+    // The interval restarts while the totals carry on
+    let summary = stats.take_summary();
+    assert_eq!((summary.total_connections, summary.closed), (2, 0));
+    assert_eq!(summary.interval_bytes_up, 0);
+}
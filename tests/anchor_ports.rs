@@ -0,0 +1,47 @@
+mod common;
+
+use common::client_hello;
+use stpro::{parse_split_config, DesyncConfig, DesyncEngine, FlowInfo, WriteOp};
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: www.example.com\r\n\r\n";
+
+/// Plan of `rule` for `buffer` sent to `port`
+fn plan(rule: &str, buffer: &[u8], port: u16) -> Vec<WriteOp> {
+    let engine = DesyncEngine::new(DesyncConfig {
+        split: vec![parse_split_config(rule).unwrap()],
+        ..DesyncConfig::default()
+    });
+    let flow = FlowInfo { host: Some("example.com".to_string()), port: Some(port) };
+    engine.plan_writes_flow(buffer, &flow)
+}
+
+#[test]
+fn sni_split_applies_on_443() {
+    let hello = client_hello("example.com");
+    assert_eq!(
+        plan("0+s", &hello, 443),
+        [WriteOp::Segment(0..61), WriteOp::Segment(61..hello.len())]
+    );
+}
+
+#[test]
+fn sni_split_is_skipped_on_other_ports() {
+    let hello = client_hello("example.com");
+    assert_eq!(plan("0+s", &hello, 8080), [WriteOp::Segment(0..hello.len())]);
+    assert_eq!(plan("0+a", &hello, 25), [WriteOp::Segment(0..hello.len())]);
+}
+
+#[test]
+fn host_split_follows_the_http_ports() {
+    assert_eq!(
+        plan("0+h", REQUEST, 80),
+        [WriteOp::Segment(0..22), WriteOp::Segment(22..REQUEST.len())]
+    );
+    assert_eq!(plan("0+h", REQUEST, 443), [WriteOp::Segment(0..REQUEST.len())]);
+}
+
+#[test]
+fn plain_offsets_ignore_the_port() {
+    let hello = client_hello("example.com");
+    assert_eq!(plan("1", &hello, 8080), [WriteOp::Segment(0..1), WriteOp::Segment(1..hello.len())]);
+}
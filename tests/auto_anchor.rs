@@ -0,0 +1,34 @@
+use stpro::{parse_split_config, sample_client_hello, DesyncConfig, DesyncEngine, WriteOp};
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+fn split(rule: &str) -> DesyncEngine {
+    DesyncEngine::new(DesyncConfig {
+        split: vec![parse_split_config(rule).unwrap()],
+        ..DesyncConfig::default()
+    })
+}
+
+#[test]
+fn lands_at_the_sni_of_a_client_hello() {
+    let hello = sample_client_hello("example.com");
+    let plan = split("0+a").plan_writes(&hello);
+    assert_eq!(plan, [WriteOp::Segment(0..61), WriteOp::Segment(61..hello.len())]);
+    assert_eq!(plan, split("0+s").plan_writes(&hello));
+}
+
+#[test]
+fn lands_at_the_host_of_an_http_request() {
+    let plan = split("0+a").plan_writes(REQUEST);
+    assert_eq!(plan, [WriteOp::Segment(0..22), WriteOp::Segment(22..REQUEST.len())]);
+    assert_eq!(plan, split("0+h").plan_writes(REQUEST));
+}
+
+#[test]
+fn falls_back_to_the_offset_for_other_data() {
+    let data = [0x42u8; 32];
+    assert_eq!(
+        split("3+a").plan_writes(&data),
+        [WriteOp::Segment(0..3), WriteOp::Segment(3..32)]
+    );
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use common::client_hello;
+use stpro::{detect, AutoConfig, AutoDetect, AutoStrategies, DesyncConfig, DetectionOutcome};
+use stpro::FirstResponse::{self, Closed, Data, Reset, Timeout};
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+const ALL: &[AutoDetect] =
+    &[AutoDetect::Torst, AutoDetect::Reset, AutoDetect::Redirect, AutoDetect::SslErr];
+
+const SERVER_HELLO: &[u8] = &[0x16, 0x03, 0x03, 0x00, 0x02, 0x02, 0x00];
+const ALERT: &[u8] = &[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28];
+const OK: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+const BLOCKPAGE: &[u8] = b"HTTP/1.1 302 Found\r\nLocation: http://blocked.example.net/\r\n\r\n";
+const SAME_HOST: &[u8] = b"HTTP/1.1 301 Moved\r\nLocation: https://www.example.com/\r\n\r\n";
+const RELATIVE: &[u8] = b"HTTP/1.1 302 Found\r\nLocation: /login\r\n\r\n";
+
+fn blocked(detector: AutoDetect) -> DetectionOutcome {
+    DetectionOutcome::Blocked(detector)
+}
+
+#[test]
+fn outcomes_per_response() {
+    let hello = client_hello("example.com");
+    let passed = DetectionOutcome::Passed;
+    let cases: &[(&[AutoDetect], &[u8], FirstResponse, DetectionOutcome)] = &[
+        // Timeout
+        (&[AutoDetect::Torst], &hello, Timeout, blocked(AutoDetect::Torst)),
+        (&[AutoDetect::Reset], &hello, Timeout, passed),
+        // Reset, and a close without a reply
+        (&[AutoDetect::Torst], &hello, Reset, blocked(AutoDetect::Torst)),
+        (&[AutoDetect::Reset], &hello, Reset, blocked(AutoDetect::Reset)),
+        (&[AutoDetect::Torst], &hello, Closed, blocked(AutoDetect::Torst)),
+        (&[AutoDetect::Reset], &hello, Closed, passed),
+        // Redirect to a block page
+        (&[AutoDetect::Redirect], REQUEST, Data(BLOCKPAGE), blocked(AutoDetect::Redirect)),
+        (&[AutoDetect::Redirect], REQUEST, Data(SAME_HOST), passed),
+        (&[AutoDetect::Redirect], REQUEST, Data(RELATIVE), passed),
+        (&[AutoDetect::Torst], REQUEST, Data(BLOCKPAGE), passed),
+        // A TLS alert in place of the ServerHello
+        (&[AutoDetect::SslErr], &hello, Data(ALERT), blocked(AutoDetect::SslErr)),
+        (&[AutoDetect::SslErr], REQUEST, Data(ALERT), passed),
+        // Success
+        (ALL, &hello, Data(SERVER_HELLO), passed),
+        (ALL, REQUEST, Data(OK), passed),
+        (&[AutoDetect::None], &hello, Reset, passed),
+        (&[], &hello, Timeout, passed),
+    ];
+    for (i, (detectors, request, response, expected)) in cases.iter().enumerate() {
+        assert_eq!(detect(detectors, request, response), *expected, "case {}", i);
+    }
+}
+
+#[test]
+fn first_detector_to_trip_is_reported() {
+    let hello = client_hello("example.com");
+    let detectors = [AutoDetect::Reset, AutoDetect::Torst];
+    assert_eq!(detect(&detectors, &hello, &Reset), blocked(AutoDetect::Reset));
+    assert_eq!(detect(&detectors, &hello, &Timeout), blocked(AutoDetect::Torst));
+}
+
+#[test]
+fn least_recently_used_host_is_forgotten() {
+    let auto = AutoStrategies::new(&AutoConfig {
+        detect: vec![AutoDetect::Reset],
+        timeout: None,
+        fallbacks: vec![DesyncConfig::default(); 2],
+        max_retries: None,
+    });
+    auto.remember("kept.example", 2);
+    for i in 0..1024 {
+        auto.remember(&format!("host{}.example", i), 1);
+        // Keeps the first host in use while the cache fills
+        assert_eq!(auto.preferred("kept.example"), 2);
+    }
+    assert_eq!(auto.preferred("host0.example"), 0);
+    assert_eq!(auto.preferred("host1.example"), 1);
+    assert_eq!(auto.preferred("host1023.example"), 1);
+}
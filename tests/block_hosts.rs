@@ -0,0 +1,252 @@
+mod common;
+
+use common::{
+    client_hello, dial, echo_server, proxy_client, serve, socks5_connect, socks5_connect_domain,
+    socks5_tunnel,
+};
+use std::sync::Arc;
+use std::time::Duration;
+use stpro::{host_pattern_matches, AccessLogConfig, AccessLogFormat, Config, ProxyServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+fn blocking(patterns: &[&str]) -> Arc<ProxyServer> {
+    Arc::new(ProxyServer::new(Config {
+        block_hosts: patterns.iter().map(|p| p.to_string()).collect(),
+        ..Config::default()
+    }))
+}
+
+#[test]
+fn exact_pattern_matches_the_host_only() {
+    assert!(host_pattern_matches("example.com", "example.com"));
+    assert!(host_pattern_matches("example.com", "EXAMPLE.com."));
+    assert!(host_pattern_matches("example.com.", "example.com"));
+    assert!(!host_pattern_matches("example.com", "www.example.com"));
+    assert!(!host_pattern_matches("example.com", "badexample.com"));
+    assert!(!host_pattern_matches("example.com", "example.co"));
+}
+
+#[test]
+fn wildcard_matches_subdomains_only() {
+    assert!(host_pattern_matches("*.example.com", "www.example.com"));
+    assert!(host_pattern_matches("*.example.com", "a.b.Example.COM"));
+    assert!(host_pattern_matches("*.example.com", "www.example.com."));
+    assert!(!host_pattern_matches("*.example.com", "example.com"));
+    assert!(!host_pattern_matches("*.example.com", "badexample.com"));
+    assert!(!host_pattern_matches("*.example.com", ".example.com"));
+    assert!(!host_pattern_matches("*.example.com", "ex\u{e4}mple.com"));
+}
+
+#[test]
+fn malformed_patterns_are_errors() {
+    let config = Config {
+        block_hosts: vec!["".into(), "*.".into(), "ads.*.com".into(), "*.ads.com".into()],
+        ..Config::default()
+    };
+    let errors: Vec<String> = config
+        .validate()
+        .into_iter()
+        .filter(|d| d.is_error())
+        .map(|d| d.message)
+        .collect();
+    assert_eq!(errors.len(), 3, "{:?}", errors);
+    assert!(errors[2].starts_with("block_hosts[2]: 'ads.*.com' has a wildcard"));
+}
+
+#[tokio::test]
+async fn socks5_domain_is_not_allowed() {
+    let server = blocking(&["*.blocked.test"]);
+    let (mut client, handler) = proxy_client(&server);
+    
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    client.write_all(&socks5_connect_domain("www.blocked.test", 443)).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    
+    assert_eq!(reply[..2], [5, 0x02]);
+    let error = handler.await.unwrap().unwrap_err();
+    assert_eq!(error.to_string(), "blocked host www.blocked.test");
+}
+
+#[tokio::test]
+async fn http_connect_is_forbidden() {
+    let server = blocking(&["blocked.test"]);
+    let (mut client, handler) = proxy_client(&server);
+    
+    client.write_all(b"CONNECT blocked.test:443 HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    
+    assert!(reply.starts_with(b"HTTP/1.1 403 "));
+    let error = handler.await.unwrap().unwrap_err();
+    assert_eq!(error.to_string(), "blocked host blocked.test");
+}
+
+#[tokio::test]
+async fn blocked_sni_closes_the_tunnel() {
+    let target = echo_server("127.0.0.1").await;
+    let server = blocking(&["*.blocked.test"]);
+    let mut client = socks5_tunnel(&server, target).await;
+    
+    client.write_all(&client_hello("www.blocked.test")).await.unwrap();
+    let mut echoed = Vec::new();
+    client.read_to_end(&mut echoed).await.unwrap();
+    assert!(echoed.is_empty());
+}
+
+#[tokio::test]
+async fn blocked_host_header_closes_the_tunnel() {
+    let target = echo_server("127.0.0.1").await;
+    let server = blocking(&["blocked.test"]);
+    let mut client = socks5_tunnel(&server, target).await;
+    
+    client.write_all(b"GET / HTTP/1.1\r\nHost: blocked.test:8080\r\n\r\n").await.unwrap();
+    let mut echoed = Vec::new();
+    client.read_to_end(&mut echoed).await.unwrap();
+    assert!(echoed.is_empty());
+}
+
+#[tokio::test]
+async fn blocked_host_header_of_a_forwarded_request_is_never_dialed() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = blocking(&["blocked.test"]);
+    let (mut client, handler) = proxy_client(&server);
+    
+    let addr = target.local_addr().unwrap();
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: blocked.test\r\n\r\n", addr);
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    
+    assert!(reply.starts_with(b"HTTP/1.1 403 "));
+    let error = handler.await.unwrap().unwrap_err();
+    assert_eq!(error.to_string(), "blocked host blocked.test");
+    let accepted = tokio::time::timeout(Duration::from_millis(200), target.accept()).await;
+    assert!(accepted.is_err(), "the target was connected");
+}
+
+#[tokio::test]
+async fn blocked_sni_never_reaches_the_target() {
+    // A tunnel's target is connected before the first flight comes in, but
+    // none of that flight is passed on
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = target.local_addr().unwrap();
+    let received = tokio::spawn(async move {
+        let (mut stream, _) = target.accept().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.ok();
+        received
+    });
+    let server = blocking(&["*.blocked.test"]);
+    let mut client = socks5_tunnel(&server, addr).await;
+    
+    client.write_all(&client_hello("www.blocked.test")).await.unwrap();
+    let mut echoed = Vec::new();
+    client.read_to_end(&mut echoed).await.unwrap();
+    assert!(received.await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn other_hosts_pass_through_intact() {
+    let target = echo_server("127.0.0.1").await;
+    let server = blocking(&["*.blocked.test"]);
+    let mut client = socks5_tunnel(&server, target).await;
+    
+    let hello = client_hello("allowed.test");
+    client.write_all(&hello).await.unwrap();
+    client.write_all(b"after").await.unwrap();
+    let mut echoed = vec![0u8; hello.len() + 5];
+    client.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(echoed, [hello.as_slice(), b"after"].concat());
+}
+
+/// Run a server blocking `*.blocked.test`, logging to a file in `Common`
+/// format, and hand a connected client to `refused`; returns whether the
+/// client was reset and the access log line
+async fn refusal<F, Fut>(abort_with_rst: bool, name: &str, refused: F) -> (bool, String)
+where
+    F: FnOnce(TcpStream) -> Fut,
+    Fut: std::future::Future<Output = TcpStream>,
+{
+    let path = std::env::temp_dir().join(format!("stpro-{}-{}.log", name, std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let access_log = AccessLogConfig { path: Some(path.clone()), format: AccessLogFormat::Common };
+    let (server, addr, running) = serve(Config {
+        block_hosts: vec!["*.blocked.test".to_string()],
+        abort_with_rst,
+        access_log: Some(access_log),
+        ..Config::default()
+    });
+    
+    let mut client = refused(dial(addr).await).await;
+    let mut rest = Vec::new();
+    let reset = match client.read_to_end(&mut rest).await {
+        Ok(_) => false,
+        Err(e) => {
+            assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset);
+            true
+        }
+    };
+    assert!(rest.is_empty(), "{:?}", rest);
+    
+    let mut log = String::new();
+    for _ in 0..100 {
+        log = std::fs::read_to_string(&path).unwrap_or_default();
+        if log.ends_with('\n') {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    server.shutdown();
+    running.await.unwrap().unwrap();
+    std::fs::remove_file(&path).ok();
+    (reset, log)
+}
+
+/// CONNECT to a blocked domain, checking the refusal reply
+async fn socks5_blocked_domain(mut client: TcpStream) -> TcpStream {
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    client.write_all(&socks5_connect_domain("www.blocked.test", 443)).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[..2], [5, 0x02]);
+    client
+}
+
+#[tokio::test]
+async fn refusal_is_reset_with_abort_with_rst() {
+    let (reset, log) = refusal(true, "blocked-rst", socks5_blocked_domain).await;
+    assert!(reset);
+    assert!(log.ends_with("\"CONNECT www.blocked.test:443\" refused 0 0\n"), "{}", log);
+}
+
+#[tokio::test]
+async fn refusal_is_closed_without_abort_with_rst() {
+    let (reset, log) = refusal(false, "blocked-fin", socks5_blocked_domain).await;
+    assert!(!reset);
+    assert!(log.ends_with("\"CONNECT www.blocked.test:443\" refused 0 0\n"), "{}", log);
+}
+
+#[tokio::test]
+async fn blocked_first_flight_is_reset() {
+    let target = echo_server("127.0.0.1").await;
+    let (reset, log) = refusal(true, "blocked-sni", |mut client| async move {
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut choice = [0u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        client.write_all(&socks5_connect(target)).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+        client.write_all(&client_hello("www.blocked.test")).await.unwrap();
+        client
+    })
+    .await;
+    assert!(reset);
+    assert!(log.contains(" refused "), "{}", log);
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use common::{echo_once, finish_request, socks5_tunnel_handled, strategies_used};
+use std::sync::Arc;
+use stpro::{parse_split_config, CanaryConfig, Config, DesyncConfig, ProxyServer};
+
+const CONNECTIONS: usize = 200;
+
+/// Server forwarding untouched except for a `percent` canary that splits
+fn canary_server(percent: f64, seed: u64) -> Arc<ProxyServer> {
+    let canary = CanaryConfig {
+        desync: DesyncConfig {
+            split: vec![parse_split_config("1").unwrap()],
+            ..DesyncConfig::default()
+        },
+        percent,
+        seed: Some(seed),
+    };
+    Arc::new(ProxyServer::new(Config {
+        canary: Some(canary),
+        desync_ports: Vec::new(),
+        ..Config::default()
+    }))
+}
+
+/// Connections the canary took out of `CONNECTIONS` served one at a time
+async fn canary_share(server: &Arc<ProxyServer>) -> u64 {
+    let target = echo_once().await;
+    for _ in 0..CONNECTIONS {
+        let (client, handler) = socks5_tunnel_handled(server, target).await;
+        finish_request(client, handler).await;
+    }
+    let used = strategies_used(server);
+    let total: u64 = used.iter().map(|(_, n)| n).sum();
+    assert_eq!(total, CONNECTIONS as u64, "{:?}", used);
+    used.iter().find(|(name, _)| *name == "split").map_or(0, |(_, n)| *n)
+}
+
+#[tokio::test]
+async fn half_of_the_connections_take_the_canary() {
+    let taken = canary_share(&canary_server(50.0, 7)).await;
+    let half = CONNECTIONS as u64 / 2;
+    assert!((half - 30..=half + 30).contains(&taken), "canary took {}", taken);
+}
+
+#[tokio::test]
+async fn same_seed_picks_the_same_connections() {
+    let first = canary_share(&canary_server(50.0, 11)).await;
+    let second = canary_share(&canary_server(50.0, 11)).await;
+    assert_eq!(first, second);
+}
+
+#[tokio::test]
+async fn bounds_take_none_or_all() {
+    assert_eq!(canary_share(&canary_server(0.0, 1)).await, 0);
+    assert_eq!(canary_share(&canary_server(100.0, 1)).await, CONNECTIONS as u64);
+}
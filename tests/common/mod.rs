@@ -0,0 +1,323 @@
+#![allow(dead_code)]
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use std::collections::HashMap;
+use stpro::{
+    Config, ConnectionOutcome, ListenAddr, ProxyServer, Resolve, ResolveFuture, ShutdownSummary,
+};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// A reassembly-free DPI box standing in for the target
+///
+/// Every `write` that reaches it is treated as one TCP segment. If a single
+/// segment contains a forbidden token the connection is "reset": that write
+/// and every later one fail with `ConnectionReset`. A token split across
+/// segments goes unnoticed, which is exactly what desync relies on.
+pub struct MockDpi {
+    forbidden: Vec<Vec<u8>>,
+    segments: Vec<Vec<u8>>,
+    reset: bool,
+}
+
+impl MockDpi {
+    pub fn new<I, T>(forbidden: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        Self {
+            forbidden: forbidden.into_iter().map(|t| t.as_ref().to_vec()).collect(),
+            segments: Vec::new(),
+            reset: false,
+        }
+    }
+    
+    pub fn is_reset(&self) -> bool {
+        self.reset
+    }
+    
+    /// Segments that made it past the inspector
+    pub fn segments(&self) -> &[Vec<u8>] {
+        &self.segments
+    }
+    
+    /// Bytes delivered to the "server" behind the inspector
+    pub fn received(&self) -> Vec<u8> {
+        self.segments.concat()
+    }
+    
+    fn inspect(&self, segment: &[u8]) -> bool {
+        self.forbidden.iter().any(|token| {
+            !token.is_empty() && segment.windows(token.len()).any(|w| w == token.as_slice())
+        })
+    }
+}
+
+impl AsyncWrite for MockDpi {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.reset || self.inspect(buf) {
+            self.reset = true;
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        
+        self.segments.push(buf.to_vec());
+        Poll::Ready(Ok(buf.len()))
+    }
+    
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Build a minimal TLS 1.2-framed ClientHello carrying `sni`
+pub fn client_hello(sni: &str) -> Vec<u8> {
+    stpro::sample_client_hello(sni)
+}
+
+/// A target behind a DPI box that resets the first `resets` connections as
+/// soon as they send something, and answers later ones with `RESPONSE`
+pub struct ResettingServer {
+    pub addr: SocketAddr,
+    connections: Arc<AtomicUsize>,
+}
+
+impl ResettingServer {
+    pub const RESPONSE: &'static [u8] = b"\x16\x03\x03\x00\x00";
+    
+    pub async fn start(resets: usize) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let index = counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 4096];
+                    if stream.read(&mut buf).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    if index < resets {
+                        // Linger 0 turns the close into a RST
+                        socket2::SockRef::from(&stream).set_linger(Some(Duration::ZERO)).ok();
+                        return;
+                    }
+                    stream.write_all(Self::RESPONSE).await.ok();
+                    let _ = stream.read(&mut buf).await;
+                });
+            }
+        });
+        Self { addr, connections }
+    }
+    
+    /// Connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+pub type Handler = JoinHandle<anyhow::Result<ConnectionOutcome>>;
+
+/// Serve one in-memory client through `server`, returning the client end
+/// and the handler
+pub fn proxy_client(server: &Arc<ProxyServer>) -> (DuplexStream, Handler) {
+    proxy_client_from(server, "127.0.0.1:40000".parse().unwrap())
+}
+
+/// Like [`proxy_client`], for a client connecting from `client_addr`
+pub fn proxy_client_from(
+    server: &Arc<ProxyServer>,
+    client_addr: SocketAddr,
+) -> (DuplexStream, Handler) {
+    let (client, stream) = tokio::io::duplex(64 * 1024);
+    let server = server.clone();
+    let handler = tokio::spawn(async move { server.handle_stream(stream, client_addr).await });
+    (client, handler)
+}
+
+/// SOCKS5 CONNECT request for an IP target
+pub fn socks5_connect(target: SocketAddr) -> Vec<u8> {
+    let mut request = vec![5, 1, 0];
+    match target {
+        SocketAddr::V4(addr) => {
+            request.push(1);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            request.push(4);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    request
+}
+
+/// SOCKS5 CONNECT request for a domain target
+pub fn socks5_connect_domain(domain: &str, port: u16) -> Vec<u8> {
+    let mut request = vec![5, 1, 0, 3, domain.len() as u8];
+    request.extend_from_slice(domain.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    request
+}
+
+/// Open a SOCKS5 tunnel to `target` without authentication
+pub async fn socks5_tunnel(server: &Arc<ProxyServer>, target: SocketAddr) -> DuplexStream {
+    socks5_tunnel_handled(server, target).await.0
+}
+
+/// Like [`socks5_tunnel`], keeping the handler to await
+pub async fn socks5_tunnel_handled(
+    server: &Arc<ProxyServer>,
+    target: SocketAddr,
+) -> (DuplexStream, Handler) {
+    let (mut client, handler) = proxy_client(server);
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    assert_eq!(choice, [5, 0]);
+    
+    client.write_all(&socks5_connect(target)).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], 0, "CONNECT failed");
+    (client, handler)
+}
+
+/// Send a request through the tunnel, read the echo, hang up and wait for
+/// the handler to record the connection
+pub async fn finish_request(mut client: DuplexStream, handler: Handler) {
+    client.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await.unwrap();
+    let mut echoed = [0u8; 27];
+    client.read_exact(&mut echoed).await.unwrap();
+    drop(client);
+    handler.await.unwrap().unwrap();
+}
+
+/// Connections recorded per strategy name
+pub fn strategies_used(server: &ProxyServer) -> Vec<(String, u64)> {
+    server
+        .stats()
+        .strategy_stats()
+        .into_iter()
+        .map(|(name, counters)| (name, counters.attempts))
+        .collect()
+}
+
+pub type Running = JoinHandle<anyhow::Result<ShutdownSummary>>;
+
+/// Run `config` on a free loopback port, returning the server, its address
+/// and the task to await after `shutdown`
+pub fn serve(config: Config) -> (Arc<ProxyServer>, SocketAddr, Running) {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let server = Arc::new(ProxyServer::new(Config { listen: ListenAddr::Tcp(addr), ..config }));
+    let running = tokio::spawn({
+        let server = server.clone();
+        async move { server.run().await }
+    });
+    (server, addr, running)
+}
+
+/// Connect to a server started by [`serve`], waiting for its listener
+pub async fn dial(addr: SocketAddr) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(addr).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("server on {} never came up", addr);
+}
+
+/// A target that echoes one request of every connection and hangs up, so
+/// tunnels through it end without waiting for the idle timeout
+pub async fn echo_once() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                stream.write_all(&request).await.ok();
+            });
+        }
+    });
+    addr
+}
+
+/// A target on loopback that echoes whatever it receives
+pub async fn echo_server(ip: &str) -> SocketAddr {
+    let listener = TcpListener::bind((ip, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                tokio::io::copy(&mut read, &mut write).await.ok();
+            });
+        }
+    });
+    addr
+}
+
+/// An address whose connects hang: a listener that never accepts, with its
+/// accept queue filled so later SYNs are dropped
+pub struct BlackHole {
+    pub addr: SocketAddr,
+    _listener: socket2::Socket,
+    _queued: Vec<std::net::TcpStream>,
+}
+
+impl BlackHole {
+    pub fn new() -> Self {
+        use socket2::{Domain, Socket, Type};
+        
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        listener.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into()).unwrap();
+        listener.listen(0).unwrap();
+        let addr = listener.local_addr().unwrap().as_socket().unwrap();
+        let queued = (0..3)
+            .filter_map(|_| {
+                std::net::TcpStream::connect_timeout(&addr, Duration::from_millis(100)).ok()
+            })
+            .collect();
+        Self { addr, _listener: listener, _queued: queued }
+    }
+}
+
+/// Resolver answering from a fixed table, so domain targets never reach DNS
+pub struct StubResolver(pub HashMap<String, SocketAddr>);
+
+impl Resolve for StubResolver {
+    fn resolve<'a>(&'a self, host: &'a str, _port: u16) -> ResolveFuture<'a> {
+        let answer = self.0.get(host).copied();
+        Box::pin(async move {
+            answer
+                .map(|addr| vec![addr])
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such host"))
+        })
+    }
+}
@@ -0,0 +1,52 @@
+use serde_json::Value;
+use std::path::PathBuf;
+use stpro::Config;
+
+/// Write `text` to a scratch file named after `name` and load it back
+fn load(name: &str, text: &str) -> anyhow::Result<Config> {
+    let path: PathBuf = std::env::temp_dir().join(format!("stpro-{}-{}", std::process::id(), name));
+    std::fs::write(&path, text).unwrap();
+    let config = Config::from_file(&path);
+    std::fs::remove_file(&path).unwrap();
+    config
+}
+
+fn to_value(config: &Config) -> Value {
+    serde_json::to_value(config).unwrap()
+}
+
+#[test]
+fn serialized_config_reads_back_identical() {
+    let config = Config::example();
+    let formats = [
+        ("roundtrip.toml", toml::to_string(&config).unwrap()),
+        ("roundtrip.json", serde_json::to_string(&config).unwrap()),
+        ("roundtrip.yaml", serde_yaml::to_string(&config).unwrap()),
+    ];
+    for (name, text) in formats {
+        let loaded = load(name, &text).unwrap_or_else(|e| panic!("{}: {:#}", name, e));
+        assert_eq!(to_value(&loaded), to_value(&config), "{}", name);
+    }
+}
+
+#[test]
+fn yaml_config_loads() {
+    let text = "listen: 127.0.0.1:1090\ndesync:\n  split:\n    - offset: 2\n";
+    let config = load("plain.yml", text).unwrap();
+    assert_eq!(config.listen.to_string(), "127.0.0.1:1090");
+    assert_eq!(config.desync.split.len(), 1);
+}
+
+#[test]
+fn toml_error_names_the_line_and_column() {
+    let text = "listen = \"127.0.0.1:1090\"\nbuffer_size = = 4\n";
+    let message = format!("{:#}", load("broken.toml", text).unwrap_err());
+    assert!(message.contains("broken.toml"), "{}", message);
+    assert!(message.contains("line 2, column 15"), "{}", message);
+}
+
+#[test]
+fn unknown_extension_is_refused() {
+    let message = format!("{:#}", load("config.ini", "").unwrap_err());
+    assert!(message.contains("expected .toml, .json or .yaml"), "{}", message);
+}
@@ -0,0 +1,74 @@
+mod common;
+
+use common::{echo_server, BlackHole};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use stpro::{Config, ConnectError, Connector};
+
+fn bound_to(bind_addr: &str) -> Connector {
+    Connector::new(&Config { bind_addr: Some(bind_addr.parse().unwrap()), ..Config::default() })
+}
+
+/// A loopback address nothing listens on, so connects are refused at once
+fn refusing() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+#[tokio::test]
+async fn ipv4_bind_address_skips_ipv6_targets() {
+    let (v6, v4) = (echo_server("::1").await, echo_server("127.0.0.1").await);
+    let connector = bound_to("127.0.0.1:0");
+    let (stream, addr) = connector.connect_any("dual.test", vec![v6, v4]).await.unwrap();
+    assert_eq!(addr, v4);
+    assert!(stream.local_addr().unwrap().is_ipv4());
+}
+
+#[tokio::test]
+async fn ipv6_bind_address_skips_ipv4_targets() {
+    let (v4, v6) = (echo_server("127.0.0.1").await, echo_server("::1").await);
+    let connector = bound_to("[::1]:0");
+    let (stream, addr) = connector.connect_any("dual.test", vec![v4, v6]).await.unwrap();
+    assert_eq!(addr, v6);
+    assert!(stream.local_addr().unwrap().is_ipv6());
+}
+
+#[tokio::test]
+async fn no_target_of_the_bind_family_is_a_mismatch() {
+    let v6 = echo_server("::1").await;
+    let err = bound_to("127.0.0.1:0").connect_any("v6.test", vec![v6]).await.unwrap_err();
+    assert!(matches!(err, ConnectError::FamilyMismatch { target, .. } if target == v6), "{}", err);
+}
+
+#[tokio::test]
+async fn address_that_connected_goes_first_next_time() {
+    let connector = Connector::new(&Config::default());
+    let (first, second) = (echo_server("127.0.0.1").await, echo_server("127.0.0.1").await);
+    
+    let (_, addr) = connector.connect_any("host.test", vec![refusing(), second]).await.unwrap();
+    assert_eq!(addr, second);
+    
+    // Both answer, so whichever is tried first wins
+    let (_, addr) = connector.connect_any("host.test", vec![first, second]).await.unwrap();
+    assert_eq!(addr, second);
+    let (_, addr) = connector.connect_any("other.test", vec![first, second]).await.unwrap();
+    assert_eq!(addr, first);
+}
+
+#[tokio::test]
+async fn repeat_connect_skips_the_head_start_of_a_dead_address() {
+    let delay = Duration::from_millis(300);
+    let config = Config { connect_attempt_delay: Some(delay), ..Config::default() };
+    let connector = Connector::new(&config);
+    let (dead, live) = (BlackHole::new(), echo_server("127.0.0.1").await);
+    
+    // The dead address goes first and holds the live one back for `delay`
+    let started = Instant::now();
+    let (_, addr) = connector.connect_any("multi.test", vec![dead.addr, live]).await.unwrap();
+    assert_eq!(addr, live);
+    assert!(started.elapsed() >= delay);
+    
+    let started = Instant::now();
+    let (_, addr) = connector.connect_any("multi.test", vec![dead.addr, live]).await.unwrap();
+    assert_eq!(addr, live);
+    assert!(started.elapsed() < delay, "live address not tried first: {:?}", started.elapsed());
+}
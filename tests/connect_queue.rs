@@ -0,0 +1,68 @@
+mod common;
+
+use common::{echo_once, proxy_client, socks5_connect, BlackHole};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use stpro::{Config, ProxyServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// SOCKS5 CONNECT to `target` through `server`, returning the reply code
+async fn connect(server: &Arc<ProxyServer>, target: SocketAddr) -> u8 {
+    let (mut client, _) = proxy_client(server);
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    client.write_all(&socks5_connect(target)).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    reply[1]
+}
+
+/// One connect slot, waited for at most `queue_timeout`; connects give up
+/// after `connect_timeout`
+fn one_slot(queue_timeout: Duration, connect_timeout: Duration) -> Arc<ProxyServer> {
+    Arc::new(ProxyServer::new(Config {
+        max_concurrent_connects: Some(1),
+        connect_queue_timeout: Some(queue_timeout),
+        connect_timeout: Some(connect_timeout),
+        ..Config::default()
+    }))
+}
+
+#[tokio::test]
+async fn queued_connect_times_out_with_host_unreachable() {
+    let hole = BlackHole::new();
+    let target = echo_once().await;
+    let server = one_slot(Duration::from_millis(200), Duration::from_secs(5));
+    
+    let stuck = tokio::spawn({
+        let server = server.clone();
+        async move { connect(&server, hole.addr).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    
+    let started = Instant::now();
+    assert_eq!(connect(&server, target).await, 0x04);
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    stuck.abort();
+}
+
+#[tokio::test]
+async fn queued_connect_proceeds_once_a_slot_frees() {
+    let hole = BlackHole::new();
+    let target = echo_once().await;
+    let server = one_slot(Duration::from_secs(5), Duration::from_millis(300));
+    
+    let stuck = tokio::spawn({
+        let server = server.clone();
+        async move { connect(&server, hole.addr).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    
+    let started = Instant::now();
+    assert_eq!(connect(&server, target).await, 0x00);
+    // It waited for the stuck connect to time out and free the slot
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert_eq!(stuck.await.unwrap(), 0x06);
+}
@@ -0,0 +1,99 @@
+#![cfg(unix)]
+
+use std::path::PathBuf;
+use std::time::Duration;
+use stpro::PidFile;
+
+/// A path in the temp directory unique to this test
+fn pid_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("stpro-{}-{}.pid", name, std::process::id()))
+}
+
+#[test]
+fn pid_file_is_written_and_removed() {
+    let path = pid_path("written");
+    let pid_file = PidFile::create(&path).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+    
+    drop(pid_file);
+    assert!(!path.exists());
+}
+
+#[test]
+fn stale_pid_file_is_overwritten() {
+    let path = pid_path("stale");
+    let mut exited = std::process::Command::new("true").spawn().unwrap();
+    exited.wait().unwrap();
+    std::fs::write(&path, format!("{}\n", exited.id())).unwrap();
+    
+    let pid_file = PidFile::create(&path).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+    drop(pid_file);
+    
+    std::fs::write(&path, "not a pid").unwrap();
+    drop(PidFile::create(&path).unwrap());
+    assert!(!path.exists());
+}
+
+#[test]
+fn live_pid_file_is_refused() {
+    let path = pid_path("live");
+    let mut running = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+    std::fs::write(&path, format!("{}\n", running.id())).unwrap();
+    
+    let error = PidFile::create(&path).unwrap_err().to_string();
+    assert!(error.contains(&format!("pid {}", running.id())), "{}", error);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{}\n", running.id()));
+    
+    running.kill().unwrap();
+    running.wait().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn pid_file_taken_over_is_left_alone() {
+    let path = pid_path("taken");
+    let pid_file = PidFile::create(&path).unwrap();
+    std::fs::write(&path, "1\n").unwrap();
+    
+    drop(pid_file);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "1\n");
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Poll `condition` for up to five seconds
+fn wait_for(condition: impl Fn() -> bool) -> bool {
+    (0..100).any(|_| {
+        std::thread::sleep(Duration::from_millis(50));
+        condition()
+    })
+}
+
+#[test]
+fn daemon_detaches_and_cleans_up_on_sigterm() {
+    let path = pid_path("daemon");
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_stpro"))
+        .args(["--daemon", "--port", &port.to_string(), "--pid-file"])
+        .arg(&path)
+        .status()
+        .unwrap();
+    // The process we started returns as soon as the daemon is forked off
+    assert!(status.success());
+    
+    assert!(wait_for(|| std::fs::read_to_string(&path).is_ok_and(|s| s.ends_with('\n'))));
+    let pid: libc::pid_t = std::fs::read_to_string(&path).unwrap().trim().parse().unwrap();
+    assert!(wait_for(|| std::net::TcpStream::connect(("127.0.0.1", port)).is_ok()));
+    
+    // A second instance sees the live PID file before forking
+    let second = std::process::Command::new(env!("CARGO_BIN_EXE_stpro"))
+        .args(["--daemon", "--port", "0", "--pid-file"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(!second.status.success());
+    assert!(String::from_utf8_lossy(&second.stderr).contains("already running"));
+    
+    assert_eq!(unsafe { libc::kill(pid, libc::SIGTERM) }, 0);
+    assert!(wait_for(|| !path.exists()));
+}
@@ -0,0 +1,88 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+const STPRO: &str = env!("CARGO_BIN_EXE_stpro");
+/// The 517-byte ClientHello of tests/data, SNI at offset 153
+const HELLO: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/client_hello.bin");
+
+fn temp_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("stpro-desync-file-{}-{}", std::process::id(), name))
+}
+
+/// stpro with none of the STPRO_* variables the test runner may have set
+fn stpro(args: &[&str]) -> Output {
+    let mut command = Command::new(STPRO);
+    for var in ["LISTEN", "SPLIT", "DISORDER", "FAKE", "TTL", "CONFIG"] {
+        command.env_remove(format!("STPRO_{}", var));
+    }
+    let output = command.args(args).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+/// Segment headers of the printed output
+fn headers(output: &Output) -> Vec<String> {
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.starts_with("--- segment"))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Segments of a length-prefixed `--out` file
+fn framed_segments(mut framed: &[u8]) -> Vec<Vec<u8>> {
+    let mut segments = Vec::new();
+    while !framed.is_empty() {
+        let len = u32::from_be_bytes(framed[..4].try_into().unwrap()) as usize;
+        segments.push(framed[4..4 + len].to_vec());
+        framed = &framed[4 + len..];
+    }
+    segments
+}
+
+#[test]
+fn config_split_cuts_the_fixture_at_the_sni() {
+    let config = temp_file("config.toml");
+    let text = "[desync]\nsplit = [{ offset = 0, flags = { sni = true } }]\n";
+    std::fs::write(&config, text).unwrap();
+    let out = temp_file("segments.bin");
+    
+    let output = stpro(&[
+        "desync-file",
+        "--in",
+        HELLO,
+        "--config",
+        config.to_str().unwrap(),
+        "--out",
+        out.to_str().unwrap(),
+    ]);
+    let framed = std::fs::read(&out).unwrap();
+    std::fs::remove_file(&config).ok();
+    std::fs::remove_file(&out).ok();
+    
+    assert_eq!(
+        headers(&output),
+        [
+            "--- segment 1 [0..153) 153 bytes ---",
+            "--- segment 2 [153..517) 364 bytes ---",
+        ]
+    );
+    let hello = std::fs::read(HELLO).unwrap();
+    assert_eq!(framed_segments(&framed), [hello[..153].to_vec(), hello[153..].to_vec()]);
+}
+
+#[test]
+fn desync_flags_apply_without_a_config() {
+    let output = stpro(&["--split", "1", "--split=-5+e", "desync-file", "--in", HELLO]);
+    assert_eq!(
+        headers(&output),
+        [
+            "--- segment 1 [0..1) 1 bytes ---",
+            "--- segment 2 [1..512) 511 bytes ---",
+            "--- segment 3 [512..517) 5 bytes ---",
+        ]
+    );
+    let dump = String::from_utf8_lossy(&output.stdout);
+    // The first line of the hex dump shows the record header
+    assert!(dump.contains("00000000  16\n"), "{}", dump);
+}
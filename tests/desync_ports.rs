@@ -0,0 +1,51 @@
+mod common;
+
+use common::{echo_once, finish_request, socks5_tunnel_handled, strategies_used};
+use std::sync::Arc;
+use stpro::{parse_split_config, Config, DesyncConfig, ProxyServer};
+
+fn split_server(desync_ports: Vec<u16>) -> Arc<ProxyServer> {
+    Arc::new(ProxyServer::new(Config {
+        desync_ports,
+        desync: DesyncConfig {
+            split: vec![parse_split_config("1").unwrap()],
+            ..DesyncConfig::default()
+        },
+        ..Config::default()
+    }))
+}
+
+#[test]
+fn web_ports_by_default() {
+    assert_eq!(Config::default().desync_ports, [443, 80]);
+}
+
+#[tokio::test]
+async fn other_ports_are_forwarded_untouched() {
+    let target = echo_once().await;
+    let server = split_server(vec![443, 80]);
+    
+    let (client, handler) = socks5_tunnel_handled(&server, target).await;
+    finish_request(client, handler).await;
+    assert_eq!(strategies_used(&server), [("none".into(), 1)]);
+}
+
+#[tokio::test]
+async fn listed_ports_are_desynced() {
+    let target = echo_once().await;
+    let server = split_server(vec![443, target.port()]);
+    
+    let (client, handler) = socks5_tunnel_handled(&server, target).await;
+    finish_request(client, handler).await;
+    assert_eq!(strategies_used(&server), [("split".into(), 1)]);
+}
+
+#[tokio::test]
+async fn empty_list_desyncs_every_port() {
+    let target = echo_once().await;
+    let server = split_server(Vec::new());
+    
+    let (client, handler) = socks5_tunnel_handled(&server, target).await;
+    finish_request(client, handler).await;
+    assert_eq!(strategies_used(&server), [("split".into(), 1)]);
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use common::client_hello;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use stpro::{parse_split_config, DesyncConfig, DesyncEngine, SegmentRecorder, WriteOp};
+
+const REQUEST: &[u8] = b"GET /index.html HTTP/1.1\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n";
+
+fn engine(disorder: &[&str], split: &[&str]) -> DesyncEngine {
+    let rules = |rules: &[&str]| rules.iter().map(|r| parse_split_config(r).unwrap()).collect();
+    DesyncEngine::new(DesyncConfig {
+        disorder: rules(disorder),
+        split: rules(split),
+        ..DesyncConfig::default()
+    })
+}
+
+/// The bytes the plan for `buffer` writes, in the order it writes them
+fn reconstruct(engine: &DesyncEngine, buffer: &[u8]) -> Vec<u8> {
+    let mut next = 0;
+    let mut bytes = Vec::new();
+    for op in engine.plan_writes(buffer) {
+        let range = match op {
+            WriteOp::Segment(range) | WriteOp::Queued(range) | WriteOp::Disordered(range) => range,
+            WriteOp::Fake { range, .. } => range,
+        };
+        assert_eq!(range.start, next, "segments out of order");
+        next = range.end;
+        bytes.extend_from_slice(&buffer[range]);
+    }
+    bytes
+}
+
+#[tokio::test]
+async fn disorder_rules_give_back_the_input() {
+    let hello = client_hello("example.com");
+    let cases: &[(&[&str], &[&str])] = &[
+        (&["1"], &[]),
+        (&["1+s"], &[]),
+        (&["-1+s"], &["3"]),
+        (&["2+s", "-4+n"], &[]),
+        (&["1:5:3"], &[]),
+        (&["50%"], &["1"]),
+        (&["2+h"], &[]),
+        (&["0+e"], &[]),
+        (&["10000"], &[]),
+    ];
+    let plan = engine(&["1+s"], &[]).plan_writes(&hello);
+    assert!(plan.iter().any(|op| matches!(op, WriteOp::Disordered(_))), "{:?}", plan);
+    
+    for (disorder, split) in cases {
+        let engine = engine(disorder, split);
+        for buffer in [hello.as_slice(), REQUEST, b"x", b""] {
+            assert_eq!(reconstruct(&engine, buffer), buffer, "{:?} {:?}", disorder, split);
+            
+            let mut recorder = SegmentRecorder::new();
+            engine.apply_desync(&mut recorder, buffer).await.unwrap();
+            assert_eq!(recorder.into_segments().concat(), buffer, "{:?}", disorder);
+        }
+    }
+}
+
+#[test]
+fn random_disorder_points_give_back_the_input() {
+    let mut rng = StdRng::seed_from_u64(271);
+    let hello = client_hello("example.com");
+    for _ in 0..2000 {
+        let rules: Vec<String> = (0..rng.gen_range(1..5))
+            .map(|_| rng.gen_range(-300i64..300).to_string())
+            .collect();
+        let rules: Vec<&str> = rules.iter().map(String::as_str).collect();
+        let len = rng.gen_range(0..=hello.len());
+        assert_eq!(reconstruct(&engine(&rules, &[]), &hello[..len]), &hello[..len], "{:?}", rules);
+    }
+}
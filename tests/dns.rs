@@ -0,0 +1,184 @@
+mod common;
+
+use common::{echo_server, proxy_client, socks5_connect_domain};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use stpro::{Config, DnsCacheConfig, ProxyServer, Resolve, ResolveFuture, Resolver};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Resolver that takes `delay` to answer every name with `addr`, counting
+/// the lookups it serves and the most it had in flight at once
+struct CountingResolver {
+    addr: SocketAddr,
+    delay: Duration,
+    lookups: AtomicUsize,
+    in_flight: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl CountingResolver {
+    fn new(addr: SocketAddr, delay: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            addr,
+            delay,
+            lookups: AtomicUsize::new(0),
+            in_flight: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl Resolve for CountingResolver {
+    fn resolve<'a>(&'a self, _host: &'a str, _port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok::<_, io::Error>(vec![self.addr])
+        })
+    }
+}
+
+/// Resolver failing every lookup as NXDOMAIN would, counting them
+#[derive(Default)]
+struct FailingResolver {
+    lookups: AtomicUsize,
+}
+
+impl Resolve for FailingResolver {
+    fn resolve<'a>(&'a self, _host: &'a str, _port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            Err(io::Error::new(io::ErrorKind::NotFound, "no such host"))
+        })
+    }
+}
+
+fn cache(ttl: Duration, negative_ttl: Duration) -> DnsCacheConfig {
+    DnsCacheConfig { capacity: 16, ttl, negative_ttl }
+}
+
+/// SOCKS5 CONNECT to `domain` through `server`, returning the reply code
+async fn connect(server: &Arc<ProxyServer>, domain: &str, port: u16) -> u8 {
+    let (mut client, _) = proxy_client(server);
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    client.write_all(&socks5_connect_domain(domain, port)).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    reply[1]
+}
+
+#[tokio::test]
+async fn excess_resolutions_wait_for_a_slot() {
+    let target = echo_server("127.0.0.1").await;
+    let resolver = CountingResolver::new(target, Duration::from_millis(50));
+    let config = Config { max_concurrent_resolves: Some(2), ..Config::default() };
+    let server = Arc::new(ProxyServer::with_resolver(config, resolver.clone()));
+    
+    let connects = (0..8).map(|i| {
+        let server = server.clone();
+        let host = format!("host{}.test", i);
+        tokio::spawn(async move { connect(&server, &host, target.port()).await })
+    });
+    for connect in connects.collect::<Vec<_>>() {
+        assert_eq!(connect.await.unwrap(), 0);
+    }
+    
+    assert_eq!(resolver.lookups.load(Ordering::SeqCst), 8);
+    assert_eq!(resolver.peak.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn unlimited_resolutions_run_at_once() {
+    let target = echo_server("127.0.0.1").await;
+    let resolver = CountingResolver::new(target, Duration::from_millis(50));
+    let server = Arc::new(ProxyServer::with_resolver(Config::default(), resolver.clone()));
+    
+    let connects = (0..4).map(|i| {
+        let server = server.clone();
+        let host = format!("host{}.test", i);
+        tokio::spawn(async move { connect(&server, &host, target.port()).await })
+    });
+    for connect in connects.collect::<Vec<_>>() {
+        assert_eq!(connect.await.unwrap(), 0);
+    }
+    
+    assert_eq!(resolver.peak.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn cached_answer_skips_the_second_lookup() {
+    let target = echo_server("127.0.0.1").await;
+    let backend = CountingResolver::new(target, Duration::ZERO);
+    let resolver = Resolver::new(backend.clone(), None)
+        .with_cache(cache(Duration::from_secs(60), Duration::from_secs(1)));
+    
+    assert_eq!(resolver.resolve("cached.test", 443).await.unwrap(), [target]);
+    assert_eq!(resolver.resolve("cached.test", 443).await.unwrap(), [target]);
+    assert_eq!(backend.lookups.load(Ordering::SeqCst), 1);
+    
+    // Entries are per port
+    resolver.resolve("cached.test", 80).await.unwrap();
+    assert_eq!(backend.lookups.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn failure_is_cached_for_the_negative_ttl() {
+    let backend = Arc::new(FailingResolver::default());
+    let resolver = Resolver::new(backend.clone(), None)
+        .with_cache(cache(Duration::from_secs(60), Duration::from_millis(200)));
+    
+    for _ in 0..2 {
+        let err = resolver.resolve("missing.test", 443).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+    assert_eq!(backend.lookups.load(Ordering::SeqCst), 1);
+    
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    resolver.resolve("missing.test", 443).await.unwrap_err();
+    assert_eq!(backend.lookups.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn lookup_within_the_ttl_is_not_resolved_again() {
+    let target = echo_server("127.0.0.1").await;
+    let backend = CountingResolver::new(target, Duration::ZERO);
+    let resolver = Resolver::new(backend.clone(), None)
+        .with_cache(cache(Duration::from_millis(300), Duration::ZERO));
+    
+    resolver.resolve("ttl.test", 443).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    resolver.resolve("ttl.test", 443).await.unwrap();
+    assert_eq!(backend.lookups.load(Ordering::SeqCst), 1);
+    
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    resolver.resolve("ttl.test", 443).await.unwrap();
+    assert_eq!(backend.lookups.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn concurrent_lookups_share_one_query() {
+    let target = echo_server("127.0.0.1").await;
+    let backend = CountingResolver::new(target, Duration::from_millis(50));
+    let resolver = Resolver::new(backend.clone(), None);
+    
+    let lookups = (0..5).map(|_| {
+        let resolver = resolver.clone();
+        tokio::spawn(async move { resolver.resolve("shared.test", 443).await })
+    });
+    for lookup in lookups.collect::<Vec<_>>() {
+        assert_eq!(lookup.await.unwrap().unwrap(), [target]);
+    }
+    assert_eq!(backend.lookups.load(Ordering::SeqCst), 1);
+    
+    // Without a cache the answer isn't kept once the query is done
+    resolver.resolve("shared.test", 443).await.unwrap();
+    assert_eq!(backend.lookups.load(Ordering::SeqCst), 2);
+}
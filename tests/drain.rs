@@ -0,0 +1,51 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::{dial, echo_server, serve, socks5_connect};
+use std::time::Duration;
+use stpro::Config;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn sigusr2_refuses_new_connections_while_open_ones_carry_on() {
+    let target = echo_server("127.0.0.1").await;
+    let (server, addr, running) = serve(Config::default());
+    
+    // A finished handshake means the accept loop, and so the signal
+    // handler, is up
+    let mut client = dial(addr).await;
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    client.write_all(&socks5_connect(target)).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], 0, "CONNECT failed");
+    
+    unsafe { libc::kill(libc::getpid(), libc::SIGUSR2) };
+    // The listener closes shortly after the signal lands
+    let mut refused = false;
+    for _ in 0..100 {
+        if TcpStream::connect(addr).await.is_err() {
+            refused = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(refused, "new connections still accepted");
+    assert!(server.is_draining());
+    
+    client.write_all(b"still here").await.unwrap();
+    let mut echoed = [0u8; 10];
+    client.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"still here");
+    assert!(!running.is_finished());
+    
+    drop(client);
+    let summary = running.await.unwrap().unwrap();
+    // Probes that got in before the listener closed are drained too
+    assert!(summary.drained >= 1, "{}", summary);
+    assert_eq!(summary.force_closed, 0);
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use common::{client_hello, dial, serve, socks5_connect, strategies_used};
+use std::net::SocketAddr;
+use std::time::Duration;
+use stpro::{parse_split_config, Config, DesyncConfig, ProxyServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// A target that reads `len` bytes from one connection, answers `ok` and
+/// hangs up, handing back what it read
+async fn target_reading(len: usize) -> (SocketAddr, JoinHandle<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let received = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = vec![0u8; len];
+        stream.read_exact(&mut received).await.unwrap();
+        stream.write_all(b"ok").await.unwrap();
+        received
+    });
+    (addr, received)
+}
+
+fn split_config() -> Config {
+    Config {
+        desync: DesyncConfig {
+            split: vec![parse_split_config("1+s").unwrap()],
+            ..DesyncConfig::default()
+        },
+        desync_ports: Vec::new(),
+        ..Config::default()
+    }
+}
+
+/// Wait for the server to finish its connections
+async fn settle(server: &ProxyServer) {
+    for _ in 0..100 {
+        if server.stats().active_connections() == 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("connections still open");
+}
+
+#[tokio::test]
+async fn socks5_handshake_and_hello_in_one_write() {
+    let hello = client_hello("example.com");
+    let (target, received) = target_reading(hello.len()).await;
+    let (server, addr, running) = serve(split_config());
+    
+    let mut burst = vec![5, 1, 0];
+    burst.extend_from_slice(&socks5_connect(target));
+    burst.extend_from_slice(&hello);
+    let mut client = dial(addr).await;
+    client.write_all(&burst).await.unwrap();
+    
+    let mut replies = [0u8; 2 + 10 + 2];
+    client.read_exact(&mut replies).await.unwrap();
+    assert_eq!(replies[..2], [5, 0]);
+    assert_eq!(replies[3], 0, "CONNECT failed");
+    assert_eq!(&replies[12..], b"ok");
+    assert_eq!(received.await.unwrap(), hello);
+    
+    drop(client);
+    settle(&server).await;
+    assert_eq!(strategies_used(&server), [("split".into(), 1)]);
+    server.shutdown();
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn http_connect_and_hello_in_one_write() {
+    let hello = client_hello("example.com");
+    let (target, received) = target_reading(hello.len()).await;
+    let (server, addr, running) = serve(split_config());
+    
+    let mut burst = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target).into_bytes();
+    burst.extend_from_slice(&hello);
+    let mut client = dial(addr).await;
+    client.write_all(&burst).await.unwrap();
+    
+    let mut reply = Vec::new();
+    while !reply.ends_with(b"ok") {
+        let mut byte = [0u8; 1];
+        client.read_exact(&mut byte).await.unwrap();
+        reply.push(byte[0]);
+    }
+    assert!(reply.starts_with(b"HTTP/1.1 200"), "{}", String::from_utf8_lossy(&reply));
+    assert_eq!(received.await.unwrap(), hello);
+    
+    drop(client);
+    settle(&server).await;
+    assert_eq!(strategies_used(&server), [("split".into(), 1)]);
+    server.shutdown();
+    running.await.unwrap().unwrap();
+}
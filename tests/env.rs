@@ -0,0 +1,119 @@
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+const STPRO: &str = env!("CARGO_BIN_EXE_stpro");
+
+/// stpro with none of the STPRO_* variables the test runner may have set
+fn stpro() -> Command {
+    let mut command = Command::new(STPRO);
+    for var in ["LISTEN", "SPLIT", "DISORDER", "FAKE", "TTL", "CONFIG"] {
+        command.env_remove(format!("STPRO_{}", var));
+    }
+    command
+}
+
+/// A config file splitting at 7 and listening on `listen`
+fn config_file(name: &str, listen: &str) -> PathBuf {
+    let file = format!("stpro-env-{}-{}.toml", name, std::process::id());
+    let path = std::env::temp_dir().join(file);
+    let text = format!("listen = \"{}\"\n\n[desync]\nsplit = [{{ offset = 7 }}]\n", listen);
+    std::fs::write(&path, text).unwrap();
+    path
+}
+
+/// The split offsets `explain` reports for the sample ClientHello
+fn split_offsets(command: &mut Command) -> Vec<u64> {
+    let output = command.args(["explain", "--json"]).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let plan: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    plan["steps"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|step| step["split"]["offset"].as_u64())
+        .collect()
+}
+
+/// The address a server started by `command` reports listening on
+fn listen_addr(command: &mut Command) -> String {
+    let mut child = command.stdout(Stdio::piped()).spawn().unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let line = stdout
+        .lines()
+        .map(|line| line.unwrap())
+        .find(|line| line.contains("listening on"))
+        .unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    line.rsplit(' ').next().unwrap().to_string()
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+#[test]
+fn split_precedence_is_cli_env_config() {
+    let config = config_file("split", "127.0.0.1:1080");
+    assert_eq!(split_offsets(stpro().env("STPRO_CONFIG", &config)), [7]);
+    assert_eq!(
+        split_offsets(stpro().env("STPRO_CONFIG", &config).env("STPRO_SPLIT", "3,5")),
+        [3, 5]
+    );
+    let mut command = stpro();
+    command.args(["-s", "4"]).env("STPRO_CONFIG", &config).env("STPRO_SPLIT", "3");
+    assert_eq!(split_offsets(&mut command), [4]);
+    assert_eq!(split_offsets(stpro().env("STPRO_SPLIT", "2+s")), [2]);
+    std::fs::remove_file(config).unwrap();
+}
+
+#[test]
+fn config_flag_wins_over_env() {
+    let from_env = config_file("env-config", "127.0.0.1:1080");
+    let from_flag = config_file("flag-config", "127.0.0.1:1080");
+    std::fs::write(&from_flag, "[desync]\nsplit = [{ offset = 9 }]\n").unwrap();
+    let mut command = stpro();
+    command.arg("--config").arg(&from_flag).env("STPRO_CONFIG", &from_env);
+    let offsets = split_offsets(&mut command);
+    assert_eq!(offsets, [9]);
+    std::fs::remove_file(from_env).unwrap();
+    std::fs::remove_file(from_flag).unwrap();
+}
+
+#[test]
+fn invalid_env_split_is_reported() {
+    let output = stpro().env("STPRO_SPLIT", "1+x").args(["explain"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("1+x"));
+}
+
+#[test]
+fn listen_precedence_is_cli_env_config() {
+    let (in_file, in_env, on_cli) = (free_port(), free_port(), free_port());
+    let config = config_file("listen", &format!("127.0.0.1:{}", in_file));
+    
+    let addr = listen_addr(stpro().env("STPRO_CONFIG", &config));
+    assert_eq!(addr, format!("127.0.0.1:{}", in_file));
+    let env_listen = format!("127.0.0.1:{}", in_env);
+    let mut command = stpro();
+    command.env("STPRO_CONFIG", &config).env("STPRO_LISTEN", &env_listen);
+    let addr = listen_addr(&mut command);
+    assert_eq!(addr, env_listen);
+    // --port replaces only the port of the address from the environment
+    let addr = listen_addr(
+        stpro().args(["--port", &on_cli.to_string()]).env("STPRO_LISTEN", &env_listen),
+    );
+    assert_eq!(addr, format!("127.0.0.1:{}", on_cli));
+    std::fs::remove_file(config).unwrap();
+}
+
+#[test]
+fn watch_config_accepts_a_config_from_env() {
+    let config = config_file("watch", "127.0.0.1:1080");
+    let output = stpro().args(["--watch-config", "presets"]).env("STPRO_CONFIG", &config).output();
+    assert!(output.unwrap().status.success());
+    let output = stpro().args(["--watch-config", "presets"]).output().unwrap();
+    assert!(!output.status.success());
+    std::fs::remove_file(config).unwrap();
+}
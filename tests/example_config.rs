@@ -0,0 +1,73 @@
+use serde_json::Value;
+use std::path::PathBuf;
+use stpro::Config;
+
+/// Write `text` to a scratch file with `extension` and load it back
+fn load(text: &str, extension: &str) -> Config {
+    let path: PathBuf = std::env::temp_dir()
+        .join(format!("stpro-example-{}.{}", std::process::id(), extension));
+    std::fs::write(&path, text).unwrap();
+    let config = Config::from_file(&path);
+    std::fs::remove_file(&path).unwrap();
+    config.unwrap()
+}
+
+fn to_value(config: &Config) -> Value {
+    serde_json::to_value(config).unwrap()
+}
+
+/// The example as loading it gives it back: without the settings it
+/// shows commented out
+fn enabled_example() -> Config {
+    let mut config = Config::example();
+    config.bind_addr = None;
+    config.outbound_ttl = None;
+    config.auth = None;
+    config.max_rate_bytes_per_sec = None;
+    config.access_log.as_mut().unwrap().path = None;
+    config.metrics_listen = None;
+    config.desync.seed = None;
+    config.canary.as_mut().unwrap().seed = None;
+    config.upstream = None;
+    config
+}
+
+#[test]
+fn toml_example_loads_back() {
+    let loaded = load(&Config::example_toml(), "toml");
+    assert_eq!(to_value(&loaded), to_value(&enabled_example()));
+}
+
+#[test]
+fn json_example_loads_back() {
+    let loaded = load(&Config::example_json(), "json");
+    assert_eq!(to_value(&loaded), to_value(&enabled_example()));
+}
+
+#[test]
+fn examples_are_valid() {
+    assert_eq!(Config::example().validate(), []);
+    assert_eq!(load(&Config::example_toml(), "toml").validate(), []);
+}
+
+#[test]
+fn example_sets_every_setting() {
+    let Value::Object(example) = to_value(&Config::example()) else { panic!() };
+    let unset: Vec<&String> = example
+        .iter()
+        .filter(|(_, value)| value.is_null())
+        .map(|(key, _)| key)
+        .collect();
+    assert!(unset.is_empty(), "unset in the example: {:?}", unset);
+}
+
+#[test]
+fn toml_example_explains_and_disables() {
+    let toml = Config::example_toml();
+    assert!(toml.starts_with("# stpro example configuration"));
+    assert!(toml.contains("# Where clients connect"));
+    assert!(toml.contains("\nlisten = \"127.0.0.1:1080\"\n"));
+    assert!(toml.contains("\n# upstream = \"socks5://127.0.0.1:9050\"\n"));
+    assert!(toml.contains("\n# [auth]\n"));
+    assert!(toml.contains("\n[[desync.split]]\n"));
+}
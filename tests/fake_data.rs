@@ -0,0 +1,89 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use stpro::{parse_split_config, FakeConfig};
+
+const STPRO: &str = env!("CARGO_BIN_EXE_stpro");
+
+/// Plan the sample ClientHello with `args` and a fake at offset 3
+fn explain(args: &[&str]) -> Output {
+    let mut command = Command::new(STPRO);
+    for var in ["LISTEN", "SPLIT", "DISORDER", "FAKE", "TTL", "CONFIG"] {
+        command.env_remove(format!("STPRO_{}", var));
+    }
+    command.args(["--fake", "3"]).args(args).args(["explain", "--json"]).output().unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn scratch(name: &str, data: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("stpro-{}-{}.bin", std::process::id(), name));
+    std::fs::write(&path, data).unwrap();
+    path
+}
+
+fn fake(offset: &str, data: &[u8]) -> FakeConfig {
+    FakeConfig {
+        split: parse_split_config(offset).unwrap(),
+        ttl: None,
+        data: Some(data.to_vec()),
+    }
+}
+
+#[test]
+fn hex_with_separators_is_accepted() {
+    let output = explain(&["--fake-hex", "16:03 01"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!stderr(&output).contains("Fake packet"), "{}", stderr(&output));
+}
+
+#[test]
+fn longer_data_than_the_fake_warns() {
+    let output = explain(&["--fake-hex", "1603010a0b"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("data is 5 bytes but only the 3 before the split point are sent"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn malformed_hex_is_an_error() {
+    for (hex, expected) in [("abc", "odd number of digits"), ("zz", "Invalid hex data: \"zz\"")] {
+        let output = explain(&["--fake-hex", hex]);
+        assert!(!output.status.success());
+        assert!(stderr(&output).contains(expected), "{}", stderr(&output));
+    }
+}
+
+#[test]
+fn data_file_is_read() {
+    let path = scratch("fake-short", b"ab");
+    let output = explain(&["--fake-data", path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    
+    let output = explain(&["--fake-data", "/nonexistent/stpro-fake.bin"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Failed to read /nonexistent/stpro-fake.bin"));
+}
+
+#[test]
+fn empty_data_file_is_an_error() {
+    let path = scratch("fake-empty", b"");
+    let output = explain(&["--fake-data", path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Fake data is empty"), "{}", stderr(&output));
+}
+
+#[test]
+fn truncation_depends_on_a_fixed_split_point() {
+    assert!(fake("3", b"abcd").truncation_warning().is_some());
+    assert_eq!(fake("4", b"abcd").truncation_warning(), None);
+    // Anchored split points aren't known before the data arrives
+    assert_eq!(fake("3+s", b"abcd").truncation_warning(), None);
+    assert_eq!(fake("-3", b"abcd").truncation_warning(), None);
+}
@@ -0,0 +1,42 @@
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use common::echo_server;
+use socket2::SockRef;
+use std::net::Shutdown;
+use stpro::{SocketControl, TtlControl};
+use tokio::net::TcpStream;
+
+/// A connection to an echo server sending with TTL 77
+async fn connection() -> TcpStream {
+    let stream = TcpStream::connect(echo_server("127.0.0.1").await).await.unwrap();
+    stream.set_ttl(77).unwrap();
+    stream
+}
+
+#[tokio::test]
+async fn ttl_is_restored_after_a_fake() {
+    let stream = connection().await;
+    let control = TtlControl::new(&stream).unwrap();
+    control.send_fake(b"fake", b"real", 3).await.unwrap();
+    assert_eq!(control.ttl().unwrap(), 77);
+}
+
+#[tokio::test]
+async fn ttl_is_restored_when_the_fake_write_fails() {
+    let stream = connection().await;
+    SockRef::from(&stream).shutdown(Shutdown::Write).unwrap();
+    let control = TtlControl::new(&stream).unwrap();
+    control.send_fake(b"fake", b"real", 3).await.unwrap_err();
+    assert_eq!(control.ttl().unwrap(), 77);
+}
+
+#[tokio::test]
+async fn mismatched_lengths_are_refused_untouched() {
+    let stream = connection().await;
+    let control = TtlControl::new(&stream).unwrap();
+    let e = control.send_fake(b"fake", b"longer", 3).await.unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(control.ttl().unwrap(), 77);
+}
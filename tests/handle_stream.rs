@@ -0,0 +1,66 @@
+mod common;
+
+use common::{echo_server, socks5_connect_domain};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use stpro::{Config, ConnectionOutcome, ProxyServer, Resolve, ResolveFuture};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Resolver that knows a single name, recording every name it is asked for
+struct MockResolver {
+    host: &'static str,
+    addr: SocketAddr,
+    asked: Mutex<Vec<String>>,
+}
+
+impl Resolve for MockResolver {
+    fn resolve<'a>(&'a self, host: &'a str, _port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            self.asked.lock().unwrap().push(host.to_string());
+            if host == self.host {
+                Ok(vec![self.addr])
+            } else {
+                Err(io::Error::new(io::ErrorKind::NotFound, "unknown host"))
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn socks5_session_runs_over_a_duplex_stream() {
+    let target = echo_server("127.0.0.1").await;
+    let resolver = Arc::new(MockResolver {
+        host: "service.test",
+        addr: target,
+        asked: Mutex::default(),
+    });
+    let server = Arc::new(ProxyServer::with_resolver(Config::default(), resolver.clone()));
+    
+    let (mut client, stream) = tokio::io::duplex(64 * 1024);
+    let client_addr = "192.0.2.7:50000".parse().unwrap();
+    let handler = tokio::spawn({
+        let server = server.clone();
+        async move { server.handle_stream(stream, client_addr).await }
+    });
+    
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    assert_eq!(choice, [5, 0]);
+    client.write_all(&socks5_connect_domain("service.test", target.port())).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[..2], [5, 0]);
+    
+    client.write_all(b"ping over the pipe").await.unwrap();
+    let mut echoed = [0u8; 18];
+    client.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"ping over the pipe");
+    
+    drop(client);
+    assert_eq!(handler.await.unwrap().unwrap(), ConnectionOutcome::Completed);
+    assert_eq!(*resolver.asked.lock().unwrap(), ["service.test"]);
+    assert_eq!(server.stats().total_connections(), 1);
+    assert_eq!(server.stats().active_connections(), 0);
+}
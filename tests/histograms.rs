@@ -0,0 +1,56 @@
+use std::time::Duration;
+use stpro::{ConnectionStats, ServerStats};
+
+/// Record a finished connection with the given timings, in milliseconds
+fn close(stats: &ServerStats, duration: u64, connect_time: Option<u64>, ttfb: Option<u64>) {
+    let mut connection = ConnectionStats::new("192.0.2.7:50000".parse().unwrap());
+    connection.duration = Duration::from_millis(duration);
+    connection.connect_time = connect_time.map(Duration::from_millis);
+    connection.ttfb = ttfb.map(Duration::from_millis);
+    stats.connection_opened();
+    stats.connection_closed(&connection);
+}
+
+fn assert_lines(metrics: &str, expected: &[&str]) {
+    for line in expected {
+        assert!(metrics.lines().any(|l| l == *line), "missing {:?} in\n{}", line, metrics);
+    }
+}
+
+#[test]
+fn buckets_count_connections_cumulatively() {
+    let stats = ServerStats::default();
+    close(&stats, 50, Some(3), Some(30));
+    close(&stats, 700, Some(40), Some(200));
+    // Never connected, so it has neither a connect time nor a TTFB
+    close(&stats, 20_000, None, None);
+    let metrics = stats.render_prometheus();
+    
+    assert_lines(&metrics, &[
+        "# TYPE stpro_connection_duration_seconds histogram",
+        "stpro_connection_duration_seconds_bucket{le=\"0.1\"} 1",
+        "stpro_connection_duration_seconds_bucket{le=\"0.5\"} 1",
+        "stpro_connection_duration_seconds_bucket{le=\"1\"} 2",
+        "stpro_connection_duration_seconds_bucket{le=\"10\"} 2",
+        "stpro_connection_duration_seconds_bucket{le=\"30\"} 3",
+        "stpro_connection_duration_seconds_bucket{le=\"+Inf\"} 3",
+        "stpro_connection_duration_seconds_sum 20.75",
+        "stpro_connection_duration_seconds_count 3",
+    ]);
+    assert_lines(&metrics, &[
+        "stpro_connect_duration_seconds_bucket{le=\"0.005\"} 1",
+        "stpro_connect_duration_seconds_bucket{le=\"0.025\"} 1",
+        "stpro_connect_duration_seconds_bucket{le=\"0.05\"} 2",
+        "stpro_connect_duration_seconds_bucket{le=\"+Inf\"} 2",
+        "stpro_connect_duration_seconds_count 2",
+    ]);
+    assert_lines(&metrics, &[
+        "stpro_ttfb_seconds_bucket{le=\"0.025\"} 0",
+        "stpro_ttfb_seconds_bucket{le=\"0.05\"} 1",
+        "stpro_ttfb_seconds_bucket{le=\"0.1\"} 1",
+        "stpro_ttfb_seconds_bucket{le=\"0.25\"} 2",
+        "stpro_ttfb_seconds_bucket{le=\"+Inf\"} 2",
+        "stpro_ttfb_seconds_sum 0.23",
+        "stpro_ttfb_seconds_count 2",
+    ]);
+}
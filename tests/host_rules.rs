@@ -0,0 +1,90 @@
+use std::net::SocketAddr;
+use stpro::{DesyncConfig, HostPattern, HostRule};
+
+fn rule(pattern: HostPattern) -> HostRule {
+    HostRule { pattern, desync: DesyncConfig::default() }
+}
+
+fn exact(name: &str) -> HostRule {
+    rule(HostPattern::Exact(name.to_string()))
+}
+
+fn suffix(name: &str) -> HostRule {
+    rule(HostPattern::Suffix(name.to_string()))
+}
+
+fn cidr(net: &str) -> HostRule {
+    rule(HostPattern::Cidr(net.parse().unwrap()))
+}
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+const ANY: &str = "192.0.2.1:443";
+
+#[test]
+fn exact_matches_the_name_only() {
+    let cases = [
+        ("example.com", true),
+        ("EXAMPLE.com", true),
+        ("example.com.", true),
+        ("www.example.com", false),
+        ("example.co", false),
+        ("notexample.com", false),
+        ("", false),
+    ];
+    for (host, expected) in cases {
+        assert_eq!(exact("example.com").matches(host, &addr(ANY)), expected, "{}", host);
+    }
+    assert!(exact("Example.COM.").matches("example.com", &addr(ANY)));
+}
+
+#[test]
+fn suffix_matches_the_domain_and_subdomains() {
+    let cases = [
+        ("example.com", true),
+        ("www.example.com", true),
+        ("a.b.EXAMPLE.COM.", true),
+        ("notexample.com", false),
+        ("example.com.evil", false),
+        ("com", false),
+        ("", false),
+        ("é.example.com", true),
+        ("éxample.com", false),
+    ];
+    for (host, expected) in cases {
+        assert_eq!(suffix("example.com").matches(host, &addr(ANY)), expected, "{}", host);
+    }
+    // Leading and trailing dots in the pattern are ignored
+    assert!(suffix(".example.com.").matches("www.example.com", &addr(ANY)));
+}
+
+#[test]
+fn suffix_on_a_multibyte_boundary_does_not_panic() {
+    // The suffix's length falls inside the two-byte 'é'
+    assert!(!suffix("b.c").matches("aé.c", &addr(ANY)));
+    assert!(!exact("b.c").matches("aé.c", &addr(ANY)));
+}
+
+#[test]
+fn cidr_matches_the_target_address() {
+    let cases = [
+        ("10.0.0.0/8", "10.1.2.3:443", true),
+        ("10.0.0.0/8", "11.0.0.1:443", false),
+        ("192.0.2.7/32", "192.0.2.7:80", true),
+        ("2001:db8::/32", "[2001:db8::1]:443", true),
+        ("2001:db8::/32", "[2001:db9::1]:443", false),
+        // IPv4-mapped IPv6 addresses count as IPv4
+        ("10.0.0.0/8", "[::ffff:10.0.0.1]:443", true),
+    ];
+    for (net, target, expected) in cases {
+        assert_eq!(cidr(net).matches("example.com", &addr(target)), expected, "{} {}", net, target);
+    }
+}
+
+#[test]
+fn names_are_ignored_by_cidr_and_addresses_by_names() {
+    assert!(cidr("0.0.0.0/0").matches("", &addr(ANY)));
+    assert!(exact("example.com").matches("example.com", &addr("[::1]:1")));
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use common::{echo_server, proxy_client, socks5_tunnel};
+use std::sync::Arc;
+use stpro::{
+    http2_goaway, is_http, is_http2_preface, Config, ConnectionOutcome, DesyncConfig,
+    DesyncEngine, ProxyServer, SplitConfig, SplitFlags, WriteOp, HTTP2_PREFACE,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const PREFACE: &[u8] = b"\x50\x52\x49\x20\x2a\x20\x48\x54\x54\x50\x2f\x32\x2e\x30\x0d\x0a\
+                         \x0d\x0a\x53\x4d\x0d\x0a\x0d\x0a";
+
+/// Empty SETTINGS frame a client sends right after the preface
+const CLIENT_SETTINGS: &[u8] = &[0, 0, 0, 0x04, 0, 0, 0, 0, 0];
+
+fn split_at(offset: i64) -> DesyncConfig {
+    DesyncConfig {
+        split: vec![SplitConfig {
+            offset,
+            flags: SplitFlags::default(),
+            repeats: None,
+            skip: None,
+            jitter: None,
+        }],
+        ..DesyncConfig::default()
+    }
+}
+
+#[test]
+fn preface_is_detected() {
+    assert_eq!(HTTP2_PREFACE, PREFACE);
+    assert!(is_http2_preface(PREFACE));
+    assert!(is_http2_preface(&[PREFACE, CLIENT_SETTINGS].concat()));
+    assert!(!is_http(PREFACE));
+}
+
+#[test]
+fn near_misses_are_not_prefaces() {
+    assert!(!is_http2_preface(&PREFACE[..23]));
+    assert!(!is_http2_preface(b"PRI * HTTP/2.0\r\n\r\n"));
+    assert!(!is_http2_preface(b"PRI * HTTP/1.1\r\n\r\nSM\r\n\r\n"));
+    assert!(!is_http2_preface(b"POST / HTTP/1.1\r\nHost: a\r\n\r\n"));
+    assert!(!is_http2_preface(b""));
+}
+
+#[test]
+fn preface_is_never_desynced() {
+    let engine = DesyncEngine::new(split_at(3));
+    let flight = [PREFACE, CLIENT_SETTINGS].concat();
+    assert_eq!(engine.plan_writes(&flight), [WriteOp::Segment(0..flight.len())]);
+}
+
+#[tokio::test]
+async fn preface_to_the_proxy_gets_goaway() {
+    let server = Arc::new(ProxyServer::new(Config::default()));
+    let (mut client, handler) = proxy_client(&server);
+    
+    client.write_all(&[PREFACE, CLIENT_SETTINGS].concat()).await.unwrap();
+    let mut reply = vec![0u8; http2_goaway(0).len()];
+    client.read_exact(&mut reply).await.unwrap();
+    
+    // Empty SETTINGS, then GOAWAY with HTTP_1_1_REQUIRED
+    assert_eq!(reply[..9], [0, 0, 0, 0x04, 0, 0, 0, 0, 0]);
+    assert_eq!(reply[9..18], [0, 0, 8, 0x07, 0, 0, 0, 0, 0]);
+    assert_eq!(reply[22..], [0, 0, 0, 0x0d]);
+    assert_eq!(handler.await.unwrap().unwrap(), ConnectionOutcome::Refused);
+}
+
+#[tokio::test]
+async fn truncated_preface_is_a_bad_request() {
+    let server = Arc::new(ProxyServer::new(Config::default()));
+    let (mut client, handler) = proxy_client(&server);
+    
+    client.write_all(b"PRI * HTTP/2.0\r\n\r\nXX\r\n\r\n").await.unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    assert!(reply.starts_with(b"HTTP/1.1 400 "));
+    assert!(handler.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn tunneled_preface_arrives_intact() {
+    let target = echo_server("127.0.0.1").await;
+    let server = Arc::new(ProxyServer::new(Config { desync: split_at(3), ..Config::default() }));
+    let mut client = socks5_tunnel(&server, target).await;
+    
+    let flight = [PREFACE, CLIENT_SETTINGS].concat();
+    client.write_all(&flight).await.unwrap();
+    let mut echoed = vec![0u8; flight.len()];
+    client.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(echoed, flight);
+}
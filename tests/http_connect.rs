@@ -0,0 +1,54 @@
+use stpro::parse_http_connect;
+
+fn target(authority: &str) -> Option<(String, u16)> {
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", authority, authority);
+    parse_http_connect(request.as_bytes())
+}
+
+fn parsed(host: &str, port: u16) -> Option<(String, u16)> {
+    Some((host.to_string(), port))
+}
+
+#[test]
+fn ipv4_target() {
+    assert_eq!(target("192.0.2.7:8443"), parsed("192.0.2.7", 8443));
+    assert_eq!(target("192.0.2.7"), parsed("192.0.2.7", 443));
+}
+
+#[test]
+fn ipv6_literal_target() {
+    assert_eq!(target("[2001:db8::1]:8443"), parsed("2001:db8::1", 8443));
+    assert_eq!(target("[::1]"), parsed("::1", 443));
+    // Unbracketed, every colon belongs to the address
+    assert_eq!(target("2001:db8::1"), parsed("2001:db8::1", 443));
+}
+
+#[test]
+fn hostname_target() {
+    assert_eq!(target("example.com:443"), parsed("example.com", 443));
+    assert_eq!(target("example.com:80"), parsed("example.com", 80));
+}
+
+#[test]
+fn port_defaults_to_443() {
+    assert_eq!(target("example.com"), parsed("example.com", 443));
+}
+
+#[test]
+fn malformed_targets_are_rejected() {
+    for authority in [
+        "example.com:",
+        "example.com:https",
+        "example.com:70000",
+        ":443",
+        "[example.com]:443",
+        "[2001:db8::1",
+        "[2001:db8::1]443",
+    ] {
+        assert_eq!(target(authority), None, "{}", authority);
+    }
+    assert_eq!(parse_http_connect(b"GET http://example.com/ HTTP/1.1\r\n\r\n"), None);
+    assert_eq!(parse_http_connect(b"CONNECT\r\n\r\n"), None);
+    assert_eq!(parse_http_connect(b""), None);
+    assert_eq!(parse_http_connect(b"CONNECT \xff:443 HTTP/1.1\r\n\r\n"), None);
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use common::{proxy_client, StubResolver};
+use std::collections::HashMap;
+use std::sync::Arc;
+use stpro::{Config, ProxyServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// A server whose resolver knows no names
+fn server() -> Arc<ProxyServer> {
+    let resolver = StubResolver(HashMap::new());
+    Arc::new(ProxyServer::with_resolver(Config::default(), Arc::new(resolver)))
+}
+
+/// Send `request` and return the status line of the reply, checking the
+/// request failed
+async fn status(server: &Arc<ProxyServer>, request: &[u8]) -> String {
+    let (mut client, handler) = proxy_client(server);
+    client.write_all(request).await.unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    assert!(handler.await.unwrap().is_err());
+    let reply = String::from_utf8_lossy(&reply);
+    reply.lines().next().unwrap_or_default().to_string()
+}
+
+#[tokio::test]
+async fn failed_resolve_of_a_connect_target_is_502() {
+    let request = b"CONNECT missing.test:443 HTTP/1.1\r\nHost: missing.test:443\r\n\r\n";
+    assert_eq!(status(&server(), request).await, "HTTP/1.1 502 Bad Gateway");
+}
+
+#[tokio::test]
+async fn failed_resolve_of_a_forwarded_request_is_502() {
+    let request = b"GET http://missing.test/ HTTP/1.1\r\nHost: missing.test\r\n\r\n";
+    assert_eq!(status(&server(), request).await, "HTTP/1.1 502 Bad Gateway");
+}
+
+#[tokio::test]
+async fn refused_connect_is_502() {
+    // Bind and drop a listener to get a port nothing listens on
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", closed, closed);
+    assert_eq!(status(&server(), request.as_bytes()).await, "HTTP/1.1 502 Bad Gateway");
+}
+
+#[tokio::test]
+async fn unknown_method_is_dropped_without_reply() {
+    assert_eq!(status(&server(), b"GOT http://a.test/ HTTP/1.1\r\n\r\n").await, "");
+}
+
+#[tokio::test]
+async fn method_trickled_in_is_still_recognized() {
+    let (mut client, handler) = proxy_client(&server());
+    for byte in b"CONN" {
+        client.write_all(&[*byte]).await.unwrap();
+        tokio::task::yield_now().await;
+    }
+    client.write_all(b"ECT missing.test:443 HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    assert!(reply.starts_with(b"HTTP/1.1 502 Bad Gateway\r\n"));
+    assert!(handler.await.unwrap().is_err());
+}
@@ -0,0 +1,61 @@
+use stpro::find_http_host_offset;
+
+/// The Host header value `find_http_host_offset` points at, up to the end
+/// of its line
+fn host_value(request: &[u8]) -> Option<&[u8]> {
+    let start = find_http_host_offset(request)?;
+    let rest = &request[start..];
+    let end = rest.windows(2).position(|w| w == b"\r\n").unwrap_or(rest.len());
+    Some(&rest[..end])
+}
+
+#[test]
+fn finds_the_host_header() {
+    let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    assert_eq!(find_http_host_offset(request), Some(22));
+    assert_eq!(host_value(request), Some(&b"example.com"[..]));
+}
+
+#[test]
+fn header_name_is_case_insensitive() {
+    assert_eq!(host_value(b"GET / HTTP/1.1\r\nhost: a.test\r\n\r\n"), Some(&b"a.test"[..]));
+    assert_eq!(host_value(b"GET / HTTP/1.1\r\nHOST:b.test\r\n\r\n"), Some(&b"b.test"[..]));
+    assert_eq!(host_value(b"GET / HTTP/1.1\r\nHost: \t c.test\r\n\r\n"), Some(&b"c.test"[..]));
+}
+
+#[test]
+fn body_containing_host_is_ignored() {
+    let request = b"POST /form HTTP/1.1\r\nContent-Length: 24\r\n\r\nHost: evil.test\r\n\r\nxxxxx";
+    assert_eq!(find_http_host_offset(request), None);
+    
+    let request = b"POST /form HTTP/1.1\r\nContent-Type: text/plain\r\nHost: real.test\r\n\r\n\
+                    Host: evil.test\r\n";
+    assert_eq!(host_value(request), Some(&b"real.test"[..]));
+}
+
+#[test]
+fn host_inside_another_header_is_ignored() {
+    let request = b"GET / HTTP/1.1\r\nX-Note: Host: evil.test\r\nX-Forwarded-Host: fwd.test\r\n\
+                    Host: real.test\r\n\r\n";
+    assert_eq!(host_value(request), Some(&b"real.test"[..]));
+}
+
+#[test]
+fn request_line_is_not_a_header() {
+    assert_eq!(find_http_host_offset(b"GET /Host: HTTP/1.1\r\n\r\n"), None);
+    assert_eq!(find_http_host_offset(b"Host: a.test\r\n\r\n"), None);
+}
+
+#[test]
+fn cut_off_head_is_searched_as_far_as_it_goes() {
+    assert_eq!(host_value(b"GET / HTTP/1.1\r\nAccept: */*\r\nHost: exa"), Some(&b"exa"[..]));
+    assert_eq!(find_http_host_offset(b"GET / HTTP/1.1\r\nAccept: */*\r\nHo"), None);
+    assert_eq!(find_http_host_offset(b"GET / HTTP/1.1"), None);
+}
+
+#[test]
+fn binary_body_does_not_hide_the_header() {
+    let mut request = b"POST / HTTP/1.1\r\nHost: bin.test\r\n\r\n".to_vec();
+    request.extend_from_slice(&[0xff, 0xfe, 0x00]);
+    assert_eq!(host_value(&request), Some(&b"bin.test"[..]));
+}
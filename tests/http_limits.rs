@@ -0,0 +1,58 @@
+mod common;
+
+use common::{echo_server, proxy_client};
+use std::sync::Arc;
+use stpro::{Config, ProxyServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn limited(request_line: usize, header_bytes: usize) -> Arc<ProxyServer> {
+    Arc::new(ProxyServer::new(Config {
+        http_max_request_line: request_line,
+        http_max_header_bytes: header_bytes,
+        ..Config::default()
+    }))
+}
+
+/// Send `request` and return the status line of the reply, checking the
+/// connection is closed after it
+async fn status(server: &Arc<ProxyServer>, request: &[u8]) -> String {
+    let (mut client, handler) = proxy_client(server);
+    // The proxy may stop reading part way through
+    client.write_all(request).await.ok();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    assert!(handler.await.unwrap().is_err());
+    let reply = String::from_utf8_lossy(&reply);
+    reply.lines().next().unwrap_or_default().to_string()
+}
+
+#[tokio::test]
+async fn oversized_request_line_is_414() {
+    let server = limited(64, 1024);
+    let request = format!("CONNECT {}.test:443 HTTP/1.1\r\n\r\n", "a".repeat(100));
+    assert_eq!(status(&server, request.as_bytes()).await, "HTTP/1.1 414 URI Too Long");
+}
+
+#[tokio::test]
+async fn oversized_header_block_is_431() {
+    let server = limited(64, 1024);
+    let headers: String =
+        (0..40).map(|i| format!("X-Filler-{}: {}\r\n", i, "b".repeat(20))).collect();
+    let request = format!("CONNECT a.test:443 HTTP/1.1\r\n{}\r\n", headers);
+    assert_eq!(
+        status(&server, request.as_bytes()).await,
+        "HTTP/1.1 431 Request Header Fields Too Large"
+    );
+}
+
+#[tokio::test]
+async fn requests_within_the_limits_pass() {
+    let server = limited(64, 1024);
+    let (mut client, _) = proxy_client(&server);
+    let target = echo_server("127.0.0.1").await;
+    let request = format!("CONNECT {} HTTP/1.1\r\nX-Filler: {}\r\n\r\n", target, "b".repeat(900));
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut reply = [0u8; 12];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"HTTP/1.1 200");
+}
@@ -0,0 +1,87 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use stpro::{Config, IpMode, ProxyServer};
+use tokio::net::TcpStream;
+
+/// A port free on both families, found by binding a dual-stack socket
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("[::]:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Which of 127.0.0.1 and ::1 reach a server listening on `ip` in `mode`
+async fn reachable(ip: &str, mode: IpMode) -> (bool, bool) {
+    let port = free_port();
+    let listen = format!("{}:{}", ip, port).parse().unwrap();
+    let server = Arc::new(ProxyServer::new(Config { listen, ip_mode: mode, ..Config::default() }));
+    let running = tokio::spawn({
+        let server = server.clone();
+        async move { server.run().await }
+    });
+    
+    let v4 = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, port));
+    let probe = || async {
+        (TcpStream::connect(v4).await.is_ok(), TcpStream::connect(v6).await.is_ok())
+    };
+    // Wait for the listener, then probe again in case it came up between
+    // the two connects
+    for _ in 0..100 {
+        if probe().await != (false, false) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let result = probe().await;
+    
+    server.shutdown();
+    running.await.unwrap().unwrap();
+    result
+}
+
+#[tokio::test]
+async fn dual_stack_accepts_both_families() {
+    assert_eq!(reachable("[::]", IpMode::DualStack).await, (true, true));
+}
+
+#[tokio::test]
+async fn v6_only_refuses_ipv4() {
+    assert_eq!(reachable("[::]", IpMode::V6Only).await, (false, true));
+}
+
+#[tokio::test]
+async fn v4_only_accepts_ipv4() {
+    assert_eq!(reachable("0.0.0.0", IpMode::V4Only).await, (true, false));
+    assert_eq!(reachable("127.0.0.1", IpMode::DualStack).await, (true, false));
+}
+
+#[test]
+fn mismatched_address_is_an_error() {
+    let check = |listen: &str, ip_mode| {
+        let config = Config { listen: listen.parse().unwrap(), ip_mode, ..Config::default() };
+        config.validate().into_iter().filter(|d| d.is_error()).map(|d| d.message).collect()
+    };
+    let errors: Vec<String> = check("[::]:1080", IpMode::V4Only);
+    assert_eq!(errors, ["ip_mode: v4_only needs an IPv4 listen address, not [::]:1080"]);
+    let errors: Vec<String> = check("127.0.0.1:1080", IpMode::V6Only);
+    assert_eq!(errors, ["ip_mode: v6_only needs an IPv6 listen address, not 127.0.0.1:1080"]);
+    assert!(check("[::1]:1080", IpMode::V6Only).is_empty());
+}
+
+#[tokio::test]
+async fn mismatched_address_fails_to_bind() {
+    let server = ProxyServer::new(Config {
+        listen: "[::1]:0".parse().unwrap(),
+        ip_mode: IpMode::V4Only,
+        ..Config::default()
+    });
+    let error = format!("{:#}", server.run().await.unwrap_err());
+    assert!(error.contains("v4_only needs an IPv4 listen address"), "{}", error);
+}
+
+#[test]
+fn mode_is_read_from_config_files() {
+    let config: Config = serde_json::from_str(r#"{"ip_mode": "v6_only"}"#).unwrap();
+    assert_eq!(config.ip_mode, IpMode::V6Only);
+    assert_eq!(Config::default().ip_mode, IpMode::DualStack);
+}
@@ -0,0 +1,37 @@
+use stpro::{
+    ja3_hash, ja3_string, parse_client_hello, parse_split_config, DesyncConfig, DesyncEngine,
+    SegmentRecorder,
+};
+
+const HELLO: &[u8] = include_bytes!("data/client_hello.bin");
+
+const HELLO_JA3: &str = "771,\
+    4866-4867-4865-49196-49200-159-52393-52392-52394-49195-49199-158-49188-49192-107-49187-\
+    49191-103-49162-49172-57-49161-49171-51-157-156-61-60-53-47-255,\
+    0-11-10-16-22-23-49-13-43-45-51-21,\
+    29-23-30-25-24-256-257-258-259-260,\
+    0-1-2";
+
+#[test]
+fn captured_hello_has_a_known_ja3() {
+    let hello = parse_client_hello(HELLO).unwrap();
+    assert_eq!(ja3_string(&hello), HELLO_JA3);
+    assert_eq!(ja3_hash(&hello), "0149f47eabf9a20d0893e2a44e5a6323");
+}
+
+#[tokio::test]
+async fn tcp_split_leaves_the_ja3_unchanged() {
+    for rule in ["1", "0+s", "-5+e", "2:3:10"] {
+        let engine = DesyncEngine::new(DesyncConfig {
+            split: vec![parse_split_config(rule).unwrap()],
+            ..DesyncConfig::default()
+        });
+        let mut recorder = SegmentRecorder::new();
+        engine.apply_desync(&mut recorder, HELLO).await.unwrap();
+        let segments = recorder.into_segments();
+        assert!(segments.len() > 1, "{} did not split", rule);
+        
+        let received = parse_client_hello(&segments.concat()).unwrap();
+        assert_eq!(ja3_string(&received), HELLO_JA3, "{}", rule);
+    }
+}
@@ -0,0 +1,161 @@
+mod common;
+
+use common::{dial, serve};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use stpro::{serve_metrics, Config, ConnectionStats, ServerStats};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Send `request` to a metrics endpoint serving `stats` and return the
+/// response
+async fn scrape(stats: Arc<ServerStats>, request: &str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve_metrics(listener, stats, Arc::default()));
+    send(addr, request).await
+}
+
+async fn send(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Check `body` against the Prometheus text exposition format, returning
+/// the type of each metric family
+fn check_exposition(body: &str) -> HashMap<String, String> {
+    assert!(body.ends_with('\n'), "body must end with a newline");
+    let mut types: HashMap<String, String> = HashMap::new();
+    let mut helped = Vec::new();
+    let mut current: Option<String> = None;
+    
+    for line in body.lines() {
+        if let Some(rest) = line.strip_prefix("# HELP ") {
+            let (name, help) = rest.split_once(' ').expect("HELP without text");
+            assert!(valid_name(name) && !help.is_empty(), "{}", line);
+            assert!(!helped.contains(&name.to_string()), "second HELP for {}", name);
+            helped.push(name.to_string());
+        } else if let Some(rest) = line.strip_prefix("# TYPE ") {
+            let (name, kind) = rest.split_once(' ').expect("TYPE without a type");
+            assert!(["counter", "gauge", "histogram"].contains(&kind), "{}", line);
+            assert!(helped.last().is_some_and(|h| h == name), "TYPE before HELP: {}", line);
+            assert!(types.insert(name.to_string(), kind.to_string()).is_none(), "{}", line);
+            current = Some(name.to_string());
+        } else {
+            let (series, value) = line.rsplit_once(' ').expect("sample without a value");
+            assert!(value.parse::<f64>().is_ok(), "bad value: {}", line);
+            let name = match series.split_once('{') {
+                Some((name, labels)) => {
+                    let labels = labels.strip_suffix('}').expect("unclosed labels");
+                    for label in labels.split(',') {
+                        let (key, value) = label.split_once('=').expect("label without value");
+                        assert!(valid_name(key), "{}", line);
+                        assert!(value.len() >= 2 && value.starts_with('"') && value.ends_with('"'));
+                    }
+                    name
+                }
+                None => series,
+            };
+            assert!(valid_name(name), "{}", line);
+            
+            // Samples follow the TYPE of their own family
+            let family = current.as_deref().expect("sample before any TYPE");
+            let suffixes: &[&str] = match types[family].as_str() {
+                "histogram" => &["_bucket", "_sum", "_count"],
+                _ => &[""],
+            };
+            assert!(
+                suffixes.iter().any(|suffix| name == format!("{}{}", family, suffix)),
+                "{} outside its family {}",
+                name,
+                family
+            );
+        }
+    }
+    types
+}
+
+#[tokio::test]
+async fn scrape_follows_the_exposition_format() {
+    let stats = Arc::new(ServerStats::default());
+    let mut connection = ConnectionStats::new("192.0.2.7:50000".parse().unwrap());
+    connection.duration = Duration::from_millis(120);
+    connection.connect_time = Some(Duration::from_millis(4));
+    stats.connection_opened();
+    stats.connection_closed(&connection);
+    stats.add_bytes(100, 2000);
+    stats.strategy_blocked("split", 0);
+    
+    let response = scrape(stats, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+    assert!(head.contains("Content-Type: text/plain; version=0.0.4"), "{}", head);
+    assert!(head.contains(&format!("Content-Length: {}", body.len())), "{}", head);
+    
+    let types = check_exposition(body);
+    assert_eq!(types["stpro_connections_total"], "counter");
+    assert_eq!(types["stpro_active_connections"], "gauge");
+    assert_eq!(types["stpro_connection_duration_seconds"], "histogram");
+    assert!(body.contains("\nstpro_bytes_down_total 2000\n"), "{}", body);
+    assert!(body.contains("\nstpro_strategy_failures_total{strategy=\"split#0\"} 1\n"), "{}", body);
+}
+
+#[tokio::test]
+async fn empty_stats_are_still_well_formed() {
+    let response = scrape(Arc::default(), "GET /metrics HTTP/1.0\r\n\r\n").await;
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    check_exposition(body);
+}
+
+#[tokio::test]
+async fn other_paths_are_not_found() {
+    let response = scrape(Arc::default(), "GET / HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+}
+
+/// Status line of `GET /readyz` on the metrics endpoint at `addr`
+async fn readiness(addr: SocketAddr) -> String {
+    let response = send(addr, "GET /readyz HTTP/1.1\r\n\r\n").await;
+    response.lines().next().unwrap_or_default().to_string()
+}
+
+#[tokio::test]
+async fn readiness_fails_once_draining() {
+    let metrics = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (server, addr, running) = serve(Config {
+        metrics_listen: Some(metrics),
+        ..Config::default()
+    });
+    // Held open so the drain has a connection to wait for
+    let mut client = dial(addr).await;
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    assert_eq!(readiness(metrics).await, "HTTP/1.1 200 OK");
+    
+    server.drain();
+    let mut status = String::new();
+    for _ in 0..100 {
+        status = readiness(metrics).await;
+        if status != "HTTP/1.1 200 OK" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+    assert!(!running.is_finished());
+    
+    drop(client);
+    running.await.unwrap().unwrap();
+}
@@ -0,0 +1,33 @@
+use stpro::{parse_split_config, sample_client_hello, DesyncConfig, DesyncEngine, WriteOp};
+
+fn split_at_one(min_segment_size: Option<usize>) -> DesyncEngine {
+    DesyncEngine::new(DesyncConfig {
+        split: vec![parse_split_config("1").unwrap()],
+        min_segment_size,
+        ..DesyncConfig::default()
+    })
+}
+
+#[test]
+fn split_is_moved_up_to_the_minimum() {
+    let hello = sample_client_hello("example.com");
+    assert_eq!(
+        split_at_one(Some(16)).plan_writes(&hello),
+        [WriteOp::Segment(0..16), WriteOp::Segment(16..hello.len())]
+    );
+}
+
+#[test]
+fn split_is_dropped_when_the_rest_would_be_too_short() {
+    let buffer = [0u8; 24];
+    assert_eq!(split_at_one(Some(16)).plan_writes(&buffer), [WriteOp::Segment(0..24)]);
+}
+
+#[test]
+fn tiny_segments_stay_without_a_minimum() {
+    let hello = sample_client_hello("example.com");
+    assert_eq!(
+        split_at_one(None).plan_writes(&hello),
+        [WriteOp::Segment(0..1), WriteOp::Segment(1..hello.len())]
+    );
+}
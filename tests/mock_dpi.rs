@@ -0,0 +1,45 @@
+mod common;
+
+use common::{client_hello, MockDpi};
+use stpro::{DesyncConfig, DesyncEngine, SplitConfig, SplitFlags};
+
+const BLOCKED: &str = "blocked.example.com";
+
+#[tokio::test]
+async fn plain_client_hello_is_reset() {
+    let engine = DesyncEngine::new(DesyncConfig::default());
+    let mut dpi = MockDpi::new([BLOCKED]);
+    
+    let err = engine
+        .apply_desync(&mut dpi, &client_hello(BLOCKED))
+        .await
+        .unwrap_err();
+    
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    assert!(dpi.is_reset());
+}
+
+#[tokio::test]
+async fn sni_split_evades_inspector() {
+    let engine = DesyncEngine::new(DesyncConfig {
+        split: vec![SplitConfig {
+            offset: 5,
+            flags: SplitFlags {
+                sni: true,
+                ..SplitFlags::default()
+            },
+            repeats: None,
+            skip: None,
+            jitter: None,
+        }],
+        ..DesyncConfig::default()
+    });
+    let hello = client_hello(BLOCKED);
+    let mut dpi = MockDpi::new([BLOCKED]);
+    
+    engine.apply_desync(&mut dpi, &hello).await.unwrap();
+    
+    assert!(!dpi.is_reset());
+    assert_eq!(dpi.segments().len(), 2);
+    assert_eq!(dpi.received(), hello);
+}
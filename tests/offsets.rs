@@ -0,0 +1,87 @@
+mod common;
+
+use common::client_hello;
+use stpro::{parse_split_config, DesyncConfig, DesyncEngine, WriteOp};
+
+/// 37 bytes, the Host value at 22
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+/// Where `rule` splits `buffer`, or None if it doesn't
+fn cut(rule: &str, buffer: &[u8]) -> Option<usize> {
+    let engine = DesyncEngine::new(DesyncConfig {
+        split: vec![parse_split_config(rule).unwrap()],
+        ..DesyncConfig::default()
+    });
+    let cuts: Vec<usize> = engine
+        .plan_writes(buffer)
+        .iter()
+        .filter_map(|op| match op {
+            WriteOp::Segment(range) if range.end < buffer.len() => Some(range.end),
+            _ => None,
+        })
+        .collect();
+    assert!(cuts.len() <= 1, "{}: {:?}", rule, cuts);
+    cuts.first().copied()
+}
+
+#[test]
+fn offsets_in_a_client_hello() {
+    // 72 bytes in one record, the SNI hostname from 61 to the end
+    let hello = client_hello("example.com");
+    assert_eq!(hello.len(), 72);
+    let cases = [
+        ("5", Some(5)),
+        ("-5", Some(67)),
+        // `end` counts back by the magnitude either way and beats anchors
+        ("5+e", Some(67)),
+        ("-5+e", Some(67)),
+        ("5+se", Some(67)),
+        ("5+s", Some(66)),
+        ("-5+s", Some(56)),
+        ("-3+n", Some(69)),
+        ("0+n", None),
+        ("3+a", Some(64)),
+        ("-2+r", Some(70)),
+        ("50%", Some(36)),
+        ("-25%", Some(54)),
+        // `middle` halves the distance from the reference point
+        ("10+m", Some(5)),
+        ("10+sm", Some(66)),
+        ("-10+sm", Some(56)),
+        ("20+em", Some(62)),
+        ("50%+m", Some(18)),
+        // Clamped to the buffer, where there is nothing left to split
+        ("1000", None),
+        ("-1000", None),
+        ("100+s", None),
+        ("0", None),
+    ];
+    for (rule, expected) in cases {
+        assert_eq!(cut(rule, &hello), expected, "{}", rule);
+    }
+}
+
+#[test]
+fn offsets_in_an_http_request() {
+    let cases = [
+        ("2+h", Some(24)),
+        ("-2+h", Some(20)),
+        ("3+a", Some(25)),
+        ("-4", Some(33)),
+        // TLS anchors aren't found in HTTP: the bare offset applies
+        ("2+s", Some(2)),
+        ("-2+s", Some(35)),
+        ("-2+n", Some(35)),
+        ("-1+r", Some(36)),
+    ];
+    for (rule, expected) in cases {
+        assert_eq!(cut(rule, REQUEST), expected, "{}", rule);
+    }
+}
+
+#[test]
+fn http_anchor_isnt_found_in_tls() {
+    let hello = client_hello("example.com");
+    assert_eq!(cut("2+h", &hello), Some(2));
+    assert_eq!(cut("-2+h", &hello), Some(70));
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use common::echo_server;
+use socket2::SockRef;
+use stpro::{Config, Connector};
+
+fn connector(outbound_ttl: Option<u8>) -> Connector {
+    Connector::new(&Config { outbound_ttl, ..Config::default() })
+}
+
+#[tokio::test]
+async fn ipv4_sockets_carry_the_outbound_ttl() {
+    let target = echo_server("127.0.0.1").await;
+    let stream = connector(Some(77)).connect(target).await.unwrap();
+    assert_eq!(SockRef::from(&stream).ttl_v4().unwrap(), 77);
+}
+
+#[tokio::test]
+async fn ipv6_sockets_carry_it_as_the_hop_limit() {
+    let target = echo_server("::1").await;
+    let stream = connector(Some(77)).connect(target).await.unwrap();
+    assert_eq!(SockRef::from(&stream).unicast_hops_v6().unwrap(), 77);
+}
+
+#[tokio::test]
+async fn system_default_without_it() {
+    let target = echo_server("127.0.0.1").await;
+    let stream = connector(None).connect(target).await.unwrap();
+    let default = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)
+        .unwrap()
+        .ttl_v4()
+        .unwrap();
+    assert_eq!(SockRef::from(&stream).ttl_v4().unwrap(), default);
+}
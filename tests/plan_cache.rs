@@ -0,0 +1,90 @@
+use stpro::{
+    parse_split_config, sample_client_hello, DesyncConfig, DesyncEngine, FlowInfo,
+    SegmentRecorder,
+};
+
+fn engine(rule: &str) -> DesyncEngine {
+    DesyncEngine::new(DesyncConfig {
+        split: vec![parse_split_config(rule).unwrap()],
+        plan_cache: true,
+        ..DesyncConfig::default()
+    })
+}
+
+fn flow(host: &str) -> FlowInfo {
+    FlowInfo { host: Some(host.to_string()), port: Some(80) }
+}
+
+/// Segments `engine` cuts `buffer` into for `flow`
+async fn segments(engine: &DesyncEngine, buffer: &[u8], flow: &FlowInfo) -> Vec<Vec<u8>> {
+    let mut recorder = SegmentRecorder::new();
+    engine.apply_desync_flow(&mut recorder, buffer, flow).await.unwrap();
+    recorder.into_segments()
+}
+
+#[tokio::test]
+async fn repeated_first_flight_skips_planning() {
+    let engine = engine("2+h");
+    let request = b"GET / HTTP/1.1\r\nHost: a.test\r\n\r\n";
+    
+    let first = segments(&engine, request, &flow("a.test")).await;
+    assert_eq!(engine.plans_computed(), 1);
+    let second = segments(&engine, request, &flow("a.test")).await;
+    assert_eq!(engine.plans_computed(), 1);
+    assert_eq!(first, second);
+    
+    // Another host is planned on its own
+    segments(&engine, request, &flow("b.test")).await;
+    assert_eq!(engine.plans_computed(), 2);
+}
+
+#[tokio::test]
+async fn new_client_random_reuses_the_plan() {
+    let engine = engine("2+s");
+    let hello = sample_client_hello("a.test");
+    let mut renewed = hello.clone();
+    renewed[11..43].fill(0xab);
+    
+    let tls = FlowInfo { port: Some(443), ..flow("a.test") };
+    let first = segments(&engine, &hello, &tls).await;
+    let second = segments(&engine, &renewed, &tls).await;
+    // Only the first ClientHello was parsed for its SNI
+    assert_eq!(engine.plans_computed(), 1);
+    let lengths = |segments: &[Vec<u8>]| segments.iter().map(Vec::len).collect::<Vec<_>>();
+    assert_eq!(lengths(&first), lengths(&second));
+    assert!(second[0].ends_with(b"a."), "{:?}", second);
+}
+
+#[tokio::test]
+async fn moved_anchor_is_planned_afresh() {
+    let engine = engine("2+h");
+    let host_first = b"GET / HTTP/1.1\r\nHost: a.test\r\nAccept: */*\r\n\r\n";
+    let host_last = b"GET / HTTP/1.1\r\nAccept: */*\r\nHost: a.test\r\n\r\n";
+    assert_eq!(host_first.len(), host_last.len());
+    
+    let segments_first = segments(&engine, host_first, &flow("a.test")).await;
+    let segments_last = segments(&engine, host_last, &flow("a.test")).await;
+    assert_eq!(engine.plans_computed(), 2);
+    // Both are cut two bytes into their own Host value
+    assert!(segments_first[0].ends_with(b"Host: a."), "{:?}", segments_first);
+    assert!(segments_last[0].ends_with(b"Host: a."), "{:?}", segments_last);
+}
+
+#[tokio::test]
+async fn least_recently_used_plan_is_evicted() {
+    let engine = engine("2+h");
+    let request = b"GET / HTTP/1.1\r\nHost: a.test\r\n\r\n";
+    
+    segments(&engine, request, &flow("kept.test")).await;
+    for i in 0..1024 {
+        // Keep the first host warm while the cache fills up
+        segments(&engine, request, &flow("kept.test")).await;
+        segments(&engine, request, &flow(&format!("host{}.test", i))).await;
+    }
+    assert_eq!(engine.plans_computed(), 1025);
+    segments(&engine, request, &flow("kept.test")).await;
+    assert_eq!(engine.plans_computed(), 1025);
+    // The oldest of the other hosts made room
+    segments(&engine, request, &flow("host0.test")).await;
+    assert_eq!(engine.plans_computed(), 1026);
+}
@@ -0,0 +1,102 @@
+mod common;
+
+use common::client_hello;
+use stpro::{parse_split_config, DesyncConfig, DesyncEngine, Protocol, WriteOp};
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n";
+/// Offset of `www.example.com` in `REQUEST`
+const HOST_AT: usize = 22;
+
+fn engine(rule: &str, strict_anchors: bool) -> DesyncEngine {
+    DesyncEngine::new(DesyncConfig {
+        split: vec![parse_split_config(rule).unwrap()],
+        strict_anchors,
+        ..DesyncConfig::default()
+    })
+}
+
+/// Segment boundaries `rule` produces in `buffer`
+fn cuts(rule: &str, strict_anchors: bool, buffer: &[u8]) -> Vec<usize> {
+    engine(rule, strict_anchors)
+        .plan_writes(buffer)
+        .iter()
+        .filter_map(|op| match op {
+            WriteOp::Segment(range) if range.end < buffer.len() => Some(range.end),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn first_flights_are_classified() {
+    assert_eq!(Protocol::detect(&client_hello("example.com")), Protocol::Tls);
+    assert_eq!(Protocol::detect(REQUEST), Protocol::Http);
+    assert_eq!(Protocol::detect(b"SSH-2.0-OpenSSH_9.6\r\n"), Protocol::Other);
+    assert_eq!(Protocol::detect(b""), Protocol::Other);
+}
+
+#[test]
+fn host_rules_anchor_on_the_host_header() {
+    assert_eq!(&REQUEST[HOST_AT..HOST_AT + 15], b"www.example.com");
+    assert_eq!(cuts("0+h", false, REQUEST), [HOST_AT]);
+    assert_eq!(cuts("4+h", false, REQUEST), [HOST_AT + 4]);
+    assert_eq!(cuts("0+a", false, REQUEST), [HOST_AT]);
+}
+
+#[test]
+fn sni_rule_on_http_falls_back_to_its_offset() {
+    assert_eq!(cuts("2+s", false, REQUEST), [2]);
+    assert_eq!(cuts("2+n", false, REQUEST), [2]);
+    assert!(cuts("2+s", true, REQUEST).is_empty());
+}
+
+#[test]
+fn host_rule_on_tls_falls_back_to_its_offset() {
+    let hello = client_hello("example.com");
+    assert_eq!(cuts("3+h", false, &hello), [3]);
+    assert!(cuts("3+h", true, &hello).is_empty());
+}
+
+#[test]
+fn host_header_outside_http_is_not_an_anchor() {
+    let banner = b"SSH-2.0-x\r\nHost: www.example.com\r\n";
+    assert_eq!(cuts("2+h", false, banner), [2]);
+    assert!(cuts("2+h", true, banner).is_empty());
+}
+
+#[test]
+fn unrecognized_flight_under_strict_anchors_and_tls_http_only() {
+    let banner: &[u8] = b"SSH-2.0-OpenSSH_9.6\r\n";
+    let engine = |rule: &str, strict_anchors, desync_only_tls_http| {
+        DesyncEngine::new(DesyncConfig {
+            split: vec![parse_split_config(rule).unwrap()],
+            strict_anchors,
+            desync_only_tls_http,
+            ..DesyncConfig::default()
+        })
+    };
+    let whole = [WriteOp::Segment(0..banner.len())];
+    let split_at_4 = [WriteOp::Segment(0..4), WriteOp::Segment(4..banner.len())];
+    
+    // Neither set: offsets split it, anchored or not
+    assert_eq!(engine("4", false, false).plan_writes(banner), split_at_4);
+    assert_eq!(engine("4+s", false, false).plan_writes(banner), split_at_4);
+    // strict_anchors only drops rules whose anchor is missing
+    assert_eq!(engine("4", true, false).plan_writes(banner), split_at_4);
+    assert_eq!(engine("4+s", true, false).plan_writes(banner), whole);
+    // desync_only_tls_http passes it through whatever the rule
+    assert_eq!(engine("4", false, true).plan_writes(banner), whole);
+    assert_eq!(engine("4+s", false, true).plan_writes(banner), whole);
+    assert_eq!(engine("4", true, true).plan_writes(banner), whole);
+}
+
+#[test]
+fn plan_names_the_protocol() {
+    let plan = engine("0+h", false).explain(REQUEST);
+    assert_eq!(plan.protocol, Protocol::Http);
+    assert!(!plan.is_tls);
+    assert!(plan.to_string().starts_with("mode: split, 54 bytes (HTTP request)"));
+    
+    let json = serde_json::to_value(&plan).unwrap();
+    assert_eq!(json["protocol"], "http");
+}
@@ -0,0 +1,45 @@
+use stpro::{extract_quic_sni, find_quic_sni_offset, is_quic_initial, quic_initial_crypto};
+
+/// A 1200-byte client Initial for `quic.example.com` (DCID 8394c8f03e515708,
+/// the one from RFC 9001 appendix A), encrypted with an independent AES-GCM
+/// implementation. Its ClientHello is split over three CRYPTO frames sent
+/// out of order, with PING and PADDING frames between them.
+const INITIAL: &[u8] = include_bytes!("data/quic_initial.bin");
+
+#[test]
+fn reads_sni_from_reordered_crypto_frames() {
+    assert!(is_quic_initial(INITIAL));
+    assert_eq!(extract_quic_sni(INITIAL).as_deref(), Some("quic.example.com"));
+    
+    let hello = quic_initial_crypto(INITIAL).unwrap();
+    assert_eq!(hello[0], 0x01, "CRYPTO stream starts with a ClientHello");
+    let offset = find_quic_sni_offset(INITIAL).unwrap();
+    assert_eq!(&hello[offset..offset + 16], b"quic.example.com");
+}
+
+#[test]
+fn tampered_initial_fails_authentication() {
+    let mut tampered = INITIAL.to_vec();
+    tampered[200] ^= 0x01;
+    
+    assert!(is_quic_initial(&tampered));
+    assert_eq!(quic_initial_crypto(&tampered), None);
+    assert_eq!(extract_quic_sni(&tampered), None);
+}
+
+#[test]
+fn other_packets_are_not_initials() {
+    // Handshake packet type
+    let mut handshake = INITIAL.to_vec();
+    handshake[0] = (handshake[0] & 0xcf) | 0x20;
+    // Unknown version
+    let mut draft = INITIAL.to_vec();
+    draft[1..5].copy_from_slice(&[0xff, 0x00, 0x00, 0x1d]);
+    // Short header (1-RTT)
+    let short = [0x40, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+    
+    for packet in [&handshake[..], &draft, &short, &[]] {
+        assert!(!is_quic_initial(packet));
+        assert_eq!(extract_quic_sni(packet), None);
+    }
+}
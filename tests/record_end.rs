@@ -0,0 +1,45 @@
+use stpro::{
+    parse_split_config, sample_client_hello, tls_record_len, DesyncConfig, DesyncEngine,
+    SegmentRecorder,
+};
+
+/// A ChangeCipherSpec record, as middlebox-compatible clients send after
+/// the ClientHello
+const CHANGE_CIPHER_SPEC: &[u8] = &[0x14, 0x03, 0x03, 0x00, 0x01, 0x01];
+
+/// Segments the rule `rule` cuts `buffer` into
+async fn segments(rule: &str, buffer: &[u8]) -> Vec<Vec<u8>> {
+    let engine = DesyncEngine::new(DesyncConfig {
+        split: vec![parse_split_config(rule).unwrap()],
+        ..DesyncConfig::default()
+    });
+    let mut recorder = SegmentRecorder::new();
+    engine.apply_desync(&mut recorder, buffer).await.unwrap();
+    recorder.into_segments()
+}
+
+#[tokio::test]
+async fn split_lands_between_the_records() {
+    let hello = sample_client_hello("example.com");
+    let buffer = [hello.as_slice(), CHANGE_CIPHER_SPEC].concat();
+    assert_eq!(tls_record_len(&buffer), Some(hello.len()));
+    
+    assert_eq!(segments("0+r", &buffer).await, [hello.clone(), CHANGE_CIPHER_SPEC.to_vec()]);
+}
+
+#[tokio::test]
+async fn offset_counts_from_the_boundary() {
+    let hello = sample_client_hello("example.com");
+    let buffer = [hello.as_slice(), CHANGE_CIPHER_SPEC].concat();
+    
+    let segments = segments("-2+r", &buffer).await;
+    assert_eq!(segments.len(), 2);
+    assert_eq!(segments[0].len(), hello.len() - 2);
+    assert_eq!(segments.concat(), buffer);
+}
+
+#[tokio::test]
+async fn lone_record_is_left_whole() {
+    let hello = sample_client_hello("example.com");
+    assert_eq!(segments("0+r", &hello).await, [hello]);
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::{echo_once, finish_request, socks5_tunnel_handled, strategies_used};
+use std::sync::Arc;
+use stpro::{parse_split_config, Config, DesyncConfig, ProxyServer};
+
+/// Defaults with desync on every port, as the test targets' are random
+fn unrestricted() -> Config {
+    Config { desync_ports: Vec::new(), ..Config::default() }
+}
+
+fn split(rule: &str) -> DesyncConfig {
+    DesyncConfig { split: vec![parse_split_config(rule).unwrap()], ..DesyncConfig::default() }
+}
+
+fn disorder(rule: &str) -> DesyncConfig {
+    DesyncConfig { disorder: vec![parse_split_config(rule).unwrap()], ..DesyncConfig::default() }
+}
+
+#[tokio::test]
+async fn open_connections_keep_their_strategy() {
+    let target = echo_once().await;
+    let server = Arc::new(ProxyServer::new(Config { desync: split("1"), ..unrestricted() }));
+    
+    let (before, before_handler) = socks5_tunnel_handled(&server, target).await;
+    let changes = server.reload(Config { desync: disorder("1"), ..unrestricted() }).unwrap();
+    assert_eq!(changes, ["desync: split -> disorder"]);
+    let (after, after_handler) = socks5_tunnel_handled(&server, target).await;
+    
+    finish_request(before, before_handler).await;
+    finish_request(after, after_handler).await;
+    assert_eq!(strategies_used(&server), [("disorder".into(), 1), ("split".into(), 1)]);
+}
+
+#[tokio::test]
+async fn invalid_config_is_not_applied() {
+    let target = echo_once().await;
+    let server = Arc::new(ProxyServer::new(Config { desync: split("1"), ..unrestricted() }));
+    
+    let desync = DesyncConfig { ttl: Some(0), ..disorder("1") };
+    let invalid = Config { desync, ..unrestricted() };
+    let error = server.reload(invalid).unwrap_err().to_string();
+    assert!(error.contains("desync.ttl"), "{}", error);
+    
+    let (client, handler) = socks5_tunnel_handled(&server, target).await;
+    finish_request(client, handler).await;
+    assert_eq!(strategies_used(&server), [("split".into(), 1)]);
+}
+
+#[test]
+fn changes_are_summarized() {
+    let server = ProxyServer::new(Config { desync: split("1"), ..unrestricted() });
+    assert_eq!(
+        server.reload(Config { desync: split("1"), ..unrestricted() }).unwrap(),
+        ["no changes"]
+    );
+    
+    let mut config = Config { desync: split("2"), ..unrestricted() };
+    config.tag_profiles.insert("video".to_string(), disorder("1"));
+    config.strategies = vec![disorder("2")];
+    config.buffer_size *= 2;
+    assert_eq!(
+        server.reload(config).unwrap(),
+        [
+            "desync: split rules changed",
+            "strategies: 1 fallbacks (was 0)",
+            "tag_profiles: added video",
+            "not applied until a restart: buffer_size",
+        ]
+    );
+}
@@ -0,0 +1,40 @@
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use common::{dial, serve};
+use std::sync::Arc;
+use stpro::{Config, ListenAddr, ProxyServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[tokio::test]
+async fn two_listeners_share_the_port() {
+    let config = Config { reuse_port: true, ..Config::default() };
+    let (first, addr, first_running) = serve(config.clone());
+    drop(dial(addr).await);
+    let second = Arc::new(ProxyServer::new(Config { listen: ListenAddr::Tcp(addr), ..config }));
+    let second_running = tokio::spawn({
+        let second = second.clone();
+        async move { second.run().await }
+    });
+    
+    // The kernel hashes each connection to one of the listeners, so keep
+    // connecting until both have taken one besides the first's wake-up dial
+    for _ in 0..200 {
+        if first.stats().total_connections() > 1 && second.stats().total_connections() > 0 {
+            break;
+        }
+        let mut client = dial(addr).await;
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut choice = [0u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [5, 0]);
+    }
+    assert!(first.stats().total_connections() > 1, "first listener never accepted");
+    assert!(second.stats().total_connections() > 0, "second listener never accepted");
+    
+    first.shutdown();
+    second.shutdown();
+    first_running.await.unwrap().unwrap();
+    second_running.await.unwrap().unwrap();
+}
@@ -0,0 +1,179 @@
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use stpro::{
+    parse_split_config, DesyncConfig, DesyncEngine, FlowInfo, SegmentRecorder, SegmentSeparation,
+    SocketControl,
+};
+
+const DATA: &[u8] = b"0123456789abcdefghij";
+
+/// A socket that reports unsent data for the first `busy` checks
+struct Backlog {
+    busy: usize,
+    checks: AtomicUsize,
+}
+
+impl Backlog {
+    fn new(busy: usize) -> Self {
+        Self { busy, checks: AtomicUsize::new(0) }
+    }
+}
+
+impl SocketControl for Backlog {
+    fn ttl(&self) -> io::Result<u8> {
+        Ok(64)
+    }
+    
+    fn set_ttl(&self, _ttl: u8) -> io::Result<()> {
+        Ok(())
+    }
+    
+    fn unsent_bytes(&self) -> io::Result<usize> {
+        let check = self.checks.fetch_add(1, Ordering::SeqCst);
+        Ok(if check < self.busy { 100 } else { 0 })
+    }
+}
+
+/// Split `DATA` at 5 and 10, separating the segments by `separation`
+async fn split(separation: SegmentSeparation, control: &Backlog) -> Vec<Vec<u8>> {
+    let engine = DesyncEngine::new(DesyncConfig {
+        split: vec![parse_split_config("5").unwrap(), parse_split_config("10").unwrap()],
+        segment_separation: separation,
+        ..DesyncConfig::default()
+    });
+    let mut recorder = SegmentRecorder::new();
+    let sent = engine
+        .apply_desync_controlled(&mut recorder, Some(control), DATA, &FlowInfo::default())
+        .await
+        .unwrap();
+    assert_eq!(sent, DATA.len());
+    recorder.into_segments()
+}
+
+#[tokio::test]
+async fn flush_does_not_look_at_the_socket() {
+    let control = Backlog::new(0);
+    assert_eq!(split(SegmentSeparation::Flush, &control).await.len(), 3);
+    assert_eq!(split(SegmentSeparation::Yield, &control).await.len(), 3);
+    assert_eq!(control.checks.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn wait_sent_waits_for_the_backlog_between_segments() {
+    let control = Backlog::new(3);
+    let segments = split(SegmentSeparation::WaitSent, &control).await;
+    assert_eq!(segments, [&DATA[..5], &DATA[5..10], &DATA[10..]]);
+    // One check finds the socket idle before the second segment after three
+    // busy ones, and another before the third
+    assert_eq!(control.checks.load(Ordering::SeqCst), 5);
+}
+
+#[tokio::test]
+async fn wait_sent_gives_up_on_a_stuck_socket() {
+    let control = Backlog::new(usize::MAX);
+    let started = Instant::now();
+    let segments = split(SegmentSeparation::WaitSent, &control).await;
+    assert_eq!(segments.concat(), DATA);
+    // Two capped waits
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(500), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+}
+
+#[test]
+fn separation_is_read_from_config_files() {
+    let config: DesyncConfig =
+        serde_json::from_str(r#"{"segment_separation": "wait_sent"}"#).unwrap();
+    assert_eq!(config.segment_separation, SegmentSeparation::WaitSent);
+    assert_eq!(DesyncConfig::default().segment_separation, SegmentSeparation::Flush);
+}
+
+/// The real socket, recording how many segments it had sent each time it
+/// was found to have nothing left to send
+#[cfg(target_os = "linux")]
+struct Recording {
+    control: stpro::TtlControl,
+    counter: stpro::SegmentCounter,
+    idle_at: std::sync::Mutex<Vec<u32>>,
+    busy_checks: AtomicUsize,
+}
+
+#[cfg(target_os = "linux")]
+impl SocketControl for Recording {
+    fn ttl(&self) -> io::Result<u8> {
+        self.control.ttl()
+    }
+    
+    fn set_ttl(&self, ttl: u8) -> io::Result<()> {
+        self.control.set_ttl(ttl)
+    }
+    
+    fn unsent_bytes(&self) -> io::Result<usize> {
+        let unsent = self.control.unsent_bytes()?;
+        if unsent == 0 {
+            self.idle_at.lock().unwrap().extend(self.counter.segments_out());
+        } else {
+            self.busy_checks.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(unsent)
+    }
+}
+
+/// Over a real connection whose receiver isn't reading yet, so the kernel
+/// holds back earlier data, the segments after the first each leave as one
+/// TCP segment instead of joining the queue
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn wait_sent_separates_segments_on_the_wire() {
+    use stpro::{SegmentCounter, TtlControl};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    
+    // A small receive window fills quickly
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.set_recv_buffer_size(4096).unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let listener = socket.listen(1).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let reader = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        received
+    });
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream.set_nodelay(true).unwrap();
+    let recording = Recording {
+        control: TtlControl::new(&stream).unwrap(),
+        counter: SegmentCounter::new(&stream),
+        idle_at: Default::default(),
+        busy_checks: AtomicUsize::new(0),
+    };
+    
+    let bulk = vec![0u8; 64 << 10];
+    stream.write_all(&bulk).await.unwrap();
+    let engine = DesyncEngine::new(DesyncConfig {
+        split: vec![parse_split_config("5").unwrap(), parse_split_config("10").unwrap()],
+        segment_separation: SegmentSeparation::WaitSent,
+        ..DesyncConfig::default()
+    });
+    engine
+        .apply_desync_controlled(&mut stream, Some(&recording), DATA, &FlowInfo::default())
+        .await
+        .unwrap();
+    while recording.control.unsent_bytes().unwrap() > 0 {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    let after = recording.counter.segments_out().unwrap();
+    
+    // The first wait found the bulk data queued and outlasted it
+    assert!(recording.busy_checks.load(Ordering::SeqCst) > 0);
+    let idle_at = recording.idle_at.lock().unwrap().clone();
+    assert_eq!(idle_at.len(), 2);
+    assert_eq!([idle_at[1] - idle_at[0], after - idle_at[1]], [1, 1]);
+    
+    drop(stream);
+    let received = reader.await.unwrap();
+    assert_eq!(&received[bulk.len()..], DATA);
+}
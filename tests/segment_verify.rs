@@ -0,0 +1,63 @@
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use common::{client_hello, socks5_tunnel_handled};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use stpro::{parse_split_config, Config, DesyncConfig, ProxyServer, SegmentSeparation};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// A target that reads `len` bytes, answers `ok` and hangs up
+async fn target_reading(len: usize) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = vec![0u8; len];
+        stream.read_exact(&mut received).await.unwrap();
+        stream.write_all(b"ok").await.unwrap();
+    });
+    addr
+}
+
+/// Flights counted as coalesced after sending one ClientHello, re-framed
+/// into two records, under `desync`
+async fn coalesced_flights(desync: DesyncConfig) -> u64 {
+    let server = Arc::new(ProxyServer::new(Config {
+        desync: DesyncConfig {
+            tls_rec: vec![parse_split_config("40").unwrap()],
+            verify_segments: true,
+            ..desync
+        },
+        desync_ports: Vec::new(),
+        ..Config::default()
+    }));
+    let hello = client_hello("example.com");
+    // Re-framing adds the header of the second record
+    let target = target_reading(hello.len() + 5).await;
+    let (mut client, handler) = socks5_tunnel_handled(&server, target).await;
+    client.write_all(&hello).await.unwrap();
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"ok");
+    drop(client);
+    handler.await.unwrap().unwrap();
+    server.stats().coalesced_flights()
+}
+
+#[tokio::test]
+async fn records_in_one_segment_are_flagged() {
+    assert_eq!(coalesced_flights(DesyncConfig::default()).await, 1);
+}
+
+#[tokio::test]
+async fn records_split_at_their_boundary_pass() {
+    let desync = DesyncConfig {
+        split: vec![parse_split_config("0+r").unwrap()],
+        segment_separation: SegmentSeparation::WaitSent,
+        ..DesyncConfig::default()
+    };
+    assert_eq!(coalesced_flights(desync).await, 0);
+}
@@ -0,0 +1,116 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use stpro::{
+    find_sni_end_offset, find_sni_offset, parse_split_config, DesyncConfig, DesyncEngine,
+    WriteOp,
+};
+
+/// The ClientHello curl 7.88 (OpenSSL 3.0) sends for `www.example.com`: 517
+/// bytes with a padding extension, GREASE-free, SNI as the first extension
+const HELLO: &[u8] = include_bytes!("data/client_hello.bin");
+const HOST: &[u8] = b"www.example.com";
+
+/// Segment boundaries `rules` produce in `buffer`
+fn cuts(rules: &[&str], buffer: &[u8]) -> Vec<usize> {
+    let engine = DesyncEngine::new(DesyncConfig {
+        split: rules.iter().map(|rule| parse_split_config(rule).unwrap()).collect(),
+        ..DesyncConfig::default()
+    });
+    engine
+        .plan_writes(buffer)
+        .iter()
+        .filter_map(|op| match op {
+            WriteOp::Segment(range) if range.end < buffer.len() => Some(range.end),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn offsets_bracket_the_hostname() {
+    let start = find_sni_offset(HELLO).unwrap();
+    let end = find_sni_end_offset(HELLO).unwrap();
+    assert_eq!(&HELLO[start..end], HOST);
+    assert_eq!((start, end), (153, 168));
+}
+
+#[test]
+fn end_is_unknown_while_the_hostname_is_cut_off() {
+    let start = find_sni_offset(HELLO).unwrap();
+    assert_eq!(find_sni_offset(&HELLO[..start + 4]), Some(start));
+    assert_eq!(find_sni_end_offset(&HELLO[..start + 4]), None);
+    assert_eq!(find_sni_end_offset(&HELLO[..start + HOST.len()]), Some(start + HOST.len()));
+    assert_eq!(find_sni_end_offset(b"GET / HTTP/1.1\r\n\r\n"), None);
+}
+
+#[test]
+fn splits_at_either_boundary() {
+    assert_eq!(cuts(&["0+s"], HELLO), [153]);
+    assert_eq!(cuts(&["0+n"], HELLO), [168]);
+    assert_eq!(cuts(&["0+s", "0+n"], HELLO), [153, 168]);
+    assert_eq!(cuts(&["-1+n"], HELLO), [167]);
+}
+
+#[test]
+fn hostname_fragments_across_segments() {
+    let cuts = cuts(&["3+s", "-4+n"], HELLO);
+    assert_eq!(cuts, [156, 164]);
+    assert_eq!(&HELLO[cuts[0]..cuts[1]], b".example");
+}
+
+#[test]
+fn sni_end_flag_parses_and_needs_tls() {
+    let rule = parse_split_config("-2+n").unwrap();
+    assert!(rule.flags.sni_end && !rule.flags.sni);
+    assert_eq!(rule.fixed_offset(), None);
+    
+    // Without a ClientHello the offset falls back to the buffer end
+    let request = b"GET / HTTP/1.1\r\nHost: www.example.com\r\n\r\n";
+    assert_eq!(cuts(&["-2+n"], request), [request.len() - 2]);
+}
+
+#[test]
+fn truncated_hellos_never_panic() {
+    for len in 0..=HELLO.len() {
+        let hello = &HELLO[..len];
+        if let Some(start) = find_sni_offset(hello) {
+            assert!(start <= len, "offset {} past {} bytes", start, len);
+        }
+        if let Some(end) = find_sni_end_offset(hello) {
+            assert!(end <= len, "end {} past {} bytes", end, len);
+        }
+    }
+}
+
+#[test]
+fn corrupted_hellos_never_panic() {
+    let mut rng = StdRng::seed_from_u64(265);
+    for _ in 0..20_000 {
+        let mut hello = HELLO.to_vec();
+        for _ in 0..rng.gen_range(1..8) {
+            let at = rng.gen_range(0..hello.len());
+            hello[at] = rng.gen();
+        }
+        hello.truncate(rng.gen_range(0..=hello.len()));
+        if let Some(start) = find_sni_offset(&hello) {
+            assert!(start <= hello.len());
+        }
+        if let Some(end) = find_sni_end_offset(&hello) {
+            assert!(end <= hello.len());
+        }
+    }
+}
+
+#[test]
+fn random_records_never_panic() {
+    let mut rng = StdRng::seed_from_u64(266);
+    for _ in 0..20_000 {
+        // A handshake record header around random bytes gets past the
+        // first checks
+        let mut buffer = vec![0x16, 0x03, 0x01, 0x02, 0x00, 0x01];
+        let len = rng.gen_range(0..600);
+        buffer.extend((0..len).map(|_| rng.gen::<u8>()));
+        find_sni_offset(&buffer);
+        find_sni_end_offset(&buffer);
+    }
+}
@@ -0,0 +1,84 @@
+#![cfg(unix)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+
+/// Start stpro the way systemd does for a socket unit: `fd` as descriptor
+/// 3 and `LISTEN_PID` naming the stpro process itself
+fn activate(fd: RawFd, listen_pid: &str, args: &[&str]) -> Child {
+    let script = format!("LISTEN_PID={} LISTEN_FDS=1 exec \"$0\" \"$@\"", listen_pid);
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(script)
+        .arg(env!("CARGO_BIN_EXE_stpro"))
+        .args(args)
+        .stdout(Stdio::piped());
+    unsafe {
+        command.pre_exec(move || {
+            // dup2 onto itself would leave close-on-exec set
+            let ret = match fd {
+                3 => libc::fcntl(fd, libc::F_SETFD, 0),
+                _ => libc::dup2(fd, 3),
+            };
+            match ret {
+                -1 => Err(std::io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        });
+    }
+    command.spawn().unwrap()
+}
+
+/// Read stdout up to the line announcing the listen address, leaving the
+/// rest to be drained in the background
+fn listening_line(child: &mut Child) -> String {
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    let line = lines.by_ref().map(|line| line.unwrap()).find(|line| line.contains("listening on"));
+    std::thread::spawn(move || lines.for_each(drop));
+    line.unwrap()
+}
+
+fn stop(mut child: Child) {
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    child.wait().unwrap();
+}
+
+/// Send a SOCKS5 greeting and read the method choice
+fn greet(addr: std::net::SocketAddr) -> [u8; 2] {
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, 0]).unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).unwrap();
+    choice
+}
+
+#[test]
+fn adopts_the_passed_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let free = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut child = activate(listener.as_raw_fd(), "$$", &["--port", &free.to_string()]);
+    
+    // The configured port is never bound
+    assert_eq!(listening_line(&mut child), format!("[*] SOCKS5 Proxy listening on {}", addr));
+    drop(listener);
+    assert_eq!(greet(addr), [5, 0]);
+    assert!(TcpStream::connect(("127.0.0.1", free)).is_err());
+    stop(child);
+}
+
+#[test]
+fn variables_meant_for_another_process_are_ignored() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut child = activate(listener.as_raw_fd(), "1", &["--port", &port.to_string()]);
+    
+    let line = listening_line(&mut child);
+    assert_eq!(line, format!("[*] SOCKS5 Proxy listening on 127.0.0.1:{}", port));
+    assert_eq!(greet(([127, 0, 0, 1], port).into()), [5, 0]);
+    stop(child);
+}
@@ -0,0 +1,213 @@
+mod common;
+
+use common::{echo_server, proxy_client, socks5_connect, socks5_connect_domain, StubResolver};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use stpro::{AuthConfig, Config, ConnectionOutcome, ProxyServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+/// Failure reply carrying no address, as sent before any connect succeeded
+fn failure_reply(rep: u8) -> [u8; 10] {
+    [5, rep, 0, 1, 0, 0, 0, 0, 0, 0]
+}
+
+fn server(config: Config) -> Arc<ProxyServer> {
+    Arc::new(ProxyServer::new(config))
+}
+
+/// Send `bytes` and read back exactly `len` bytes
+async fn exchange(client: &mut DuplexStream, bytes: &[u8], len: usize) -> Vec<u8> {
+    client.write_all(bytes).await.unwrap();
+    let mut reply = vec![0u8; len];
+    client.read_exact(&mut reply).await.unwrap();
+    reply
+}
+
+/// Everything the proxy sends until it closes the connection
+async fn rest(client: &mut DuplexStream) -> Vec<u8> {
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).await.unwrap();
+    rest
+}
+
+#[tokio::test]
+async fn connect_ipv4() {
+    let target = echo_server("127.0.0.1").await;
+    let (mut client, _) = proxy_client(&server(Config::default()));
+    
+    assert_eq!(exchange(&mut client, &[5, 1, 0], 2).await, [5, 0]);
+    let reply = exchange(&mut client, &socks5_connect(target), 10).await;
+    // The bound address is the proxy's end of the target connection
+    assert_eq!(reply[..8], [5, 0, 0, 1, 127, 0, 0, 1]);
+    assert_eq!(exchange(&mut client, b"ping", 4).await, b"ping");
+}
+
+#[tokio::test]
+async fn connect_ipv6() {
+    let target = echo_server("::1").await;
+    let (mut client, _) = proxy_client(&server(Config::default()));
+    
+    assert_eq!(exchange(&mut client, &[5, 1, 0], 2).await, [5, 0]);
+    let reply = exchange(&mut client, &socks5_connect(target), 22).await;
+    let mut expected = vec![5, 0, 0, 4];
+    expected.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+    assert_eq!(reply[..20], expected);
+    assert_eq!(exchange(&mut client, b"ping", 4).await, b"ping");
+}
+
+#[tokio::test]
+async fn connect_domain_through_resolver() {
+    let target = echo_server("127.0.0.1").await;
+    let resolver = StubResolver(HashMap::from([("target.test".to_string(), target)]));
+    let server = Arc::new(ProxyServer::with_resolver(Config::default(), Arc::new(resolver)));
+    let (mut client, _) = proxy_client(&server);
+    
+    assert_eq!(exchange(&mut client, &[5, 1, 0], 2).await, [5, 0]);
+    let request = socks5_connect_domain("target.test", target.port());
+    let reply = exchange(&mut client, &request, 10).await;
+    assert_eq!(reply[..8], [5, 0, 0, 1, 127, 0, 0, 1]);
+    assert_eq!(exchange(&mut client, b"ping", 4).await, b"ping");
+}
+
+#[tokio::test]
+async fn unresolvable_domain_is_host_unreachable() {
+    let resolver = StubResolver(HashMap::new());
+    let server = Arc::new(ProxyServer::with_resolver(Config::default(), Arc::new(resolver)));
+    let (mut client, handler) = proxy_client(&server);
+    
+    assert_eq!(exchange(&mut client, &[5, 1, 0], 2).await, [5, 0]);
+    let request = socks5_connect_domain("missing.test", 443);
+    assert_eq!(exchange(&mut client, &request, 10).await, failure_reply(4));
+    assert!(handler.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn refused_connect_is_reported() {
+    // Bind and drop a listener to get a port nothing listens on
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (mut client, _) = proxy_client(&server(Config::default()));
+    
+    assert_eq!(exchange(&mut client, &[5, 1, 0], 2).await, [5, 0]);
+    assert_eq!(exchange(&mut client, &socks5_connect(closed), 10).await, failure_reply(5));
+}
+
+#[tokio::test]
+async fn bad_version_is_dropped_without_reply() {
+    let (mut client, handler) = proxy_client(&server(Config::default()));
+    
+    client.write_all(&[4, 1, 0]).await.unwrap();
+    assert!(rest(&mut client).await.is_empty());
+    assert!(handler.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn no_acceptable_method() {
+    let (mut client, handler) = proxy_client(&server(Config::default()));
+    
+    assert_eq!(exchange(&mut client, &[5, 1, 0x80], 2).await, [5, 0xFF]);
+    assert!(rest(&mut client).await.is_empty());
+    assert_eq!(handler.await.unwrap().unwrap(), ConnectionOutcome::Refused);
+}
+
+#[tokio::test]
+async fn unsupported_command() {
+    let (mut client, handler) = proxy_client(&server(Config::default()));
+    
+    assert_eq!(exchange(&mut client, &[5, 1, 0], 2).await, [5, 0]);
+    // BIND
+    let request = [5, 2, 0, 1, 127, 0, 0, 1, 0, 80];
+    assert_eq!(exchange(&mut client, &request, 10).await, failure_reply(7));
+    assert!(handler.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn unsupported_address_type() {
+    let (mut client, handler) = proxy_client(&server(Config::default()));
+    
+    assert_eq!(exchange(&mut client, &[5, 1, 0], 2).await, [5, 0]);
+    assert_eq!(exchange(&mut client, &[5, 1, 0, 9], 10).await, failure_reply(8));
+    assert!(handler.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn ipv6_target_refused_when_disabled() {
+    let (mut client, handler) = proxy_client(&server(Config {
+        disable_ipv6: true,
+        ..Config::default()
+    }));
+    
+    assert_eq!(exchange(&mut client, &[5, 1, 0], 2).await, [5, 0]);
+    let target: SocketAddr = "[::1]:443".parse().unwrap();
+    assert_eq!(exchange(&mut client, &socks5_connect(target), 10).await, failure_reply(8));
+    assert_eq!(handler.await.unwrap().unwrap(), ConnectionOutcome::Refused);
+}
+
+fn with_user(username: &str, password: &str) -> Config {
+    Config {
+        auth: Some(AuthConfig {
+            users: HashMap::from([(username.to_string(), password.to_string())]),
+        }),
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn username_password_login() {
+    let target = echo_server("127.0.0.1").await;
+    let (mut client, _) = proxy_client(&server(with_user("alice", "secret")));
+    
+    // Only username/password is acceptable once users are configured
+    assert_eq!(exchange(&mut client, &[5, 2, 0, 2], 2).await, [5, 2]);
+    let login = [&[1, 5][..], b"alice", &[6], b"secret"].concat();
+    assert_eq!(exchange(&mut client, &login, 2).await, [1, 0]);
+    let reply = exchange(&mut client, &socks5_connect(target), 10).await;
+    assert_eq!(reply[..2], [5, 0]);
+}
+
+#[tokio::test]
+async fn wrong_password_is_rejected() {
+    let (mut client, handler) = proxy_client(&server(with_user("alice", "secret")));
+    
+    assert_eq!(exchange(&mut client, &[5, 1, 2], 2).await, [5, 2]);
+    let login = [&[1, 5][..], b"alice", &[5], b"wrong"].concat();
+    assert_eq!(exchange(&mut client, &login, 2).await, [1, 1]);
+    assert!(rest(&mut client).await.is_empty());
+    assert!(handler.await.unwrap().is_err());
+}
+
+fn with_priority(auth_method_priority: Vec<u8>) -> Arc<ProxyServer> {
+    server(Config { auth_method_priority, ..Config::default() })
+}
+
+#[tokio::test]
+async fn priority_selects_username_password_over_no_auth() {
+    let target = echo_server("127.0.0.1").await;
+    let (mut client, _) = proxy_client(&with_priority(vec![2, 0]));
+    
+    assert_eq!(exchange(&mut client, &[5, 2, 0, 2], 2).await, [5, 2]);
+    // Without configured users any login is taken, for accounting
+    let login = [&[1, 3][..], b"bob", &[1], b"x"].concat();
+    assert_eq!(exchange(&mut client, &login, 2).await, [1, 0]);
+    let reply = exchange(&mut client, &socks5_connect(target), 10).await;
+    assert_eq!(reply[..2], [5, 0]);
+}
+
+#[tokio::test]
+async fn priority_falls_back_to_an_offered_method() {
+    let (mut client, _) = proxy_client(&with_priority(vec![2, 0]));
+    assert_eq!(exchange(&mut client, &[5, 1, 0], 2).await, [5, 0]);
+}
+
+#[tokio::test]
+async fn default_priority_prefers_no_auth() {
+    let (mut client, _) = proxy_client(&server(Config::default()));
+    assert_eq!(exchange(&mut client, &[5, 2, 2, 0], 2).await, [5, 0]);
+}
+
+#[tokio::test]
+async fn method_outside_the_priority_is_not_acceptable() {
+    let (mut client, handler) = proxy_client(&with_priority(vec![2]));
+    assert_eq!(exchange(&mut client, &[5, 1, 0], 2).await, [5, 0xFF]);
+    assert_eq!(handler.await.unwrap().unwrap(), ConnectionOutcome::Refused);
+}
@@ -0,0 +1,121 @@
+use stpro::{parse_split_config, DesyncConfig, DesyncEngine, SplitConfig, WriteOp};
+
+fn parse(s: &str) -> SplitConfig {
+    parse_split_config(s).unwrap()
+}
+
+fn error(s: &str) -> String {
+    parse_split_config(s).unwrap_err().to_string()
+}
+
+/// Segment boundaries `rule` produces in a `len`-byte buffer
+fn cuts(rule: &str, len: usize) -> Vec<usize> {
+    let engine = DesyncEngine::new(DesyncConfig {
+        split: vec![parse(rule)],
+        ..DesyncConfig::default()
+    });
+    engine
+        .plan_writes(&vec![0u8; len])
+        .iter()
+        .filter_map(|op| match op {
+            WriteOp::Segment(range) if range.end < len => Some(range.end),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn plain_offset() {
+    let rule = parse("5");
+    assert_eq!(rule.offset, 5);
+    assert!(!rule.flags.percent);
+    assert_eq!((rule.repeats, rule.skip), (None, None));
+    assert_eq!(parse("-3").offset, -3);
+}
+
+#[test]
+fn offset_with_flags() {
+    let rule = parse("1+sm");
+    assert_eq!(rule.offset, 1);
+    assert!(rule.flags.sni && rule.flags.middle);
+    assert!(!rule.flags.host);
+}
+
+#[test]
+fn repeats_and_skip() {
+    let rule = parse("2:4:8+e");
+    assert_eq!((rule.offset, rule.repeats, rule.skip), (2, Some(4), Some(8)));
+    assert!(rule.flags.end);
+    assert_eq!(parse("2:4").repeats, Some(4));
+    assert_eq!(parse("2:4").skip, None);
+}
+
+#[test]
+fn percent_offset() {
+    let rule = parse("50%");
+    assert_eq!(rule.offset, 50);
+    assert!(rule.flags.percent);
+    assert_eq!(rule.fixed_offset(), None);
+    
+    let rule = parse("-25%:2:3+h");
+    assert_eq!((rule.offset, rule.repeats, rule.skip), (-25, Some(2), Some(3)));
+    assert!(rule.flags.percent && rule.flags.host);
+}
+
+#[test]
+fn range_form() {
+    let rule = parse("5-100/10");
+    assert_eq!((rule.offset, rule.repeats, rule.skip), (5, Some(10), Some(10)));
+    
+    let rule = parse("-10--2/4+e");
+    assert_eq!((rule.offset, rule.repeats, rule.skip), (-10, Some(3), Some(4)));
+    assert!(rule.flags.end);
+    
+    assert_eq!(parse("7-7/1").repeats, Some(1));
+}
+
+#[test]
+fn percent_is_taken_of_the_buffer_length() {
+    assert_eq!(cuts("50%", 200), [100]);
+    assert_eq!(cuts("50%", 30), [15]);
+    assert_eq!(cuts("-10%", 200), [180]);
+    assert_eq!(cuts("50%+m", 200), [50]);
+    assert_eq!(cuts("25%:3:10", 100), [25, 35, 45]);
+}
+
+#[test]
+fn repeats_step_by_skip() {
+    assert_eq!(cuts("2:3:10", 100), [2, 12, 22]);
+    assert_eq!(cuts("2:1:10", 100), [2]);
+    // Repeats past the end of the buffer are dropped
+    assert_eq!(cuts("2:3:10", 20), [2, 12]);
+}
+
+#[test]
+fn range_splits_every_step_up_to_the_end() {
+    assert_eq!(cuts("5-100/10", 200), [5, 15, 25, 35, 45, 55, 65, 75, 85, 95]);
+    assert_eq!(cuts("5-100/10", 200), cuts("5:10:10", 200));
+    assert_eq!(cuts("10-20/5", 200), [10, 15, 20]);
+}
+
+#[test]
+fn malformed_rules_are_rejected() {
+    assert!(error("1+x").contains("valid flags: s, n, h, e, m, r, a"));
+    assert!(error("+s").contains("Missing offset"));
+    assert!(error("%").contains("Missing offset"));
+    assert!(error("1:x").contains("Invalid repeats 'x'"));
+    assert!(error("1:2:y").contains("Invalid skip 'y'"));
+    assert!(error("1:2:3:4").contains("expected offset[:repeats[:skip]][+flags]"));
+    assert!(error("abc").contains("Invalid offset 'abc'"));
+    assert!(error("5%%").contains("Invalid offset '5%'"));
+}
+
+#[test]
+fn malformed_ranges_are_rejected() {
+    assert!(error("5/10").contains("expected start-end/step"));
+    assert!(error("5-x/10").contains("Invalid range end 'x'"));
+    assert!(error("5-10/0").contains("step must be positive"));
+    assert!(error("5-10/-1").contains("step must be positive"));
+    assert!(error("10-5/1").contains("ends before it starts"));
+    assert!(error("5%-10/1").contains("Invalid range start '5%'"));
+}
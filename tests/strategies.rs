@@ -0,0 +1,112 @@
+mod common;
+
+use common::{client_hello, socks5_tunnel, socks5_tunnel_handled, ResettingServer};
+use std::sync::Arc;
+use std::time::Duration;
+use stpro::{Config, DesyncConfig, ProxyServer, SplitConfig, SplitFlags, StrategyStats};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn split_at(offset: i64) -> DesyncConfig {
+    DesyncConfig {
+        split: vec![SplitConfig {
+            offset,
+            flags: SplitFlags::default(),
+            repeats: None,
+            skip: None,
+            jitter: None,
+        }],
+        ..DesyncConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn reset_first_flight_is_retried_with_next_strategy() {
+    let target = ResettingServer::start(1).await;
+    let server = Arc::new(ProxyServer::new(Config {
+        strategies: vec![split_at(1), split_at(2)],
+        desync_ports: Vec::new(),
+        ..Config::default()
+    }));
+    
+    let mut client = socks5_tunnel(&server, target.addr).await;
+    client.write_all(&client_hello("blocked.example.com")).await.unwrap();
+    let mut response = [0u8; ResettingServer::RESPONSE.len()];
+    client.read_exact(&mut response).await.unwrap();
+    
+    assert_eq!(response, ResettingServer::RESPONSE);
+    assert_eq!(target.connections(), 2);
+}
+
+#[tokio::test]
+async fn retries_stop_at_strategy_retries() {
+    let target = ResettingServer::start(usize::MAX).await;
+    let server = Arc::new(ProxyServer::new(Config {
+        strategies: vec![split_at(1), split_at(2), split_at(3)],
+        strategy_retries: Some(1),
+        desync_ports: Vec::new(),
+        ..Config::default()
+    }));
+    
+    let mut client = socks5_tunnel(&server, target.addr).await;
+    client.write_all(&client_hello("blocked.example.com")).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    let _ = client.read_to_end(&mut response).await;
+    
+    assert!(response.is_empty());
+    assert_eq!(target.connections(), 2);
+}
+
+#[tokio::test]
+async fn blocked_and_working_strategies_are_counted_apart() {
+    let target = ResettingServer::start(1).await;
+    let disorder = DesyncConfig { disorder: split_at(2).split, ..DesyncConfig::default() };
+    let server = Arc::new(ProxyServer::new(Config {
+        desync: split_at(1),
+        strategies: vec![disorder],
+        desync_ports: Vec::new(),
+        // The target holds the tunnel open, so let it idle out
+        idle_timeout: Some(Duration::from_millis(200)),
+        ..Config::default()
+    }));
+    
+    let (mut client, handler) = socks5_tunnel_handled(&server, target.addr).await;
+    client.write_all(&client_hello("blocked.example.com")).await.unwrap();
+    let mut response = [0u8; ResettingServer::RESPONSE.len()];
+    client.read_exact(&mut response).await.unwrap();
+    drop(client);
+    handler.await.unwrap().unwrap();
+    
+    let failed = StrategyStats { attempts: 1, successes: 0, failures: 1 };
+    let worked = StrategyStats { attempts: 1, successes: 1, failures: 0 };
+    assert_eq!(
+        server.stats().strategy_stats(),
+        [("disorder#1".into(), worked), ("split#0".into(), failed)]
+    );
+}
+
+#[tokio::test]
+async fn strategies_with_the_same_technique_are_counted_apart() {
+    let target = ResettingServer::start(1).await;
+    let server = Arc::new(ProxyServer::new(Config {
+        desync: split_at(1),
+        strategies: vec![split_at(2)],
+        desync_ports: Vec::new(),
+        idle_timeout: Some(Duration::from_millis(200)),
+        ..Config::default()
+    }));
+    
+    let (mut client, handler) = socks5_tunnel_handled(&server, target.addr).await;
+    client.write_all(&client_hello("blocked.example.com")).await.unwrap();
+    let mut response = [0u8; ResettingServer::RESPONSE.len()];
+    client.read_exact(&mut response).await.unwrap();
+    drop(client);
+    handler.await.unwrap().unwrap();
+    
+    let failed = StrategyStats { attempts: 1, successes: 0, failures: 1 };
+    let worked = StrategyStats { attempts: 1, successes: 1, failures: 0 };
+    assert_eq!(
+        server.stats().strategy_stats(),
+        [("split#0".into(), failed), ("split#1".into(), worked)]
+    );
+}
@@ -0,0 +1,96 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::Duration;
+
+const STPRO: &str = env!("CARGO_BIN_EXE_stpro");
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// A target that echoes one request and hangs up
+fn echo_once() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0u8; REQUEST.len()];
+        stream.read_exact(&mut request).unwrap();
+        stream.write_all(&request).unwrap();
+    });
+    port
+}
+
+/// Tunnel one request to `target` through the proxy on `port`
+fn tunnel_once(port: u16, target: u16) {
+    let mut client = (0..100)
+        .find_map(|_| {
+            TcpStream::connect(("127.0.0.1", port))
+                .map_err(|_| std::thread::sleep(Duration::from_millis(20)))
+                .ok()
+        })
+        .expect("proxy never came up");
+    client.write_all(&[5, 1, 0]).unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).unwrap();
+    let mut connect = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    connect.extend_from_slice(&target.to_be_bytes());
+    client.write_all(&connect).unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[1], 0, "CONNECT failed");
+    client.write_all(REQUEST).unwrap();
+    let mut echoed = [0u8; REQUEST.len()];
+    client.read_exact(&mut echoed).unwrap();
+}
+
+#[test]
+fn summary_reports_a_finished_connection() {
+    let port = free_port();
+    let config = std::env::temp_dir().join(format!("stpro-summary-{}.toml", std::process::id()));
+    let text = format!(
+        "listen = \"127.0.0.1:{}\"\nsummary_interval = {{ secs = 0, nanos = 200000000 }}\n",
+        port
+    );
+    std::fs::write(&config, text).unwrap();
+    let mut child = Command::new(STPRO)
+        .env_remove("STPRO_CONFIG")
+        .env_remove("STPRO_LISTEN")
+        .arg("--config")
+        .arg(&config)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Read on a thread so a missing summary fails the test instead of
+    // blocking it
+    let stderr = BufReader::new(child.stderr.take().unwrap());
+    let (lines, logged) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in stderr.lines().map_while(Result::ok) {
+            if lines.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    
+    let target = echo_once();
+    tunnel_once(port, target);
+    let target = format!("127.0.0.1:{}", target);
+    // The first summary may predate the connection's close
+    let summary = std::iter::from_fn(|| logged.recv_timeout(Duration::from_secs(5)).ok())
+        .filter_map(|line| line.strip_prefix("[*] Summary: ").map(str::to_string))
+        .find(|summary| summary.contains("closed=1"));
+    child.kill().unwrap();
+    child.wait().unwrap();
+    std::fs::remove_file(&config).ok();
+    
+    let summary = summary.expect("no summary logged");
+    assert!(summary.starts_with("connections total=1 active=0 closed=1"), "{}", summary);
+    assert!(summary.contains("bytes up=27 (+27) down=27 (+27)"), "{}", summary);
+    assert!(summary.contains(&format!("| top {}=1 |", target)), "{}", summary);
+    assert!(summary.contains("| modes none=1 |"), "{}", summary);
+}
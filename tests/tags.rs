@@ -0,0 +1,94 @@
+mod common;
+
+use common::{dial, echo_once, serve, socks5_connect};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+use stpro::{parse_split_config, AccessLogConfig, AccessLogFormat, Config, DesyncConfig};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+
+/// Forward untouched, except for clients tagged `video`, which split
+fn tagged_config(log: &Path) -> Config {
+    let video = DesyncConfig {
+        split: vec![parse_split_config("1").unwrap()],
+        ..DesyncConfig::default()
+    };
+    Config {
+        tag_profiles: HashMap::from([("video".to_string(), video)]),
+        auth_method_priority: vec![0x02, 0x00],
+        desync_ports: Vec::new(),
+        access_log: Some(AccessLogConfig {
+            path: Some(log.to_path_buf()),
+            format: AccessLogFormat::Combined,
+        }),
+        ..Config::default()
+    }
+}
+
+/// Log in as `username`, tunnel one request and hang up
+async fn socks5_as(client: &mut TcpStream, username: &str) {
+    let target = echo_once().await;
+    client.write_all(&[5, 1, 2]).await.unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    assert_eq!(choice, [5, 2]);
+    let login = [&[1, username.len() as u8][..], username.as_bytes(), &[1], b"x"].concat();
+    client.write_all(&login).await.unwrap();
+    let mut status = [0u8; 2];
+    client.read_exact(&mut status).await.unwrap();
+    client.write_all(&socks5_connect(target)).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    client.write_all(REQUEST).await.unwrap();
+    let mut echoed = [0u8; REQUEST.len()];
+    client.read_exact(&mut echoed).await.unwrap();
+}
+
+/// The access log lines of one connection per `username`, in order
+async fn logged(name: &str, usernames: &[&str]) -> Vec<String> {
+    let path = std::env::temp_dir().join(format!("stpro-tags-{}-{}.log", name, std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let (server, addr, running) = serve(tagged_config(&path));
+    
+    for username in usernames {
+        let mut client = dial(addr).await;
+        socks5_as(&mut client, username).await;
+    }
+    let mut lines = Vec::new();
+    for _ in 0..100 {
+        let log = std::fs::read_to_string(&path).unwrap_or_default();
+        lines = log.lines().map(str::to_string).collect();
+        if lines.len() == usernames.len() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    server.shutdown();
+    running.await.unwrap().unwrap();
+    std::fs::remove_file(&path).ok();
+    lines
+}
+
+#[tokio::test]
+async fn username_tag_selects_its_profile() {
+    let lines = logged("socks5", &["tag:video"]).await;
+    assert_eq!(lines.len(), 1, "{:?}", lines);
+    assert!(lines[0].contains(" - tag:video ["), "{}", lines[0]);
+    assert!(lines[0].contains(" \"split\" "), "{}", lines[0]);
+    assert!(lines[0].ends_with(" \"video\""), "{}", lines[0]);
+}
+
+#[tokio::test]
+async fn unknown_tag_is_recorded_with_the_default_strategy() {
+    let lines = logged("unknown", &["tag:music", "alice"]).await;
+    assert_eq!(lines.len(), 2, "{:?}", lines);
+    let line = |needle: &str| lines.iter().find(|line| line.contains(needle)).unwrap().clone();
+    let music = line("tag:music");
+    assert!(music.contains(" \"none\" "), "{}", music);
+    assert!(music.ends_with(" \"music\""), "{}", music);
+    let alice = line(" - alice [");
+    assert!(alice.ends_with(" \"-\""), "{}", alice);
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use common::{echo_server, proxy_client, socks5_connect, socks5_tunnel, BlackHole};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use stpro::{Config, ProxyServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn server(config: Config) -> Arc<ProxyServer> {
+    Arc::new(ProxyServer::new(config))
+}
+
+/// SOCKS5 CONNECT to `target` through `server`, returning the reply code
+async fn connect(server: &Arc<ProxyServer>, target: SocketAddr) -> u8 {
+    let (mut client, _) = proxy_client(server);
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    client.write_all(&socks5_connect(target)).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    reply[1]
+}
+
+#[tokio::test]
+async fn socks5_connect_timeout_is_ttl_expired() {
+    let hole = BlackHole::new();
+    let server = server(Config {
+        connect_timeout: Some(Duration::from_millis(300)),
+        ..Config::default()
+    });
+    
+    let started = Instant::now();
+    assert_eq!(connect(&server, hole.addr).await, 0x06);
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(300), "gave up after {:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(3), "gave up after {:?}", elapsed);
+}
+
+#[tokio::test]
+async fn http_connect_timeout_is_504() {
+    let hole = BlackHole::new();
+    let server = server(Config {
+        connect_timeout: Some(Duration::from_millis(300)),
+        ..Config::default()
+    });
+    
+    let (mut client, _) = proxy_client(&server);
+    let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", hole.addr);
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    assert!(reply.starts_with(b"HTTP/1.1 504 Gateway Timeout\r\n"));
+}
+
+#[tokio::test]
+async fn idle_tunnel_is_closed() {
+    let target = echo_server("127.0.0.1").await;
+    let server = server(Config {
+        idle_timeout: Some(Duration::from_millis(300)),
+        ..Config::default()
+    });
+    
+    let mut client = socks5_tunnel(&server, target).await;
+    client.write_all(b"ping").await.unwrap();
+    let mut echoed = [0u8; 4];
+    client.read_exact(&mut echoed).await.unwrap();
+    
+    let started = Instant::now();
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(250), "closed after {:?}", elapsed);
+}
@@ -0,0 +1,98 @@
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use stpro::{parse_split_config, Config, DesyncConfig, ProxyServer};
+
+/// Whether `program` can be run from PATH
+fn available(program: &str) -> bool {
+    Command::new(program).arg("--version").output().is_ok_and(|o| o.status.success())
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Write a throwaway self-signed certificate for localhost into `dir`
+fn self_signed(dir: &Path) {
+    let status = Command::new("openssl")
+        .args(["req", "-x509", "-newkey", "ec", "-pkeyopt", "ec_paramgen_curve:prime256v1"])
+        .args(["-nodes", "-subj", "/CN=localhost", "-days", "1"])
+        .args(["-keyout", "key.pem", "-out", "cert.pem"])
+        .current_dir(dir)
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+/// curl (OpenSSL) fetches a page from `openssl s_server` through the proxy
+/// while `tls_rec` cuts its ClientHello into four records; the server has to
+/// reassemble them and complete the handshake
+#[tokio::test(flavor = "multi_thread")]
+async fn handshake_completes_over_split_records() {
+    if !available("openssl") || !available("curl") {
+        eprintln!("skipping: needs the openssl and curl command-line tools");
+        return;
+    }
+    let dir = std::env::temp_dir().join(format!("stpro-tls-rec-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    self_signed(&dir);
+    
+    let tls_port = free_port();
+    let mut tls_server = Command::new("openssl")
+        .args(["s_server", "-accept", &format!("127.0.0.1:{}", tls_port)])
+        .args(["-cert", "cert.pem", "-key", "key.pem", "-www", "-msg"])
+        .current_dir(&dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut trace = BufReader::new(tls_server.stdout.take().unwrap()).lines();
+    assert!(trace.by_ref().any(|line| line.unwrap() == "ACCEPT"));
+    
+    let proxy_port = free_port();
+    let desync = DesyncConfig {
+        tls_rec: ["20", "60", "200"].iter().map(|r| parse_split_config(r).unwrap()).collect(),
+        ..DesyncConfig::default()
+    };
+    let server = Arc::new(ProxyServer::new(Config {
+        listen: format!("127.0.0.1:{}", proxy_port).parse().unwrap(),
+        desync_ports: Vec::new(),
+        desync,
+        ..Config::default()
+    }));
+    let running = tokio::spawn({
+        let server = server.clone();
+        async move { server.run().await }
+    });
+    
+    let fetched = tokio::task::spawn_blocking(move || {
+        Command::new("curl")
+            .args(["-sk", "--retry", "5", "--retry-connrefused", "--retry-delay", "0"])
+            .args(["--proxy", &format!("socks5://127.0.0.1:{}", proxy_port)])
+            .args(["--resolve", &format!("localhost:{}:127.0.0.1", tls_port)])
+            .args(["-o", "/dev/null", "-w", "%{http_code}"])
+            .arg(format!("https://localhost:{}/", tls_port))
+            .output()
+            .unwrap()
+    })
+    .await
+    .unwrap();
+    
+    server.shutdown();
+    running.await.unwrap().unwrap();
+    tls_server.kill().unwrap();
+    tls_server.wait().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    
+    assert_eq!(String::from_utf8_lossy(&fetched.stdout), "200");
+    // s_server -msg shows each record header it reads
+    let records = trace
+        .map(|line| line.unwrap())
+        .take_while(|line| !line.contains("ClientHello"))
+        .filter(|line| line.starts_with("<<<") && line.contains("RecordHeader"))
+        .count();
+    assert_eq!(records, 4);
+}
@@ -0,0 +1,49 @@
+use stpro::{
+    parse_split_config, sample_client_hello, tls_record_len, DesyncConfig, DesyncEngine, FlowInfo,
+};
+
+/// The record lengths `tls_rec` cuts `hello` into at `positions`
+fn record_lengths(hello: &[u8], positions: &[usize]) -> Vec<usize> {
+    let engine = DesyncEngine::new(DesyncConfig {
+        tls_rec: positions.iter().map(|p| parse_split_config(&p.to_string()).unwrap()).collect(),
+        ..DesyncConfig::default()
+    });
+    let framed = engine.apply_tls_rec(hello, &FlowInfo::default());
+    let mut lengths = Vec::new();
+    let mut rest = &*framed;
+    while let Some(len) = tls_record_len(rest) {
+        lengths.push(len);
+        rest = &rest[len..];
+    }
+    assert!(rest.is_empty());
+    lengths
+}
+
+#[test]
+fn split_leaving_under_five_bytes_is_skipped() {
+    let hello = sample_client_hello("example.com");
+    let end = hello.len();
+    for short in 1..5 {
+        assert_eq!(record_lengths(&hello, &[end - short]), [end], "{} bytes left", short);
+    }
+    assert_eq!(record_lengths(&hello, &[end - 5]), [end - 5, 5 + 5]);
+}
+
+#[test]
+fn split_leaving_under_five_bytes_before_it_is_skipped() {
+    let hello = sample_client_hello("example.com");
+    let end = hello.len();
+    for short in 1..5 {
+        assert_eq!(record_lengths(&hello, &[5 + short]), [end], "{} bytes before", short);
+    }
+    assert_eq!(record_lengths(&hello, &[5 + 5]), [5 + 5, end - 5]);
+}
+
+#[test]
+fn later_cut_counts_as_the_end_for_earlier_ones() {
+    // 3 bytes between the cuts: the later one is kept, the earlier skipped
+    let hello = sample_client_hello("example.com");
+    let end = hello.len();
+    assert_eq!(record_lengths(&hello, &[40, 43]), [43, end - 43 + 5]);
+    assert_eq!(record_lengths(&hello, &[40, 45]), [40, 5 + 5, end - 45 + 5]);
+}
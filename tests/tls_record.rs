@@ -0,0 +1,147 @@
+use std::io::ErrorKind;
+use stpro::{split_tls_record, split_tls_record_multi};
+
+/// A handshake record (TLS 1.0 version) carrying `payload`
+fn record(payload: &[u8]) -> Vec<u8> {
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+fn error_kind(mut buffer: Vec<u8>, position: usize) -> ErrorKind {
+    let before = buffer.clone();
+    let kind = split_tls_record(&mut buffer, position).unwrap_err().kind();
+    assert_eq!(buffer, before, "a failed split must leave the buffer alone");
+    kind
+}
+
+#[test]
+fn splits_a_record_in_two() {
+    let mut buffer = record(b"abcdefgh");
+    split_tls_record(&mut buffer, 8).unwrap();
+    assert_eq!(buffer, [record(b"abc"), record(b"defgh")].concat());
+}
+
+#[test]
+fn position_beyond_the_record_is_invalid_input() {
+    // The record claims 4 bytes, the buffer holds 12
+    let mut buffer = record(b"abcd");
+    buffer.extend_from_slice(b"trailing");
+    assert_eq!(error_kind(buffer.clone(), 9), ErrorKind::InvalidInput);
+    assert_eq!(error_kind(buffer.clone(), 12), ErrorKind::InvalidInput);
+    assert_eq!(error_kind(buffer, usize::MAX), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn position_must_leave_both_records_non_empty() {
+    let buffer = record(b"abcd");
+    assert_eq!(error_kind(buffer.clone(), 5), ErrorKind::InvalidInput);
+    assert_eq!(error_kind(buffer.clone(), 3), ErrorKind::InvalidInput);
+    assert_eq!(error_kind(buffer.clone(), 9), ErrorKind::InvalidInput);
+    
+    let mut buffer = buffer;
+    split_tls_record(&mut buffer, 8).unwrap();
+    assert_eq!(buffer, [record(b"abc"), record(b"d")].concat());
+}
+
+#[test]
+fn short_buffers_are_invalid_input() {
+    assert_eq!(error_kind(Vec::new(), 6), ErrorKind::InvalidInput);
+    assert_eq!(error_kind(vec![0x16, 0x03, 0x01, 0x00], 6), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn only_the_first_of_several_records_is_split() {
+    let mut buffer = [record(b"abcdef"), record(b"second"), record(b"third")].concat();
+    split_tls_record(&mut buffer, 7).unwrap();
+    let expected = [record(b"ab"), record(b"cdef"), record(b"second"), record(b"third")];
+    assert_eq!(buffer, expected.concat());
+    
+    // Positions inside a later record are rejected, not mis-framed
+    let buffer = [record(b"abcdef"), record(b"second")].concat();
+    assert_eq!(error_kind(buffer, 14), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn record_cut_off_by_the_buffer_end_splits_within_the_bytes_present() {
+    // A 10-byte record of which 6 bytes have arrived
+    let mut buffer = record(b"abcdefghij");
+    buffer.truncate(11);
+    split_tls_record(&mut buffer, 8).unwrap();
+    let mut expected = [record(b"abc"), record(b"defghij")].concat();
+    expected.truncate(16);
+    assert_eq!(buffer, expected);
+    
+    // Past the bytes present there is nothing to split
+    let mut buffer = record(b"abcdefghij");
+    buffer.truncate(11);
+    assert_eq!(error_kind(buffer, 12), ErrorKind::InvalidInput);
+}
+
+fn multi_error_kind(mut buffer: Vec<u8>, positions: &[usize]) -> ErrorKind {
+    let before = buffer.clone();
+    let kind = split_tls_record_multi(&mut buffer, positions).unwrap_err().kind();
+    assert_eq!(buffer, before, "a failed split must leave the buffer alone");
+    kind
+}
+
+#[test]
+fn multi_splits_into_several_records() {
+    let mut buffer = record(b"abcdefghij");
+    split_tls_record_multi(&mut buffer, &[7, 10, 11]).unwrap();
+    let expected = [record(b"ab"), record(b"cde"), record(b"f"), record(b"ghij")];
+    assert_eq!(buffer, expected.concat());
+}
+
+#[test]
+fn multi_takes_positions_in_any_order() {
+    let mut sorted = record(b"abcdefghij");
+    split_tls_record_multi(&mut sorted, &[7, 10]).unwrap();
+    let mut unsorted = record(b"abcdefghij");
+    split_tls_record_multi(&mut unsorted, &[10, 7]).unwrap();
+    assert_eq!(sorted, unsorted);
+}
+
+#[test]
+fn multi_matches_repeated_single_splits() {
+    // Cutting from the back keeps the earlier positions where they were
+    let mut single = record(b"abcdefghijklmnop");
+    for pos in [18, 12, 9] {
+        split_tls_record(&mut single, pos).unwrap();
+    }
+    let mut multi = record(b"abcdefghijklmnop");
+    split_tls_record_multi(&mut multi, &[9, 12, 18]).unwrap();
+    assert_eq!(multi, single);
+}
+
+#[test]
+fn multi_keeps_later_records_and_cut_off_data() {
+    let mut buffer = [record(b"abcdef"), record(b"second")].concat();
+    split_tls_record_multi(&mut buffer, &[6, 8]).unwrap();
+    let expected = [record(b"a"), record(b"bc"), record(b"def"), record(b"second")];
+    assert_eq!(buffer, expected.concat());
+    
+    // 6 of 10 payload bytes present: the last record's header still
+    // announces the rest
+    let mut buffer = record(b"abcdefghij");
+    buffer.truncate(11);
+    split_tls_record_multi(&mut buffer, &[7, 9]).unwrap();
+    let mut expected = [record(b"ab"), record(b"cd"), record(b"efghij")].concat();
+    expected.truncate(21);
+    assert_eq!(buffer, expected);
+}
+
+#[test]
+fn multi_rejects_any_invalid_position() {
+    let buffer = record(b"abcdef");
+    assert_eq!(multi_error_kind(buffer.clone(), &[7, 5]), ErrorKind::InvalidInput);
+    assert_eq!(multi_error_kind(buffer.clone(), &[7, 11]), ErrorKind::InvalidInput);
+    assert_eq!(multi_error_kind(buffer.clone(), &[8, 8]), ErrorKind::InvalidInput);
+    assert_eq!(multi_error_kind(buffer.clone(), &[usize::MAX]), ErrorKind::InvalidInput);
+    assert_eq!(multi_error_kind(vec![0x16, 0x03], &[]), ErrorKind::InvalidInput);
+    
+    let mut unchanged = buffer.clone();
+    split_tls_record_multi(&mut unchanged, &[]).unwrap();
+    assert_eq!(unchanged, buffer);
+}
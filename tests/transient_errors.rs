@@ -0,0 +1,137 @@
+mod common;
+
+use common::{echo_once, socks5_connect};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use stpro::{parse_split_config, Config, ConnectionOutcome, DesyncConfig, ProxyServer};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+
+/// SOCKS5 greeting plus an IPv4 CONNECT request
+const HANDSHAKE_LEN: usize = 3 + 10;
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+
+/// Client end of the proxy that fails one read with `error` once the
+/// handshake is through
+struct FailingOnce {
+    inner: DuplexStream,
+    read: usize,
+    error: Option<io::ErrorKind>,
+}
+
+impl AsyncRead for FailingOnce {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.read >= HANDSHAKE_LEN {
+            if let Some(kind) = self.error.take() {
+                return Poll::Ready(Err(kind.into()));
+            }
+        }
+        // The handshake reads are exact-sized, so this counts them off
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.read += buf.filled().len() - before;
+        result
+    }
+}
+
+impl AsyncWrite for FailingOnce {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+    
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Tunnel one request to an echo target through `config`, with the first
+/// forwarding read failing with `error`, and return what came back and how
+/// the connection ended
+async fn relay_past(
+    config: Config,
+    error: io::ErrorKind,
+) -> (Vec<u8>, anyhow::Result<ConnectionOutcome>) {
+    let target: SocketAddr = echo_once().await;
+    let server = Arc::new(ProxyServer::new(config));
+    let (mut client, inner) = tokio::io::duplex(64 * 1024);
+    let stream = FailingOnce { inner, read: 0, error: Some(error) };
+    let handler = tokio::spawn(async move {
+        server.handle_stream(stream, "127.0.0.1:40000".parse().unwrap()).await
+    });
+    
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    client.write_all(&socks5_connect(target)).await.unwrap();
+    let mut replies = [0u8; 2 + 10];
+    client.read_exact(&mut replies).await.unwrap();
+    assert_eq!(replies[3], 0, "CONNECT failed");
+    
+    // A reset tunnel may be gone before the request is in
+    client.write_all(REQUEST).await.ok();
+    let mut echoed = vec![0u8; REQUEST.len()];
+    if client.read_exact(&mut echoed).await.is_err() {
+        echoed.clear();
+    }
+    drop(client);
+    (echoed, handler.await.unwrap())
+}
+
+fn split_config() -> Config {
+    let desync = DesyncConfig {
+        split: vec![parse_split_config("2").unwrap()],
+        ..DesyncConfig::default()
+    };
+    // Every port, so the echo target's is desynced too
+    Config { desync, desync_ports: Vec::new(), ..Config::default() }
+}
+
+#[tokio::test]
+async fn interrupted_read_is_retried() {
+    let (echoed, outcome) = relay_past(Config::default(), io::ErrorKind::Interrupted).await;
+    assert_eq!(echoed, REQUEST);
+    assert_eq!(outcome.unwrap(), ConnectionOutcome::Completed);
+}
+
+#[tokio::test]
+async fn interrupted_read_is_retried_while_desyncing() {
+    let (echoed, outcome) = relay_past(split_config(), io::ErrorKind::Interrupted).await;
+    assert_eq!(echoed, REQUEST);
+    assert_eq!(outcome.unwrap(), ConnectionOutcome::Completed);
+}
+
+#[tokio::test]
+async fn reset_client_closes_cleanly() {
+    let (echoed, outcome) = relay_past(Config::default(), io::ErrorKind::ConnectionReset).await;
+    assert!(echoed.is_empty(), "{:?}", echoed);
+    assert_eq!(outcome.unwrap(), ConnectionOutcome::Completed);
+}
+
+#[tokio::test]
+async fn would_block_read_is_not_retried() {
+    let (echoed, outcome) = relay_past(Config::default(), io::ErrorKind::WouldBlock).await;
+    assert!(echoed.is_empty(), "{:?}", echoed);
+    assert_eq!(outcome.unwrap(), ConnectionOutcome::Completed);
+}
+
+#[tokio::test]
+async fn would_block_read_is_not_retried_while_desyncing() {
+    // The target never hears from the client, so let the tunnel idle out
+    let config = Config { idle_timeout: Some(Duration::from_millis(300)), ..split_config() };
+    let (echoed, outcome) = relay_past(config, io::ErrorKind::WouldBlock).await;
+    assert!(echoed.is_empty(), "{:?}", echoed);
+    assert_eq!(outcome.unwrap(), ConnectionOutcome::Completed);
+}
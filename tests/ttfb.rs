@@ -0,0 +1,74 @@
+mod common;
+
+use common::{finish_request, socks5_tunnel_handled};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use stpro::{parse_split_config, Config, DesyncConfig, ProxyServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const DELAY: Duration = Duration::from_millis(200);
+
+/// A target that echoes one request `DELAY` after it arrived, and hangs up
+async fn slow_echo() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut request = [0u8; 27];
+                stream.read_exact(&mut request).await.unwrap();
+                tokio::time::sleep(DELAY).await;
+                stream.write_all(&request).await.ok();
+            });
+        }
+    });
+    addr
+}
+
+/// Time-to-first-byte recorded for one request through `config`
+async fn ttfb(config: Config) -> Duration {
+    let server = Arc::new(ProxyServer::new(config));
+    let target = slow_echo().await;
+    let (client, handler) = socks5_tunnel_handled(&server, target).await;
+    finish_request(client, handler).await;
+    server.stats().take_summary().avg_ttfb.expect("no ttfb recorded")
+}
+
+#[tokio::test]
+async fn delayed_first_byte_shows_in_ttfb() {
+    let ttfb = ttfb(Config::default()).await;
+    assert!((DELAY..DELAY * 3).contains(&ttfb), "{:?}", ttfb);
+}
+
+#[tokio::test]
+async fn desynced_first_flight_is_timed_too() {
+    let config = Config {
+        desync: DesyncConfig {
+            split: vec![parse_split_config("2").unwrap()],
+            ..DesyncConfig::default()
+        },
+        desync_ports: Vec::new(),
+        ..Config::default()
+    };
+    let ttfb = ttfb(config).await;
+    assert!((DELAY..DELAY * 3).contains(&ttfb), "{:?}", ttfb);
+}
+
+#[tokio::test]
+async fn ttfb_lands_in_the_histogram() {
+    let server = Arc::new(ProxyServer::new(Config::default()));
+    let target = slow_echo().await;
+    let (client, handler) = socks5_tunnel_handled(&server, target).await;
+    finish_request(client, handler).await;
+    
+    let metrics = server.stats().render_prometheus();
+    // Nothing within 0.1 s, the request within 0.5 s
+    let bucket = |le: &str| {
+        let prefix = format!("stpro_ttfb_seconds_bucket{{le=\"{}\"}} ", le);
+        metrics.lines().find_map(|line| line.strip_prefix(&prefix)?.parse::<u64>().ok())
+    };
+    assert_eq!(bucket("0.1"), Some(0), "{}", metrics);
+    assert_eq!(bucket("0.5"), Some(1), "{}", metrics);
+}
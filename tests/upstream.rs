@@ -0,0 +1,106 @@
+mod common;
+
+use common::{dial, echo_server, proxy_client, serve, socks5_connect, socks5_connect_domain};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use stpro::{Config, ProxyServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// SOCKS5 success reply with an all-zero IPv4 bound address
+const SUCCESS: &[u8] = &[5, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+
+/// An upstream SOCKS5 proxy that reads each step's number of bytes and
+/// answers with its reply, then echoes one `ping`; hands back everything
+/// it read
+async fn scripted_upstream(
+    steps: Vec<(usize, &'static [u8])>,
+) -> (SocketAddr, JoinHandle<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let received = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        for (len, reply) in steps {
+            let mut step = vec![0u8; len];
+            stream.read_exact(&mut step).await.unwrap();
+            received.extend_from_slice(&step);
+            stream.write_all(reply).await.unwrap();
+        }
+        let mut ping = [0u8; 4];
+        stream.read_exact(&mut ping).await.unwrap();
+        stream.write_all(&ping).await.unwrap();
+        received
+    });
+    (addr, received)
+}
+
+fn via(upstream: &str) -> Config {
+    Config {
+        upstream: Some(upstream.parse().unwrap()),
+        desync_ports: Vec::new(),
+        ..Config::default()
+    }
+}
+
+/// Open a tunnel through a proxy served by `config` with `request` and
+/// check a `ping` comes back
+async fn ping_through(config: Config, request: &[u8]) {
+    let server = Arc::new(ProxyServer::new(config));
+    let (mut client, _) = proxy_client(&server);
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    assert_eq!(choice, [5, 0]);
+    client.write_all(request).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], 0, "CONNECT failed");
+    
+    client.write_all(b"ping").await.unwrap();
+    let mut echoed = [0u8; 4];
+    client.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"ping");
+}
+
+#[tokio::test]
+async fn domain_target_is_passed_on_unresolved() {
+    let connect = socks5_connect_domain("target.test", 443);
+    let steps = vec![(3, &[5, 0][..]), (connect.len(), SUCCESS)];
+    let (upstream, received) = scripted_upstream(steps).await;
+    ping_through(via(&format!("socks5://{}", upstream)), &connect).await;
+    
+    let mut expected = vec![5, 1, 0];
+    expected.extend_from_slice(&[5, 1, 0, 3, 11]);
+    expected.extend_from_slice(b"target.test");
+    expected.extend_from_slice(&443u16.to_be_bytes());
+    assert_eq!(received.await.unwrap(), expected);
+}
+
+#[tokio::test]
+async fn ip_target_and_credentials() {
+    let target: SocketAddr = "192.0.2.1:443".parse().unwrap();
+    let login_len = 2 + "user".len() + 1 + "secret".len();
+    let steps = vec![(3, &[5, 2][..]), (login_len, &[1, 0][..]), (10, SUCCESS)];
+    let (upstream, received) = scripted_upstream(steps).await;
+    let config = via(&format!("socks5://user:secret@{}", upstream));
+    ping_through(config, &socks5_connect(target)).await;
+    
+    let mut expected = vec![5, 1, 2];
+    expected.extend_from_slice(b"\x01\x04user\x06secret");
+    expected.extend_from_slice(&[5, 1, 0, 1, 192, 0, 2, 1, 1, 187]);
+    assert_eq!(received.await.unwrap(), expected);
+}
+
+#[tokio::test]
+async fn chained_through_another_instance() {
+    let target = echo_server("127.0.0.1").await;
+    let (second, second_addr, running) = serve(Config::default());
+    drop(dial(second_addr).await);
+    
+    ping_through(via(&format!("socks5://{}", second_addr)), &socks5_connect(target)).await;
+    assert_eq!(second.stats().total_connections(), 2);
+    second.shutdown();
+    running.await.unwrap().unwrap();
+}
@@ -0,0 +1,52 @@
+use std::process::{Command, Output};
+
+const CONFLICTING: &str = r#"listen = "0.0.0.0:1080"
+metrics_listen = "0.0.0.0:1080"
+
+[desync]
+ttl = 0
+split = [{ offset = 100000 }]
+"#;
+
+/// Run `stpro validate` on a config file holding `text`
+fn validate(name: &str, text: &str) -> Output {
+    let path = std::env::temp_dir().join(format!("stpro-{}-{}.toml", std::process::id(), name));
+    std::fs::write(&path, text).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_stpro"))
+        .env_remove("STPRO_CONFIG")
+        .arg("validate")
+        .arg("--config")
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).ok();
+    output
+}
+
+#[test]
+fn conflicting_config_fails_with_each_problem_listed() {
+    let output = validate("conflicting", CONFLICTING);
+    
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 5, "{}", stdout);
+    for expected in [
+        "error: metrics_listen: 0.0.0.0:1080 collides with listen 0.0.0.0:1080",
+        "error: desync.ttl: must be between 1 and 255; a TTL of 0 never leaves this host",
+        "warning: desync.split[0]: offset 100000 is outside any 16384-byte first flight",
+    ] {
+        assert!(lines.contains(&expected), "missing {:?} in\n{}", expected, stdout);
+    }
+    assert!(lines.iter().any(|l| l.starts_with("warning: listen:") && l.contains("open proxy")));
+    assert!(lines[4].ends_with("2 error(s), 2 warning(s)"), "{}", stdout);
+}
+
+#[test]
+fn clean_config_passes() {
+    let output = validate("clean", "listen = \"127.0.0.1:1080\"\n");
+    
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.ends_with(": 0 error(s), 0 warning(s)\n"), "{}", stdout);
+}
@@ -0,0 +1,117 @@
+use stpro::{parse_split_config, Config, DesyncConfig, FakeConfig, Severity};
+
+fn with_desync(desync: DesyncConfig) -> Config {
+    Config { desync, ..Config::default() }
+}
+
+fn split(rule: &str) -> DesyncConfig {
+    DesyncConfig { split: vec![parse_split_config(rule).unwrap()], ..DesyncConfig::default() }
+}
+
+fn fake(data: Option<&[u8]>, ttl: Option<u8>) -> FakeConfig {
+    FakeConfig {
+        split: parse_split_config("1").unwrap(),
+        ttl,
+        data: data.map(<[u8]>::to_vec),
+    }
+}
+
+/// Messages of the diagnostics of `severity` whose message starts with `at`
+fn found(config: &Config, severity: Severity, at: &str) -> Vec<String> {
+    config
+        .validate()
+        .into_iter()
+        .filter(|d| d.severity == severity && d.message.starts_with(at))
+        .map(|d| d.message)
+        .collect()
+}
+
+#[test]
+fn default_config_is_clean() {
+    assert_eq!(Config::default().validate(), []);
+    assert_eq!(with_desync(split("1+s")).validate(), []);
+}
+
+#[test]
+fn offset_beyond_any_first_flight() {
+    let config = Config { buffer_size: 16384, ..with_desync(split("20000")) };
+    assert_eq!(found(&config, Severity::Warning, "desync.split[0]").len(), 1);
+    assert_eq!(found(&with_desync(split("-20000")), Severity::Warning, "desync.split[0]").len(), 1);
+    assert!(with_desync(split("100")).validate().is_empty());
+}
+
+#[test]
+fn percentage_beyond_the_buffer() {
+    let messages = found(&with_desync(split("150%")), Severity::Warning, "desync.split[0]");
+    assert_eq!(messages, ["desync.split[0]: offset 150% is beyond the end of the buffer"]);
+    assert!(with_desync(split("100%")).validate().is_empty());
+}
+
+#[test]
+fn ttl_zero_is_an_error() {
+    let config = with_desync(DesyncConfig { ttl: Some(0), ..split("1") });
+    assert_eq!(found(&config, Severity::Error, "desync.ttl").len(), 1);
+    
+    let config = with_desync(DesyncConfig {
+        fake: vec![fake(Some(b"GET / HTTP/1.1\r\n"), Some(0))],
+        ..DesyncConfig::default()
+    });
+    assert_eq!(found(&config, Severity::Error, "desync.fake[0].ttl").len(), 1);
+    
+    let config = with_desync(DesyncConfig { ttl: Some(1), ..split("1") });
+    assert!(config.validate().is_empty());
+}
+
+#[test]
+fn fake_data_must_not_be_empty() {
+    let config = with_desync(DesyncConfig {
+        fake: vec![fake(Some(b""), None)],
+        ..DesyncConfig::default()
+    });
+    assert_eq!(found(&config, Severity::Error, "desync.fake[0].data").len(), 1);
+}
+
+#[test]
+fn fake_without_data_sends_nothing() {
+    let config = with_desync(DesyncConfig {
+        fake: vec![fake(None, None)],
+        ..DesyncConfig::default()
+    });
+    assert_eq!(found(&config, Severity::Warning, "desync.fake[0].data").len(), 1);
+}
+
+#[test]
+fn only_the_first_fake_is_used() {
+    let config = with_desync(DesyncConfig {
+        fake: vec![fake(Some(b"x"), None), fake(Some(b"y"), None)],
+        ..DesyncConfig::default()
+    });
+    let messages = found(&config, Severity::Warning, "desync.fake:");
+    assert_eq!(messages, ["desync.fake: only the first of 2 fakes is used"]);
+}
+
+#[test]
+fn desync_settings_without_rules() {
+    let config = with_desync(DesyncConfig { ttl: Some(3), ..DesyncConfig::default() });
+    let messages = found(&config, Severity::Warning, "desync.ttl");
+    assert_eq!(messages.len(), 1);
+    assert!(messages[0].contains("nothing is desynced"));
+    
+    let config = with_desync(DesyncConfig {
+        desync_only_tls_http: true,
+        ..DesyncConfig::default()
+    });
+    assert_eq!(found(&config, Severity::Warning, "desync.desync_only_tls_http").len(), 1);
+}
+
+#[test]
+fn all_problems_are_reported_together() {
+    let config = with_desync(DesyncConfig {
+        ttl: Some(0),
+        fake: vec![fake(Some(b""), Some(0))],
+        ..split("150%")
+    });
+    let diagnostics = config.validate();
+    assert_eq!(diagnostics.iter().filter(|d| d.is_error()).count(), 3);
+    assert_eq!(diagnostics.iter().filter(|d| !d.is_error()).count(), 1);
+}
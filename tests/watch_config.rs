@@ -0,0 +1,94 @@
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+const STPRO: &str = env!("CARGO_BIN_EXE_stpro");
+
+/// Longer than the watcher's debounce, with room for a slow machine
+const SETTLE: Duration = Duration::from_millis(1500);
+
+/// A server watching its config at `path`, with its stderr lines
+fn watching(path: &Path) -> (Child, Receiver<String>) {
+    let mut command = Command::new(STPRO);
+    for var in ["LISTEN", "SPLIT", "DISORDER", "FAKE", "TTL", "CONFIG"] {
+        command.env_remove(format!("STPRO_{}", var));
+    }
+    let mut child = command
+        .arg("--config")
+        .arg(path)
+        .arg("--watch-config")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    
+    let stderr = BufReader::new(child.stderr.take().unwrap());
+    let (sender, lines) = mpsc::channel();
+    thread::spawn(move || {
+        for line in stderr.lines().map_while(Result::ok) {
+            let _ = sender.send(line);
+        }
+    });
+    let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    assert!(stdout.any(|line| line.unwrap().contains("listening on")));
+    // Give the watcher time to start
+    thread::sleep(Duration::from_millis(300));
+    (child, lines)
+}
+
+/// Config text listening on `port` and splitting at `offset`, with `extra`
+/// desync settings
+fn config(port: u16, offset: u32, extra: &str) -> String {
+    format!(
+        "listen = \"127.0.0.1:{}\"\n\n[desync]\nsplit = [{{ offset = {} }}]\n{}",
+        port, offset, extra
+    )
+}
+
+/// Wait for the watcher to act and return what the server logged meanwhile
+fn settle(lines: &Receiver<String>) -> Vec<String> {
+    thread::sleep(SETTLE);
+    lines.try_iter().collect()
+}
+
+fn count(lines: &[String], needle: &str) -> usize {
+    lines.iter().filter(|line| line.contains(needle)).count()
+}
+
+fn scratch_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("stpro-{}-watch", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn quick_writes_reload_once_and_invalid_files_are_ignored() {
+    let dir = scratch_dir();
+    let path = dir.join("config.toml");
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    std::fs::write(&path, config(port, 1, "")).unwrap();
+    let (mut child, lines) = watching(&path);
+    
+    std::fs::write(&path, config(port, 2, "")).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    std::fs::write(&path, config(port, 3, "")).unwrap();
+    let logged = settle(&lines);
+    assert_eq!(count(&logged, "changed, reloading the config"), 1, "{:#?}", logged);
+    assert_eq!(count(&logged, "Reload failed"), 0, "{:#?}", logged);
+    
+    std::fs::write(&path, config(port, 3, "ttl = 0\n")).unwrap();
+    let logged = settle(&lines);
+    assert_eq!(count(&logged, "Reload failed, keeping the current config"), 1, "{:#?}", logged);
+    
+    // Writing the last good config back changes nothing, so it was kept
+    std::fs::write(&path, config(port, 3, "")).unwrap();
+    let logged = settle(&lines);
+    assert_eq!(count(&logged, "Reload: no changes"), 1, "{:#?}", logged);
+    
+    child.kill().unwrap();
+    child.wait().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,83 @@
+use stpro::{DesyncConfig, SplitConfig};
+
+fn import(options: &str) -> anyhow::Result<DesyncConfig> {
+    let words: Vec<String> = options.split_whitespace().map(String::from).collect();
+    DesyncConfig::from_zapret_args(&words)
+}
+
+fn error(options: &str) -> String {
+    format!("{:#}", import(options).unwrap_err())
+}
+
+/// Offset of each rule, and whether it counts from the hostname
+fn positions(rules: &[SplitConfig]) -> Vec<(i64, bool)> {
+    rules.iter().map(|rule| (rule.offset, rule.flags.auto_anchor)).collect()
+}
+
+#[test]
+fn split_modes() {
+    let config = import("--dpi-desync=split2 --dpi-desync-split-pos=1").unwrap();
+    assert_eq!(positions(&config.split), [(1, false)]);
+    assert!(config.disorder.is_empty() && config.fake.is_empty());
+    
+    // Without a position zapret splits at 2
+    let config = import("--dpi-desync=split").unwrap();
+    assert_eq!(positions(&config.split), [(2, false)]);
+}
+
+#[test]
+fn multi_modes_use_every_position() {
+    let options = "--dpi-desync=multisplit --dpi-desync-split-pos=method+2,host,host-1,-3";
+    let config = import(options).unwrap();
+    assert_eq!(positions(&config.split), [(2, false), (0, true), (-1, true), (-3, false)]);
+    
+    // The others only the first
+    let config = import("--dpi-desync=disorder2 --dpi-desync-split-pos=host+1,5").unwrap();
+    assert_eq!(positions(&config.disorder), [(1, true)]);
+}
+
+#[test]
+fn disorder_with_a_ttl() {
+    let config = import("--dpi-desync multidisorder --dpi-desync-ttl 5").unwrap();
+    assert_eq!(positions(&config.disorder), [(2, false)]);
+    assert_eq!(config.ttl, Some(5));
+}
+
+#[test]
+fn fake_uses_a_decoy_or_the_given_payload() {
+    let config = import("--dpi-desync=fake --dpi-desync-ttl=4").unwrap();
+    assert_eq!(config.fake.len(), 1);
+    assert_eq!(config.fake[0].ttl, Some(4));
+    assert_eq!(config.fake[0].split.offset, 2);
+    assert!(config.fake[0].data.as_ref().is_some_and(|data| data[0] == 0x16));
+    
+    let path = std::env::temp_dir().join(format!("stpro-{}-zapret-fake.bin", std::process::id()));
+    std::fs::write(&path, b"fake payload").unwrap();
+    let config = import(&format!("--dpi-desync=fake --dpi-desync-fake-tls={}", path.display()));
+    std::fs::write(&path, b"").unwrap();
+    let empty = error(&format!("--dpi-desync=fake --dpi-desync-fake-tls={}", path.display()));
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(config.unwrap().fake[0].data.as_deref(), Some(&b"fake payload"[..]));
+    assert!(empty.contains("is empty"), "{}", empty);
+}
+
+#[test]
+fn unsupported_options_are_errors() {
+    let cases = [
+        ("--dpi-desync=split --dpi-desync-fooling=md5sig", "option not supported"),
+        ("--dpi-desync=split --dpi-desync-autottl=2", "option not supported"),
+        ("--dpi-desync=split --hostlist=list.txt", "option not supported"),
+        ("--dpi-desync=fake,split2", "combining modes is not supported"),
+        ("--dpi-desync=syndata", "mode not supported"),
+        ("--dpi-desync=split --dpi-desync-split-pos=midsld", "position \"midsld\" not supported"),
+        ("--dpi-desync=split --dpi-desync-split-pos=host+x", "invalid offset"),
+        ("--dpi-desync=split --dpi-desync-ttl=300", "not a TTL"),
+        ("--dpi-desync=split --dpi-desync-split-pos", "needs a value"),
+        ("--dpi-desync-split-pos=1", "no --dpi-desync mode given"),
+        ("", "no --dpi-desync mode given"),
+    ];
+    for (options, expected) in cases {
+        let message = error(options);
+        assert!(message.contains(expected), "{}: {}", options, message);
+    }
+}